@@ -0,0 +1,162 @@
+//! Where a storage keeps the files of its instances: a local directory
+//! or a bucket of an S3-compatible object store. The files are named by
+//! keys, relative paths separated with `/`.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use color_eyre::eyre::Context;
+use tracing::debug;
+
+use crate::{
+    s3::S3Backend,
+    storage::{stored_sop_instance_uid, write_atomically},
+    utils::Storage,
+};
+
+/// What is known of a stored file without reading all of it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct FileInfo {
+    /// The SOP Instance UID of the instance in the file
+    pub(crate) sop_instance_uid: Option<String>,
+    /// The SHA-256 of the file, in hexadecimal, when the backend keeps it
+    pub(crate) sha256: Option<String>,
+}
+
+/// The files of a storage, shared by the channel writing them and the
+/// threads reading them back or deleting them
+pub(crate) trait StorageBackend: fmt::Debug + Send + Sync {
+    /// Writes the file, replacing any file with the key. The backends
+    /// that can keep `info` along with the file do.
+    fn put(&self, key: &str, bytes: &[u8], info: &FileInfo) -> color_eyre::Result<()>;
+
+    /// Reads the file, if there is one with the key
+    fn get(&self, key: &str) -> color_eyre::Result<Option<Vec<u8>>>;
+
+    /// What is known of the file, if there is one with the key
+    fn head(&self, key: &str) -> color_eyre::Result<Option<FileInfo>>;
+
+    /// Deletes the file, a missing one being already deleted
+    fn delete(&self, key: &str) -> color_eyre::Result<()>;
+
+    /// Where the file is, as shown to the users and kept in the index
+    fn location(&self, key: &str) -> String;
+
+    /// The key of a location given by `location`, if it is one of the
+    /// backend
+    fn key(&self, location: &str) -> Option<String>;
+
+    /// The file of the key on the local file system, for the backends
+    /// that have one
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// The backend of a storage: its bucket if it has one, its `out_dir`
+/// otherwise
+pub(crate) fn build_backend(storage: &Storage) -> color_eyre::Result<Arc<dyn StorageBackend>> {
+    Ok(match &storage.s3 {
+        Some(s3) => Arc::new(S3Backend::new(s3).wrap_err("Invalid S3 storage")?),
+        None => Arc::new(LocalBackend::new(&storage.out_dir)),
+    })
+}
+
+/// The files in a directory, written atomically
+#[derive(Debug)]
+pub(crate) struct LocalBackend {
+    dir: PathBuf,
+}
+
+impl LocalBackend {
+    pub(crate) fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn put(&self, key: &str, bytes: &[u8], _info: &FileInfo) -> color_eyre::Result<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Could not create the directory {}", parent.display()))?;
+        }
+        write_atomically(&path, bytes)
+    }
+
+    fn get(&self, key: &str) -> color_eyre::Result<Option<Vec<u8>>> {
+        let path = self.path(key);
+        match fs::read(&path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).wrap_err_with(|| format!("Could not read {}", path.display())),
+        }
+    }
+
+    /// Reads the file meta information of the file only, the checksum
+    /// is not kept
+    fn head(&self, key: &str) -> color_eyre::Result<Option<FileInfo>> {
+        let path = self.path(key);
+        match File::open(&path) {
+            Ok(file) => Ok(Some(FileInfo {
+                sop_instance_uid: stored_sop_instance_uid(BufReader::new(file)),
+                sha256: None,
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).wrap_err_with(|| format!("Could not read {}", path.display())),
+        }
+    }
+
+    /// Also removes the directories left empty
+    fn delete(&self, key: &str) -> color_eyre::Result<()> {
+        let path = self.path(key);
+        match fs::remove_file(&path) {
+            Ok(()) => debug!("Deleted {}", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Could not delete {}", path.display()))
+            }
+        }
+        remove_empty_parents(&path, &self.dir);
+        Ok(())
+    }
+
+    fn location(&self, key: &str) -> String {
+        self.path(key).display().to_string()
+    }
+
+    fn key(&self, location: &str) -> Option<String> {
+        let relative = Path::new(location).strip_prefix(&self.dir).ok()?;
+        let components: Vec<String> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect();
+        (!components.is_empty()).then(|| components.join("/"))
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+}
+
+/// Removes the directories of the path left empty, up to `root`
+fn remove_empty_parents(path: &Path, root: &Path) {
+    let mut current = path.parent();
+    while let Some(dir) = current {
+        if dir == root || !dir.starts_with(root) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+        current = dir.parent();
+    }
+}
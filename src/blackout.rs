@@ -0,0 +1,558 @@
+//! Blacking out the regions of the pixels where some devices burn the
+//! patient's identity, for the de-identified destinations. The pixel
+//! data is decoded when compressed: RLE Lossless is encoded again, the
+//! JPEG transfer syntaxes are written uncompressed as there is no JPEG
+//! encoder, which also spares a second lossy compression.
+
+use color_eyre::eyre::{bail, eyre, ContextCompat};
+use dicom::{
+    core::{
+        value::{Value, C},
+        DataElement, PrimitiveValue, Tag, VR,
+    },
+    dicom_value,
+    dictionary_std::tags,
+    encoding::{transfer_syntax::Codec, TransferSyntaxIndex},
+    object::{DefaultDicomObject, InMemDicomObject},
+    transfer_syntax::TransferSyntaxRegistry,
+};
+
+use tracing::warn;
+
+use crate::utils::{BlackoutMask, Region};
+
+const RLE_LOSSLESS: &str = "1.2.840.10008.1.2.5";
+const EXPLICIT_VR_LE: &str = "1.2.840.10008.1.2.1";
+/// The lossy JPEG transfer syntaxes, baseline and extended
+const JPEG_LOSSY: [&str; 2] = ["1.2.840.10008.1.2.4.50", "1.2.840.10008.1.2.4.51"];
+/// The most segments an RLE frame can have
+const MAX_RLE_SEGMENTS: usize = 15;
+
+/// Blacks out the regions of the masks matching the instance and sets
+/// BurnedInAnnotation to NO, returning whether the pixels were cleaned.
+/// An instance with burned in annotations that no mask covers is an
+/// error, for it not to leave with them.
+pub(crate) fn apply(
+    object: &mut DefaultDicomObject,
+    masks: &[BlackoutMask],
+) -> color_eyre::Result<bool> {
+    let regions: Vec<Region> = masks
+        .iter()
+        .filter(|mask| matches(mask, object))
+        .flat_map(|mask| mask.regions.iter().copied())
+        .collect();
+    if regions.is_empty() {
+        if !masks.is_empty() && value_of(object, tags::BURNED_IN_ANNOTATION) == "YES" {
+            bail!(
+                "It has burned in annotations and no blackout mask matches {} {} ({}x{})",
+                value_of(object, tags::MANUFACTURER),
+                value_of(object, tags::MANUFACTURER_MODEL_NAME),
+                value_of(object, tags::COLUMNS),
+                value_of(object, tags::ROWS)
+            );
+        }
+        return Ok(false);
+    }
+    if object.element(tags::PIXEL_DATA).is_err() {
+        return Ok(false);
+    }
+
+    let uid = object
+        .meta()
+        .transfer_syntax()
+        .trim_end_matches('\0')
+        .to_string();
+    let ts = TransferSyntaxRegistry
+        .get(&uid)
+        .wrap_err_with(|| format!("Unknown transfer syntax {}", uid))?;
+    let mut layout = Layout::of(object)?;
+    match ts.codec() {
+        Codec::None => {
+            if layout.photometric.ends_with("_422") {
+                bail!(
+                    "Cannot black out {} pixels, they are subsampled",
+                    layout.photometric
+                );
+            }
+            let element = object.element(tags::PIXEL_DATA)?;
+            let vr = element.vr();
+            let (mut data, words) = match element.value() {
+                Value::Primitive(PrimitiveValue::U8(bytes)) => (bytes.to_vec(), false),
+                Value::Primitive(PrimitiveValue::U16(words)) => (
+                    words.iter().flat_map(|word| word.to_le_bytes()).collect(),
+                    true,
+                ),
+                _ => bail!("Unexpected pixel data value"),
+            };
+            layout.fill(&mut data, &regions);
+            put_native(object, vr, data, words);
+        }
+        Codec::PixelData(adapter) => {
+            let mut data = Vec::new();
+            adapter
+                .decode(&*object, &mut data)
+                .map_err(|e| eyre!("Could not decode the {} pixel data: {}", ts.name(), e))?;
+            if uid == RLE_LOSSLESS {
+                // The frames are decoded a plane after the other
+                layout.planar = true;
+                layout.fill(&mut data, &regions);
+                let fragments = layout.encode_rle(&data)?;
+                object.put(DataElement::new(
+                    tags::PIXEL_DATA,
+                    VR::OB,
+                    Value::PixelSequence {
+                        offset_table: C::new(),
+                        fragments: fragments.into(),
+                    },
+                ));
+            } else {
+                layout.planar = false;
+                if layout.samples == 3 {
+                    layout.photometric = "RGB".to_string();
+                }
+                if layout.bytes_per_sample == 2 {
+                    // The JPEG decoder writes the samples in native order
+                    for sample in data.chunks_exact_mut(2) {
+                        let value = u16::from_ne_bytes([sample[0], sample[1]]);
+                        sample.copy_from_slice(&value.to_le_bytes());
+                    }
+                }
+                layout.fill(&mut data, &regions);
+                let photometric = value_of(object, tags::PHOTOMETRIC_INTERPRETATION);
+                write_uncompressed(object, &layout, data, JPEG_LOSSY.contains(&uid.as_str()));
+                let changed = if photometric == layout.photometric {
+                    String::new()
+                } else {
+                    format!(", from {} to {}", photometric, layout.photometric)
+                };
+                warn!(
+                    "The pixels of {} are blacked out and sent uncompressed, \
+                    as Explicit VR Little Endian instead of {}{}",
+                    object.meta().media_storage_sop_instance_uid(),
+                    ts.name(),
+                    changed
+                );
+            }
+        }
+        _ => bail!("Cannot decode the {} pixel data to black it out", ts.name()),
+    }
+
+    object.put(DataElement::new(
+        tags::BURNED_IN_ANNOTATION,
+        VR::CS,
+        dicom_value!(Str, "NO"),
+    ));
+    Ok(true)
+}
+
+/// Whether the instance comes from the device of the mask
+fn matches(mask: &BlackoutMask, object: &InMemDicomObject) -> bool {
+    let same_text = |expected: &Option<String>, tag| {
+        expected
+            .as_ref()
+            .is_none_or(|expected| expected.trim().eq_ignore_ascii_case(&value_of(object, tag)))
+    };
+    let same_size = |expected: Option<u16>, tag| {
+        expected.is_none_or(|expected| {
+            object
+                .element(tag)
+                .ok()
+                .and_then(|element| element.to_int::<u16>().ok())
+                == Some(expected)
+        })
+    };
+    same_text(&mask.manufacturer, tags::MANUFACTURER)
+        && same_text(&mask.model, tags::MANUFACTURER_MODEL_NAME)
+        && same_size(mask.rows, tags::ROWS)
+        && same_size(mask.columns, tags::COLUMNS)
+}
+
+fn value_of(object: &InMemDicomObject, tag: Tag) -> String {
+    object
+        .element(tag)
+        .ok()
+        .and_then(|element| element.to_str().ok())
+        .map(|value| value.trim_matches([' ', '\0']).to_string())
+        .unwrap_or_default()
+}
+
+/// How the samples of the decoded frames are laid out, little endian
+#[derive(Debug)]
+struct Layout {
+    rows: usize,
+    columns: usize,
+    samples: usize,
+    bytes_per_sample: usize,
+    bits_stored: u16,
+    signed: bool,
+    photometric: String,
+    /// Whether each frame holds a plane per sample, rather than the
+    /// samples of each pixel together
+    planar: bool,
+}
+
+impl Layout {
+    fn of(object: &InMemDicomObject) -> color_eyre::Result<Self> {
+        let int = |tag, name| {
+            object
+                .element(tag)
+                .ok()
+                .and_then(|element| element.to_int::<u16>().ok())
+                .wrap_err_with(|| format!("Missing {}", name))
+        };
+        let samples = int(tags::SAMPLES_PER_PIXEL, "SamplesPerPixel")?;
+        let bits_allocated = int(tags::BITS_ALLOCATED, "BitsAllocated")?;
+        if bits_allocated != 8 && bits_allocated != 16 {
+            bail!("Cannot black out pixels of {} bits", bits_allocated);
+        }
+        Ok(Self {
+            rows: int(tags::ROWS, "Rows")?.into(),
+            columns: int(tags::COLUMNS, "Columns")?.into(),
+            samples: samples.into(),
+            bytes_per_sample: (bits_allocated / 8).into(),
+            bits_stored: int(tags::BITS_STORED, "BitsStored")
+                .unwrap_or(bits_allocated)
+                .clamp(1, bits_allocated),
+            signed: int(tags::PIXEL_REPRESENTATION, "PixelRepresentation").unwrap_or(0) == 1,
+            photometric: value_of(object, tags::PHOTOMETRIC_INTERPRETATION),
+            planar: samples > 1 && int(tags::PLANAR_CONFIGURATION, "").unwrap_or(0) == 1,
+        })
+    }
+
+    fn frame_len(&self) -> usize {
+        self.rows * self.columns * self.samples * self.bytes_per_sample
+    }
+
+    fn offset(&self, frame: usize, row: usize, column: usize, sample: usize) -> usize {
+        let pixel = row * self.columns + column;
+        let index = if self.planar {
+            sample * self.rows * self.columns + pixel
+        } else {
+            pixel * self.samples + sample
+        };
+        frame * self.frame_len() + index * self.bytes_per_sample
+    }
+
+    /// The samples of a black pixel
+    fn black(&self) -> Vec<u16> {
+        let max = ((1u32 << self.bits_stored) - 1) as u16;
+        let half = (1u32 << (self.bits_stored - 1)) as u16;
+        match self.photometric.as_str() {
+            "MONOCHROME1" if self.signed => vec![half - 1],
+            "MONOCHROME1" => vec![max],
+            // The smallest value, sign extended
+            "MONOCHROME2" if self.signed => vec![half.wrapping_neg()],
+            photometric if photometric.starts_with("YBR") => {
+                let mut black = vec![half; self.samples];
+                black[0] = 0;
+                black
+            }
+            _ => vec![0; self.samples],
+        }
+    }
+
+    fn fill(&self, data: &mut [u8], regions: &[Region]) {
+        let black = self.black();
+        let frames = data.len() / self.frame_len().max(1);
+        for frame in 0..frames {
+            for region in regions {
+                let rows = usize::from(region.y)
+                    ..(usize::from(region.y) + usize::from(region.height)).min(self.rows);
+                let columns = usize::from(region.x)
+                    ..(usize::from(region.x) + usize::from(region.width)).min(self.columns);
+                for row in rows {
+                    for column in columns.clone() {
+                        for (sample, value) in black.iter().enumerate() {
+                            let offset = self.offset(frame, row, column, sample);
+                            data[offset..offset + self.bytes_per_sample]
+                                .copy_from_slice(&value.to_le_bytes()[..self.bytes_per_sample]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Encodes the planar frames as RLE Lossless, a fragment per frame
+    /// with a segment per byte of each sample, most significant first
+    fn encode_rle(&self, data: &[u8]) -> color_eyre::Result<Vec<Vec<u8>>> {
+        let segments = self.samples * self.bytes_per_sample;
+        if segments > MAX_RLE_SEGMENTS {
+            bail!("Too many samples for RLE Lossless");
+        }
+        let frames = data.len() / self.frame_len().max(1);
+        let mut fragments = Vec::with_capacity(frames);
+        for frame in 0..frames {
+            let mut header = vec![0u8; 64];
+            header[..4].copy_from_slice(&(segments as u32).to_le_bytes());
+            let mut body = Vec::new();
+            let mut index = 0;
+            for sample in 0..self.samples {
+                for byte in (0..self.bytes_per_sample).rev() {
+                    let offset = (64 + body.len()) as u32;
+                    index += 1;
+                    header[index * 4..index * 4 + 4].copy_from_slice(&offset.to_le_bytes());
+                    for row in 0..self.rows {
+                        let bytes: Vec<u8> = (0..self.columns)
+                            .map(|column| data[self.offset(frame, row, column, sample) + byte])
+                            .collect();
+                        pack_bits(&bytes, &mut body);
+                    }
+                    // Segments have an even length, padded with a no-op
+                    if body.len() % 2 == 1 {
+                        body.push(0x80);
+                    }
+                }
+            }
+            header.extend(body);
+            fragments.push(header);
+        }
+        Ok(fragments)
+    }
+}
+
+/// Appends the PackBits encoding of a row: runs of a repeated byte and
+/// literal bytes, at most 128 at a time
+fn pack_bits(row: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < row.len() {
+        let mut run = 1;
+        while i + run < row.len() && run < 128 && row[i + run] == row[i] {
+            run += 1;
+        }
+        if run > 1 {
+            out.push((1 - run as i16) as u8);
+            out.push(row[i]);
+            i += run;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        while i < row.len() && i - start < 128 && !(i + 1 < row.len() && row[i] == row[i + 1]) {
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&row[start..i]);
+    }
+}
+
+fn put_native(object: &mut DefaultDicomObject, vr: VR, data: Vec<u8>, words: bool) {
+    let value = if words {
+        PrimitiveValue::U16(
+            data.chunks_exact(2)
+                .map(|word| u16::from_le_bytes([word[0], word[1]]))
+                .collect(),
+        )
+    } else {
+        PrimitiveValue::U8(data.into())
+    };
+    object.put(DataElement::new(tags::PIXEL_DATA, vr, value));
+}
+
+/// Replaces the decoded JPEG pixel data with the uncompressed pixels,
+/// keeping the trace of a lossy compression
+fn write_uncompressed(
+    object: &mut DefaultDicomObject,
+    layout: &Layout,
+    data: Vec<u8>,
+    lossy: bool,
+) {
+    let words = layout.bytes_per_sample == 2;
+    put_native(object, if words { VR::OW } else { VR::OB }, data, words);
+    object.put(DataElement::new(
+        tags::PHOTOMETRIC_INTERPRETATION,
+        VR::CS,
+        dicom_value!(Str, layout.photometric.as_str()),
+    ));
+    if layout.samples > 1 {
+        object.put(DataElement::new(
+            tags::PLANAR_CONFIGURATION,
+            VR::US,
+            dicom_value!(U16, [0]),
+        ));
+    }
+    if lossy && value_of(object, tags::LOSSY_IMAGE_COMPRESSION) != "01" {
+        object.put(DataElement::new(
+            tags::LOSSY_IMAGE_COMPRESSION,
+            VR::CS,
+            dicom_value!(Str, "01"),
+        ));
+        object.put(DataElement::new(
+            tags::LOSSY_IMAGE_COMPRESSION_METHOD,
+            VR::CS,
+            dicom_value!(Str, "ISO_10918_1"),
+        ));
+    }
+    let meta = object.meta_mut();
+    meta.transfer_syntax = format!("{}\0", EXPLICIT_VR_LE);
+    meta.update_information_group_length();
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::object::FileMetaTableBuilder;
+
+    use super::*;
+
+    const REGION: Region = Region {
+        x: 1,
+        y: 1,
+        width: 2,
+        height: 5,
+    };
+
+    /// An instance of the ACME device, its pixel data left to the test
+    fn image(
+        transfer_syntax: &str,
+        rows: u16,
+        columns: u16,
+        samples: u16,
+        bits: (u16, u16),
+        photometric: &str,
+    ) -> DefaultDicomObject {
+        let object = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::MANUFACTURER, VR::LO, dicom_value!(Str, "ACME")),
+            DataElement::new(tags::BURNED_IN_ANNOTATION, VR::CS, dicom_value!(Str, "YES")),
+            DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, dicom_value!(U16, samples)),
+            DataElement::new(
+                tags::PHOTOMETRIC_INTERPRETATION,
+                VR::CS,
+                dicom_value!(Str, photometric),
+            ),
+            DataElement::new(tags::ROWS, VR::US, dicom_value!(U16, rows)),
+            DataElement::new(tags::COLUMNS, VR::US, dicom_value!(U16, columns)),
+            DataElement::new(tags::BITS_ALLOCATED, VR::US, dicom_value!(U16, bits.0)),
+            DataElement::new(tags::BITS_STORED, VR::US, dicom_value!(U16, bits.1)),
+            DataElement::new(tags::HIGH_BIT, VR::US, dicom_value!(U16, bits.1 - 1)),
+            DataElement::new(tags::PIXEL_REPRESENTATION, VR::US, dicom_value!(U16, 0)),
+        ]);
+        let meta = FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+            .media_storage_sop_instance_uid("1.2.3.4.5.1")
+            .transfer_syntax(transfer_syntax)
+            .build()
+            .unwrap();
+        object.with_exact_meta(meta)
+    }
+
+    fn masks(manufacturer: &str) -> Vec<BlackoutMask> {
+        vec![BlackoutMask {
+            manufacturer: Some(manufacturer.to_string()),
+            regions: vec![REGION],
+            ..Default::default()
+        }]
+    }
+
+    fn in_region(row: usize, column: usize) -> bool {
+        (1..3).contains(&column) && row >= 1
+    }
+
+    /// Decodes the pixel data as a destination would
+    fn decode_rle(object: &DefaultDicomObject) -> Vec<u8> {
+        let Codec::PixelData(adapter) = TransferSyntaxRegistry.get(RLE_LOSSLESS).unwrap().codec()
+        else {
+            panic!("No RLE Lossless decoder");
+        };
+        let mut data = Vec::new();
+        adapter.decode(object, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn blacks_out_native_pixels_on_every_frame() {
+        let mut object = image(EXPLICIT_VR_LE, 4, 4, 1, (8, 8), "MONOCHROME2");
+        object.put(DataElement::new(
+            tags::NUMBER_OF_FRAMES,
+            VR::IS,
+            dicom_value!(Str, "2"),
+        ));
+        let pixels: Vec<u8> = (1..=32).collect();
+        put_native(&mut object, VR::OB, pixels.clone(), false);
+
+        assert!(apply(&mut object, &masks("acme")).unwrap());
+        let element = object.element(tags::PIXEL_DATA).unwrap();
+        let blacked = element.to_bytes().unwrap();
+        for (i, (&before, &after)) in pixels.iter().zip(blacked.iter()).enumerate() {
+            let (row, column) = ((i % 16) / 4, i % 4);
+            let expected = if in_region(row, column) { 0 } else { before };
+            assert_eq!(after, expected, "pixel {}", i);
+        }
+        assert_eq!(value_of(&object, tags::BURNED_IN_ANNOTATION), "NO");
+        assert_eq!(object.meta().transfer_syntax(), EXPLICIT_VR_LE);
+    }
+
+    #[test]
+    fn black_follows_the_photometric_interpretation() {
+        let mut object = image(EXPLICIT_VR_LE, 2, 2, 1, (16, 12), "MONOCHROME1");
+        put_native(&mut object, VR::OW, [0x34, 0x12].repeat(4), true);
+        assert!(apply(&mut object, &masks("ACME")).unwrap());
+        let element = object.element(tags::PIXEL_DATA).unwrap();
+        assert_eq!(element.vr(), VR::OW);
+        assert_eq!(
+            element.to_multi_int::<u16>().unwrap(),
+            [0x1234, 0x1234, 0x1234, 0x0fff]
+        );
+
+        let mut object = image(EXPLICIT_VR_LE, 2, 2, 1, (16, 12), "MONOCHROME2");
+        object.put(DataElement::new(
+            tags::PIXEL_REPRESENTATION,
+            VR::US,
+            dicom_value!(U16, 1),
+        ));
+        let layout = Layout::of(&object).unwrap();
+        assert_eq!(layout.black(), [0xf800]);
+        let layout = Layout::of(&image(EXPLICIT_VR_LE, 2, 2, 3, (8, 8), "YBR_FULL")).unwrap();
+        assert_eq!(layout.black(), [0, 0x80, 0x80]);
+    }
+
+    #[test]
+    fn blacks_out_rle_pixels_and_compresses_them_again() {
+        // Wide enough for runs and literals longer than 128 bytes
+        let (rows, columns) = (3, 300);
+        let mut object = image(RLE_LOSSLESS, rows, columns, 3, (8, 8), "RGB");
+        let mut layout = Layout::of(&object).unwrap();
+        layout.planar = true;
+        let plane = usize::from(rows) * usize::from(columns);
+        let pixels: Vec<u8> = (0..3 * plane)
+            .map(|i| {
+                if i % plane < 150 {
+                    0x40
+                } else {
+                    (i % 251) as u8
+                }
+            })
+            .collect();
+        object.put(DataElement::new(
+            tags::PIXEL_DATA,
+            VR::OB,
+            Value::PixelSequence {
+                offset_table: C::new(),
+                fragments: layout.encode_rle(&pixels).unwrap().into(),
+            },
+        ));
+        // The encoding alone is lossless
+        assert_eq!(decode_rle(&object), pixels);
+
+        assert!(apply(&mut object, &masks("ACME")).unwrap());
+        assert_eq!(object.meta().transfer_syntax(), RLE_LOSSLESS);
+        let blacked = decode_rle(&object);
+        assert_eq!(blacked.len(), pixels.len());
+        for (i, (&before, &after)) in pixels.iter().zip(blacked.iter()).enumerate() {
+            let pixel = i % plane;
+            let (row, column) = (pixel / usize::from(columns), pixel % usize::from(columns));
+            let expected = if in_region(row, column) { 0 } else { before };
+            assert_eq!(after, expected, "sample {}", i);
+        }
+    }
+
+    #[test]
+    fn refuses_burned_in_annotations_without_a_mask() {
+        let mut object = image(EXPLICIT_VR_LE, 2, 2, 1, (8, 8), "MONOCHROME2");
+        put_native(&mut object, VR::OB, vec![1; 4], false);
+        let error = apply(&mut object, &masks("Other")).unwrap_err();
+        assert!(error.to_string().contains("no blackout mask matches ACME"));
+        // Without masks, nothing is blacked out
+        assert!(!apply(&mut object, &[]).unwrap());
+        assert_eq!(value_of(&object, tags::BURNED_IN_ANNOTATION), "YES");
+    }
+}
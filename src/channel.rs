@@ -0,0 +1,102 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use tracing::{info, warn};
+
+use crate::{
+    pipeline::{Bus, Pipeline},
+    retention::Retention,
+    source::build_source,
+    utils::{Channel, Config},
+};
+
+/// A channel running in the background: the thread of its source, the
+/// one of its retention and the signal used to stop them.
+pub(crate) struct RunningChannel {
+    name: String,
+    /// Set to true when the source should shut down
+    shutdown_signal: Arc<AtomicBool>,
+    /// Unblocks the source once the shutdown signal is set
+    waker: Box<dyn Fn() + Send>,
+    handle: JoinHandle<()>,
+    pipeline: Arc<Pipeline>,
+    /// The thread deleting the stored instances, for the channels with
+    /// a retention policy
+    retention: Option<JoinHandle<()>>,
+}
+
+impl RunningChannel {
+    /// Launches the source of the channel
+    pub(crate) fn start(
+        channel: &Channel,
+        config: &Config,
+        bus: &Arc<Bus>,
+    ) -> color_eyre::Result<Self> {
+        let mut source = build_source(channel, config, bus)?;
+        info!("Launching channel {} ({})", channel.name, channel.source);
+        let waker = source.waker();
+        let shutdown_signal = Arc::new(AtomicBool::new(false));
+        let signal = shutdown_signal.clone();
+        let pipeline = Arc::new(Pipeline::new(
+            channel,
+            config,
+            bus.clone(),
+            shutdown_signal.clone(),
+        )?);
+        let retention = channel
+            .storage
+            .as_ref()
+            .zip(pipeline.backend())
+            .and_then(|(storage, backend)| {
+                Retention::new(&channel.name, storage, pipeline.index(), backend)
+            })
+            .map(|retention| retention.start(shutdown_signal.clone()));
+        let source_pipeline = pipeline.clone();
+        let channel_name = channel.name.clone();
+        let handle = thread::spawn(move || {
+            if let Err(e) = source.run(&source_pipeline, &signal) {
+                warn!("The source of channel {} stopped: {:#}", channel_name, e);
+            }
+        });
+        Ok(Self {
+            name: channel.name.clone(),
+            shutdown_signal,
+            waker,
+            handle,
+            pipeline,
+            retention,
+        })
+    }
+
+    /// Reloads the script and the mapping of the channel if their files
+    /// changed
+    pub(crate) fn reload(&self) {
+        self.pipeline.reload();
+    }
+
+    /// Runs the instances released from the quarantine of the channel
+    /// through it again
+    pub(crate) fn release_quarantined(&self) {
+        self.pipeline.release_quarantined();
+    }
+
+    /// Stops the source and the cleanup, waiting for them to release
+    /// their resources
+    pub(crate) fn stop(self) {
+        self.shutdown_signal.store(true, Ordering::SeqCst);
+        (self.waker)();
+        if self.handle.join().is_err() {
+            warn!("The source of channel {} panicked", self.name);
+        }
+        if let Some(retention) = self.retention {
+            if retention.join().is_err() {
+                warn!("The cleanup of channel {} panicked", self.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,565 @@
+//! The normalization of the text of the instances to UTF-8 (ISO_IR 192),
+//! whatever the Specific Character Set they were written with.
+//!
+//! The character sets dicom-rs cannot decode are read as ISO 8859-1,
+//! which keeps the received bytes: they are decoded again here.
+
+use std::fmt;
+
+use color_eyre::eyre::bail;
+use dicom::{
+    core::{value::Value, DataDictionary, DataElement, Length, PrimitiveValue, Tag, VR},
+    dictionary_std::{tags, StandardDataDictionary},
+    encoding::text::SpecificCharacterSet,
+    object::InMemDicomObject,
+};
+use encoding_rs::{
+    Encoding, EUC_JP, EUC_KR, GB18030, GBK, ISO_8859_2, ISO_8859_3, ISO_8859_4, ISO_8859_5,
+    ISO_8859_6, ISO_8859_7, ISO_8859_8, UTF_8, WINDOWS_1254, WINDOWS_874,
+};
+
+/// The Specific Character Set of the normalized instances
+const ISO_IR_192: &str = "ISO_IR 192";
+
+const ESC: u8 = 0x1b;
+
+/// A graphic character set, designated to G0 or G1
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Set {
+    /// ISO 646 (ISO-IR 6), and JIS X 0201 Romaji (ISO-IR 14) read the same
+    Ascii,
+    /// The right-hand part of ISO 8859-1 (ISO-IR 100)
+    Latin1,
+    /// The right-hand part of another single byte character set
+    Single(&'static Encoding),
+    /// JIS X 0201 Katakana (ISO-IR 13)
+    Katakana,
+    /// JIS X 0208 Kanji (ISO-IR 87)
+    Jis0208,
+    /// JIS X 0212 supplementary Kanji (ISO-IR 159)
+    Jis0212,
+    /// KS X 1001 Hangul and Hanja (ISO-IR 149)
+    Ksc5601,
+    /// GB 2312 Simplified Chinese (ISO-IR 58)
+    Gb2312,
+}
+
+/// How the values of an instance are encoded
+#[derive(Debug, Clone, PartialEq)]
+enum Charset {
+    /// A character set without code extensions, the whole value decoded
+    /// at once
+    Whole(&'static Encoding),
+    /// ISO 8859-1, or the default repertoire when `strict`
+    Latin1 { strict: bool },
+    /// ISO 2022 code extensions, starting with these sets
+    Iso2022 { g0: Set, g1: Option<Set> },
+}
+
+/// A text value with characters that have no Unicode equivalent, or
+/// bytes not allowed by the character set
+#[derive(Debug, Clone)]
+pub(crate) struct Unmappable {
+    pub(crate) tag: Tag,
+    /// The value as decoded, with U+FFFD for what could not be
+    pub(crate) text: String,
+}
+
+impl fmt::Display for Unmappable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match StandardDataDictionary.by_tag(self.tag) {
+            Some(entry) => write!(f, "{} {:?}", entry.alias, self.text),
+            None => write!(f, "{} {:?}", self.tag, self.text),
+        }
+    }
+}
+
+/// Decodes the text of the instance again according to its Specific
+/// Character Set, so that it is written in UTF-8, and returns the
+/// values that could not be decoded entirely. Fails without modifying
+/// the instance when the character set is unknown.
+pub(crate) fn normalize(object: &mut InMemDicomObject) -> color_eyre::Result<Vec<Unmappable>> {
+    let mut unmappable = Vec::new();
+    let read_as = SpecificCharacterSet::Default;
+    // The unknown character sets are found before anything is changed
+    check_terms(object)?;
+    normalize_dataset(object, None, read_as, &mut unmappable)?;
+    Ok(unmappable)
+}
+
+/// Fails on the first unknown Specific Character Set of the dataset or
+/// its items
+fn check_terms(object: &InMemDicomObject) -> color_eyre::Result<()> {
+    if let Some(terms) = terms_of(object) {
+        charset(&terms)?;
+    }
+    for element in object.iter() {
+        for item in element.items().into_iter().flatten() {
+            check_terms(item)?;
+        }
+    }
+    Ok(())
+}
+
+fn terms_of(object: &InMemDicomObject) -> Option<Vec<String>> {
+    let element = object.element(tags::SPECIFIC_CHARACTER_SET).ok()?;
+    let terms = element.to_multi_str().ok()?;
+    Some(
+        terms
+            .iter()
+            .map(|term| term.trim_matches([' ', '\0']).to_string())
+            .collect(),
+    )
+}
+
+/// `inherited` is the character set of the enclosing dataset, and
+/// `read_as` the one dicom-rs read the values with
+fn normalize_dataset(
+    object: &mut InMemDicomObject,
+    inherited: Option<&Charset>,
+    read_as: SpecificCharacterSet,
+    unmappable: &mut Vec<Unmappable>,
+) -> color_eyre::Result<()> {
+    let terms = terms_of(object);
+    let (charset, read_as) = match &terms {
+        Some(terms) => (
+            charset(terms)?,
+            // dicom-rs keeps its character set when it does not know
+            // the first term
+            terms
+                .first()
+                .and_then(|term| SpecificCharacterSet::from_code(term))
+                .unwrap_or(read_as),
+        ),
+        None => (
+            inherited
+                .cloned()
+                .unwrap_or(Charset::Latin1 { strict: true }),
+            read_as,
+        ),
+    };
+
+    let element_tags: Vec<Tag> = object.tags().collect();
+    for tag in element_tags {
+        let Ok(element) = object.take_element(tag) else {
+            continue;
+        };
+        let vr = element.vr();
+        match vr {
+            VR::SQ => {
+                let mut items = element.into_value().into_items().unwrap_or_default();
+                for item in items.iter_mut() {
+                    normalize_dataset(item, Some(&charset), read_as, unmappable)?;
+                }
+                object.put(DataElement::new(
+                    tag,
+                    vr,
+                    Value::Sequence {
+                        items,
+                        size: Length::UNDEFINED,
+                    },
+                ));
+            }
+            VR::SH | VR::LO | VR::ST | VR::LT | VR::UT | VR::PN | VR::UC => {
+                let values = match element.value() {
+                    Value::Primitive(PrimitiveValue::Str(value)) => vec![value.clone()],
+                    Value::Primitive(PrimitiveValue::Strs(values)) => values.to_vec(),
+                    _ => {
+                        object.put(element);
+                        continue;
+                    }
+                };
+                let mut decoded = Vec::with_capacity(values.len());
+                for value in values {
+                    let (text, complete) = match received_bytes(&value, read_as) {
+                        Some(bytes) => decode(&bytes, &charset, vr == VR::PN),
+                        // Not read by dicom-rs, set since then
+                        None => (value, true),
+                    };
+                    if !complete || text.contains('\u{fffd}') {
+                        unmappable.push(Unmappable {
+                            tag,
+                            text: text.clone(),
+                        });
+                    }
+                    decoded.push(text);
+                }
+                object.put(DataElement::new(
+                    tag,
+                    vr,
+                    PrimitiveValue::Strs(decoded.into()),
+                ));
+            }
+            _ => {
+                object.put(element);
+            }
+        }
+    }
+    if terms.is_some() || inherited.is_none() {
+        object.put(DataElement::new(
+            tags::SPECIFIC_CHARACTER_SET,
+            VR::CS,
+            PrimitiveValue::from(ISO_IR_192),
+        ));
+    }
+    Ok(())
+}
+
+/// The character set of the terms of a Specific Character Set
+fn charset(terms: &[String]) -> color_eyre::Result<Charset> {
+    match terms {
+        [] => Ok(Charset::Latin1 { strict: true }),
+        [term] if !term.starts_with("ISO 2022") => Ok(match term.as_str() {
+            "" | "ISO_IR 6" => Charset::Latin1 { strict: true },
+            "ISO_IR 100" => Charset::Latin1 { strict: false },
+            "ISO_IR 192" => Charset::Whole(UTF_8),
+            "GB18030" => Charset::Whole(GB18030),
+            "GBK" => Charset::Whole(GBK),
+            "ISO_IR 13" => Charset::Iso2022 {
+                g0: Set::Ascii,
+                g1: Some(Set::Katakana),
+            },
+            term => match single_byte(term.trim_start_matches("ISO_IR ")) {
+                Some(set) => Charset::Iso2022 {
+                    g0: Set::Ascii,
+                    g1: Some(set),
+                },
+                None => bail!("Unknown Specific Character Set {:?}", term),
+            },
+        }),
+        terms => {
+            let mut g0 = Set::Ascii;
+            let mut g1 = None;
+            for (i, term) in terms.iter().enumerate() {
+                let number = match term.as_str() {
+                    "" if i == 0 => continue,
+                    term => match term.strip_prefix("ISO 2022 IR ") {
+                        Some(number) => number,
+                        None => bail!("{:?} cannot be used with code extensions", term),
+                    },
+                };
+                let sets = match number {
+                    "6" => (Some(Set::Ascii), None),
+                    "13" => (Some(Set::Ascii), Some(Set::Katakana)),
+                    "87" => (Some(Set::Jis0208), None),
+                    "159" => (Some(Set::Jis0212), None),
+                    "149" => (None, Some(Set::Ksc5601)),
+                    "58" => (None, Some(Set::Gb2312)),
+                    number => match single_byte(number) {
+                        Some(set) => (None, Some(set)),
+                        None => bail!("Unknown Specific Character Set {:?}", term),
+                    },
+                };
+                // The first term designates the sets in use at the
+                // start of the values
+                if i == 0 {
+                    g0 = sets.0.unwrap_or(g0);
+                    g1 = sets.1.or(g1);
+                }
+            }
+            Ok(Charset::Iso2022 { g0, g1 })
+        }
+    }
+}
+
+/// The single byte character sets, by ISO-IR number
+fn single_byte(number: &str) -> Option<Set> {
+    Some(match number {
+        "100" => Set::Latin1,
+        "101" => Set::Single(ISO_8859_2),
+        "109" => Set::Single(ISO_8859_3),
+        "110" => Set::Single(ISO_8859_4),
+        "144" => Set::Single(ISO_8859_5),
+        "127" => Set::Single(ISO_8859_6),
+        "126" => Set::Single(ISO_8859_7),
+        "138" => Set::Single(ISO_8859_8),
+        "148" => Set::Single(WINDOWS_1254),
+        "166" => Set::Single(WINDOWS_874),
+        _ => return None,
+    })
+}
+
+/// The bytes dicom-rs decoded a value from
+fn received_bytes(value: &str, read_as: SpecificCharacterSet) -> Option<Vec<u8>> {
+    let encoding = match read_as {
+        SpecificCharacterSet::Default | SpecificCharacterSet::IsoIr100 => {
+            return value
+                .chars()
+                .map(|c| u8::try_from(u32::from(c)).ok())
+                .collect();
+        }
+        SpecificCharacterSet::IsoIr101 => ISO_8859_2,
+        SpecificCharacterSet::IsoIr109 => ISO_8859_3,
+        SpecificCharacterSet::IsoIr110 => ISO_8859_4,
+        SpecificCharacterSet::IsoIr144 => ISO_8859_5,
+        SpecificCharacterSet::IsoIr192 => UTF_8,
+        SpecificCharacterSet::Gb18030 => GB18030,
+        _ => return None,
+    };
+    let (bytes, _, unmappable) = encoding.encode(value);
+    (!unmappable).then(|| bytes.into_owned())
+}
+
+/// Decodes a value, of a person name when `person_name`, telling
+/// whether every byte could be
+fn decode(bytes: &[u8], charset: &Charset, person_name: bool) -> (String, bool) {
+    match charset {
+        Charset::Whole(encoding) => {
+            let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+            (text.into_owned(), !had_errors)
+        }
+        Charset::Latin1 { strict } => (
+            bytes.iter().map(|&b| char::from(b)).collect(),
+            !strict || bytes.is_ascii(),
+        ),
+        Charset::Iso2022 { g0, g1 } => decode_iso_2022(bytes, *g0, *g1, person_name),
+    }
+}
+
+/// Decodes a value with ISO 2022 code extensions: the escape sequences
+/// designate the sets of the bytes after them, G0 for the bytes below
+/// 0x80 and G1 for the others. The sets in use at the start are active
+/// again after the delimiters of PS3.5 6.1.2.5.3, which include the
+/// component and group delimiters of the person names.
+fn decode_iso_2022(
+    bytes: &[u8],
+    mut g0: Set,
+    mut g1: Option<Set>,
+    person_name: bool,
+) -> (String, bool) {
+    let initial = (g0, g1);
+    let mut text = String::with_capacity(bytes.len());
+    let mut complete = true;
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if b == ESC {
+            match escape_sequence(&bytes[i + 1..]) {
+                Some((length, Designation::G0(set))) => {
+                    g0 = set;
+                    i += 1 + length;
+                }
+                Some((length, Designation::G1(set))) => {
+                    g1 = Some(set);
+                    i += 1 + length;
+                }
+                None => {
+                    text.push('\u{fffd}');
+                    complete = false;
+                    i += 1;
+                }
+            }
+            continue;
+        }
+        // The delimiters and control characters are always ASCII
+        let set = if b < 0x80 { Some(g0) } else { g1 };
+        let width = match set {
+            Some(Set::Jis0208 | Set::Jis0212 | Set::Ksc5601 | Set::Gb2312) if b > 0x20 => 2,
+            _ => 1,
+        };
+        let Some(chunk) = bytes.get(i..i + width) else {
+            text.push('\u{fffd}');
+            complete = false;
+            break;
+        };
+        i += width;
+        if width == 1
+            && (matches!(b, b'\r' | b'\n' | b'\t' | 0x0c)
+                || person_name && matches!(b, b'^' | b'='))
+        {
+            (g0, g1) = initial;
+        }
+        let decoded = match (set, width) {
+            (_, 1) if b < 0x80 => Some(char::from(b).to_string()),
+            (Some(Set::Latin1), _) => Some(char::from(b).to_string()),
+            (Some(Set::Single(encoding)), _) => decode_chunk(encoding, chunk),
+            (Some(Set::Katakana), _) if (0xa1..=0xdf).contains(&b) => {
+                char::from_u32(0xff61 + u32::from(b - 0xa1)).map(String::from)
+            }
+            (Some(Set::Jis0208), _) => decode_chunk(EUC_JP, &[chunk[0] | 0x80, chunk[1] | 0x80]),
+            (Some(Set::Jis0212), _) => {
+                decode_chunk(EUC_JP, &[0x8f, chunk[0] | 0x80, chunk[1] | 0x80])
+            }
+            (Some(Set::Ksc5601), _) => decode_chunk(EUC_KR, &[chunk[0] | 0x80, chunk[1] | 0x80]),
+            (Some(Set::Gb2312), _) => decode_chunk(GBK, &[chunk[0] | 0x80, chunk[1] | 0x80]),
+            _ => None,
+        };
+        match decoded {
+            Some(decoded) => text.push_str(&decoded),
+            None => {
+                text.push('\u{fffd}');
+                complete = false;
+            }
+        }
+    }
+    (text, complete)
+}
+
+fn decode_chunk(encoding: &'static Encoding, bytes: &[u8]) -> Option<String> {
+    let (text, had_errors) = encoding.decode_without_bom_handling(bytes);
+    (!had_errors).then(|| text.into_owned())
+}
+
+enum Designation {
+    G0(Set),
+    G1(Set),
+}
+
+/// The escape sequences of PS3.3 C.12.1.1.2, after the ESC, and their
+/// length
+fn escape_sequence(bytes: &[u8]) -> Option<(usize, Designation)> {
+    use Designation::*;
+    Some(match bytes {
+        [b'(', b'B', ..] | [b'(', b'J', ..] => (2, G0(Set::Ascii)),
+        [b')', b'I', ..] => (2, G1(Set::Katakana)),
+        [b'$', b'B', ..] => (2, G0(Set::Jis0208)),
+        [b'$', b'(', b'D', ..] => (3, G0(Set::Jis0212)),
+        [b'$', b')', b'C', ..] => (3, G1(Set::Ksc5601)),
+        [b'$', b')', b'A', ..] => (3, G1(Set::Gb2312)),
+        [b'-', final_byte, ..] => {
+            let number = match final_byte {
+                b'A' => "100",
+                b'B' => "101",
+                b'C' => "109",
+                b'D' => "110",
+                b'L' => "144",
+                b'G' => "127",
+                b'F' => "126",
+                b'H' => "138",
+                b'M' => "148",
+                b'T' => "166",
+                _ => return None,
+            };
+            (2, G1(single_byte(number)?))
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{encoding::TransferSyntaxIndex, transfer_syntax::TransferSyntaxRegistry};
+
+    use super::*;
+
+    /// An element in explicit VR little endian, padded to an even length
+    fn element(tag: Tag, vr: &[u8; 2], value: &[u8]) -> Vec<u8> {
+        let mut value = value.to_vec();
+        if value.len() % 2 == 1 {
+            value.push(b' ');
+        }
+        let mut bytes = Vec::new();
+        bytes.extend(tag.group().to_le_bytes());
+        bytes.extend(tag.element().to_le_bytes());
+        bytes.extend(vr);
+        bytes.extend((value.len() as u16).to_le_bytes());
+        bytes.extend(value);
+        bytes
+    }
+
+    /// A dataset read by dicom-rs from the bytes of its Specific
+    /// Character Set and of a Patient's Name
+    fn read(charset: &[u8], name: &[u8]) -> InMemDicomObject {
+        let mut bytes = element(tags::SPECIFIC_CHARACTER_SET, b"CS", charset);
+        bytes.extend(element(tags::PATIENT_NAME, b"PN", name));
+        let ts = TransferSyntaxRegistry.get("1.2.840.10008.1.2.1").unwrap();
+        InMemDicomObject::read_dataset_with_ts(bytes.as_slice(), ts).unwrap()
+    }
+
+    fn text(object: &InMemDicomObject, tag: Tag) -> String {
+        object.element(tag).unwrap().to_str().unwrap().to_string()
+    }
+
+    /// The name normalized, checking that the dataset is now in UTF-8
+    fn normalized(charset: &[u8], name: &[u8]) -> (String, Vec<Unmappable>) {
+        let mut object = read(charset, name);
+        let unmappable = normalize(&mut object).unwrap();
+        assert_eq!(text(&object, tags::SPECIFIC_CHARACTER_SET), ISO_IR_192);
+        (text(&object, tags::PATIENT_NAME), unmappable)
+    }
+
+    #[test]
+    fn decodes_japanese_with_code_extensions() {
+        // PS3.5 H.3.1, ISO 2022 IR 87
+        let (name, unmappable) = normalized(
+            b"\\ISO 2022 IR 87",
+            b"Yamada^Tarou=\x1b$B;3ED\x1b(B^\x1b$BB@O:\x1b(B=\
+            \x1b$B$d$^$@\x1b(B^\x1b$B$?$m$&\x1b(B",
+        );
+        assert_eq!(name, "Yamada^Tarou=山田^太郎=やまだ^たろう");
+        assert!(unmappable.is_empty());
+
+        // PS3.5 H.3.2, ISO 2022 IR 13 and ISO 2022 IR 87
+        let (name, unmappable) = normalized(
+            b"ISO 2022 IR 13\\ISO 2022 IR 87",
+            b"\xd4\xcf\xc0\xde^\xc0\xdb\xb3=\x1b$B;3ED\x1b(J^\x1b$BB@O:\x1b(J=\
+            \x1b$B$d$^$@\x1b(J^\x1b$B$?$m$&\x1b(J",
+        );
+        assert_eq!(name, "ﾔﾏﾀﾞ^ﾀﾛｳ=山田^太郎=やまだ^たろう");
+        assert!(unmappable.is_empty());
+    }
+
+    #[test]
+    fn decodes_latin_1_and_gb18030() {
+        let (name, unmappable) = normalized(b"ISO_IR 100", b"Buc^J\xe9r\xf4me");
+        assert_eq!(name, "Buc^Jérôme");
+        assert!(unmappable.is_empty());
+
+        // PS3.5 J.3.1
+        let (name, unmappable) =
+            normalized(b"GB18030", b"Wang^XiaoDong=\xcd\xf5^\xd0\xa1\xb6\xab=");
+        assert_eq!(name, "Wang^XiaoDong=王^小东=");
+        assert!(unmappable.is_empty());
+    }
+
+    #[test]
+    fn person_name_delimiters_restore_the_initial_sets() {
+        // PS3.5 I.2, KS X 1001 designated again after each delimiter
+        let (name, unmappable) = normalized(
+            b"\\ISO 2022 IR 149",
+            b"Hong^Gildong=\x1b$)C\xfb\xf3^\x1b$)C\xd1\xce\xd4\xd7=\
+            \x1b$)C\xc8\xab^\x1b$)C\xb1\xe6\xb5\xbf",
+        );
+        assert_eq!(name, "Hong^Gildong=洪^吉洞=홍^길동");
+        assert!(unmappable.is_empty());
+
+        // Katakana is in G1 again after the component group delimiter
+        let (name, _) = normalized(b"ISO 2022 IR 13", b"\x1b-A\xb1=\xb1");
+        assert_eq!(name, "±=ｱ");
+        // Not in the values of other VRs
+        let bytes = b"\x1b-A\xb1=\xb1";
+        let charset = charset(&["ISO 2022 IR 13".to_string()]).unwrap();
+        assert_eq!(decode(bytes, &charset, false), ("±=±".to_string(), true));
+    }
+
+    #[test]
+    fn reports_the_unmappable_values() {
+        // Only ASCII is allowed without a Specific Character Set
+        let (name, unmappable) = normalized(b"", b"Caf\xe9");
+        assert_eq!(name, "Café");
+        assert_eq!(unmappable.len(), 1);
+        assert_eq!(unmappable[0].tag, tags::PATIENT_NAME);
+
+        // An unknown escape sequence
+        let (name, unmappable) = normalized(b"\\ISO 2022 IR 87", b"Yamada\x1b-Z^Taro");
+        assert_eq!(name, "Yamada\u{fffd}-Z^Taro");
+        assert_eq!(unmappable[0].text, name);
+        assert_eq!(
+            unmappable[0].to_string(),
+            "PatientName \"Yamada\u{fffd}-Z^Taro\""
+        );
+
+        // A JIS X 0208 character cut short
+        let (name, unmappable) = normalized(b"\\ISO 2022 IR 87", b"A\x1b$B;3E");
+        assert_eq!(name, "A山\u{fffd}");
+        assert_eq!(unmappable.len(), 1);
+    }
+
+    #[test]
+    fn refuses_unknown_character_sets() {
+        let mut object = read(b"ISO_IR 999", b"Doe^John");
+        assert!(normalize(&mut object).is_err());
+        assert_eq!(text(&object, tags::SPECIFIC_CHARACTER_SET), "ISO_IR 999");
+        assert!(charset(&["ISO 2022 IR 100".to_string(), "GB18030".to_string()]).is_err());
+    }
+}
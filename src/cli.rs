@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use crate::{utils::DEFAULT_CALLING_AET, CONFIG_FILE};
+
+/// DICOM Enterprise Application Integration
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+pub(crate) struct Cli {
+    /// The configuration file used by the daemon
+    #[arg(short, long, global = true, default_value = CONFIG_FILE)]
+    pub(crate) config: PathBuf,
+    /// The command to execute, defaults to `run`
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Run the daemon, launching the configured channels and
+    /// following the changes of the configuration file
+    Run,
+    /// Check a configuration file without running it
+    Validate {
+        /// The configuration file to check
+        config: PathBuf,
+    },
+    /// Send a C-ECHO request to a remote node
+    Echo {
+        /// The remote node, as aet@host:port
+        address: String,
+        /// The calling AE title
+        #[arg(long, default_value = DEFAULT_CALLING_AET)]
+        calling_aet: String,
+    },
+    /// Send DICOM files to a remote node with C-STORE requests
+    Send {
+        /// The files to send, directories are walked recursively
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// The remote node, as aet@host:port
+        #[arg(long)]
+        to: String,
+        /// The calling AE title
+        #[arg(long, default_value = DEFAULT_CALLING_AET)]
+        calling_aet: String,
+        /// The maximum PDU size
+        #[arg(long, default_value_t = 16384)]
+        max_pdu: u32,
+    },
+    /// List the configured channels and their status
+    Channels,
+    /// Convert a configuration file written by an older version
+    Migrate {
+        /// The configuration file to convert, in place
+        config: PathBuf,
+    },
+    /// Print the original values of pseudonyms from a key store
+    Reidentify {
+        /// The key store of the pseudonymization
+        key_store: PathBuf,
+        /// The pseudonyms to look up, every pseudonym when none is given
+        pseudonyms: Vec<String>,
+        /// The environment variable holding the passphrase of an
+        /// encrypted key store
+        #[arg(long)]
+        passphrase_env: Option<String>,
+    },
+    /// Manage the instances set aside in the quarantine of a channel
+    Quarantine {
+        /// The channel, by name or id
+        channel: String,
+        #[command(subcommand)]
+        action: QuarantineAction,
+    },
+    /// Read the instances stored by a channel again, checking them
+    /// against the checksums of its `out_dir`
+    Verify {
+        /// The channel, by name or id
+        channel: String,
+    },
+    /// Print where the instances of a patient, study, series or
+    /// accession number are, from the index of a channel
+    Locate {
+        /// The channel, by name or id
+        channel: String,
+        /// A PatientID, StudyInstanceUID, SeriesInstanceUID,
+        /// AccessionNumber or SOPInstanceUID
+        key: String,
+    },
+    /// Copy the instances of a study or patient stored by a channel to
+    /// a directory laid out as DICOM media, with its DICOMDIR
+    Export {
+        /// The channel, by name or id
+        channel: String,
+        /// A StudyInstanceUID or PatientID
+        key: String,
+        /// The empty directory to write to, such as a mounted USB drive
+        dir: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum QuarantineAction {
+    /// List the quarantined instances
+    List,
+    /// Print why and from where an instance was quarantined
+    Inspect {
+        /// The quarantined instance, as listed
+        item: String,
+    },
+    /// Hand quarantined instances back to the running channel
+    Release {
+        /// The quarantined instances, as listed
+        items: Vec<String>,
+        /// Release every quarantined instance
+        #[arg(long, conflicts_with = "items")]
+        all: bool,
+    },
+    /// Delete quarantined instances
+    Purge {
+        /// The quarantined instances, as listed
+        items: Vec<String>,
+        /// Delete every quarantined instance
+        #[arg(long, conflicts_with = "items")]
+        all: bool,
+    },
+}
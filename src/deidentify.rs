@@ -0,0 +1,883 @@
+//! De-identification with the Basic Application Level Confidentiality
+//! Profile of PS3.15 Annex E and some of its options.
+
+use std::{collections::HashMap, env};
+
+use color_eyre::eyre::eyre;
+use dicom::{
+    core::{value::Value, DataElement, Length, PrimitiveValue, Tag, VR},
+    dicom_value,
+    dictionary_std::tags,
+    object::{DefaultDicomObject, InMemDicomObject},
+};
+use regex::Regex;
+use ring::hmac;
+
+use crate::{
+    blackout,
+    utils::{BlackoutMask, Deidentification},
+};
+
+/// What happens to an attribute, the action codes of PS3.15 Table E.1-1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// Removed (X)
+    Remove,
+    /// Replaced with a zero length value (Z)
+    Empty,
+    /// Kept (K), the items of a sequence are de-identified
+    Keep,
+    /// Kept with the patient's names and identifiers removed (C)
+    Clean,
+    /// Replaced with a UID derived from the original one (U)
+    ReplaceUid,
+}
+
+/// The options of the profile, as columns of the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProfileOption {
+    Temporal,
+    PatientCharacteristics,
+    Device,
+    Institution,
+    Uids,
+    Descriptors,
+}
+
+use Action::*;
+use ProfileOption::*;
+
+/// The action of an attribute when an option is enabled
+type OptionAction = (ProfileOption, Action);
+
+/// The attributes of PS3.15 Table E.1-1, with their action and, when an
+/// option changes it, the option and its action. Retired attributes are
+/// listed as they still show up in older instances. The attributes left
+/// out are handled by `Deidentifier::action`.
+#[rustfmt::skip]
+#[allow(deprecated)]
+static PROFILE: &[(Tag, Action, Option<OptionAction>)] = &[
+    // Dates and times
+    (tags::INSTANCE_CREATION_DATE, Remove, Some((Temporal, Keep))),
+    (tags::INSTANCE_CREATION_TIME, Remove, Some((Temporal, Keep))),
+    (tags::STUDY_DATE, Empty, Some((Temporal, Keep))),
+    (tags::SERIES_DATE, Remove, Some((Temporal, Keep))),
+    (tags::ACQUISITION_DATE, Remove, Some((Temporal, Keep))),
+    (tags::CONTENT_DATE, Empty, Some((Temporal, Keep))),
+    (tags::ACQUISITION_DATE_TIME, Remove, Some((Temporal, Keep))),
+    (tags::STUDY_TIME, Empty, Some((Temporal, Keep))),
+    (tags::SERIES_TIME, Remove, Some((Temporal, Keep))),
+    (tags::ACQUISITION_TIME, Remove, Some((Temporal, Keep))),
+    (tags::CONTENT_TIME, Empty, Some((Temporal, Keep))),
+    (tags::DATE_OF_SECONDARY_CAPTURE, Remove, Some((Temporal, Keep))),
+    (tags::TIME_OF_SECONDARY_CAPTURE, Remove, Some((Temporal, Keep))),
+    (tags::DATE_OF_LAST_CALIBRATION, Remove, Some((Temporal, Keep))),
+    (tags::TIME_OF_LAST_CALIBRATION, Remove, Some((Temporal, Keep))),
+    (tags::FRAME_ACQUISITION_DATE_TIME, Remove, Some((Temporal, Keep))),
+    (tags::LAST_MENSTRUAL_DATE, Remove, Some((Temporal, Keep))),
+    (tags::PERFORMED_PROCEDURE_STEP_START_DATE, Remove, Some((Temporal, Keep))),
+    (tags::PERFORMED_PROCEDURE_STEP_START_TIME, Remove, Some((Temporal, Keep))),
+    (tags::PERFORMED_PROCEDURE_STEP_END_DATE, Remove, Some((Temporal, Keep))),
+    (tags::PERFORMED_PROCEDURE_STEP_END_TIME, Remove, Some((Temporal, Keep))),
+    (tags::SCHEDULED_PROCEDURE_STEP_START_DATE, Remove, Some((Temporal, Keep))),
+    (tags::SCHEDULED_PROCEDURE_STEP_START_TIME, Remove, Some((Temporal, Keep))),
+    (tags::SCHEDULED_PROCEDURE_STEP_END_DATE, Remove, Some((Temporal, Keep))),
+    (tags::SCHEDULED_PROCEDURE_STEP_END_TIME, Remove, Some((Temporal, Keep))),
+    (tags::CURVE_DATE, Remove, Some((Temporal, Keep))),
+    (tags::CURVE_TIME, Remove, Some((Temporal, Keep))),
+    (tags::OVERLAY_DATE, Remove, Some((Temporal, Keep))),
+    (tags::OVERLAY_TIME, Remove, Some((Temporal, Keep))),
+    (tags::TIMEZONE_OFFSET_FROM_UTC, Remove, Some((Temporal, Keep))),
+    // Patient
+    (tags::PATIENT_NAME, Empty, None),
+    (tags::PATIENT_ID, Empty, None),
+    (tags::ISSUER_OF_PATIENT_ID, Remove, None),
+    (tags::PATIENT_BIRTH_DATE, Empty, None),
+    (tags::PATIENT_BIRTH_TIME, Remove, None),
+    (tags::PATIENT_SEX, Empty, Some((PatientCharacteristics, Keep))),
+    (tags::OTHER_PATIENT_I_DS_SEQUENCE, Remove, None),
+    (tags::OTHER_PATIENT_NAMES, Remove, None),
+    (tags::PATIENT_BIRTH_NAME, Remove, None),
+    (tags::PATIENT_AGE, Remove, Some((PatientCharacteristics, Keep))),
+    (tags::PATIENT_SIZE, Remove, Some((PatientCharacteristics, Keep))),
+    (tags::PATIENT_WEIGHT, Remove, Some((PatientCharacteristics, Keep))),
+    (tags::PATIENT_ADDRESS, Remove, None),
+    (tags::PATIENT_MOTHER_BIRTH_NAME, Remove, None),
+    (tags::MILITARY_RANK, Remove, None),
+    (tags::BRANCH_OF_SERVICE, Remove, None),
+    (tags::MEDICAL_RECORD_LOCATOR, Remove, None),
+    (tags::MEDICAL_ALERTS, Remove, Some((PatientCharacteristics, Keep))),
+    (tags::ALLERGIES, Remove, Some((PatientCharacteristics, Keep))),
+    (tags::COUNTRY_OF_RESIDENCE, Remove, None),
+    (tags::REGION_OF_RESIDENCE, Remove, None),
+    (tags::PATIENT_TELEPHONE_NUMBERS, Remove, None),
+    (tags::ETHNIC_GROUP, Remove, Some((PatientCharacteristics, Keep))),
+    (tags::OCCUPATION, Remove, None),
+    (tags::SMOKING_STATUS, Remove, Some((PatientCharacteristics, Keep))),
+    (tags::ADDITIONAL_PATIENT_HISTORY, Remove, Some((Descriptors, Clean))),
+    (tags::PREGNANCY_STATUS, Remove, Some((PatientCharacteristics, Keep))),
+    (tags::PATIENT_RELIGIOUS_PREFERENCE, Remove, None),
+    (tags::PATIENT_SEX_NEUTERED, Remove, Some((PatientCharacteristics, Keep))),
+    (tags::RESPONSIBLE_PERSON, Remove, None),
+    (tags::RESPONSIBLE_ORGANIZATION, Remove, None),
+    (tags::PATIENT_COMMENTS, Remove, Some((Descriptors, Clean))),
+    (tags::SPECIAL_NEEDS, Remove, Some((PatientCharacteristics, Keep))),
+    (tags::PATIENT_STATE, Remove, Some((PatientCharacteristics, Keep))),
+    (tags::PATIENT_INSURANCE_PLAN_CODE_SEQUENCE, Remove, None),
+    (tags::PATIENT_PRIMARY_LANGUAGE_CODE_SEQUENCE, Remove, None),
+    (tags::PATIENT_PRIMARY_LANGUAGE_MODIFIER_CODE_SEQUENCE, Remove, None),
+    (tags::OTHER_PATIENT_I_DS, Remove, None),
+    (tags::INSURANCE_PLAN_IDENTIFICATION, Remove, None),
+    (tags::PATIENT_TRANSPORT_ARRANGEMENTS, Remove, None),
+    (tags::REFERENCED_PATIENT_ALIAS_SEQUENCE, Remove, None),
+    (tags::CONFIDENTIALITY_CONSTRAINT_ON_PATIENT_DATA_DESCRIPTION, Remove, None),
+    (tags::PRE_MEDICATION, Remove, Some((PatientCharacteristics, Keep))),
+    // Study, visit and request
+    (tags::ACCESSION_NUMBER, Empty, None),
+    (tags::STUDY_ID, Empty, None),
+    (tags::REFERRING_PHYSICIAN_NAME, Empty, None),
+    (tags::REFERRING_PHYSICIAN_ADDRESS, Remove, None),
+    (tags::REFERRING_PHYSICIAN_TELEPHONE_NUMBERS, Remove, None),
+    (tags::REFERRING_PHYSICIAN_IDENTIFICATION_SEQUENCE, Remove, None),
+    (tags::PHYSICIANS_OF_RECORD, Remove, None),
+    (tags::PHYSICIANS_OF_RECORD_IDENTIFICATION_SEQUENCE, Remove, None),
+    (tags::PERFORMING_PHYSICIAN_NAME, Remove, None),
+    (tags::PERFORMING_PHYSICIAN_IDENTIFICATION_SEQUENCE, Remove, None),
+    (tags::NAME_OF_PHYSICIANS_READING_STUDY, Remove, None),
+    (tags::PHYSICIANS_READING_STUDY_IDENTIFICATION_SEQUENCE, Remove, None),
+    (tags::OPERATORS_NAME, Remove, None),
+    (tags::OPERATOR_IDENTIFICATION_SEQUENCE, Remove, None),
+    (tags::REQUESTING_PHYSICIAN, Remove, None),
+    (tags::REQUESTING_SERVICE, Remove, None),
+    (tags::REFERENCED_PATIENT_SEQUENCE, Remove, None),
+    (tags::REFERENCED_STUDY_SEQUENCE, Remove, None),
+    (tags::REFERENCED_PERFORMED_PROCEDURE_STEP_SEQUENCE, Remove, None),
+    (tags::REQUEST_ATTRIBUTES_SEQUENCE, Remove, None),
+    (tags::REQUESTED_PROCEDURE_ID, Remove, None),
+    (tags::PERFORMED_PROCEDURE_STEP_ID, Remove, None),
+    (tags::PERFORMED_LOCATION, Remove, None),
+    (tags::PLACER_ORDER_NUMBER_IMAGING_SERVICE_REQUEST, Empty, None),
+    (tags::FILLER_ORDER_NUMBER_IMAGING_SERVICE_REQUEST, Empty, None),
+    (tags::ORDER_ENTERED_BY, Remove, None),
+    (tags::ORDER_ENTERER_LOCATION, Remove, None),
+    (tags::ORDER_CALLBACK_PHONE_NUMBER, Remove, None),
+    (tags::ADMISSION_ID, Remove, None),
+    (tags::CURRENT_PATIENT_LOCATION, Remove, None),
+    (tags::PATIENT_INSTITUTION_RESIDENCE, Remove, None),
+    (tags::VISIT_COMMENTS, Remove, None),
+    (tags::ADMITTING_DATE, Remove, Some((Temporal, Keep))),
+    (tags::ADMITTING_TIME, Remove, Some((Temporal, Keep))),
+    (tags::ADMITTING_DIAGNOSES_CODE_SEQUENCE, Remove, Some((Descriptors, Keep))),
+    (tags::DISCHARGE_DIAGNOSIS_DESCRIPTION, Remove, Some((Descriptors, Clean))),
+    (tags::ISSUER_OF_ADMISSION_ID, Remove, None),
+    (tags::ISSUER_OF_ADMISSION_ID_SEQUENCE, Remove, None),
+    (tags::SERVICE_EPISODE_ID, Remove, None),
+    (tags::SERVICE_EPISODE_DESCRIPTION, Remove, None),
+    (tags::ISSUER_OF_SERVICE_EPISODE_ID, Remove, None),
+    (tags::SCHEDULED_PATIENT_INSTITUTION_RESIDENCE, Remove, None),
+    (tags::CONSULTING_PHYSICIAN_NAME, Remove, None),
+    (tags::CONSULTING_PHYSICIAN_IDENTIFICATION_SEQUENCE, Remove, None),
+    (tags::REQUESTED_CONTRAST_AGENT, Remove, None),
+    (tags::REQUESTED_PROCEDURE_COMMENTS, Remove, None),
+    (tags::REQUESTED_PROCEDURE_LOCATION, Remove, None),
+    (tags::REASON_FOR_THE_IMAGING_SERVICE_REQUEST, Remove, None),
+    (tags::IMAGING_SERVICE_REQUEST_COMMENTS, Remove, None),
+    (tags::NAMES_OF_INTENDED_RECIPIENTS_OF_RESULTS, Remove, None),
+    (tags::INTENDED_RECIPIENTS_OF_RESULTS_IDENTIFICATION_SEQUENCE, Remove, None),
+    (tags::STUDY_ID_ISSUER, Remove, None),
+    (tags::SCHEDULED_STUDY_LOCATION, Remove, None),
+    (tags::SCHEDULED_STUDY_LOCATION_AE_TITLE, Remove, None),
+    (tags::SCHEDULED_PERFORMING_PHYSICIAN_NAME, Remove, None),
+    (tags::SCHEDULED_PERFORMING_PHYSICIAN_IDENTIFICATION_SEQUENCE, Remove, None),
+    (tags::SCHEDULED_PROCEDURE_STEP_DESCRIPTION, Remove, None),
+    (tags::SCHEDULED_PROCEDURE_STEP_ID, Remove, None),
+    (tags::SCHEDULED_PROCEDURE_STEP_LOCATION, Remove, None),
+    (tags::SCHEDULED_STATION_AE_TITLE, Remove, None),
+    (tags::SCHEDULED_STATION_NAME, Remove, None),
+    (tags::SCHEDULED_STATION_NAME_CODE_SEQUENCE, Remove, None),
+    (tags::SCHEDULED_STATION_GEOGRAPHIC_LOCATION_CODE_SEQUENCE, Remove, None),
+    (tags::SCHEDULED_HUMAN_PERFORMERS_SEQUENCE, Remove, None),
+    (tags::PERFORMED_STATION_AE_TITLE, Remove, None),
+    (tags::PERFORMED_STATION_NAME, Remove, None),
+    (tags::PERFORMED_STATION_NAME_CODE_SEQUENCE, Remove, None),
+    (tags::PERFORMED_STATION_GEOGRAPHIC_LOCATION_CODE_SEQUENCE, Remove, None),
+    (tags::COMMENTS_ON_THE_PERFORMED_PROCEDURE_STEP, Remove, None),
+    (tags::ACTUAL_HUMAN_PERFORMERS_SEQUENCE, Remove, None),
+    (tags::HUMAN_PERFORMER_NAME, Remove, None),
+    (tags::HUMAN_PERFORMER_ORGANIZATION, Remove, None),
+    (tags::ACQUISITION_CONTEXT_SEQUENCE, Remove, None),
+    (tags::ACQUISITION_COMMENTS, Remove, None),
+    (tags::ACQUISITION_DEVICE_PROCESSING_DESCRIPTION, Remove, Some((Device, Keep))),
+    (tags::ACQUISITION_PROTOCOL_DESCRIPTION, Remove, Some((Descriptors, Clean))),
+    (tags::CONTRAST_BOLUS_AGENT, Empty, Some((Descriptors, Clean))),
+    // People, in structured reports and elsewhere
+    (tags::PERSON_NAME, Empty, None),
+    (tags::PERSON_ADDRESS, Remove, None),
+    (tags::PERSON_TELEPHONE_NUMBERS, Remove, None),
+    (tags::PERSON_IDENTIFICATION_CODE_SEQUENCE, Remove, None),
+    (tags::VERIFYING_OBSERVER_NAME, Empty, None),
+    (tags::VERIFYING_OBSERVER_IDENTIFICATION_CODE_SEQUENCE, Empty, None),
+    (tags::VERIFYING_ORGANIZATION, Remove, None),
+    (tags::AUTHOR_OBSERVER_SEQUENCE, Remove, None),
+    (tags::PARTICIPANT_SEQUENCE, Remove, None),
+    (tags::CUSTODIAL_ORGANIZATION_SEQUENCE, Remove, None),
+    (tags::CURRENT_OBSERVER_TRIAL, Remove, None),
+    (tags::CONTENT_CREATOR_IDENTIFICATION_CODE_SEQUENCE, Remove, None),
+    (tags::TEXT_VALUE, Remove, None),
+    (tags::REVIEWER_NAME, Remove, None),
+    // Interpretations and results
+    (tags::INTERPRETATION_APPROVER_SEQUENCE, Remove, None),
+    (tags::INTERPRETATION_AUTHOR, Remove, None),
+    (tags::INTERPRETATION_DIAGNOSIS_DESCRIPTION, Remove, None),
+    (tags::INTERPRETATION_ID_ISSUER, Remove, None),
+    (tags::INTERPRETATION_RECORDER, Remove, None),
+    (tags::INTERPRETATION_TEXT, Remove, None),
+    (tags::INTERPRETATION_TRANSCRIBER, Remove, None),
+    (tags::PHYSICIAN_APPROVING_INTERPRETATION, Remove, None),
+    (tags::IMPRESSIONS, Remove, None),
+    (tags::RESULTS_COMMENTS, Remove, None),
+    (tags::RESULTS_DISTRIBUTION_LIST_SEQUENCE, Remove, None),
+    (tags::RESULTS_ID_ISSUER, Remove, None),
+    (tags::DISTRIBUTION_NAME, Remove, None),
+    (tags::DISTRIBUTION_ADDRESS, Remove, None),
+    (tags::TOPIC_TITLE, Remove, None),
+    (tags::TOPIC_SUBJECT, Remove, None),
+    (tags::TOPIC_AUTHOR, Remove, None),
+    (tags::TOPIC_KEYWORDS, Remove, None),
+    (tags::ARBITRARY, Remove, None),
+    // Modifications, signatures and other copies of the attributes
+    (tags::ORIGINAL_ATTRIBUTES_SEQUENCE, Remove, None),
+    (tags::MODIFIED_ATTRIBUTES_SEQUENCE, Remove, None),
+    (tags::ENCRYPTED_ATTRIBUTES_SEQUENCE, Remove, None),
+    (tags::DIGITAL_SIGNATURES_SEQUENCE, Remove, None),
+    (tags::DIGITAL_SIGNATURE_UID, Remove, None),
+    (tags::REFERENCED_DIGITAL_SIGNATURE_SEQUENCE, Remove, None),
+    (tags::REFERENCED_SOP_INSTANCE_MAC_SEQUENCE, Remove, None),
+    (tags::MAC, Remove, None),
+    (tags::MODIFIED_IMAGE_DESCRIPTION, Remove, None),
+    (tags::MODIFYING_DEVICE_ID, Remove, Some((Device, Keep))),
+    (tags::MODIFYING_DEVICE_MANUFACTURER, Remove, Some((Device, Keep))),
+    (tags::ICON_IMAGE_SEQUENCE, Remove, None),
+    (tags::DATA_SET_TRAILING_PADDING, Remove, None),
+    (tags::CONTENT_CREATOR_NAME, Empty, None),
+    (tags::CONTENT_SEQUENCE, Remove, None),
+    (tags::TEXT_STRING, Remove, None),
+    (tags::TEXT_COMMENTS, Remove, None),
+    // Descriptors
+    (tags::STUDY_DESCRIPTION, Remove, Some((Descriptors, Clean))),
+    (tags::SERIES_DESCRIPTION, Remove, Some((Descriptors, Clean))),
+    (tags::ADMITTING_DIAGNOSES_DESCRIPTION, Remove, Some((Descriptors, Clean))),
+    (tags::DERIVATION_DESCRIPTION, Remove, Some((Descriptors, Clean))),
+    (tags::PROTOCOL_NAME, Remove, Some((Descriptors, Clean))),
+    (tags::IMAGE_COMMENTS, Remove, Some((Descriptors, Clean))),
+    (tags::STUDY_COMMENTS, Remove, Some((Descriptors, Clean))),
+    (tags::REASON_FOR_STUDY, Remove, Some((Descriptors, Clean))),
+    (tags::REQUESTED_PROCEDURE_DESCRIPTION, Remove, Some((Descriptors, Clean))),
+    (tags::PERFORMED_PROCEDURE_STEP_DESCRIPTION, Remove, Some((Descriptors, Clean))),
+    (tags::IDENTIFYING_COMMENTS, Remove, Some((Descriptors, Clean))),
+    (tags::FRAME_COMMENTS, Remove, Some((Descriptors, Clean))),
+    (tags::IMAGE_PRESENTATION_COMMENTS, Remove, Some((Descriptors, Clean))),
+    // Institution
+    (tags::INSTITUTION_NAME, Remove, Some((Institution, Keep))),
+    (tags::INSTITUTION_ADDRESS, Remove, Some((Institution, Keep))),
+    (tags::INSTITUTION_CODE_SEQUENCE, Remove, Some((Institution, Keep))),
+    (tags::INSTITUTIONAL_DEPARTMENT_NAME, Remove, Some((Institution, Keep))),
+    // Devices
+    (tags::STATION_NAME, Remove, Some((Device, Keep))),
+    (tags::DEVICE_SERIAL_NUMBER, Remove, Some((Device, Keep))),
+    (tags::DEVICE_UID, ReplaceUid, Some((Device, Keep))),
+    (tags::PLATE_ID, Remove, Some((Device, Keep))),
+    (tags::GENERATOR_ID, Remove, Some((Device, Keep))),
+    (tags::CASSETTE_ID, Remove, Some((Device, Keep))),
+    (tags::GANTRY_ID, Remove, Some((Device, Keep))),
+    (tags::DETECTOR_ID, Remove, Some((Device, Keep))),
+    (tags::DEVICE_DESCRIPTION, Remove, Some((Device, Keep))),
+    // UIDs
+    (tags::INSTANCE_CREATOR_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::SOP_INSTANCE_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::REFERENCED_SOP_INSTANCE_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::TRANSACTION_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::IRRADIATION_EVENT_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::STUDY_INSTANCE_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::SERIES_INSTANCE_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::FRAME_OF_REFERENCE_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::SYNCHRONIZATION_FRAME_OF_REFERENCE_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::CONCATENATION_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::DIMENSION_ORGANIZATION_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::FIDUCIAL_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::STORAGE_MEDIA_FILE_SET_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::REFERENCED_FRAME_OF_REFERENCE_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::RELATED_FRAME_OF_REFERENCE_UID, ReplaceUid, Some((Uids, Keep))),
+    // Acquisition UID, not in the dictionary yet
+    (Tag(0x0008, 0x0017), ReplaceUid, Some((Uids, Keep))),
+    (tags::CONTEXT_GROUP_EXTENSION_CREATOR_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::DOSE_REFERENCE_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::FAILED_SOP_INSTANCE_UID_LIST, ReplaceUid, Some((Uids, Keep))),
+    (tags::LARGE_PALETTE_COLOR_LOOKUP_TABLE_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::REFERENCED_GENERAL_PURPOSE_SCHEDULED_PROCEDURE_STEP_TRANSACTION_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE, ReplaceUid, Some((Uids, Keep))),
+    (tags::TEMPLATE_EXTENSION_CREATOR_UID, ReplaceUid, Some((Uids, Keep))),
+    (tags::TEMPLATE_EXTENSION_ORGANIZATION_UID, ReplaceUid, Some((Uids, Keep))),
+];
+
+/// The text attributes kept although not listed by the profile, as they
+/// describe the codes and the device rather than the patient
+#[rustfmt::skip]
+static STRUCTURAL: &[Tag] = &[
+    tags::CODE_VALUE,
+    tags::CODING_SCHEME_DESIGNATOR,
+    tags::CODING_SCHEME_VERSION,
+    tags::CODE_MEANING,
+    tags::LONG_CODE_VALUE,
+    tags::URN_CODE_VALUE,
+    tags::CONTEXT_IDENTIFIER,
+    tags::CONTEXT_GROUP_VERSION,
+    tags::CONTEXT_GROUP_LOCAL_VERSION,
+    tags::MAPPING_RESOURCE,
+    tags::MANUFACTURER,
+    tags::MANUFACTURER_MODEL_NAME,
+    tags::SOFTWARE_VERSIONS,
+    tags::CONVOLUTION_KERNEL,
+    tags::SEQUENCE_NAME,
+    tags::FILTER_TYPE,
+];
+
+/// The environment variable holding the secret of the replacing UIDs,
+/// when the configuration does not name one
+const DEFAULT_UID_SECRET_ENV: &str = "EAI_UID_SECRET";
+
+/// The code and meaning of the profile and of its options in the
+/// De-identification Method Code Sequence (CID 7050)
+const PROFILE_CODE: (&str, &str) = ("113100", "Basic Application Confidentiality Profile");
+const CLEAN_PIXEL_DATA_CODE: (&str, &str) = ("113101", "Clean Pixel Data Option");
+
+fn option_code(option: ProfileOption) -> (&'static str, &'static str) {
+    match option {
+        Temporal => (
+            "113106",
+            "Retain Longitudinal Temporal Information Full Dates Option",
+        ),
+        PatientCharacteristics => ("113108", "Retain Patient Characteristics Option"),
+        Device => ("113109", "Retain Device Identity Option"),
+        Institution => ("113112", "Retain Institution Identity Option"),
+        Uids => ("113110", "Retain UIDs Option"),
+        Descriptors => ("113105", "Clean Descriptors Option"),
+    }
+}
+
+/// Applies the profile with the options of a stage
+#[derive(Debug)]
+pub(crate) struct Deidentifier {
+    actions: HashMap<Tag, Action>,
+    options: Vec<ProfileOption>,
+    masks: Vec<BlackoutMask>,
+    /// The secret the replacing UIDs are derived from, when some are
+    /// replaced
+    uid_key: Option<hmac::Key>,
+}
+
+impl Deidentifier {
+    /// `pseudonymized` keeps the UIDs and PatientID, for them to be
+    /// replaced by the pseudonymization that follows. Fails when UIDs
+    /// are replaced and their secret is not set.
+    pub(crate) fn new(config: &Deidentification, pseudonymized: bool) -> color_eyre::Result<Self> {
+        let options: Vec<ProfileOption> = [
+            (config.retain_longitudinal_temporal_information, Temporal),
+            (
+                config.retain_patient_characteristics,
+                PatientCharacteristics,
+            ),
+            (config.retain_device_identity, Device),
+            (config.retain_institution_identity, Institution),
+            (config.retain_uids, Uids),
+            (config.clean_descriptors, Descriptors),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, option)| enabled.then_some(option))
+        .collect();
+        let actions: HashMap<Tag, Action> = PROFILE
+            .iter()
+            .map(|(tag, action, option)| match option {
+                _ if pseudonymized && (*action == ReplaceUid || *tag == tags::PATIENT_ID) => {
+                    (*tag, Keep)
+                }
+                // The option keeps every UID, some of which are also kept
+                // by another option
+                _ if *action == ReplaceUid && options.contains(&Uids) => (*tag, Keep),
+                Some((option, changed)) if options.contains(option) => (*tag, *changed),
+                _ => (*tag, *action),
+            })
+            .collect();
+        let uid_key = if actions.values().any(|action| *action == ReplaceUid) {
+            let variable = config
+                .uid_secret_env
+                .as_deref()
+                .unwrap_or(DEFAULT_UID_SECRET_ENV);
+            let secret = env::var(variable)
+                .ok()
+                .filter(|secret| !secret.is_empty())
+                .ok_or_else(|| {
+                    eyre!(
+                        "The secret the UIDs are derived from is not set in {}",
+                        variable
+                    )
+                })?;
+            Some(hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()))
+        } else {
+            None
+        };
+        Ok(Self {
+            actions,
+            options,
+            masks: config.blackout.clone(),
+            uid_key,
+        })
+    }
+
+    /// De-identifies the instance and records how in its attributes.
+    /// Fails when the burned in annotations cannot be blacked out.
+    pub(crate) fn apply(&self, object: &mut DefaultDicomObject) -> color_eyre::Result<()> {
+        // The masks match on device attributes the profile may remove
+        let clean_pixels = blackout::apply(object, &self.masks)?;
+        let identifiers = identifying_values(object);
+        self.clean_dataset(object, identifiers.as_ref());
+
+        object.put(DataElement::new(
+            tags::PATIENT_IDENTITY_REMOVED,
+            VR::CS,
+            dicom_value!(Str, "YES"),
+        ));
+        let codes: Vec<(&str, &str)> = std::iter::once(PROFILE_CODE)
+            .chain(clean_pixels.then_some(CLEAN_PIXEL_DATA_CODE))
+            .chain(self.options.iter().map(|option| option_code(*option)))
+            .collect();
+        object.put(DataElement::new(
+            tags::DEIDENTIFICATION_METHOD,
+            VR::LO,
+            PrimitiveValue::Strs(
+                codes
+                    .iter()
+                    .map(|(_, meaning)| meaning.to_string())
+                    .collect(),
+            ),
+        ));
+        object.put(DataElement::new(
+            tags::DEIDENTIFICATION_METHOD_CODE_SEQUENCE,
+            VR::SQ,
+            Value::Sequence {
+                items: codes
+                    .iter()
+                    .map(|(value, meaning)| code_item(value, meaning))
+                    .collect(),
+                size: Length::UNDEFINED,
+            },
+        ));
+        let temporal = if self.options.contains(&Temporal) {
+            "UNMODIFIED"
+        } else {
+            "REMOVED"
+        };
+        object.put(DataElement::new(
+            tags::LONGITUDINAL_TEMPORAL_INFORMATION_MODIFIED,
+            VR::CS,
+            dicom_value!(Str, temporal),
+        ));
+
+        // The file meta information follows the new SOP Instance UID
+        let sop_instance_uid = object
+            .element(tags::SOP_INSTANCE_UID)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|uid| uid.trim_end_matches('\0').to_string());
+        if let Some(uid) = sop_instance_uid {
+            set_media_storage_sop_instance_uid(object, &uid);
+        }
+        Ok(())
+    }
+
+    fn action(&self, tag: Tag, vr: VR) -> Action {
+        // Curves, and the data and comments of overlays
+        if tag.group() & 0xFF00 == 0x5000
+            || (tag.group() & 0xFF00 == 0x6000
+                && (tag.element() == 0x3000 || tag.element() == 0x4000))
+        {
+            return Remove;
+        }
+        if let Some(action) = self.actions.get(&tag) {
+            return *action;
+        }
+        // Private attributes may hold anything
+        if tag.group() % 2 == 1 {
+            return Remove;
+        }
+        // Group lengths, the file meta information, the pixels and how
+        // they are presented
+        if tag.element() == 0
+            || matches!(tag.group(), 0x0002 | 0x0028 | 0x7FE0)
+            || STRUCTURAL.contains(&tag)
+        {
+            return Keep;
+        }
+        match vr {
+            VR::DA | VR::DT | VR::TM if self.options.contains(&Temporal) => Keep,
+            // Free text, names, dates and what cannot be told
+            VR::DA
+            | VR::DT
+            | VR::TM
+            | VR::AE
+            | VR::AS
+            | VR::LO
+            | VR::LT
+            | VR::PN
+            | VR::SH
+            | VR::ST
+            | VR::UC
+            | VR::UR
+            | VR::UT
+            | VR::UN => Remove,
+            // Numbers, coded values, the UIDs of classes, binary data and
+            // the sequences, whose items are de-identified
+            _ => Keep,
+        }
+    }
+
+    fn clean_dataset(&self, object: &mut InMemDicomObject, identifiers: Option<&Regex>) {
+        let tags: Vec<Tag> = object.tags().collect();
+        for tag in tags {
+            let Ok(element) = object.take_element(tag) else {
+                continue;
+            };
+            let vr = element.vr();
+            match self.action(tag, vr) {
+                Remove => {}
+                Empty if vr == VR::SQ => {
+                    object.put(DataElement::new(
+                        tag,
+                        vr,
+                        Value::Sequence {
+                            items: Default::default(),
+                            size: Length::UNDEFINED,
+                        },
+                    ));
+                }
+                Empty => {
+                    object.put(DataElement::new(tag, vr, PrimitiveValue::Empty));
+                }
+                Keep if vr == VR::SQ => {
+                    let mut items = element.into_value().into_items().unwrap_or_default();
+                    for item in items.iter_mut() {
+                        self.clean_dataset(item, identifiers);
+                    }
+                    object.put(DataElement::new(
+                        tag,
+                        vr,
+                        Value::Sequence {
+                            items,
+                            size: Length::UNDEFINED,
+                        },
+                    ));
+                }
+                Keep => {
+                    object.put(element);
+                }
+                Clean => {
+                    let text = element.to_str().map(|text| text.to_string());
+                    match (text, identifiers) {
+                        (Ok(text), Some(identifiers)) => {
+                            let cleaned = identifiers.replace_all(&text, "");
+                            object.put(DataElement::new(tag, vr, dicom_value!(Str, cleaned)));
+                        }
+                        (Ok(_), None) => {
+                            object.put(element);
+                        }
+                        // Nothing to clean in a value that is not text
+                        (Err(_), _) => {}
+                    }
+                }
+                ReplaceUid => {
+                    let (Some(key), Ok(uids)) = (&self.uid_key, element.to_multi_str()) else {
+                        continue;
+                    };
+                    let uids: Vec<String> = uids
+                        .iter()
+                        .map(|uid| remap_uid(key, uid.trim_end_matches('\0')))
+                        .collect();
+                    object.put(DataElement::new(
+                        tag,
+                        VR::UI,
+                        PrimitiveValue::Strs(uids.into()),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Matches the names and identifiers of the patient, to remove them
+/// from the descriptions
+fn identifying_values(object: &InMemDicomObject) -> Option<Regex> {
+    let mut values = Vec::new();
+    for tag in [
+        tags::PATIENT_NAME,
+        tags::PATIENT_ID,
+        tags::OTHER_PATIENT_NAMES,
+        tags::PATIENT_BIRTH_NAME,
+        tags::ACCESSION_NUMBER,
+    ] {
+        let Ok(element) = object.element(tag) else {
+            continue;
+        };
+        let Ok(text) = element.to_str() else {
+            continue;
+        };
+        // Person names are matched by component
+        values.extend(
+            text.split(['^', '\\', '=', ' '])
+                .map(|value| value.trim_end_matches('\0').trim())
+                .filter(|value| value.chars().count() > 1)
+                .map(regex::escape),
+        );
+    }
+    if values.is_empty() {
+        return None;
+    }
+    // Longest first, so that a value is not partly removed by a shorter one
+    values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+    Regex::new(&format!("(?i){}", values.join("|"))).ok()
+}
+
+fn code_item(value: &str, meaning: &str) -> InMemDicomObject {
+    InMemDicomObject::from_element_iter([
+        DataElement::new(tags::CODE_VALUE, VR::SH, dicom_value!(Str, value)),
+        DataElement::new(
+            tags::CODING_SCHEME_DESIGNATOR,
+            VR::SH,
+            dicom_value!(Str, "DCM"),
+        ),
+        DataElement::new(tags::CODE_MEANING, VR::LO, dicom_value!(Str, meaning)),
+    ])
+}
+
+/// Whether the profile replaces the UIDs of the attribute
+pub(crate) fn is_replaced_uid(tag: Tag) -> bool {
+    PROFILE
+        .iter()
+        .any(|(profile_tag, action, _)| *profile_tag == tag && *action == ReplaceUid)
+}
+
+/// The UID replacing `uid`, the same for a given UID so that the
+/// references between instances are kept. It is keyed with a secret,
+/// for the original UIDs not to give the replacing ones away.
+fn remap_uid(key: &hmac::Key, uid: &str) -> String {
+    uid_from_digest(hmac::sign(key, uid.as_bytes()).as_ref())
+}
+
+/// A UUID derived UID, as in PS3.5 B.2, made of the first 16 bytes of
+/// the digest
+pub(crate) fn uid_from_digest(digest: &[u8]) -> String {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    format!("2.25.{}", u128::from_be_bytes(bytes))
+}
+
+/// Updates the file meta information after the SOP Instance UID changed
+pub(crate) fn set_media_storage_sop_instance_uid(object: &mut DefaultDicomObject, uid: &str) {
+    let mut uid = uid.to_string();
+    if uid.len() % 2 == 1 {
+        uid.push('\0');
+    }
+    let meta = object.meta_mut();
+    meta.media_storage_sop_instance_uid = uid;
+    // The group length is written as stored, a stale one would move
+    // the last meta attributes into the data set
+    meta.update_information_group_length();
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::object::FileMetaTableBuilder;
+
+    use super::*;
+
+    /// A config whose UID secret is read from `variable`, set to `secret`
+    fn config(variable: &str, secret: &str) -> Deidentification {
+        env::set_var(variable, secret);
+        Deidentification {
+            uid_secret_env: Some(variable.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn text(object: &InMemDicomObject, tag: Tag) -> Option<String> {
+        object
+            .element(tag)
+            .ok()
+            .map(|e| e.to_str().unwrap().trim_end_matches('\0').to_string())
+    }
+
+    fn instance() -> DefaultDicomObject {
+        let object = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                dicom_value!(Str, "1.2.840.10008.5.1.4.1.1.7"),
+            ),
+            DataElement::new(
+                tags::SOP_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, "1.2.3.4.5.1"),
+            ),
+            DataElement::new(tags::STUDY_DATE, VR::DA, dicom_value!(Str, "20240102")),
+            DataElement::new(tags::MODALITY, VR::CS, dicom_value!(Str, "OT")),
+            DataElement::new(
+                tags::INSTITUTION_NAME,
+                VR::LO,
+                dicom_value!(Str, "General Hospital"),
+            ),
+            DataElement::new(
+                tags::SERIES_DESCRIPTION,
+                VR::LO,
+                dicom_value!(Str, "Chest DOE JOHN"),
+            ),
+            DataElement::new(tags::PATIENT_NAME, VR::PN, dicom_value!(Str, "Doe^John")),
+            DataElement::new(tags::PATIENT_ID, VR::LO, dicom_value!(Str, "PID001")),
+            DataElement::new(Tag(0x0009, 0x0010), VR::LO, dicom_value!(Str, "ACME")),
+            DataElement::new(Tag(0x0009, 0x1001), VR::LO, dicom_value!(Str, "Doe")),
+            DataElement::new(
+                tags::STUDY_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, "1.2.3.4"),
+            ),
+            DataElement::new(tags::ROWS, VR::US, dicom_value!(U16, 8)),
+        ]);
+        let meta = FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+            .media_storage_sop_instance_uid("1.2.3.4.5.1")
+            .transfer_syntax("1.2.840.10008.1.2.1")
+            .build()
+            .unwrap();
+        object.with_exact_meta(meta)
+    }
+
+    #[test]
+    fn unlisted_attributes_default_to_remove() {
+        let deidentifier =
+            Deidentifier::new(&config("EAI_TEST_SECRET_DEFAULT", "s"), false).unwrap();
+        // Private, unlisted text and unlisted dates
+        assert_eq!(deidentifier.action(Tag(0x0009, 0x1001), VR::US), Remove);
+        assert_eq!(deidentifier.action(Tag(0x0018, 0x9999), VR::LO), Remove);
+        assert_eq!(deidentifier.action(Tag(0x0018, 0x9998), VR::DA), Remove);
+        // Structural attributes, numbers and codes
+        assert_eq!(deidentifier.action(tags::ROWS, VR::US), Keep);
+        assert_eq!(deidentifier.action(tags::CODE_VALUE, VR::SH), Keep);
+        assert_eq!(deidentifier.action(Tag(0x0018, 0x9997), VR::DS), Keep);
+        assert_eq!(deidentifier.action(Tag(0x5000, 0x3000), VR::OW), Remove);
+    }
+
+    #[test]
+    fn applies_the_basic_profile() {
+        let deidentifier = Deidentifier::new(&config("EAI_TEST_SECRET_BASIC", "s"), false).unwrap();
+        let mut object = instance();
+        deidentifier.apply(&mut object).unwrap();
+
+        assert_eq!(text(&object, tags::PATIENT_NAME).as_deref(), Some(""));
+        assert_eq!(text(&object, tags::PATIENT_ID).as_deref(), Some(""));
+        assert_eq!(text(&object, tags::STUDY_DATE).as_deref(), Some(""));
+        assert_eq!(text(&object, tags::MODALITY).as_deref(), Some("OT"));
+        assert!(object.element(tags::INSTITUTION_NAME).is_err());
+        assert!(object.element(tags::SERIES_DESCRIPTION).is_err());
+        assert!(object.element(Tag(0x0009, 0x0010)).is_err());
+        assert!(object.element(Tag(0x0009, 0x1001)).is_err());
+        assert_eq!(
+            object.element(tags::ROWS).unwrap().to_int::<u16>().unwrap(),
+            8
+        );
+        assert_eq!(
+            text(&object, tags::PATIENT_IDENTITY_REMOVED).as_deref(),
+            Some("YES")
+        );
+        assert_eq!(
+            text(&object, tags::LONGITUDINAL_TEMPORAL_INFORMATION_MODIFIED).as_deref(),
+            Some("REMOVED")
+        );
+
+        let sop_instance_uid = text(&object, tags::SOP_INSTANCE_UID).unwrap();
+        assert!(sop_instance_uid.starts_with("2.25."));
+        assert_eq!(
+            object
+                .meta()
+                .media_storage_sop_instance_uid()
+                .trim_end_matches('\0'),
+            sop_instance_uid
+        );
+    }
+
+    #[test]
+    fn options_keep_or_clean_attributes() {
+        let config = Deidentification {
+            retain_longitudinal_temporal_information: true,
+            retain_institution_identity: true,
+            retain_uids: true,
+            clean_descriptors: true,
+            ..Default::default()
+        };
+        // No UID is replaced, no secret is needed
+        let deidentifier = Deidentifier::new(&config, false).unwrap();
+        let mut object = instance();
+        deidentifier.apply(&mut object).unwrap();
+
+        assert_eq!(text(&object, tags::STUDY_DATE).as_deref(), Some("20240102"));
+        assert_eq!(
+            text(&object, tags::INSTITUTION_NAME).as_deref(),
+            Some("General Hospital")
+        );
+        assert_eq!(
+            text(&object, tags::SERIES_DESCRIPTION).as_deref(),
+            Some("Chest")
+        );
+        assert_eq!(
+            text(&object, tags::STUDY_INSTANCE_UID).as_deref(),
+            Some("1.2.3.4")
+        );
+        assert_eq!(
+            text(&object, tags::SOP_INSTANCE_UID).as_deref(),
+            Some("1.2.3.4.5.1")
+        );
+    }
+
+    #[test]
+    fn replacing_uids_needs_a_secret() {
+        let config = config("EAI_TEST_SECRET_MISSING", "");
+        assert!(Deidentifier::new(&config, false).is_err());
+        // The pseudonymization replaces them instead
+        assert!(Deidentifier::new(&config, true).is_ok());
+    }
+
+    #[test]
+    fn uids_are_remapped_with_the_secret() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"other secret");
+        let uid = remap_uid(&key, "1.2.3.4");
+
+        assert_eq!(uid, remap_uid(&key, "1.2.3.4"));
+        assert_ne!(uid, remap_uid(&key, "1.2.3.5"));
+        assert_ne!(uid, remap_uid(&other, "1.2.3.4"));
+        assert!(uid.starts_with("2.25."));
+        assert!(uid.len() <= 64);
+    }
+
+    #[test]
+    fn pseudonymized_keeps_the_uids_and_patient_id() {
+        let deidentifier = Deidentifier::new(&Deidentification::default(), true).unwrap();
+        let mut object = instance();
+        deidentifier.apply(&mut object).unwrap();
+
+        assert_eq!(text(&object, tags::PATIENT_ID).as_deref(), Some("PID001"));
+        assert_eq!(
+            text(&object, tags::STUDY_INSTANCE_UID).as_deref(),
+            Some("1.2.3.4")
+        );
+        assert_eq!(text(&object, tags::PATIENT_NAME).as_deref(), Some(""));
+    }
+}
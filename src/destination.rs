@@ -0,0 +1,413 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{sync_channel, RecvTimeoutError, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use color_eyre::eyre::{bail, eyre, Context, ContextCompat};
+use dicom::object::{file::ReadPreamble, OpenFileOptions};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    backend::{LocalBackend, StorageBackend},
+    external_command::CommandDestination,
+    filtering::InstanceFilter,
+    index::{DeliveryState, InstanceIndex},
+    pipeline::{Instance, Origin, Verdict},
+    source::POLL_TIMEOUT,
+    stage::Stage,
+    storage::FileStore,
+    store_scu::DicomDestination,
+    stow_rs::StowRsDestination,
+    utils::{
+        Channel, Config, DestinationConfig, DestinationKind, DuplicatePolicy, DEFAULT_CALLING_AET,
+    },
+    zip_archive::ZipArchive,
+};
+
+/// The number of instances a destination can lag behind its channel
+/// before the channel waits for it
+const QUEUE_CAPACITY: usize = 256;
+
+/// How long a destination waits for new instances before being told
+/// it is idle
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Somewhere a channel forwards its instances to
+pub(crate) trait Destination: Send {
+    /// Delivers one instance, an error means it should be tried again
+    fn send(&mut self, instance: &Instance) -> color_eyre::Result<()>;
+
+    /// Called when no instance came in for a while, to release the
+    /// resources kept between sends
+    fn idle(&mut self) {}
+
+    /// How many times a failed send is retried, and the wait between
+    /// two attempts
+    fn retries(&self) -> (u32, Duration) {
+        (0, Duration::ZERO)
+    }
+}
+
+/// Builds the destination described in the channel's config
+pub(crate) fn build_destination(
+    destination: &DestinationConfig,
+    channel: &Channel,
+    config: &Config,
+) -> color_eyre::Result<Box<dyn Destination>> {
+    Ok(match &destination.kind {
+        DestinationKind::Dicom { remote_ae } => {
+            let remote_ae = config
+                .remote_ae(remote_ae)
+                .wrap_err_with(|| format!("Unknown remote AE {}", remote_ae))?;
+            // Refused rather than sent in cleartext
+            if remote_ae.tls.is_some() {
+                bail!(
+                    "The remote AE {} is set up for TLS, which is not supported yet",
+                    remote_ae.aet
+                );
+            }
+            // Call the remote node with the AET the instances came in
+            // with, unless told otherwise
+            let calling_aet = remote_ae
+                .calling_aet
+                .clone()
+                .or_else(|| {
+                    channel
+                        .source
+                        .local_ae()
+                        .and_then(|name| config.local_ae(name))
+                        .map(|local_ae| local_ae.aet().clone())
+                })
+                .unwrap_or_else(|| DEFAULT_CALLING_AET.to_string());
+            Box::new(DicomDestination::new(calling_aet, remote_ae.clone()))
+        }
+        DestinationKind::Folder {
+            path,
+            path_template,
+        } => Box::new(FolderDestination {
+            store: FileStore::new(
+                Arc::new(LocalBackend::new(path)),
+                path_template.as_deref(),
+                false,
+                DuplicatePolicy::Overwrite,
+            )?,
+        }),
+        DestinationKind::StowRs { url, headers } => {
+            Box::new(StowRsDestination::new(url.clone(), headers.clone()))
+        }
+        DestinationKind::ZipArchive { path } => Box::new(ZipArchive::new(path.clone())),
+        DestinationKind::Command {
+            program,
+            args,
+            timeout,
+        } => Box::new(CommandDestination::new(
+            program.clone(),
+            args.clone(),
+            Duration::from_secs(*timeout),
+        )),
+    })
+}
+
+/// What became of the instances of a destination since its channel
+/// started
+#[derive(Debug, Default)]
+struct DeliveryStats {
+    sent: AtomicU64,
+    failed: AtomicU64,
+    /// Filtered out, the sender being told they were stored
+    dropped: AtomicU64,
+}
+
+impl DeliveryStats {
+    fn log(&self, name: &str) {
+        info!(
+            "{}: {} sent, {} failed, {} dropped",
+            name,
+            self.sent.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// An instance queued for a destination: kept in memory, or stored by
+/// the channel and read back from its backend when its turn comes
+pub(crate) enum Queued {
+    Instance(Box<Instance>),
+    Stored {
+        backend: Arc<dyn StorageBackend>,
+        key: String,
+        sop_instance_uid: String,
+        origin: Origin,
+        /// The id of the instance in the index of the channel, if indexed
+        instance_id: Option<i64>,
+    },
+}
+
+impl Queued {
+    fn sop_instance_uid(&self) -> &str {
+        match self {
+            Queued::Instance(instance) => instance.sop_instance_uid(),
+            Queued::Stored {
+                sop_instance_uid, ..
+            } => sop_instance_uid,
+        }
+    }
+
+    fn instance_id(&self) -> Option<i64> {
+        match self {
+            Queued::Instance(_) => None,
+            Queued::Stored { instance_id, .. } => *instance_id,
+        }
+    }
+
+    /// The instance, read back from the backend if it was stored
+    fn load(self) -> color_eyre::Result<Instance> {
+        match self {
+            Queued::Instance(instance) => Ok(*instance),
+            Queued::Stored {
+                backend,
+                key,
+                origin,
+                ..
+            } => {
+                let location = backend.location(&key);
+                let bytes = backend
+                    .get(&key)?
+                    .ok_or_else(|| eyre!("{} is not in the storage anymore", location))?;
+                let object = OpenFileOptions::new()
+                    .read_preamble(ReadPreamble::Always)
+                    .from_reader(bytes.as_slice())
+                    .wrap_err_with(|| format!("Could not read {}", location))?;
+                Ok(Instance { object, origin })
+            }
+        }
+    }
+}
+
+/// A destination served by its own thread, fed through a queue
+pub(crate) struct Delivery {
+    name: String,
+    filter: InstanceFilter,
+    post: Arc<Stage>,
+    stats: Arc<DeliveryStats>,
+    sender: Option<SyncSender<Queued>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Delivery {
+    /// Launches the thread delivering to `destination`. The instances
+    /// of `channel` go through the post stage of the destination before
+    /// each send, and what became of them is recorded in the index of
+    /// the channel if any. Retries are abandoned once the shutdown
+    /// signal is set.
+    pub(crate) fn start(
+        name: String,
+        channel: String,
+        filter: InstanceFilter,
+        destination: Box<dyn Destination>,
+        post: Stage,
+        index: Option<Arc<InstanceIndex>>,
+        shutdown_signal: Arc<AtomicBool>,
+    ) -> Self {
+        let (sender, receiver) = sync_channel::<Queued>(QUEUE_CAPACITY);
+        let thread_name = name.clone();
+        let post = Arc::new(post);
+        let thread_post = post.clone();
+        let stats = Arc::new(DeliveryStats::default());
+        let thread_stats = stats.clone();
+        let handle = thread::spawn(move || {
+            let post = thread_post;
+            let mut destination = destination;
+            let mut pending = false;
+            let note = |instance_id: Option<i64>, sop_instance_uid: &str, state| {
+                if let (Some(index), Some(instance_id)) = (&index, instance_id) {
+                    index.note_delivery(instance_id, sop_instance_uid, &thread_name, state);
+                }
+            };
+            loop {
+                match receiver.recv_timeout(IDLE_TIMEOUT) {
+                    Ok(queued) => {
+                        // As indexed, the post stage may change it
+                        let sop_instance_uid = queued.sop_instance_uid().to_string();
+                        let instance_id = queued.instance_id();
+                        let mut instance = match queued.load() {
+                            Ok(instance) => instance,
+                            Err(e) => {
+                                error!(
+                                    "Not sending {} to {}: {:#}",
+                                    sop_instance_uid, thread_name, e
+                                );
+                                thread_stats.failed.fetch_add(1, Ordering::Relaxed);
+                                note(instance_id, &sop_instance_uid, DeliveryState::Failed);
+                                continue;
+                            }
+                        };
+                        let names = [thread_name.clone()];
+                        match post.apply(&mut instance, &channel, &names) {
+                            Ok(Verdict::Forward(names)) if names.contains(&thread_name) => {}
+                            Ok(_) => {
+                                info!(
+                                    "The post stage of {} dropped {}",
+                                    thread_name,
+                                    instance.sop_instance_uid()
+                                );
+                                thread_stats.dropped.fetch_add(1, Ordering::Relaxed);
+                                note(instance_id, &sop_instance_uid, DeliveryState::Dropped);
+                                continue;
+                            }
+                            Err(e) => {
+                                error!(
+                                    "Not sending {} to {}: {:#}",
+                                    instance.sop_instance_uid(),
+                                    thread_name,
+                                    e
+                                );
+                                thread_stats.failed.fetch_add(1, Ordering::Relaxed);
+                                note(instance_id, &sop_instance_uid, DeliveryState::Failed);
+                                continue;
+                            }
+                        }
+                        let (counter, state) = if deliver(
+                            &thread_name,
+                            destination.as_mut(),
+                            &instance,
+                            &shutdown_signal,
+                        ) {
+                            (&thread_stats.sent, DeliveryState::Sent)
+                        } else {
+                            (&thread_stats.failed, DeliveryState::Failed)
+                        };
+                        counter.fetch_add(1, Ordering::Relaxed);
+                        note(instance_id, &sop_instance_uid, state);
+                        pending = true;
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        if pending {
+                            destination.idle();
+                            thread_stats.log(&thread_name);
+                            pending = false;
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            destination.idle();
+        });
+        Self {
+            name,
+            filter,
+            post,
+            stats,
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Compiles the script of the post stage again if its file changed
+    pub(crate) fn reload(&self) {
+        self.post.reload();
+    }
+
+    /// Whether the destination does not want the instance, counting it
+    /// as dropped
+    pub(crate) fn drops(&self, instance: &Instance) -> bool {
+        let drops = self.filter.drops(&instance.object);
+        if drops {
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        drops
+    }
+
+    /// Queues the instance, waits if the queue is full
+    pub(crate) fn push(&self, instance: Queued) {
+        if let Some(sender) = &self.sender {
+            if sender.send(instance).is_err() {
+                warn!("The delivery thread of {} is gone", self.name);
+            }
+        }
+    }
+}
+
+impl Drop for Delivery {
+    /// Lets the thread deliver what is queued and waits for it
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                warn!("The delivery thread of {} panicked", self.name);
+            }
+        }
+        self.stats.log(&self.name);
+    }
+}
+
+/// Sends the instance, retrying as configured by the destination,
+/// and returns whether it was delivered
+fn deliver(
+    name: &str,
+    destination: &mut dyn Destination,
+    instance: &Instance,
+    shutdown_signal: &AtomicBool,
+) -> bool {
+    let (retries, retry_interval) = destination.retries();
+    let mut attempt = 0;
+    loop {
+        match destination.send(instance) {
+            Ok(()) => {
+                info!("Sent {} to {}", instance.sop_instance_uid(), name);
+                return true;
+            }
+            Err(e) if attempt < retries && !shutdown_signal.load(Ordering::SeqCst) => {
+                attempt += 1;
+                warn!(
+                    "Could not send {} to {}, attempt {} of {}: {:#}",
+                    instance.sop_instance_uid(),
+                    name,
+                    attempt,
+                    retries + 1,
+                    e
+                );
+            }
+            Err(e) => {
+                error!(
+                    "Giving up sending {} to {}: {:#}",
+                    instance.sop_instance_uid(),
+                    name,
+                    e
+                );
+                return false;
+            }
+        }
+        let mut waited = Duration::ZERO;
+        while waited < retry_interval {
+            if shutdown_signal.load(Ordering::SeqCst) {
+                debug!("Shutting down, not retrying {}", name);
+                break;
+            }
+            thread::sleep(POLL_TIMEOUT);
+            waited += POLL_TIMEOUT;
+        }
+    }
+}
+
+/// Writes the instances to a directory
+pub(crate) struct FolderDestination {
+    store: FileStore,
+}
+
+impl Destination for FolderDestination {
+    fn send(&mut self, instance: &Instance) -> color_eyre::Result<()> {
+        self.store.store(instance)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use color_eyre::eyre::{bail, Context, ContextCompat};
+use dicom::{
+    core::{DataElement, PrimitiveValue, VR},
+    dicom_value,
+    dictionary_std::tags,
+    object::{InMemDicomObject, StandardDataDictionary},
+};
+use dicom_ul::{association::ClientAssociationOptions, pdu::PDataValueType, Pdu};
+use tracing::{debug, info};
+
+use crate::utils::even_len;
+
+const VERIFICATION_SOP_CLASS: &str = "1.2.840.10008.1.1";
+
+/// Sends a C-ECHO-RQ to the given `aet@host:port` address and
+/// returns the status of the C-ECHO-RSP.
+pub(crate) fn echo_scu(calling_aet: &str, address: &str) -> color_eyre::Result<u16> {
+    let mut association = ClientAssociationOptions::new()
+        .calling_ae_title(calling_aet)
+        .with_abstract_syntax(VERIFICATION_SOP_CLASS)
+        .establish_with(address)
+        .wrap_err_with(|| format!("Could not establish association with {}", address))?;
+
+    let presentation_context = association
+        .presentation_contexts()
+        .first()
+        .wrap_err("No presentation context accepted for the Verification SOP Class")?
+        .clone();
+
+    // Commands are always in implict VR LE
+    let ts = dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN.erased();
+    let mut cecho_data = Vec::new();
+    create_cecho_request(1)
+        .write_dataset_with_ts(&mut cecho_data, &ts)
+        .wrap_err("Could not write C-ECHO request object")?;
+
+    association
+        .send(&Pdu::PData {
+            data: vec![dicom_ul::pdu::PDataValue {
+                presentation_context_id: presentation_context.id,
+                value_type: PDataValueType::Command,
+                is_last: true,
+                data: cecho_data,
+            }],
+        })
+        .wrap_err("Failed to send C-ECHO request")?;
+
+    let status = match association
+        .receive()
+        .wrap_err("Failed to receive C-ECHO response")?
+    {
+        Pdu::PData { data } if !data.is_empty() => {
+            let obj = InMemDicomObject::read_dataset_with_ts(data[0].data.as_slice(), &ts)
+                .wrap_err("Failed to read C-ECHO response")?;
+            debug!("C-ECHO response: {:?}", obj);
+            obj.element(tags::STATUS)
+                .wrap_err("Missing Status in C-ECHO response")?
+                .uint16()
+                .wrap_err("Status is not an integer")?
+        }
+        pdu => bail!("Unexpected PDU in response to C-ECHO: {:?}", pdu),
+    };
+
+    association
+        .release()
+        .wrap_err("Error releasing the association")?;
+    info!("C-ECHO to {} returned status {:#06x}", address, status);
+
+    Ok(status)
+}
+
+fn create_cecho_request(message_id: u16) -> InMemDicomObject<StandardDataDictionary> {
+    InMemDicomObject::from_element_iter([
+        DataElement::new(
+            tags::COMMAND_GROUP_LENGTH,
+            VR::UL,
+            PrimitiveValue::from(8 + even_len(VERIFICATION_SOP_CLASS) + 8 + 2 + 8 + 2 + 8 + 2),
+        ),
+        DataElement::new(
+            tags::AFFECTED_SOP_CLASS_UID,
+            VR::UI,
+            dicom_value!(Str, VERIFICATION_SOP_CLASS),
+        ),
+        DataElement::new(tags::COMMAND_FIELD, VR::US, dicom_value!(U16, [0x0030])),
+        DataElement::new(tags::MESSAGE_ID, VR::US, dicom_value!(U16, [message_id])),
+        DataElement::new(
+            tags::COMMAND_DATA_SET_TYPE,
+            VR::US,
+            dicom_value!(U16, [0x0101]),
+        ),
+    ])
+}
@@ -0,0 +1,157 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    path::Path,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{bail, Context};
+use tracing::{debug, warn};
+
+use crate::{
+    destination::Destination,
+    pipeline::{write_temporary, Instance},
+};
+
+/// How often a running program is checked for completion
+const WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The placeholder replaced with the path of the instance in the
+/// arguments of a program
+const FILE_PLACEHOLDER: &str = "{file}";
+
+/// A destination running a program for each instance, with the path
+/// of a temporary copy of the instance as argument
+pub(crate) struct CommandDestination {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl CommandDestination {
+    pub(crate) fn new(program: String, args: Vec<String>, timeout: Duration) -> Self {
+        Self {
+            program,
+            args,
+            timeout,
+        }
+    }
+}
+
+impl Destination for CommandDestination {
+    fn send(&mut self, instance: &Instance) -> color_eyre::Result<()> {
+        let file = write_temporary(instance)?;
+        let result = run(
+            &self.program,
+            &substitute(&self.args, &file),
+            None,
+            self.timeout,
+        );
+        if let Err(e) = fs::remove_file(&file) {
+            debug!("Could not delete {}: {}", file.display(), e);
+        }
+        let output = result?;
+        if !output.status.success() {
+            bail!("{} exited with {}", self.program, output.status);
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the placeholder with the path of the file, or appends it
+/// when no argument contains the placeholder
+pub(crate) fn substitute(args: &[String], file: &Path) -> Vec<String> {
+    let file = file.display().to_string();
+    if args.iter().any(|arg| arg.contains(FILE_PLACEHOLDER)) {
+        args.iter()
+            .map(|arg| arg.replace(FILE_PLACEHOLDER, &file))
+            .collect()
+    } else {
+        args.iter().cloned().chain([file]).collect()
+    }
+}
+
+/// What a program wrote on its standard output, and how it exited
+pub(crate) struct Output {
+    pub(crate) status: ExitStatus,
+    pub(crate) stdout: Vec<u8>,
+}
+
+/// Runs the program, killing it when it takes longer than `timeout`.
+/// `input` is written to its standard input, its standard output is
+/// returned and its standard error is logged.
+pub(crate) fn run(
+    program: &str,
+    args: &[String],
+    input: Option<Vec<u8>>,
+    timeout: Duration,
+) -> color_eyre::Result<Output> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err_with(|| format!("Could not run {}", program))?;
+    // Feed and read the pipes while the program runs, it would block
+    // once one of them is full
+    let writer = child.stdin.take().zip(input).map(|(mut stdin, input)| {
+        thread::spawn(move || {
+            // The program may exit without reading everything
+            let _ = stdin.write_all(&input);
+        })
+    });
+    let stdout = child.stdout.take().map(|mut stdout| {
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = stdout.read_to_end(&mut output);
+            output
+        })
+    });
+    let stderr = child.stderr.take().map(|mut stderr| {
+        thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stderr.read_to_string(&mut output);
+            output
+        })
+    });
+    let status =
+        wait(&mut child, timeout).wrap_err_with(|| format!("Could not wait for {}", program))?;
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    if let Some(output) = stderr.and_then(|reader| reader.join().ok()) {
+        for line in output.lines().filter(|line| !line.trim().is_empty()) {
+            warn!("{}: {}", program, line);
+        }
+    }
+    let stdout = stdout
+        .and_then(|reader| reader.join().ok())
+        .unwrap_or_default();
+    match status {
+        Some(status) => Ok(Output { status, stdout }),
+        None => bail!("{} did not finish within {:?}", program, timeout),
+    }
+}
+
+/// Waits for the child to exit, kills it and returns None after `timeout`
+fn wait(child: &mut Child, timeout: Duration) -> std::io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(WAIT_INTERVAL);
+    }
+}
@@ -0,0 +1,181 @@
+//! The instances a destination does not want, such as localizers or
+//! dose reports, dropped before they are queued for it
+
+use color_eyre::eyre::{bail, Context};
+use dicom::{core::Tag, dictionary_std::tags, object::InMemDicomObject};
+use regex::Regex;
+
+use crate::utils::DropFilter;
+
+/// The drop filters of a destination, checked once
+#[derive(Debug, Default)]
+pub(crate) struct InstanceFilter {
+    filters: Vec<CompiledFilter>,
+}
+
+/// A filter with its regular expression compiled
+#[derive(Debug)]
+struct CompiledFilter {
+    sop_classes: Vec<String>,
+    modalities: Vec<String>,
+    image_type: Vec<String>,
+    series_description: Option<Regex>,
+}
+
+impl InstanceFilter {
+    /// Checks the filters, failing on invalid regular expressions and
+    /// filters without criteria, which would drop every instance
+    pub(crate) fn new(filters: &[DropFilter]) -> color_eyre::Result<Self> {
+        let filters = filters
+            .iter()
+            .map(|filter| {
+                if filter.sop_classes.is_empty()
+                    && filter.modalities.is_empty()
+                    && filter.image_type.is_empty()
+                    && filter.series_description.is_none()
+                {
+                    bail!("A drop filter has no criteria");
+                }
+                Ok(CompiledFilter {
+                    sop_classes: filter.sop_classes.clone(),
+                    modalities: filter.modalities.clone(),
+                    image_type: filter.image_type.clone(),
+                    series_description: filter
+                        .series_description
+                        .as_deref()
+                        .map(|pattern| {
+                            Regex::new(pattern).wrap_err_with(|| {
+                                format!("Invalid regular expression {:?}", pattern)
+                            })
+                        })
+                        .transpose()?,
+                })
+            })
+            .collect::<color_eyre::Result<_>>()?;
+        Ok(Self { filters })
+    }
+
+    /// Whether one of the filters matches the instance
+    pub(crate) fn drops(&self, object: &InMemDicomObject) -> bool {
+        self.filters.iter().any(|filter| filter.matches(object))
+    }
+}
+
+impl CompiledFilter {
+    fn matches(&self, object: &InMemDicomObject) -> bool {
+        let one_of = |expected: &[String], tag| {
+            expected.is_empty()
+                || values_of(object, tag).iter().any(|value| {
+                    expected
+                        .iter()
+                        .any(|expected| expected.trim().eq_ignore_ascii_case(value))
+                })
+        };
+        one_of(&self.sop_classes, tags::SOP_CLASS_UID)
+            && one_of(&self.modalities, tags::MODALITY)
+            && one_of(&self.image_type, tags::IMAGE_TYPE)
+            && self.series_description.as_ref().is_none_or(|regex| {
+                values_of(object, tags::SERIES_DESCRIPTION)
+                    .iter()
+                    .any(|value| regex.is_match(value))
+            })
+    }
+}
+
+/// The values of an attribute, without their padding
+fn values_of(object: &InMemDicomObject, tag: Tag) -> Vec<String> {
+    object
+        .element(tag)
+        .ok()
+        .and_then(|element| element.to_multi_str().ok())
+        .map(|values| {
+            values
+                .iter()
+                .map(|value| value.trim_matches([' ', '\0']).to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{
+        core::{DataElement, PrimitiveValue, VR},
+        dicom_value,
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    /// The filters of the README, and derived CT and MR images
+    fn filter() -> InstanceFilter {
+        let filters: Vec<DropFilter> = serde_json::from_value(json!([
+            { "image_type": ["LOCALIZER"] },
+            { "sop_classes": ["1.2.840.10008.5.1.4.1.1.88.67"] },
+            { "series_description": "(?i)raw" },
+            { "modalities": ["CT", "MR"], "image_type": ["DERIVED"] },
+        ]))
+        .unwrap();
+        InstanceFilter::new(&filters).unwrap()
+    }
+
+    fn object(
+        sop_class: &str,
+        modality: &str,
+        image_type: &[&str],
+        description: &str,
+    ) -> InMemDicomObject {
+        InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SOP_CLASS_UID, VR::UI, dicom_value!(Str, sop_class)),
+            DataElement::new(tags::MODALITY, VR::CS, dicom_value!(Str, modality)),
+            DataElement::new(
+                tags::IMAGE_TYPE,
+                VR::CS,
+                PrimitiveValue::Strs(image_type.iter().map(|value| value.to_string()).collect()),
+            ),
+            DataElement::new(
+                tags::SERIES_DESCRIPTION,
+                VR::LO,
+                dicom_value!(Str, description),
+            ),
+        ])
+    }
+
+    const CT: &str = "1.2.840.10008.5.1.4.1.1.2";
+
+    #[test]
+    fn drops_the_instances_matching_one_of_the_filters() {
+        let filter = filter();
+        assert!(filter.drops(&object(CT, "CT", &["ORIGINAL", "PRIMARY", "LOCALIZER"], "")));
+        assert!(filter.drops(&object("1.2.840.10008.5.1.4.1.1.88.67", "SR", &[], "Dose")));
+        assert!(filter.drops(&object(
+            CT,
+            "CT",
+            &["ORIGINAL", "PRIMARY"],
+            "Thorax RAW data"
+        )));
+        // The values are compared ignoring the case and the padding
+        assert!(filter.drops(&object(CT, "ct ", &["derived", "SECONDARY"], "")));
+
+        assert!(!filter.drops(&object(
+            CT,
+            "CT",
+            &["ORIGINAL", "PRIMARY", "AXIAL"],
+            "Thorax"
+        )));
+        // All the criteria of a filter have to match
+        assert!(!filter.drops(&object(CT, "US", &["DERIVED", "SECONDARY"], "")));
+        assert!(!filter.drops(&InMemDicomObject::new_empty()));
+        assert!(!InstanceFilter::default().drops(&object(CT, "CT", &["LOCALIZER"], "raw")));
+    }
+
+    #[test]
+    fn refuses_invalid_filters() {
+        assert!(InstanceFilter::new(&[DropFilter::default()]).is_err());
+        let filter = DropFilter {
+            series_description: Some("(unclosed".to_string()),
+            ..Default::default()
+        };
+        assert!(InstanceFilter::new(&[filter]).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::eyre::Context;
+use dicom::object::open_file;
+use tracing::{info, warn};
+
+use crate::{
+    pipeline::{is_success, Instance, Origin, Pipeline, STATUS_OUT_OF_RESOURCES},
+    source::{Source, POLL_TIMEOUT},
+};
+
+/// Files modified more recently than this are considered still being
+/// written and are picked up on the next scan
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// A source scanning a directory for DICOM files
+pub(crate) struct HotFolder {
+    path: PathBuf,
+    poll_interval: Duration,
+    processed_dir: Option<PathBuf>,
+    failed_dir: Option<PathBuf>,
+    /// The files that failed and are left in place
+    ignored: HashSet<PathBuf>,
+}
+
+impl HotFolder {
+    pub(crate) fn new(
+        path: PathBuf,
+        poll_interval: Duration,
+        processed_dir: Option<PathBuf>,
+        failed_dir: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            path,
+            poll_interval,
+            processed_dir,
+            failed_dir,
+            ignored: HashSet::new(),
+        }
+    }
+
+    /// Ingests every settled file of the watched directory
+    fn scan(&mut self, pipeline: &Pipeline, shutdown_signal: &AtomicBool) {
+        let mut files = Vec::new();
+        self.collect_files(&self.path, &mut files);
+        files.sort();
+        for file in files {
+            if shutdown_signal.load(Ordering::SeqCst) {
+                return;
+            }
+            if self.ignored.contains(&file) || !is_settled(&file) {
+                continue;
+            }
+            let status = match open_file(&file) {
+                Ok(object) => pipeline.ingest(Instance {
+                    object,
+                    origin: Origin {
+                        peer: file.display().to_string(),
+                        ..Default::default()
+                    },
+                }),
+                Err(e) => {
+                    warn!("Could not read {} as DICOM: {}", file.display(), e);
+                    self.fail(&file);
+                    continue;
+                }
+            };
+            if is_success(status) {
+                self.done(&file);
+            } else if status == STATUS_OUT_OF_RESOURCES {
+                // Left in place for the scans once there is room again
+                warn!(
+                    "{} is left until the channel has room again",
+                    file.display()
+                );
+                return;
+            } else {
+                warn!("{} was refused with status {:#06x}", file.display(), status);
+                self.fail(&file);
+            }
+        }
+    }
+
+    fn collect_files(&self, dir: &Path, files: &mut Vec<PathBuf>) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not read directory {}: {}", dir.display(), e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if Some(&path) == self.processed_dir.as_ref() || Some(&path) == self.failed_dir.as_ref()
+            {
+                continue;
+            }
+            if path.is_dir() {
+                self.collect_files(&path, files);
+            } else if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+
+    /// Moves an ingested file to the processed directory, or deletes it
+    fn done(&mut self, file: &Path) {
+        let result = match &self.processed_dir {
+            Some(dir) => move_into(file, dir),
+            None => fs::remove_file(file).wrap_err("Could not delete the file"),
+        };
+        if let Err(e) = result {
+            warn!("{}: {:#}", file.display(), e);
+            self.ignored.insert(file.to_path_buf());
+        }
+    }
+
+    /// Moves a file that could not be ingested to the failed directory,
+    /// or remembers to leave it alone
+    fn fail(&mut self, file: &Path) {
+        if let Some(dir) = &self.failed_dir {
+            match move_into(file, dir) {
+                Ok(()) => return,
+                Err(e) => warn!("{}: {:#}", file.display(), e),
+            }
+        }
+        self.ignored.insert(file.to_path_buf());
+    }
+}
+
+impl Source for HotFolder {
+    fn run(&mut self, pipeline: &Pipeline, shutdown_signal: &AtomicBool) -> color_eyre::Result<()> {
+        info!("Watching {}", self.path.display());
+        while !shutdown_signal.load(Ordering::SeqCst) {
+            self.scan(pipeline, shutdown_signal);
+            // Forget the ignored files that were removed by hand
+            self.ignored.retain(|file| file.exists());
+            let mut waited = Duration::ZERO;
+            while waited < self.poll_interval && !shutdown_signal.load(Ordering::SeqCst) {
+                std::thread::sleep(POLL_TIMEOUT);
+                waited += POLL_TIMEOUT;
+            }
+        }
+        info!("Stopped watching {}", self.path.display());
+        Ok(())
+    }
+}
+
+fn is_settled(file: &Path) -> bool {
+    fs::metadata(file)
+        .and_then(|metadata| metadata.modified())
+        .map(|modified| {
+            SystemTime::now()
+                .duration_since(modified)
+                .map(|age| age >= SETTLE_TIME)
+                .unwrap_or(false)
+        })
+        .unwrap_or(false)
+}
+
+/// Moves the file into `dir`, copying it when a rename is not possible
+fn move_into(file: &Path, dir: &Path) -> color_eyre::Result<()> {
+    fs::create_dir_all(dir)
+        .wrap_err_with(|| format!("Could not create the directory {}", dir.display()))?;
+    let target = dir.join(file.file_name().unwrap_or_default());
+    if fs::rename(file, &target).is_err() {
+        fs::copy(file, &target)
+            .wrap_err_with(|| format!("Could not copy the file to {}", target.display()))?;
+        fs::remove_file(file).wrap_err("Could not delete the file")?;
+    }
+    Ok(())
+}
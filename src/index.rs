@@ -0,0 +1,523 @@
+//! The index of the instances stored by a channel, in a SQLite
+//! database in the `out_dir` of its storage: their key attributes, where they come from
+//! and whether they reached each destination
+
+use std::{
+    fmt, fs,
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{bail, Context};
+use dicom::dictionary_std::tags;
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::warn;
+
+use crate::{pipeline::Instance, storage::value_of};
+
+/// The database of the index in the directory of the storage
+pub(crate) const INDEX_DATABASE: &str = "index.sqlite";
+
+/// Whether the instance `i` is neither pending delivery nor failed to
+/// be delivered to a destination
+const DELIVERED: &str = "NOT EXISTS (SELECT 1 FROM deliveries d
+    WHERE d.instance_id = i.id AND d.state IN ('Pending', 'Failed'))";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS instances (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    patient_id TEXT NOT NULL,
+    patient_name TEXT NOT NULL,
+    study_instance_uid TEXT NOT NULL,
+    study_date TEXT NOT NULL,
+    accession_number TEXT NOT NULL,
+    series_instance_uid TEXT NOT NULL,
+    modality TEXT NOT NULL,
+    sop_class_uid TEXT NOT NULL,
+    sop_instance_uid TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    transfer_syntax TEXT NOT NULL,
+    calling_aet TEXT,
+    peer TEXT NOT NULL,
+    received_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS instances_sop_instance_uid ON instances (sop_instance_uid);
+CREATE INDEX IF NOT EXISTS instances_study_instance_uid ON instances (study_instance_uid);
+CREATE INDEX IF NOT EXISTS instances_patient_id ON instances (patient_id);
+CREATE TABLE IF NOT EXISTS deliveries (
+    instance_id INTEGER NOT NULL REFERENCES instances (id) ON DELETE CASCADE,
+    destination TEXT NOT NULL,
+    state TEXT NOT NULL,
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (instance_id, destination)
+);
+";
+
+/// Where an instance stands with a destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DeliveryState {
+    /// Queued for the destination
+    Pending,
+    Sent,
+    /// The destination could not be reached or refused the instance
+    Failed,
+    /// Filtered out or dropped by the post stage of the destination
+    Dropped,
+}
+
+impl fmt::Display for DeliveryState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl FromStr for DeliveryState {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Pending" => Ok(DeliveryState::Pending),
+            "Sent" => Ok(DeliveryState::Sent),
+            "Failed" => Ok(DeliveryState::Failed),
+            "Dropped" => Ok(DeliveryState::Dropped),
+            _ => bail!("Unknown delivery state {:?}", s),
+        }
+    }
+}
+
+/// The id of an instance in the index and where it is stored
+pub(crate) type IndexedFile = (i64, String);
+
+/// An instance as recorded in the index
+#[derive(Debug)]
+pub(crate) struct IndexedInstance {
+    /// Where the instance is in the backend of the storage
+    pub(crate) location: String,
+    pub(crate) patient_id: String,
+    pub(crate) study_instance_uid: String,
+    pub(crate) sop_instance_uid: String,
+    pub(crate) modality: String,
+    pub(crate) size: u64,
+    pub(crate) transfer_syntax: String,
+    pub(crate) calling_aet: Option<String>,
+    pub(crate) peer: String,
+    /// In seconds since the Unix epoch
+    pub(crate) received_at: u64,
+    /// The destinations and where the instance stands with them
+    pub(crate) deliveries: Vec<(String, DeliveryState)>,
+}
+
+/// The index of a storage, shared by the pipeline and the delivery
+/// threads of its channel
+pub(crate) struct InstanceIndex {
+    connection: Mutex<Connection>,
+}
+
+impl InstanceIndex {
+    /// Opens the index of the storage in `dir`, creating it if needed
+    pub(crate) fn open(dir: &Path) -> color_eyre::Result<Self> {
+        fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Could not create the directory {}", dir.display()))?;
+        let path = dir.join(INDEX_DATABASE);
+        let connection = Connection::open(&path)
+            .wrap_err_with(|| format!("Could not open {}", path.display()))?;
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .and_then(|_| connection.pragma_update(None, "foreign_keys", true))
+            .and_then(|_| connection.busy_timeout(std::time::Duration::from_secs(5)))
+            .and_then(|_| connection.execute_batch(SCHEMA))
+            .wrap_err_with(|| format!("Could not set up {}", path.display()))?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Records the instance stored at `location`, replacing what was
+    /// recorded for the file, and returns its id. The deliveries settled
+    /// for the content a file replaces are forgotten, those pending or
+    /// failed are kept as the file still has to reach the destination.
+    pub(crate) fn record(
+        &self,
+        instance: &Instance,
+        location: &str,
+        size: u64,
+    ) -> color_eyre::Result<i64> {
+        let object = &instance.object;
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection
+            .transaction()
+            .wrap_err("Could not record the instance")?;
+        let id = transaction
+            .query_row(
+                "INSERT INTO instances (path, patient_id, patient_name, study_instance_uid,
+                    study_date, accession_number, series_instance_uid, modality, sop_class_uid,
+                    sop_instance_uid, size, transfer_syntax, calling_aet, peer, received_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)
+                ON CONFLICT (path) DO UPDATE SET
+                    patient_id = excluded.patient_id, patient_name = excluded.patient_name,
+                    study_instance_uid = excluded.study_instance_uid,
+                    study_date = excluded.study_date,
+                    accession_number = excluded.accession_number,
+                    series_instance_uid = excluded.series_instance_uid,
+                    modality = excluded.modality, sop_class_uid = excluded.sop_class_uid,
+                    sop_instance_uid = excluded.sop_instance_uid, size = excluded.size,
+                    transfer_syntax = excluded.transfer_syntax,
+                    calling_aet = excluded.calling_aet, peer = excluded.peer,
+                    received_at = excluded.received_at
+                RETURNING id",
+                params![
+                    location,
+                    value_of(object, tags::PATIENT_ID),
+                    value_of(object, tags::PATIENT_NAME),
+                    value_of(object, tags::STUDY_INSTANCE_UID),
+                    value_of(object, tags::STUDY_DATE),
+                    value_of(object, tags::ACCESSION_NUMBER),
+                    value_of(object, tags::SERIES_INSTANCE_UID),
+                    value_of(object, tags::MODALITY),
+                    instance.sop_class_uid().trim_end_matches('\0'),
+                    instance.sop_instance_uid(),
+                    size,
+                    object.meta().transfer_syntax(),
+                    instance.origin.calling_aet,
+                    instance.origin.peer,
+                    now(),
+                ],
+                |row| row.get(0),
+            )
+            .and_then(|id| {
+                transaction.execute(
+                    "DELETE FROM deliveries WHERE instance_id = ?1 AND state IN ('Sent', 'Dropped')",
+                    [id],
+                )?;
+                Ok(id)
+            })
+            .wrap_err("Could not record the instance")?;
+        transaction
+            .commit()
+            .wrap_err("Could not record the instance")?;
+        Ok(id)
+    }
+
+    /// Records where the instance with the id stands with a destination
+    pub(crate) fn set_delivery(
+        &self,
+        instance_id: i64,
+        destination: &str,
+        state: DeliveryState,
+    ) -> color_eyre::Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO deliveries (instance_id, destination, state, updated_at)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (instance_id, destination) DO UPDATE SET
+                    state = excluded.state, updated_at = excluded.updated_at",
+                params![instance_id, destination, state.to_string(), now()],
+            )
+            .wrap_err("Could not record the delivery")?;
+        Ok(())
+    }
+
+    /// Records the state of the delivery of the instance with the id,
+    /// only warning when it fails as the index must not stop the
+    /// instances
+    pub(crate) fn note_delivery(
+        &self,
+        instance_id: i64,
+        sop_instance_uid: &str,
+        destination: &str,
+        state: DeliveryState,
+    ) {
+        if let Err(e) = self.set_delivery(instance_id, destination, state) {
+            warn!(
+                "Could not index the delivery of {} to {}: {:#}",
+                sop_instance_uid, destination, e
+            );
+        }
+    }
+
+    /// The instances of a patient, study, series or accession number,
+    /// or the instance with the SOP Instance UID, oldest first
+    pub(crate) fn find(&self, key: &str) -> color_eyre::Result<Vec<IndexedInstance>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT id, path, patient_id, study_instance_uid, sop_instance_uid, modality, size,
+                transfer_syntax, calling_aet, peer, received_at
+            FROM instances
+            WHERE ?1 IN (patient_id, study_instance_uid, series_instance_uid, accession_number,
+                sop_instance_uid)
+            ORDER BY received_at, id",
+        )?;
+        let rows = statement
+            .query_map([key], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    IndexedInstance {
+                        location: row.get(1)?,
+                        patient_id: row.get(2)?,
+                        study_instance_uid: row.get(3)?,
+                        sop_instance_uid: row.get(4)?,
+                        modality: row.get(5)?,
+                        size: row.get(6)?,
+                        transfer_syntax: row.get(7)?,
+                        calling_aet: row.get(8)?,
+                        peer: row.get(9)?,
+                        received_at: row.get(10)?,
+                        deliveries: Vec::new(),
+                    },
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .wrap_err("Could not read the index")?;
+        let mut deliveries = connection.prepare(
+            "SELECT destination, state FROM deliveries WHERE instance_id = ?1 ORDER BY destination",
+        )?;
+        rows.into_iter()
+            .map(|(id, mut instance)| {
+                instance.deliveries = deliveries
+                    .query_map([id], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?
+                    .map(|delivery| {
+                        let (destination, state) = delivery?;
+                        Ok((destination, state.parse()?))
+                    })
+                    .collect::<color_eyre::Result<_>>()?;
+                Ok(instance)
+            })
+            .collect()
+    }
+
+    /// The instances received before `received_before`, in seconds
+    /// since the Unix epoch, that are no longer pending delivery nor
+    /// failed to be delivered, oldest first
+    pub(crate) fn delivered(&self, received_before: u64) -> color_eyre::Result<Vec<IndexedFile>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT id, path FROM instances i
+            WHERE received_at < ?1 AND {}
+            ORDER BY received_at, id",
+            DELIVERED
+        ))?;
+        let instances = statement
+            .query_map([received_before], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()
+            .wrap_err("Could not read the index")?;
+        Ok(instances)
+    }
+
+    /// The instances of the study received last the longest ago among
+    /// the studies whose instances were all received before
+    /// `received_before` and are no longer pending delivery nor failed
+    /// to be delivered
+    pub(crate) fn oldest_delivered_study(
+        &self,
+        received_before: u64,
+    ) -> color_eyre::Result<Option<(String, Vec<IndexedFile>)>> {
+        let connection = self.connection.lock().unwrap();
+        let study: Option<String> = connection
+            .query_row(
+                &format!(
+                    "SELECT study_instance_uid FROM instances i
+                    GROUP BY study_instance_uid
+                    HAVING MAX(received_at) < ?1 AND MIN({}) = 1
+                    ORDER BY MAX(received_at)
+                    LIMIT 1",
+                    DELIVERED
+                ),
+                [received_before],
+                |row| row.get(0),
+            )
+            .optional()
+            .wrap_err("Could not read the index")?;
+        let Some(study) = study else {
+            return Ok(None);
+        };
+        let mut statement =
+            connection.prepare("SELECT id, path FROM instances WHERE study_instance_uid = ?1")?;
+        let instances = statement
+            .query_map([&study], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()
+            .wrap_err("Could not read the index")?;
+        Ok(Some((study, instances)))
+    }
+
+    /// Forgets an instance and its deliveries, once its file is deleted
+    pub(crate) fn remove(&self, id: i64) -> color_eyre::Result<()> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM instances WHERE id = ?1", [id])
+            .wrap_err("Could not update the index")?;
+        Ok(())
+    }
+}
+
+/// The current time, in seconds since the Unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+
+    use dicom::{
+        core::{DataElement, VR},
+        dicom_value,
+        object::{FileMetaTableBuilder, InMemDicomObject},
+    };
+
+    use super::*;
+    use crate::pipeline::Origin;
+
+    /// An empty directory of its own for a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("eai-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn instance(study_uid: &str, sop_uid: &str) -> Instance {
+        let object = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::PATIENT_ID, VR::LO, dicom_value!(Str, "PID001")),
+            DataElement::new(
+                tags::STUDY_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, study_uid),
+            ),
+        ]);
+        let meta = FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+            .media_storage_sop_instance_uid(sop_uid)
+            .transfer_syntax("1.2.840.10008.1.2.1")
+            .build()
+            .unwrap();
+        Instance {
+            object: object.with_exact_meta(meta),
+            origin: Origin {
+                calling_aet: Some("MODALITY".to_string()),
+                called_aet: Some("EAI".to_string()),
+                peer: "127.0.0.1:40000".to_string(),
+            },
+        }
+    }
+
+    fn ids(files: &[IndexedFile]) -> Vec<i64> {
+        files.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn deliveries_follow_the_copy_they_were_queued_for() {
+        let index = InstanceIndex::open(&test_dir("index-copies")).unwrap();
+        let first = index
+            .record(&instance("1.2.3", "1.2.3.4"), "a.dcm", 10)
+            .unwrap();
+        index
+            .set_delivery(first, "pacs", DeliveryState::Pending)
+            .unwrap();
+        // A newer copy of the instance, kept beside the first one
+        let second = index
+            .record(&instance("1.2.3", "1.2.3.4"), "b.dcm", 10)
+            .unwrap();
+        assert_ne!(first, second);
+        index
+            .set_delivery(second, "pacs", DeliveryState::Pending)
+            .unwrap();
+
+        index
+            .set_delivery(first, "pacs", DeliveryState::Sent)
+            .unwrap();
+        assert_eq!(ids(&index.delivered(now() + 1).unwrap()), [first]);
+        let found = index.find("1.2.3.4").unwrap();
+        assert_eq!(
+            found[0].deliveries,
+            [("pacs".to_string(), DeliveryState::Sent)]
+        );
+        assert_eq!(
+            found[1].deliveries,
+            [("pacs".to_string(), DeliveryState::Pending)]
+        );
+    }
+
+    #[test]
+    fn pending_and_failed_instances_are_never_delivered() {
+        let index = InstanceIndex::open(&test_dir("index-pending")).unwrap();
+        let sent = index
+            .record(&instance("1.2.3", "1.2.3.1"), "a.dcm", 10)
+            .unwrap();
+        let pending = index
+            .record(&instance("1.2.3", "1.2.3.2"), "b.dcm", 10)
+            .unwrap();
+        let failed = index
+            .record(&instance("1.2.4", "1.2.4.1"), "c.dcm", 10)
+            .unwrap();
+        index
+            .set_delivery(sent, "pacs", DeliveryState::Sent)
+            .unwrap();
+        index
+            .set_delivery(pending, "pacs", DeliveryState::Sent)
+            .unwrap();
+        index
+            .set_delivery(pending, "archive", DeliveryState::Pending)
+            .unwrap();
+        index
+            .set_delivery(failed, "pacs", DeliveryState::Failed)
+            .unwrap();
+
+        assert_eq!(ids(&index.delivered(now() + 1).unwrap()), [sent]);
+        // Neither study is delivered as a whole
+        assert!(index.oldest_delivered_study(now() + 1).unwrap().is_none());
+
+        index
+            .set_delivery(pending, "archive", DeliveryState::Dropped)
+            .unwrap();
+        let (study, instances) = index.oldest_delivered_study(now() + 1).unwrap().unwrap();
+        assert_eq!(study, "1.2.3");
+        assert_eq!(ids(&instances), [sent, pending]);
+        // Nothing received before the time given is delivered
+        assert!(index.delivered(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn replacing_a_file_forgets_its_settled_deliveries() {
+        let index = InstanceIndex::open(&test_dir("index-replaced")).unwrap();
+        let id = index
+            .record(&instance("1.2.3", "1.2.3.4"), "a.dcm", 10)
+            .unwrap();
+        index.set_delivery(id, "pacs", DeliveryState::Sent).unwrap();
+        index
+            .set_delivery(id, "archive", DeliveryState::Failed)
+            .unwrap();
+        index
+            .set_delivery(id, "viewer", DeliveryState::Pending)
+            .unwrap();
+
+        assert_eq!(
+            index
+                .record(&instance("1.2.3", "1.2.3.4"), "a.dcm", 20)
+                .unwrap(),
+            id
+        );
+        let found = index.find("1.2.3.4").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].size, 20);
+        assert_eq!(
+            found[0].deliveries,
+            [
+                ("archive".to_string(), DeliveryState::Failed),
+                ("viewer".to_string(), DeliveryState::Pending)
+            ]
+        );
+
+        index.remove(id).unwrap();
+        assert!(index.find("1.2.3.4").unwrap().is_empty());
+        assert!(index.set_delivery(id, "pacs", DeliveryState::Sent).is_err());
+    }
+}
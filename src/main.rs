@@ -1,45 +1,506 @@
-use std::{collections::HashMap, path::Path, thread};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 // use bogus::bogus_config;
-use color_eyre::eyre::Context;
-use tracing::{debug, info, subscriber};
+use clap::Parser;
+use color_eyre::eyre::{bail, Context};
+use tracing::{debug, info, subscriber, warn};
 use tracing_subscriber::FmtSubscriber;
 
 use crate::{
     // bogus::update_bogus_config,
-    store_scp::store_scp,
-    utils::{Config, State},
+    channel::RunningChannel,
+    cli::{Cli, Command, QuarantineAction},
+    echo_scu::echo_scu,
+    index::InstanceIndex,
+    pipeline::Bus,
+    pseudonymize::{open_key_store, Entry},
+    quarantine::{find_item, list_items, QuarantineItem},
+    store_scu::send_files,
+    utils::{
+        Channel, Config, DestinationConfig, DestinationKind, LogLevel, Pseudonymization,
+        SourceConfig, State,
+    },
 };
 
+pub mod backend;
+pub mod blackout;
 pub mod bogus;
+pub mod channel;
+pub mod charset;
+pub mod cli;
+pub mod deidentify;
+pub mod destination;
+pub mod echo_scu;
+pub mod external_command;
+pub mod filtering;
+pub mod hot_folder;
+pub mod index;
+pub mod media;
+pub mod migration;
+pub mod morphing;
+pub mod pipeline;
+pub mod processor;
+pub mod pseudonymize;
+pub mod quarantine;
+pub mod reconciliation;
+pub mod retention;
+pub mod s3;
+pub mod scripting;
+pub mod source;
+pub mod stage;
+pub mod storage;
 pub mod store_scp;
+pub mod store_scu;
+pub mod stow_rs;
 pub mod utils;
+pub mod validation;
+pub mod zip_archive;
 
 const CONFIG_FILE: &str = "config.json";
 
 fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
 
-    let config_path = Path::new(CONFIG_FILE);
+    let cli = Cli::parse();
 
-    let mut config = Config::from_json_file(Path::new(config_path))?;
-    let mut state = State::new();
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(&cli.config),
+        Command::Validate { config } => validate(&config),
+        Command::Echo {
+            address,
+            calling_aet,
+        } => {
+            init_tracing(&LogLevel::Info)?;
+            let status = echo_scu(&calling_aet, &address)?;
+            if status != 0x0000 {
+                bail!("C-ECHO failed with status {:#06x}", status);
+            }
+            println!("C-ECHO to {} succeeded", address);
+            Ok(())
+        }
+        Command::Send {
+            paths,
+            to,
+            calling_aet,
+            max_pdu,
+        } => {
+            init_tracing(&LogLevel::Info)?;
+            let stored = send_files(&calling_aet, &to, max_pdu, &paths)?;
+            println!("{} instance(s) sent to {}", stored, to);
+            Ok(())
+        }
+        Command::Channels => list_channels(&cli.config),
+        Command::Migrate { config } => migrate_config(&config),
+        Command::Reidentify {
+            key_store,
+            pseudonyms,
+            passphrase_env,
+        } => reidentify(&key_store, &pseudonyms, passphrase_env),
+        Command::Quarantine { channel, action } => manage_quarantine(&cli.config, &channel, action),
+        Command::Verify { channel } => verify_storage(&cli.config, &channel),
+        Command::Locate { channel, key } => locate(&cli.config, &channel, &key),
+        Command::Export { channel, key, dir } => export_media(&cli.config, &channel, &key, &dir),
+    }
+}
 
+fn init_tracing(log_level: &LogLevel) -> color_eyre::Result<()> {
     subscriber::set_global_default(
         FmtSubscriber::builder()
-            .with_max_level(config.log_level.to_tracing_level())
+            .with_max_level(log_level.to_tracing_level())
             .finish(),
     )
-    .wrap_err("Error setting the global tracing subscriber")?;
+    .wrap_err("Error setting the global tracing subscriber")
+}
+
+/// Checks that the config file can be parsed and is coherent
+fn validate(config_path: &Path) -> color_eyre::Result<()> {
+    if !config_path.exists() {
+        bail!("{} does not exist", config_path.display());
+    }
+    let config = Config::from_json_file(config_path)
+        .wrap_err_with(|| format!("Could not parse {}", config_path.display()))?;
+    let problems = config.validate();
+    if !problems.is_empty() {
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        bail!(
+            "{} has {} problem(s)",
+            config_path.display(),
+            problems.len()
+        );
+    }
+    println!("{} is valid", config_path.display());
+    Ok(())
+}
+
+/// Rewrites a config file in the current format, keeping a copy of
+/// the original next to it
+fn migrate_config(config_path: &Path) -> color_eyre::Result<()> {
+    if !config_path.exists() {
+        bail!("{} does not exist", config_path.display());
+    }
+    let config = Config::from_json_file(config_path)
+        .wrap_err_with(|| format!("Could not parse {}", config_path.display()))?;
+    let mut backup = config_path.as_os_str().to_owned();
+    backup.push(".bak");
+    let backup = PathBuf::from(backup);
+    std::fs::copy(config_path, &backup)
+        .wrap_err_with(|| format!("Could not back up {}", config_path.display()))?;
+    config
+        .to_json_file(config_path)
+        .wrap_err_with(|| format!("Could not write {}", config_path.display()))?;
+    println!(
+        "{} migrated to version {}, the original is saved as {}",
+        config_path.display(),
+        config.version,
+        backup.display()
+    );
+    Ok(())
+}
+
+/// Prints the original values of pseudonyms, or of every pseudonym
+/// when none is given
+fn reidentify(
+    key_store: &Path,
+    pseudonyms: &[String],
+    passphrase_env: Option<String>,
+) -> color_eyre::Result<()> {
+    let store = open_key_store(&Pseudonymization {
+        key_store: key_store.to_path_buf(),
+        passphrase_env,
+        patient_id_prefix: String::new(),
+    })?;
+    let mut entries: Vec<&Entry> = if pseudonyms.is_empty() {
+        store.entries().collect()
+    } else {
+        let mut entries = Vec::new();
+        for pseudonym in pseudonyms {
+            match store.original(pseudonym) {
+                Some(entry) => entries.push(entry),
+                None => println!("{} is not in {}", pseudonym, key_store.display()),
+            }
+        }
+        entries
+    };
+    entries.sort_by(|a, b| (a.kind, &a.pseudonym).cmp(&(b.kind, &b.pseudonym)));
+    for entry in entries {
+        println!("{:?}\t{}\t{}", entry.kind, entry.pseudonym, entry.original);
+    }
+    Ok(())
+}
+
+/// The channel named on the command line, by name or id
+fn find_channel<'a>(
+    config: &'a Config,
+    config_path: &Path,
+    channel: &str,
+) -> color_eyre::Result<&'a Channel> {
+    let found = config.channel_by_name(channel).or_else(|| {
+        channel
+            .parse()
+            .ok()
+            .and_then(|id: u64| config.channels.get(&id))
+    });
+    match found {
+        Some(found) => Ok(found),
+        None => bail!(
+            "There is no channel {} in {}",
+            channel,
+            config_path.display()
+        ),
+    }
+}
+
+/// Lists, inspects, releases or deletes the instances in the
+/// quarantine of a channel
+fn manage_quarantine(
+    config_path: &Path,
+    channel: &str,
+    action: QuarantineAction,
+) -> color_eyre::Result<()> {
+    let config = Config::from_json_file(config_path)
+        .wrap_err_with(|| format!("Could not parse {}", config_path.display()))?;
+    let found = find_channel(&config, config_path, channel)?;
+    let Some(dir) = &found.quarantine_dir else {
+        bail!("Channel {} has no quarantine_dir", found.name);
+    };
+    // The items named on the command line, or all of them
+    let select = |items: Vec<String>, all: bool| -> color_eyre::Result<Vec<QuarantineItem>> {
+        if all {
+            list_items(dir)
+        } else if items.is_empty() {
+            bail!("No quarantined instance given, use --all for all of them")
+        } else {
+            items.iter().map(|id| find_item(dir, id)).collect()
+        }
+    };
+    match action {
+        QuarantineAction::List => {
+            println!(
+                "{:<50} {:<20} {:<8} {:<30} REASON",
+                "ITEM", "QUARANTINED (UTC)", "STATE", "FROM"
+            );
+            for item in list_items(dir)? {
+                let quarantined_at =
+                    chrono::NaiveDateTime::from_timestamp_opt(item.record.quarantined_at as i64, 0)
+                        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                        .unwrap_or_default();
+                let state = match (item.is_released(), item.is_raw()) {
+                    (true, _) => "released",
+                    (false, true) => "raw",
+                    (false, false) => "held",
+                };
+                let from = match &item.record.calling_aet {
+                    Some(aet) => format!("{} ({})", aet, item.record.peer),
+                    None => item.record.peer.clone(),
+                };
+                let reason = item.record.reason.lines().next().unwrap_or_default();
+                println!(
+                    "{:<50} {:<20} {:<8} {:<30} {}",
+                    item.id, quarantined_at, state, from, reason
+                );
+            }
+        }
+        QuarantineAction::Inspect { item } => {
+            let item = find_item(dir, &item)?;
+            println!("{}", serde_json::to_string_pretty(&item.record)?);
+            match item.instance() {
+                Ok(instance) => println!(
+                    "{} is readable, SOP Class {}",
+                    item.path.display(),
+                    instance.sop_class_uid().trim_end_matches('\0')
+                ),
+                Err(e) => println!("{} is not readable: {:#}", item.path.display(), e),
+            }
+        }
+        QuarantineAction::Release { items, all } => {
+            let items = select(items, all)?;
+            for item in &items {
+                item.release()?;
+            }
+            println!(
+                "{} instance(s) released, channel {} takes them again once running",
+                items.len(),
+                found.name
+            );
+        }
+        QuarantineAction::Purge { items, all } => {
+            let items = select(items, all)?;
+            for item in &items {
+                item.purge()?;
+            }
+            println!("{} instance(s) deleted", items.len());
+        }
+    }
+    Ok(())
+}
+
+/// Checks the instances stored by a channel, printing the files that
+/// are corrupted
+fn verify_storage(config_path: &Path, channel: &str) -> color_eyre::Result<()> {
+    let config = Config::from_json_file(config_path)
+        .wrap_err_with(|| format!("Could not parse {}", config_path.display()))?;
+    let found = find_channel(&config, config_path, channel)?;
+    let Some(storage) = &found.storage else {
+        bail!("Channel {} has no storage", found.name);
+    };
+    if storage.s3.is_some() {
+        bail!(
+            "Channel {} stores its instances in a bucket, which has no checksums",
+            found.name
+        );
+    }
+    let verification = storage::verify(&storage.out_dir)?;
+    for problem in &verification.problems {
+        println!("{}", problem);
+    }
+    if !verification.problems.is_empty() {
+        bail!(
+            "{} has {} problem(s) among {} file(s)",
+            storage.out_dir.display(),
+            verification.problems.len(),
+            verification.checked
+        );
+    }
+    println!(
+        "{} file(s) in {} are intact",
+        verification.checked,
+        storage.out_dir.display()
+    );
+    Ok(())
+}
+
+/// Prints the stored instances matching the key and where they stand
+/// with each destination
+fn locate(config_path: &Path, channel: &str, key: &str) -> color_eyre::Result<()> {
+    let config = Config::from_json_file(config_path)
+        .wrap_err_with(|| format!("Could not parse {}", config_path.display()))?;
+    let found = find_channel(&config, config_path, channel)?;
+    let Some(storage) = found.storage.as_ref().filter(|storage| storage.index) else {
+        bail!("Channel {} has no instance index", found.name);
+    };
+    let instances = InstanceIndex::open(&storage.out_dir)?.find(key)?;
+    if instances.is_empty() {
+        bail!("Nothing matches {} in the index of {}", key, found.name);
+    }
+    println!(
+        "{:<20} {:<30} {:<16} {:<8} {:<10} LOCATION",
+        "RECEIVED (UTC)", "FROM", "PATIENT", "MODALITY", "SIZE"
+    );
+    for instance in &instances {
+        let received_at = chrono::NaiveDateTime::from_timestamp_opt(instance.received_at as i64, 0)
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        let from = match &instance.calling_aet {
+            Some(aet) => format!("{} ({})", aet, instance.peer),
+            None => instance.peer.clone(),
+        };
+        println!(
+            "{:<20} {:<30} {:<16} {:<8} {:<10} {}",
+            received_at,
+            from,
+            instance.patient_id,
+            instance.modality,
+            instance.size,
+            instance.location
+        );
+        println!(
+            "  {} {}, study {}",
+            instance.sop_instance_uid, instance.transfer_syntax, instance.study_instance_uid
+        );
+        for (destination, state) in &instance.deliveries {
+            println!("  {:<8} {}", state, destination);
+        }
+    }
+    println!("{} instance(s)", instances.len());
+    Ok(())
+}
 
-    match Config::to_json_file(&config, Path::new("config.json")) {
+/// Writes the instances of a study or patient stored by a channel to
+/// DICOM media
+fn export_media(
+    config_path: &Path,
+    channel: &str,
+    key: &str,
+    dir: &Path,
+) -> color_eyre::Result<()> {
+    let config = Config::from_json_file(config_path)
+        .wrap_err_with(|| format!("Could not parse {}", config_path.display()))?;
+    let found = find_channel(&config, config_path, channel)?;
+    let Some(storage) = &found.storage else {
+        bail!("Channel {} has no storage", found.name);
+    };
+    let exported = media::export(storage, key, dir)?;
+    println!(
+        "{} instance(s) of {} patient(s), {} study(ies) and {} series written to {}",
+        exported.instances,
+        exported.patients,
+        exported.studies,
+        exported.series,
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Prints the configured channels and their status
+fn list_channels(config_path: &Path) -> color_eyre::Result<()> {
+    let config = Config::from_json_file(config_path)
+        .wrap_err_with(|| format!("Could not parse {}", config_path.display()))?;
+    let mut channels: Vec<_> = config.channels.iter().collect();
+    channels.sort_by_key(|(id, _)| **id);
+    println!(
+        "{:<6} {:<20} {:<30} {:<8} DESTINATIONS",
+        "ID", "NAME", "SOURCE", "STATUS"
+    );
+    for (id, channel) in channels {
+        let destinations: Vec<String> = channel
+            .destinations
+            .iter()
+            .map(|destination| describe_destination(&config, destination))
+            .collect();
+        println!(
+            "{:<6} {:<20} {:<30} {:<8} {}",
+            id,
+            channel.name,
+            describe_source(&config, &channel.source),
+            format!("{:?}", channel.status),
+            destinations.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn describe_source(config: &Config, source: &SourceConfig) -> String {
+    match source {
+        SourceConfig::Dicom { local_ae: name } => match config.local_ae(name) {
+            Some(ae) => format!("{} ({}@{}:{})", source, ae.aet, ae.ip, ae.port),
+            None => format!("{} (unknown)", source),
+        },
+        _ => source.to_string(),
+    }
+}
+
+fn describe_destination(config: &Config, destination: &DestinationConfig) -> String {
+    match &destination.kind {
+        DestinationKind::Dicom { remote_ae: name } => match config.remote_ae(name) {
+            Some(ae) => format!("{} ({})", destination, ae.address()),
+            None => format!("{} (unknown)", destination),
+        },
+        _ => destination.to_string(),
+    }
+}
+
+/// Launches the source of the channel
+fn start_channel(
+    config: &Config,
+    channel: &Channel,
+    bus: &Arc<Bus>,
+    running: &mut HashMap<String, RunningChannel>,
+) {
+    match RunningChannel::start(channel, config, bus) {
+        Ok(running_channel) => {
+            running.insert(channel.name.clone(), running_channel);
+        }
+        Err(e) => warn!("Could not start channel {}: {:#}", channel.name, e),
+    }
+}
+
+/// Stops the source of the channel and waits for it to release its resources
+fn stop_channel(channel: &Channel, running: &mut HashMap<String, RunningChannel>) {
+    if let Some(running_channel) = running.remove(&channel.name) {
+        running_channel.stop();
+    }
+}
+
+/// Runs the daemon: launches the channels described in the config
+/// file and follows its changes
+fn run(config_path: &Path) -> color_eyre::Result<()> {
+    if Config::is_outdated(config_path)? {
+        bail!(
+            "{} is in the format of an older version, run `eai-rs migrate {}` to convert it first",
+            config_path.display(),
+            config_path.display()
+        );
+    }
+    let mut config = Config::from_json_file(config_path)?;
+    let mut state = State::new();
+
+    init_tracing(&config.log_level)?;
+
+    match Config::to_json_file(&config, config_path) {
         Ok(_) => info!("Config file created"),
         Err(e) => info!("Error creating the config file: {}", e),
     }
 
     debug!("{:?}", config);
 
-    let mut handles = HashMap::new();
+    let mut running = HashMap::new();
+    let bus = Arc::new(Bus::default());
 
     // let mut bogus_wait = 0;
     loop {
@@ -50,20 +511,18 @@ fn main() -> color_eyre::Result<()> {
 
             for action in actions {
                 match action {
-                    utils::Actions::Create(mut channel) => {
+                    utils::Actions::Create(channel) => {
                         info!("Creating channel {}", channel.name);
-                        info!(
-                            "Launching the storescp for {} at {}:{}",
-                            channel.source.aet, channel.source.ip, channel.source.port
-                        );
-                        let handle = thread::spawn(move || channel.source.start_node());
-                        handles.insert(channel.name, handle);
+                        start_channel(&config, &channel, &bus, &mut running);
                     }
-                    utils::Actions::Modify(_) => todo!(),
-                    utils::Actions::Delete(mut channel) => {
+                    utils::Actions::Modify(old_channel, channel) => {
+                        info!("Restarting channel {}", channel.name);
+                        stop_channel(&old_channel, &mut running);
+                        start_channel(&config, &channel, &bus, &mut running);
+                    }
+                    utils::Actions::Delete(channel) => {
                         info!("Deleting channel {}", channel.name);
-                        channel.source.stop_node();
-                        handles.remove(&channel.name).unwrap();
+                        stop_channel(&channel, &mut running);
                         // handle.join().unwrap();
                         // let addresses = channel.addresses.clone();
                         // for (mut node, _) in addresses {
@@ -83,6 +542,10 @@ fn main() -> color_eyre::Result<()> {
 
             state = config.clone();
         }
+        for running_channel in running.values() {
+            running_channel.reload();
+            running_channel.release_quarantined();
+        }
         // update config
         // if bogus_wait == 3 {
         //     info!("Updating the config");
@@ -92,7 +555,7 @@ fn main() -> color_eyre::Result<()> {
 
         info!("Sleeping for 1 second...");
 
-        config = Config::from_json_file(Path::new(config_path))?;
+        config = Config::from_json_file(config_path)?;
         // sleep to go easy on the CPU
         std::thread::sleep(std::time::Duration::from_secs(1));
     }
@@ -0,0 +1,863 @@
+//! The export of the stored instances of a study or a patient to a
+//! directory laid out as DICOM media (PS3.10), such as a USB drive: the
+//! files under `DICOM/` with 8.3 file IDs, and a DICOMDIR following the
+//! General Purpose profile, with the patient, study, series and image
+//! records.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{bail, eyre, Context};
+use dicom::{
+    core::{header::HasLength, DataElement, PrimitiveValue, Tag, VR},
+    dicom_value,
+    dictionary_std::tags,
+    encoding::TransferSyntaxIndex,
+    object::{
+        file::ReadPreamble, DefaultDicomObject, FileMetaTableBuilder, InMemDicomObject,
+        OpenFileOptions,
+    },
+    transfer_syntax::TransferSyntaxRegistry,
+};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::{
+    backend::{build_backend, StorageBackend},
+    deidentify::uid_from_digest,
+    index::{InstanceIndex, INDEX_DATABASE},
+    storage::{value_of, INDEX_FILE},
+    utils::Storage,
+};
+
+/// The name of the directory file at the root of the media
+const DICOMDIR: &str = "DICOMDIR";
+/// The directory of the instances, the first component of their file IDs
+const FILES_DIR: &str = "DICOM";
+const MEDIA_STORAGE_DIRECTORY: &str = "1.2.840.10008.1.3.10";
+const FILE_SET_ID: &str = "EAI_RS";
+const EXPLICIT_VR_LE: &str = "1.2.840.10008.1.2.1";
+/// The uncompressed transfer syntaxes other than Explicit VR Little
+/// Endian, which the General Purpose profile does not allow
+const NATIVE_TRANSFER_SYNTAXES: [&str; 2] = ["1.2.840.10008.1.2", "1.2.840.10008.1.2.2"];
+/// The preamble and the `DICM` prefix before the file meta group
+const PREAMBLE_LENGTH: u32 = 128 + 4;
+/// The tag, VR, reserved bytes and length of the Directory Record Sequence
+const SEQUENCE_HEADER_LENGTH: u32 = 12;
+/// The tag and the length of an item
+const ITEM_HEADER_LENGTH: u32 = 8;
+
+/// What an exported instance is missing among the keys of its record
+#[derive(Clone, Copy)]
+enum Fallback {
+    /// The key is left out
+    Skip,
+    /// The key is written without a value
+    Empty,
+    /// The key takes the number of the record among its siblings
+    Number,
+}
+
+type Key = (Tag, VR, Fallback);
+
+const PATIENT_KEYS: &[Key] = &[
+    (tags::SPECIFIC_CHARACTER_SET, VR::CS, Fallback::Skip),
+    (tags::PATIENT_NAME, VR::PN, Fallback::Empty),
+    (tags::PATIENT_ID, VR::LO, Fallback::Empty),
+];
+const STUDY_KEYS: &[Key] = &[
+    (tags::SPECIFIC_CHARACTER_SET, VR::CS, Fallback::Skip),
+    (tags::STUDY_DATE, VR::DA, Fallback::Empty),
+    (tags::STUDY_TIME, VR::TM, Fallback::Empty),
+    (tags::ACCESSION_NUMBER, VR::SH, Fallback::Empty),
+    (tags::STUDY_DESCRIPTION, VR::LO, Fallback::Empty),
+    (tags::STUDY_INSTANCE_UID, VR::UI, Fallback::Empty),
+    (tags::STUDY_ID, VR::SH, Fallback::Number),
+];
+const SERIES_KEYS: &[Key] = &[
+    (tags::SPECIFIC_CHARACTER_SET, VR::CS, Fallback::Skip),
+    (tags::MODALITY, VR::CS, Fallback::Empty),
+    (tags::SERIES_INSTANCE_UID, VR::UI, Fallback::Empty),
+    (tags::SERIES_NUMBER, VR::IS, Fallback::Number),
+];
+/// The keys of every instance record, whatever its type
+const INSTANCE_KEYS: &[Key] = &[
+    (tags::SPECIFIC_CHARACTER_SET, VR::CS, Fallback::Skip),
+    (tags::INSTANCE_NUMBER, VR::IS, Fallback::Number),
+];
+const DOCUMENT_KEYS: &[Key] = &[
+    (tags::COMPLETION_FLAG, VR::CS, Fallback::Skip),
+    (tags::VERIFICATION_FLAG, VR::CS, Fallback::Skip),
+    (tags::CONTENT_DATE, VR::DA, Fallback::Skip),
+    (tags::CONTENT_TIME, VR::TM, Fallback::Skip),
+    (tags::CONCEPT_NAME_CODE_SEQUENCE, VR::SQ, Fallback::Skip),
+    (tags::DOCUMENT_TITLE, VR::ST, Fallback::Skip),
+    (
+        tags::MIME_TYPE_OF_ENCAPSULATED_DOCUMENT,
+        VR::LO,
+        Fallback::Skip,
+    ),
+];
+const PRESENTATION_KEYS: &[Key] = &[
+    (tags::CONTENT_LABEL, VR::CS, Fallback::Skip),
+    (tags::CONTENT_DESCRIPTION, VR::LO, Fallback::Skip),
+    (tags::PRESENTATION_CREATION_DATE, VR::DA, Fallback::Skip),
+    (tags::PRESENTATION_CREATION_TIME, VR::TM, Fallback::Skip),
+    (tags::CONTENT_CREATOR_NAME, VR::PN, Fallback::Skip),
+    (tags::REFERENCED_SERIES_SEQUENCE, VR::SQ, Fallback::Skip),
+];
+
+/// What was written to the media
+#[derive(Debug)]
+pub(crate) struct Exported {
+    pub(crate) patients: usize,
+    pub(crate) studies: usize,
+    pub(crate) series: usize,
+    pub(crate) instances: usize,
+}
+
+/// A directory record with the records of its lower level, in the
+/// order they are read
+struct Node {
+    /// The PatientID or the UID the instances are grouped by
+    uid: String,
+    /// The component of the file IDs, such as `STU00001`
+    name: String,
+    sort_key: String,
+    record: InMemDicomObject,
+    children: Vec<Node>,
+}
+
+impl Node {
+    /// The record of the level, found or added for the instance
+    fn child(
+        &mut self,
+        uid: String,
+        prefix: &str,
+        sort_key: String,
+        record: impl FnOnce(usize) -> InMemDicomObject,
+    ) -> &mut Node {
+        let position = match self.children.iter().position(|child| child.uid == uid) {
+            Some(position) => position,
+            None => {
+                let number = self.children.len() + 1;
+                self.children.push(Node {
+                    uid,
+                    name: format!("{}{:05}", prefix, number),
+                    sort_key,
+                    record: record(number),
+                    children: Vec::new(),
+                });
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[position]
+    }
+
+    fn sort(&mut self) {
+        self.children
+            .sort_by(|a, b| (&a.sort_key, &a.uid).cmp(&(&b.sort_key, &b.uid)));
+        for child in self.children.iter_mut() {
+            child.sort();
+        }
+    }
+}
+
+/// The records of the nodes, each one followed by those of its lower
+/// level, with their depth
+fn flatten(nodes: Vec<Node>, depth: usize, records: &mut Vec<(usize, InMemDicomObject)>) {
+    for Node {
+        record, children, ..
+    } in nodes
+    {
+        records.push((depth, record));
+        flatten(children, depth + 1, records);
+    }
+}
+
+/// Copies the instances of the study or patient `key` stored by a
+/// channel to `dir`, which must be empty, with a DICOMDIR
+pub(crate) fn export(storage: &Storage, key: &str, dir: &Path) -> color_eyre::Result<Exported> {
+    if fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()) {
+        bail!("{} is not empty", dir.display());
+    }
+    let backend = build_backend(storage)?;
+    let keys = stored_keys(storage, backend.as_ref(), key)?;
+    if keys.is_empty() {
+        bail!(
+            "No stored instance has the StudyInstanceUID or PatientID {}",
+            key
+        );
+    }
+    let mut root = Node {
+        uid: String::new(),
+        name: String::new(),
+        sort_key: String::new(),
+        record: InMemDicomObject::new_empty(),
+        children: Vec::new(),
+    };
+    for stored in keys.iter() {
+        let bytes = backend
+            .get(stored)?
+            .ok_or_else(|| eyre!("{} is missing", backend.location(stored)))?;
+        let object = OpenFileOptions::new()
+            .read_preamble(ReadPreamble::Always)
+            .from_reader(bytes.as_slice())
+            .wrap_err_with(|| format!("Could not read {}", backend.location(stored)))?;
+        let object = to_general_purpose(object)?;
+        let file_id = add_records(&mut root, &object);
+        let path = file_id
+            .iter()
+            .fold(dir.to_path_buf(), |path, id| path.join(id));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("Could not create the directory {}", parent.display()))?;
+        }
+        object
+            .write_to_file(&path)
+            .wrap_err_with(|| format!("Could not write {}", path.display()))?;
+        debug!(
+            "Exported {} to {}",
+            backend.location(stored),
+            path.display()
+        );
+    }
+    root.sort();
+    let mut records = Vec::new();
+    flatten(root.children, 0, &mut records);
+    let count = |level| records.iter().filter(|(depth, _)| *depth == level).count();
+    let exported = Exported {
+        patients: count(0),
+        studies: count(1),
+        series: count(2),
+        instances: count(3),
+    };
+    let path = dir.join(DICOMDIR);
+    fs::write(&path, directory_file(records, &keys)?)
+        .wrap_err_with(|| format!("Could not write {}", path.display()))?;
+    Ok(exported)
+}
+
+/// The keys in the backend of the instances of the study or patient,
+/// the last one stored of an instance stored twice. They are found in
+/// the index, or in `out_dir` when the channel has none.
+fn stored_keys(
+    storage: &Storage,
+    backend: &dyn StorageBackend,
+    key: &str,
+) -> color_eyre::Result<Vec<String>> {
+    let mut found: Vec<(String, String)> = Vec::new();
+    if storage.index {
+        for instance in InstanceIndex::open(&storage.out_dir)?.find(key)? {
+            if instance.patient_id != key && instance.study_instance_uid != key {
+                continue;
+            }
+            let stored = backend
+                .key(&instance.location)
+                .ok_or_else(|| eyre!("{} is not in the storage", instance.location))?;
+            found.push((instance.sop_instance_uid, stored));
+        }
+    } else if storage.s3.is_some() {
+        bail!("The instances in a bucket are only found with an index");
+    } else {
+        scan(&storage.out_dir, Path::new(""), key, &mut found)?;
+    }
+    let mut last = HashMap::new();
+    for (i, (sop_instance_uid, _)) in found.iter().enumerate() {
+        last.insert(sop_instance_uid.clone(), i);
+    }
+    Ok(found
+        .into_iter()
+        .enumerate()
+        .filter(|(i, (sop_instance_uid, _))| last.get(sop_instance_uid) == Some(i))
+        .map(|(_, (_, stored))| stored)
+        .collect())
+}
+
+/// Reads the headers of the files in the directory, keeping the SOP
+/// Instance UID and the key of those of the study or patient
+fn scan(
+    root: &Path,
+    relative: &Path,
+    key: &str,
+    found: &mut Vec<(String, String)>,
+) -> color_eyre::Result<()> {
+    let dir = root.join(relative);
+    let mut entries: Vec<PathBuf> = fs::read_dir(&dir)
+        .wrap_err_with(|| format!("Could not read the directory {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+    for path in entries {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        // The temporary files, the checksums and the index
+        if name.starts_with('.') || name == INDEX_FILE || name.starts_with(INDEX_DATABASE) {
+            continue;
+        }
+        if path.is_dir() {
+            scan(root, &relative.join(&name), key, found)?;
+            continue;
+        }
+        let object = match OpenFileOptions::new()
+            .read_until(tags::PIXEL_DATA)
+            .open_file(&path)
+        {
+            Ok(object) => object,
+            Err(e) => {
+                debug!("Skipped {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if value_of(&object, tags::PATIENT_ID) == key
+            || value_of(&object, tags::STUDY_INSTANCE_UID) == key
+        {
+            let stored = relative
+                .join(&name)
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+            found.push((value_of(&object, tags::SOP_INSTANCE_UID), stored));
+        }
+    }
+    Ok(())
+}
+
+/// The instance in Explicit VR Little Endian when it is uncompressed,
+/// as it is otherwise
+fn to_general_purpose(mut object: DefaultDicomObject) -> color_eyre::Result<DefaultDicomObject> {
+    let transfer_syntax = object.meta().transfer_syntax().to_string();
+    if NATIVE_TRANSFER_SYNTAXES.contains(&transfer_syntax.as_str()) {
+        let meta = object.meta_mut();
+        meta.transfer_syntax = format!("{}\0", EXPLICIT_VR_LE);
+        meta.update_information_group_length();
+    } else if TransferSyntaxRegistry.get(&transfer_syntax).is_none() {
+        bail!("Unknown transfer syntax {}", transfer_syntax);
+    }
+    Ok(object)
+}
+
+/// Adds the records of the instance to the tree, returning the
+/// components of its file ID
+fn add_records(root: &mut Node, object: &DefaultDicomObject) -> Vec<String> {
+    let mut file_id = vec![FILES_DIR.to_string()];
+    let patient = root.child(
+        value_of(object, tags::PATIENT_ID),
+        "PAT",
+        value_of(object, tags::PATIENT_ID),
+        |number| record("PATIENT", PATIENT_KEYS, object, number),
+    );
+    file_id.push(patient.name.clone());
+    let study = patient.child(
+        value_of(object, tags::STUDY_INSTANCE_UID),
+        "STU",
+        format!(
+            "{}{}",
+            value_of(object, tags::STUDY_DATE),
+            value_of(object, tags::STUDY_TIME)
+        ),
+        |number| record("STUDY", STUDY_KEYS, object, number),
+    );
+    file_id.push(study.name.clone());
+    let series = study.child(
+        value_of(object, tags::SERIES_INSTANCE_UID),
+        "SER",
+        format!("{:>12}", value_of(object, tags::SERIES_NUMBER)),
+        |number| record("SERIES", SERIES_KEYS, object, number),
+    );
+    file_id.push(series.name.clone());
+    let (record_type, keys) = instance_record_type(&value_of(object, tags::SOP_CLASS_UID));
+    let image = series.child(
+        value_of(object, tags::SOP_INSTANCE_UID),
+        "IMG",
+        format!("{:>12}", value_of(object, tags::INSTANCE_NUMBER)),
+        |number| {
+            let mut record = record(record_type, INSTANCE_KEYS, object, number);
+            for (tag, vr, fallback) in keys {
+                put_key(&mut record, object, *tag, *vr, *fallback, number);
+            }
+            let mut file_id = file_id.clone();
+            file_id.push(format!("IMG{:05}", number));
+            record.put(DataElement::new(
+                tags::REFERENCED_FILE_ID,
+                VR::CS,
+                PrimitiveValue::Strs(file_id.into()),
+            ));
+            let meta = object.meta();
+            for (tag, value) in [
+                (
+                    tags::REFERENCED_SOP_CLASS_UID_IN_FILE,
+                    meta.media_storage_sop_class_uid(),
+                ),
+                (
+                    tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE,
+                    meta.media_storage_sop_instance_uid(),
+                ),
+                (
+                    tags::REFERENCED_TRANSFER_SYNTAX_UID_IN_FILE,
+                    meta.transfer_syntax(),
+                ),
+            ] {
+                record.put(DataElement::new(
+                    tag,
+                    VR::UI,
+                    dicom_value!(Str, value.trim_end_matches('\0')),
+                ));
+            }
+            record
+        },
+    );
+    file_id.push(image.name.clone());
+    file_id
+}
+
+/// The type of the record of an instance of the SOP class, with its
+/// keys beyond those of every instance
+fn instance_record_type(sop_class_uid: &str) -> (&'static str, &'static [Key]) {
+    const KEY_OBJECT_SELECTION: &str = "1.2.840.10008.5.1.4.1.1.88.59";
+    const STRUCTURED_REPORTS: &str = "1.2.840.10008.5.1.4.1.1.88.";
+    const PRESENTATION_STATES: &str = "1.2.840.10008.5.1.4.1.1.11.";
+    const ENCAPSULATED_DOCUMENTS: &str = "1.2.840.10008.5.1.4.1.1.104.";
+    if sop_class_uid == KEY_OBJECT_SELECTION {
+        ("KEY OBJECT DOC", DOCUMENT_KEYS)
+    } else if sop_class_uid.starts_with(STRUCTURED_REPORTS) {
+        ("SR DOCUMENT", DOCUMENT_KEYS)
+    } else if sop_class_uid.starts_with(PRESENTATION_STATES) {
+        ("PRESENTATION", PRESENTATION_KEYS)
+    } else if sop_class_uid.starts_with(ENCAPSULATED_DOCUMENTS) {
+        ("ENCAP DOC", DOCUMENT_KEYS)
+    } else {
+        ("IMAGE", &[])
+    }
+}
+
+/// A directory record of the type with the keys of the instance, its
+/// offsets left to `directory_file`
+fn record(
+    record_type: &str,
+    keys: &[Key],
+    object: &DefaultDicomObject,
+    number: usize,
+) -> InMemDicomObject {
+    let mut record = InMemDicomObject::new_empty();
+    record.put(DataElement::new(
+        tags::RECORD_IN_USE_FLAG,
+        VR::US,
+        dicom_value!(U16, [0xFFFF]),
+    ));
+    record.put(DataElement::new(
+        tags::DIRECTORY_RECORD_TYPE,
+        VR::CS,
+        dicom_value!(Str, record_type),
+    ));
+    for (tag, vr, fallback) in keys {
+        put_key(&mut record, object, *tag, *vr, *fallback, number);
+    }
+    record
+}
+
+fn put_key(
+    record: &mut InMemDicomObject,
+    object: &DefaultDicomObject,
+    tag: Tag,
+    vr: VR,
+    fallback: Fallback,
+    number: usize,
+) {
+    if let Ok(element) = object.element(tag) {
+        if !element.is_empty() || matches!(fallback, Fallback::Skip | Fallback::Empty) {
+            record.put(element.clone());
+            return;
+        }
+    }
+    match fallback {
+        Fallback::Skip => {}
+        Fallback::Empty => {
+            record.put(DataElement::new(tag, vr, PrimitiveValue::Empty));
+        }
+        Fallback::Number => {
+            record.put(DataElement::new(
+                tag,
+                vr,
+                dicom_value!(Str, number.to_string()),
+            ));
+        }
+    }
+}
+
+/// The DICOMDIR of the records, each one followed by those of its lower
+/// level. The offsets are counted from the start of the file.
+fn directory_file(
+    mut records: Vec<(usize, InMemDicomObject)>,
+    exported_keys: &[String],
+) -> color_eyre::Result<Vec<u8>> {
+    let ts = TransferSyntaxRegistry
+        .get(EXPLICIT_VR_LE)
+        .ok_or_else(|| eyre!("Unknown transfer syntax {}", EXPLICIT_VR_LE))?;
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(MEDIA_STORAGE_DIRECTORY)
+        .media_storage_sop_instance_uid(uid_from_digest(&Sha256::digest(
+            exported_keys.join("\\").as_bytes(),
+        )))
+        .transfer_syntax(EXPLICIT_VR_LE)
+        .build()
+        .wrap_err("Failed to build DICOM meta file information")?;
+    let mut meta_bytes = Vec::new();
+    meta.write(&mut meta_bytes)
+        .wrap_err("Could not encode the file meta information")?;
+
+    // The offsets do not change the length of the records, which are
+    // encoded once to find where they start
+    let encode = |object: &InMemDicomObject| -> color_eyre::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        object
+            .write_dataset_with_ts(&mut bytes, ts)
+            .wrap_err("Could not encode the DICOMDIR")?;
+        Ok(bytes)
+    };
+    for (_, record) in records.iter_mut() {
+        set_offset(record, tags::OFFSET_OF_THE_NEXT_DIRECTORY_RECORD, 0);
+        set_offset(
+            record,
+            tags::OFFSET_OF_REFERENCED_LOWER_LEVEL_DIRECTORY_ENTITY,
+            0,
+        );
+    }
+    let mut header = InMemDicomObject::new_empty();
+    header.put(DataElement::new(
+        tags::FILE_SET_ID,
+        VR::CS,
+        dicom_value!(Str, FILE_SET_ID),
+    ));
+    set_offset(
+        &mut header,
+        tags::OFFSET_OF_THE_FIRST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY,
+        0,
+    );
+    set_offset(
+        &mut header,
+        tags::OFFSET_OF_THE_LAST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY,
+        0,
+    );
+    header.put(DataElement::new(
+        tags::FILE_SET_CONSISTENCY_FLAG,
+        VR::US,
+        dicom_value!(U16, [0]),
+    ));
+    let mut offset = PREAMBLE_LENGTH
+        + meta_bytes.len() as u32
+        + encode(&header)?.len() as u32
+        + SEQUENCE_HEADER_LENGTH;
+    let mut offsets = Vec::with_capacity(records.len());
+    for (_, record) in records.iter() {
+        offsets.push(offset);
+        offset += ITEM_HEADER_LENGTH + encode(record)?.len() as u32;
+    }
+
+    // Links each record to the next one of its level and to the first
+    // one of its lower level
+    let depths: Vec<usize> = records.iter().map(|(depth, _)| *depth).collect();
+    let next_of = |i: usize| {
+        depths[i + 1..]
+            .iter()
+            .position(|depth| *depth <= depths[i])
+            .map(|position| i + 1 + position)
+            .filter(|&next| depths[next] == depths[i])
+    };
+    for (i, (_, record)) in records.iter_mut().enumerate() {
+        if let Some(next) = next_of(i) {
+            set_offset(
+                record,
+                tags::OFFSET_OF_THE_NEXT_DIRECTORY_RECORD,
+                offsets[next],
+            );
+        }
+        if depths.get(i + 1).is_some_and(|depth| *depth > depths[i]) {
+            set_offset(
+                record,
+                tags::OFFSET_OF_REFERENCED_LOWER_LEVEL_DIRECTORY_ENTITY,
+                offsets[i + 1],
+            );
+        }
+    }
+    let roots: Vec<usize> = (0..records.len()).filter(|&i| depths[i] == 0).collect();
+    if let (Some(first), Some(last)) = (roots.first(), roots.last()) {
+        set_offset(
+            &mut header,
+            tags::OFFSET_OF_THE_FIRST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY,
+            offsets[*first],
+        );
+        set_offset(
+            &mut header,
+            tags::OFFSET_OF_THE_LAST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY,
+            offsets[*last],
+        );
+    }
+
+    let mut sequence = Vec::new();
+    for (_, record) in records.iter() {
+        let item = encode(record)?;
+        sequence.extend_from_slice(&[0xFE, 0xFF, 0x00, 0xE0]);
+        sequence.extend_from_slice(&(item.len() as u32).to_le_bytes());
+        sequence.extend_from_slice(&item);
+    }
+    let mut bytes = vec![0; 128];
+    bytes.extend_from_slice(b"DICM");
+    bytes.extend_from_slice(&meta_bytes);
+    bytes.extend_from_slice(&encode(&header)?);
+    bytes.extend_from_slice(&[0x04, 0x00, 0x20, 0x12, b'S', b'Q', 0, 0]);
+    bytes.extend_from_slice(&(sequence.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&sequence);
+    Ok(bytes)
+}
+
+fn set_offset(object: &mut InMemDicomObject, tag: Tag, offset: u32) {
+    object.put(DataElement::new(tag, VR::UL, dicom_value!(U32, [offset])));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CT_IMAGE: &str = "1.2.840.10008.5.1.4.1.1.2";
+    const IMPLICIT_VR_LE: &str = "1.2.840.10008.1.2";
+
+    /// An instance of series `series` of study `study`
+    fn instance(study: &str, study_date: &str, series: u32, number: u32) -> DefaultDicomObject {
+        let sop_instance_uid = format!("{}.{}.{}", study, series, number);
+        InMemDicomObject::from_element_iter([
+            DataElement::new(tags::SOP_CLASS_UID, VR::UI, dicom_value!(Str, CT_IMAGE)),
+            DataElement::new(
+                tags::SOP_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, sop_instance_uid.as_str()),
+            ),
+            DataElement::new(tags::STUDY_DATE, VR::DA, dicom_value!(Str, study_date)),
+            DataElement::new(tags::MODALITY, VR::CS, dicom_value!(Str, "CT")),
+            DataElement::new(tags::PATIENT_NAME, VR::PN, dicom_value!(Str, "Doe^John")),
+            DataElement::new(tags::PATIENT_ID, VR::LO, dicom_value!(Str, "PID001")),
+            DataElement::new(tags::STUDY_INSTANCE_UID, VR::UI, dicom_value!(Str, study)),
+            DataElement::new(
+                tags::SERIES_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, format!("{}.{}", study, series)),
+            ),
+            DataElement::new(
+                tags::SERIES_NUMBER,
+                VR::IS,
+                dicom_value!(Str, series.to_string()),
+            ),
+            DataElement::new(
+                tags::INSTANCE_NUMBER,
+                VR::IS,
+                dicom_value!(Str, number.to_string()),
+            ),
+        ])
+        .with_exact_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(CT_IMAGE)
+                .media_storage_sop_instance_uid(sop_instance_uid)
+                .transfer_syntax(IMPLICIT_VR_LE)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    fn root() -> Node {
+        Node {
+            uid: String::new(),
+            name: String::new(),
+            sort_key: String::new(),
+            record: InMemDicomObject::new_empty(),
+            children: Vec::new(),
+        }
+    }
+
+    fn offset(object: &InMemDicomObject, tag: Tag) -> u32 {
+        object.element(tag).unwrap().to_int().unwrap()
+    }
+
+    /// The record encoded at `offset` in the DICOMDIR
+    fn record_at(bytes: &[u8], offset: u32) -> InMemDicomObject {
+        let offset = offset as usize;
+        assert_eq!(bytes[offset..offset + 4], [0xFE, 0xFF, 0x00, 0xE0]);
+        let length = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap());
+        let item = &bytes[offset + 8..offset + 8 + length as usize];
+        let ts = TransferSyntaxRegistry.get(EXPLICIT_VR_LE).unwrap();
+        InMemDicomObject::read_dataset_with_ts(item, ts).unwrap()
+    }
+
+    #[test]
+    fn names_the_files_by_level() {
+        let mut root = root();
+        let first = add_records(&mut root, &instance("1.2.3", "20240101", 1, 1));
+        let second = add_records(&mut root, &instance("1.2.3", "20240101", 1, 2));
+        let other_series = add_records(&mut root, &instance("1.2.3", "20240101", 2, 1));
+        let other_study = add_records(&mut root, &instance("1.2.4", "20230101", 1, 1));
+        // The same instance again keeps its file ID
+        let again = add_records(&mut root, &instance("1.2.3", "20240101", 1, 1));
+
+        assert_eq!(
+            first,
+            ["DICOM", "PAT00001", "STU00001", "SER00001", "IMG00001"]
+        );
+        assert_eq!(
+            second,
+            ["DICOM", "PAT00001", "STU00001", "SER00001", "IMG00002"]
+        );
+        assert_eq!(
+            other_series,
+            ["DICOM", "PAT00001", "STU00001", "SER00002", "IMG00001"]
+        );
+        assert_eq!(
+            other_study,
+            ["DICOM", "PAT00001", "STU00002", "SER00001", "IMG00001"]
+        );
+        assert_eq!(again, first);
+        assert!(first.iter().all(|id| id.len() <= 8));
+    }
+
+    #[test]
+    fn links_the_records_of_the_dicomdir() {
+        let mut root = root();
+        for (study, date, series, number) in [
+            ("1.2.3", "20240101", 2, 1),
+            ("1.2.3", "20240101", 1, 2),
+            ("1.2.3", "20240101", 1, 1),
+            ("1.2.4", "20230101", 1, 1),
+        ] {
+            add_records(&mut root, &instance(study, date, series, number));
+        }
+        root.sort();
+        let mut records = Vec::new();
+        flatten(root.children, 0, &mut records);
+        let bytes = directory_file(records, &["a".to_string()]).unwrap();
+
+        let dicomdir = OpenFileOptions::new()
+            .read_preamble(ReadPreamble::Always)
+            .from_reader(bytes.as_slice())
+            .unwrap();
+        assert_eq!(
+            dicomdir
+                .meta()
+                .media_storage_sop_class_uid()
+                .trim_end_matches('\0'),
+            MEDIA_STORAGE_DIRECTORY
+        );
+        assert_eq!(value_of(&dicomdir, tags::FILE_SET_ID), FILE_SET_ID);
+        let items = dicomdir
+            .element(tags::DIRECTORY_RECORD_SEQUENCE)
+            .unwrap()
+            .items()
+            .unwrap();
+        let types: Vec<String> = items
+            .iter()
+            .map(|item| value_of(item, tags::DIRECTORY_RECORD_TYPE))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                "PATIENT", "STUDY", "SERIES", "IMAGE", "STUDY", "SERIES", "IMAGE", "IMAGE",
+                "SERIES", "IMAGE"
+            ]
+        );
+
+        // The offsets lead from the root to every record
+        let first = offset(
+            &dicomdir,
+            tags::OFFSET_OF_THE_FIRST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY,
+        );
+        assert_eq!(
+            first,
+            offset(
+                &dicomdir,
+                tags::OFFSET_OF_THE_LAST_DIRECTORY_RECORD_OF_THE_ROOT_DIRECTORY_ENTITY
+            )
+        );
+        let patient = record_at(&bytes, first);
+        assert_eq!(value_of(&patient, tags::PATIENT_ID), "PID001");
+        assert_eq!(
+            offset(&patient, tags::OFFSET_OF_THE_NEXT_DIRECTORY_RECORD),
+            0
+        );
+
+        // The studies are sorted by date, the series and images by number
+        let lower = tags::OFFSET_OF_REFERENCED_LOWER_LEVEL_DIRECTORY_ENTITY;
+        let next = tags::OFFSET_OF_THE_NEXT_DIRECTORY_RECORD;
+        let study = record_at(&bytes, offset(&patient, lower));
+        assert_eq!(value_of(&study, tags::STUDY_INSTANCE_UID), "1.2.4");
+        let study = record_at(&bytes, offset(&study, next));
+        assert_eq!(value_of(&study, tags::STUDY_INSTANCE_UID), "1.2.3");
+        assert_eq!(offset(&study, next), 0);
+        let series = record_at(&bytes, offset(&study, lower));
+        assert_eq!(value_of(&series, tags::SERIES_NUMBER), "1");
+        let image = record_at(&bytes, offset(&series, lower));
+        assert_eq!(value_of(&image, tags::INSTANCE_NUMBER), "1");
+        assert_eq!(offset(&image, lower), 0);
+        let image = record_at(&bytes, offset(&image, next));
+        assert_eq!(value_of(&image, tags::INSTANCE_NUMBER), "2");
+        assert_eq!(offset(&image, next), 0);
+        let series = record_at(&bytes, offset(&series, next));
+        assert_eq!(value_of(&series, tags::SERIES_NUMBER), "2");
+
+        // Each image references its file and what the file holds
+        let image = record_at(&bytes, offset(&series, lower));
+        let file_id = image
+            .element(tags::REFERENCED_FILE_ID)
+            .unwrap()
+            .to_multi_str()
+            .unwrap();
+        assert_eq!(
+            file_id.as_ref(),
+            ["DICOM", "PAT00001", "STU00001", "SER00001", "IMG00001"]
+        );
+        assert_eq!(
+            value_of(&image, tags::REFERENCED_SOP_INSTANCE_UID_IN_FILE),
+            "1.2.3.2.1"
+        );
+        assert_eq!(
+            value_of(&image, tags::REFERENCED_TRANSFER_SYNTAX_UID_IN_FILE),
+            IMPLICIT_VR_LE
+        );
+    }
+
+    #[test]
+    fn writes_the_native_instances_in_explicit_vr_little_endian() {
+        let object = to_general_purpose(instance("1.2.3", "20240101", 1, 1)).unwrap();
+        assert_eq!(
+            object.meta().transfer_syntax().trim_end_matches('\0'),
+            EXPLICIT_VR_LE
+        );
+
+        let mut object = instance("1.2.3", "20240101", 1, 1);
+        object.meta_mut().transfer_syntax = "1.2.3.4.5".to_string();
+        assert!(to_general_purpose(object).is_err());
+    }
+
+    #[test]
+    fn types_the_records_by_sop_class() {
+        assert_eq!(instance_record_type(CT_IMAGE).0, "IMAGE");
+        assert_eq!(
+            instance_record_type("1.2.840.10008.5.1.4.1.1.88.22").0,
+            "SR DOCUMENT"
+        );
+        assert_eq!(
+            instance_record_type("1.2.840.10008.5.1.4.1.1.88.59").0,
+            "KEY OBJECT DOC"
+        );
+        assert_eq!(
+            instance_record_type("1.2.840.10008.5.1.4.1.1.11.1").0,
+            "PRESENTATION"
+        );
+        assert_eq!(
+            instance_record_type("1.2.840.10008.5.1.4.1.1.104.1").0,
+            "ENCAP DOC"
+        );
+    }
+}
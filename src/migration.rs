@@ -0,0 +1,478 @@
+//! Conversion of the config files written by older versions of eai-rs
+//! into the current format.
+//!
+//! - version 0: the channels embed their source and destination nodes
+//! - version 1: the channels reference the nodes of a `nodes` registry
+//! - version 2: the nodes are split into `local_aes` and `remote_aes`
+//! - version 3: the channel sources are typed and the output directory
+//!   moves from the local AE to the channel `storage`
+//! - version 4: the channel destinations are typed
+//! - version 5: the processing steps are grouped in the `pre` stage of
+//!   the channels and the `post` stage of their destinations
+
+use serde_json::{Map, Value};
+
+/// The version of the config format written by this version of eai-rs
+pub(crate) const CONFIG_VERSION: u32 = 5;
+
+/// Upgrades a config to the current format, returns it untouched if
+/// it already is, but for the version added when left out.
+pub(crate) fn migrate(mut config: Value) -> Result<Value, String> {
+    let version = version(&config)?;
+    if version > CONFIG_VERSION as u64 {
+        return Err(format!(
+            "Config version {} is newer than the supported version {}",
+            version, CONFIG_VERSION
+        ));
+    }
+    if version < 1 {
+        config = extract_nodes(config)?;
+    }
+    if version < 2 {
+        config = split_nodes(config)?;
+    }
+    if version < 3 {
+        config = type_sources(config)?;
+    }
+    if version < 4 {
+        config = type_destinations(config)?;
+    }
+    if version < 5 {
+        config = group_stages(config)?;
+    }
+    if config.get("version").is_none() {
+        as_object_mut(&mut config, "The config")?
+            .insert("version".to_string(), Value::from(CONFIG_VERSION));
+    }
+    Ok(config)
+}
+
+/// Returns the format version of a config, the unversioned ones being
+/// told apart by their layout
+pub(crate) fn version(config: &Value) -> Result<u64, String> {
+    match config.get("version") {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| format!("Invalid config version {}", version)),
+        // The version can be left out of a config written by hand in
+        // the current format
+        None if is_current(config) => Ok(CONFIG_VERSION as u64),
+        None if embeds_nodes(config) => Ok(0),
+        None => Ok(1),
+    }
+}
+
+/// The sources of the channels of the config
+fn sources(config: &Value) -> impl Iterator<Item = &Value> {
+    config
+        .get("channels")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flat_map(|channels| channels.values())
+        .filter_map(|channel| channel.get("source"))
+}
+
+/// Whether the config has the AE registries or the typed sources of
+/// the current format
+fn is_current(config: &Value) -> bool {
+    config.get("local_aes").is_some()
+        || config.get("remote_aes").is_some()
+        || sources(config).any(|source| source.get("type").is_some())
+}
+
+/// Whether the channels of the config embed their nodes
+fn embeds_nodes(config: &Value) -> bool {
+    sources(config).any(Value::is_object)
+}
+
+fn as_object_mut<'a>(
+    value: &'a mut Value,
+    what: &str,
+) -> Result<&'a mut Map<String, Value>, String> {
+    value
+        .as_object_mut()
+        .ok_or_else(|| format!("{} is not an object", what))
+}
+
+/// Version 0 to 1: moves the embedded nodes into a `nodes` registry,
+/// named after their AE title.
+fn extract_nodes(mut config: Value) -> Result<Value, String> {
+    let mut nodes: Map<String, Value> = Map::new();
+    let root = as_object_mut(&mut config, "The config")?;
+    if let Some(channels) = root.get_mut("channels") {
+        for (id, channel) in as_object_mut(channels, "channels")?.iter_mut() {
+            let channel = as_object_mut(channel, &format!("Channel {}", id))?;
+            if let Some(source) = channel.get_mut("source") {
+                *source = register_node(&mut nodes, source.take())?;
+            }
+            if let Some(destinations) = channel.get_mut("destinations") {
+                let destinations = destinations
+                    .as_array_mut()
+                    .ok_or_else(|| format!("The destinations of channel {} are not a list", id))?;
+                for destination in destinations.iter_mut() {
+                    *destination = register_node(&mut nodes, destination.take())?;
+                }
+            }
+        }
+    }
+    root.insert("nodes".to_string(), Value::Object(nodes));
+    Ok(config)
+}
+
+/// Adds the node to the registry and returns the name it is
+/// registered under. Identical nodes share the same entry.
+fn register_node(nodes: &mut Map<String, Value>, node: Value) -> Result<Value, String> {
+    let aet = node
+        .get("aet")
+        .and_then(Value::as_str)
+        .ok_or_else(|| "A node has no AE title".to_string())?
+        .trim()
+        .to_string();
+    let mut name = aet.clone();
+    let mut suffix = 1;
+    while let Some(existing) = nodes.get(&name) {
+        if *existing == node {
+            return Ok(Value::String(name));
+        }
+        suffix += 1;
+        name = format!("{}_{}", aet, suffix);
+    }
+    nodes.insert(name.clone(), node);
+    Ok(Value::String(name))
+}
+
+/// Version 1 to 2: splits the `nodes` registry into `local_aes`, the
+/// nodes used as channel sources, and `remote_aes`, the nodes used as
+/// destinations. A node used as both ends up in both registries.
+fn split_nodes(mut config: Value) -> Result<Value, String> {
+    let root = as_object_mut(&mut config, "The config")?;
+    let nodes = match root.remove("nodes") {
+        Some(Value::Object(nodes)) => nodes,
+        Some(_) => return Err("nodes is not an object".to_string()),
+        None => Map::new(),
+    };
+
+    let mut sources = Vec::new();
+    let mut destinations = Vec::new();
+    if let Some(channels) = root.get("channels").and_then(Value::as_object) {
+        for channel in channels.values() {
+            if let Some(source) = channel.get("source").and_then(Value::as_str) {
+                sources.push(source.to_string());
+            }
+            if let Some(names) = channel.get("destinations").and_then(Value::as_array) {
+                destinations.extend(names.iter().filter_map(Value::as_str).map(str::to_string));
+            }
+        }
+    }
+
+    let mut local_aes = Map::new();
+    let mut remote_aes = Map::new();
+    for (name, node) in nodes {
+        let is_source = sources.contains(&name);
+        let is_destination = destinations.contains(&name);
+        let has_out_dir = node.get("out_dir").is_some_and(|dir| !dir.is_null());
+        if is_source || (!is_destination && has_out_dir) {
+            local_aes.insert(
+                name.clone(),
+                pick(
+                    &node,
+                    &[
+                        "aet",
+                        "ip",
+                        "port",
+                        "uncompressed_only",
+                        "max_pdu",
+                        "strict",
+                        "out_dir",
+                    ],
+                ),
+            );
+        }
+        if is_destination || (!is_source && !has_out_dir) {
+            remote_aes.insert(
+                name,
+                pick(
+                    &node,
+                    &["aet", "ip", "port", "uncompressed_only", "max_pdu"],
+                ),
+            );
+        }
+    }
+
+    root.insert("version".to_string(), Value::from(2));
+    root.insert("local_aes".to_string(), Value::Object(local_aes));
+    root.insert("remote_aes".to_string(), Value::Object(remote_aes));
+    Ok(config)
+}
+
+/// Copies the given fields of a node
+fn pick(node: &Value, fields: &[&str]) -> Value {
+    let mut picked = Map::new();
+    for field in fields {
+        if let Some(value) = node.get(*field) {
+            picked.insert(field.to_string(), value.clone());
+        }
+    }
+    Value::Object(picked)
+}
+
+/// Version 2 to 3: the local AE name of each channel becomes a DICOM
+/// source, and the `out_dir` of the local AE becomes the storage of the
+/// channels it feeds.
+fn type_sources(mut config: Value) -> Result<Value, String> {
+    let root = as_object_mut(&mut config, "The config")?;
+    let mut out_dirs = Map::new();
+    if let Some(local_aes) = root.get_mut("local_aes") {
+        for (name, local_ae) in as_object_mut(local_aes, "local_aes")?.iter_mut() {
+            let local_ae = as_object_mut(local_ae, &format!("Local AE {}", name))?;
+            if let Some(out_dir) = local_ae.remove("out_dir") {
+                out_dirs.insert(name.clone(), out_dir);
+            }
+        }
+    }
+    if let Some(channels) = root.get_mut("channels") {
+        for (id, channel) in as_object_mut(channels, "channels")?.iter_mut() {
+            let channel = as_object_mut(channel, &format!("Channel {}", id))?;
+            let Some(Value::String(local_ae)) = channel.get("source").cloned() else {
+                continue;
+            };
+            let storage = match out_dirs.get(&local_ae) {
+                Some(out_dir) if !out_dir.is_null() => {
+                    let mut storage = Map::new();
+                    storage.insert("out_dir".to_string(), out_dir.clone());
+                    Value::Object(storage)
+                }
+                _ => Value::Null,
+            };
+            let mut source = Map::new();
+            source.insert("type".to_string(), Value::from("Dicom"));
+            source.insert("local_ae".to_string(), Value::String(local_ae));
+            channel.insert("source".to_string(), Value::Object(source));
+            channel.insert("storage".to_string(), storage);
+        }
+    }
+    root.insert("version".to_string(), Value::from(3));
+    Ok(config)
+}
+
+/// Version 3 to 4: the remote AE names of each channel become DICOM
+/// destinations.
+fn type_destinations(mut config: Value) -> Result<Value, String> {
+    let root = as_object_mut(&mut config, "The config")?;
+    if let Some(channels) = root.get_mut("channels") {
+        for (id, channel) in as_object_mut(channels, "channels")?.iter_mut() {
+            let channel = as_object_mut(channel, &format!("Channel {}", id))?;
+            let Some(destinations) = channel.get_mut("destinations") else {
+                continue;
+            };
+            let destinations = destinations
+                .as_array_mut()
+                .ok_or_else(|| format!("The destinations of channel {} are not a list", id))?;
+            for destination in destinations.iter_mut() {
+                if let Value::String(remote_ae) = destination {
+                    let mut typed = Map::new();
+                    typed.insert("type".to_string(), Value::from("Dicom"));
+                    typed.insert("remote_ae".to_string(), Value::String(remote_ae.clone()));
+                    *destination = Value::Object(typed);
+                }
+            }
+        }
+    }
+    root.insert("version".to_string(), Value::from(4));
+    Ok(config)
+}
+
+/// Version 4 to 5: the rules and script of each channel move to its
+/// `pre` stage, the de-identification, pseudonymization and rules of
+/// each destination to its `post` stage.
+fn group_stages(mut config: Value) -> Result<Value, String> {
+    let root = as_object_mut(&mut config, "The config")?;
+    if let Some(channels) = root.get_mut("channels") {
+        for (id, channel) in as_object_mut(channels, "channels")?.iter_mut() {
+            let channel = as_object_mut(channel, &format!("Channel {}", id))?;
+            move_into_stage(channel, "pre", &["rules", "script"]);
+            let Some(destinations) = channel.get_mut("destinations") else {
+                continue;
+            };
+            let destinations = destinations
+                .as_array_mut()
+                .ok_or_else(|| format!("The destinations of channel {} are not a list", id))?;
+            for destination in destinations.iter_mut() {
+                let destination =
+                    as_object_mut(destination, &format!("A destination of channel {}", id))?;
+                move_into_stage(
+                    destination,
+                    "post",
+                    &["deidentify", "pseudonymize", "rules"],
+                );
+            }
+        }
+    }
+    root.insert("version".to_string(), Value::from(5));
+    Ok(config)
+}
+
+/// Moves the set fields of the object into a new stage object
+fn move_into_stage(object: &mut Map<String, Value>, stage: &str, fields: &[&str]) {
+    let mut steps = Map::new();
+    for field in fields {
+        match object.remove(*field) {
+            Some(Value::Null) | None => {}
+            Some(value) => {
+                steps.insert(field.to_string(), value);
+            }
+        }
+    }
+    object.insert(stage.to_string(), Value::Object(steps));
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::utils::Config;
+
+    /// A node as written by version 0
+    fn node(aet: &str, ip: &str, port: u16) -> Value {
+        json!({
+            "aet": aet,
+            "ip": ip,
+            "port": port,
+            "uncompressed_only": false,
+            "max_pdu": 16384,
+            "strict": false
+        })
+    }
+
+    /// A version 0 config, with the nodes embedded in the channels
+    fn version_0() -> Value {
+        let mut source = node("EAI", "0.0.0.0", 11112);
+        source["out_dir"] = json!("/data");
+        json!({
+            "log_level": "Info",
+            "channels": {
+                "1": {
+                    "name": "in",
+                    "source": source,
+                    "destinations": [node("PACS", "10.0.0.2", 104), node("PACS", "10.0.0.3", 104)],
+                    "rules": [],
+                    "status": "Started"
+                },
+                "2": {
+                    "name": "out",
+                    "source": node("PACS", "10.0.0.2", 104),
+                    "destinations": [],
+                    "status": "Stopped"
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn tells_the_version_of_unversioned_configs() {
+        assert_eq!(version(&version_0()), Ok(0));
+        assert_eq!(version(&json!({ "nodes": {}, "channels": {} })), Ok(1));
+        assert_eq!(version(&json!({ "version": 3 })), Ok(3));
+        assert!(version(&json!({ "version": "3" })).is_err());
+    }
+
+    #[test]
+    fn recognises_unversioned_current_configs() {
+        let typed = json!({
+            "channels": { "1": { "source": { "type": "Dicom", "local_ae": "a" } } }
+        });
+        assert_eq!(version(&typed), Ok(CONFIG_VERSION as u64));
+        let registries = json!({
+            "local_aes": { "a": { "aet": "A" } },
+            "remote_aes": { "b": { "aet": "B" } },
+            "channels": {}
+        });
+        assert_eq!(version(&registries), Ok(CONFIG_VERSION as u64));
+
+        // Kept as they are, with the version added
+        let migrated = migrate(registries.clone()).unwrap();
+        assert_eq!(migrated["version"], json!(CONFIG_VERSION));
+        assert_eq!(migrated["local_aes"], registries["local_aes"]);
+        assert_eq!(migrated["remote_aes"], registries["remote_aes"]);
+        assert_eq!(
+            migrate(typed).unwrap()["channels"]["1"]["source"]["type"],
+            json!("Dicom")
+        );
+    }
+
+    #[test]
+    fn migrates_a_version_0_config() {
+        let config = migrate(version_0()).unwrap();
+
+        assert_eq!(config["version"], json!(CONFIG_VERSION));
+        assert!(config.get("nodes").is_none());
+        let local_aes = config["local_aes"].as_object().unwrap();
+        assert_eq!(local_aes.keys().collect::<Vec<_>>(), ["EAI", "PACS"]);
+        assert!(local_aes["EAI"].get("out_dir").is_none());
+        // The node used as both a source and a destination is in both
+        // registries, the differing nodes of a same AE title are suffixed
+        let remote_aes = config["remote_aes"].as_object().unwrap();
+        assert_eq!(remote_aes.keys().collect::<Vec<_>>(), ["PACS", "PACS_2"]);
+        assert_eq!(remote_aes["PACS_2"]["ip"], json!("10.0.0.3"));
+        assert!(remote_aes["PACS"].get("strict").is_none());
+
+        let channel = &config["channels"]["1"];
+        assert_eq!(
+            channel["source"],
+            json!({ "type": "Dicom", "local_ae": "EAI" })
+        );
+        assert_eq!(channel["storage"], json!({ "out_dir": "/data" }));
+        assert_eq!(
+            channel["destinations"],
+            json!([
+                { "type": "Dicom", "remote_ae": "PACS", "post": {} },
+                { "type": "Dicom", "remote_ae": "PACS_2", "post": {} }
+            ])
+        );
+        assert_eq!(channel["pre"], json!({ "rules": [] }));
+        assert!(channel.get("rules").is_none());
+        assert_eq!(config["channels"]["2"]["storage"], Value::Null);
+
+        serde_json::from_value::<Config>(config).unwrap();
+    }
+
+    #[test]
+    fn groups_the_processing_into_stages() {
+        let config = json!({
+            "version": 4,
+            "channels": {
+                "1": {
+                    "rules": [{ "action": "drop" }],
+                    "script": null,
+                    "destinations": [
+                        { "type": "Folder", "path": "/out", "deidentify": {}, "rules": null }
+                    ]
+                }
+            }
+        });
+        let config = migrate(config).unwrap();
+        let channel = &config["channels"]["1"];
+        assert_eq!(channel["pre"], json!({ "rules": [{ "action": "drop" }] }));
+        assert!(channel.get("script").is_none());
+        assert_eq!(
+            channel["destinations"][0],
+            json!({ "type": "Folder", "path": "/out", "post": { "deidentify": {} } })
+        );
+    }
+
+    #[test]
+    fn leaves_current_configs_untouched() {
+        let config = json!({ "version": CONFIG_VERSION, "channels": { "1": { "rules": [] } } });
+        assert_eq!(migrate(config.clone()), Ok(config));
+    }
+
+    #[test]
+    fn rejects_newer_and_invalid_configs() {
+        assert!(migrate(json!({ "version": CONFIG_VERSION + 1 })).is_err());
+        assert!(migrate(json!({ "version": -1 })).is_err());
+        assert!(migrate(json!([])).is_err());
+        let nameless = json!({ "channels": { "1": { "source": { "ip": "0.0.0.0" } } } });
+        assert!(migrate(nameless).is_err());
+    }
+}
@@ -0,0 +1,468 @@
+use std::str::FromStr;
+
+use chrono::{Duration, NaiveDate};
+use color_eyre::eyre::{bail, eyre, Context};
+use dicom::{
+    core::{dictionary::DictionaryEntry, DataDictionary, DataElement, PrimitiveValue, Tag, VR},
+    dictionary_std::StandardDataDictionary,
+    object::InMemDicomObject,
+};
+use regex::Regex;
+use tracing::warn;
+
+use crate::utils::Rule;
+
+/// The rules of a channel or destination, checked once and applied to
+/// every instance going through it
+#[derive(Debug, Default)]
+pub(crate) struct Morpher {
+    rules: Vec<CompiledRule>,
+}
+
+/// A rule with its tags resolved and its regular expressions compiled
+#[derive(Debug)]
+enum CompiledRule {
+    Set {
+        tag: Tag,
+        vr: VR,
+        value: String,
+    },
+    Remove {
+        tag: Tag,
+    },
+    Copy {
+        from: Tag,
+        to: Tag,
+    },
+    RegexReplace {
+        tag: Tag,
+        regex: Regex,
+        replacement: String,
+    },
+    Prefix {
+        tag: Tag,
+        prefix: String,
+    },
+    Uppercase {
+        tag: Tag,
+    },
+    DateShift {
+        tag: Tag,
+        days: i64,
+    },
+    If {
+        tag: Tag,
+        regex: Regex,
+        then: Vec<CompiledRule>,
+        otherwise: Vec<CompiledRule>,
+    },
+}
+
+impl Morpher {
+    /// Checks the rules, failing on unknown tags, invalid regular
+    /// expressions and values that do not fit the VR of their tag
+    pub(crate) fn new(rules: &[Rule]) -> color_eyre::Result<Self> {
+        Ok(Self {
+            rules: compile(rules)?,
+        })
+    }
+
+    /// Applies the rules in order. A rule that cannot be applied to
+    /// the instance is skipped with a warning.
+    pub(crate) fn apply(&self, object: &mut InMemDicomObject) {
+        apply_rules(&self.rules, object);
+    }
+}
+
+fn compile(rules: &[Rule]) -> color_eyre::Result<Vec<CompiledRule>> {
+    rules.iter().map(compile_rule).collect()
+}
+
+fn compile_rule(rule: &Rule) -> color_eyre::Result<CompiledRule> {
+    Ok(match rule {
+        Rule::Set { tag, value } => {
+            let tag = parse_tag(tag)?;
+            let vr = dictionary_vr(tag);
+            to_value(vr, value).wrap_err_with(|| format!("Cannot set {} to {:?}", tag, value))?;
+            CompiledRule::Set {
+                tag,
+                vr,
+                value: value.clone(),
+            }
+        }
+        Rule::Remove { tag } => CompiledRule::Remove {
+            tag: parse_tag(tag)?,
+        },
+        Rule::Copy { from, to } => CompiledRule::Copy {
+            from: parse_tag(from)?,
+            to: parse_tag(to)?,
+        },
+        Rule::RegexReplace {
+            tag,
+            pattern,
+            replacement,
+        } => CompiledRule::RegexReplace {
+            tag: parse_tag(tag)?,
+            regex: parse_regex(pattern)?,
+            replacement: replacement.clone(),
+        },
+        Rule::Prefix { tag, prefix } => CompiledRule::Prefix {
+            tag: parse_tag(tag)?,
+            prefix: prefix.clone(),
+        },
+        Rule::Uppercase { tag } => CompiledRule::Uppercase {
+            tag: parse_tag(tag)?,
+        },
+        Rule::DateShift { tag, days } => CompiledRule::DateShift {
+            tag: parse_tag(tag)?,
+            days: *days,
+        },
+        Rule::If {
+            tag,
+            matches,
+            then,
+            otherwise,
+        } => CompiledRule::If {
+            tag: parse_tag(tag)?,
+            regex: parse_regex(matches)?,
+            then: compile(then)?,
+            otherwise: compile(otherwise)?,
+        },
+    })
+}
+
+/// Parses a tag given by keyword or number
+pub(crate) fn parse_tag(text: &str) -> color_eyre::Result<Tag> {
+    let text = text.trim();
+    if let Ok(tag) = Tag::from_str(text) {
+        return Ok(tag);
+    }
+    if text.len() == 8 {
+        if let (Ok(group), Ok(element)) = (
+            u16::from_str_radix(&text[..4], 16),
+            u16::from_str_radix(&text[4..], 16),
+        ) {
+            return Ok(Tag(group, element));
+        }
+    }
+    StandardDataDictionary
+        .by_name(text)
+        .map(|entry| entry.tag())
+        .ok_or_else(|| eyre!("Unknown tag {}", text))
+}
+
+fn parse_regex(pattern: &str) -> color_eyre::Result<Regex> {
+    Regex::new(pattern).wrap_err_with(|| format!("Invalid regular expression {:?}", pattern))
+}
+
+/// The VR of the tag in the standard dictionary, LO for the tags it
+/// does not know
+fn dictionary_vr(tag: Tag) -> VR {
+    StandardDataDictionary
+        .by_tag(tag)
+        .map(|entry| entry.vr())
+        .unwrap_or(VR::LO)
+}
+
+/// Converts a text, with values separated by backslashes, into a value
+/// of the given VR
+fn to_value(vr: VR, text: &str) -> color_eyre::Result<PrimitiveValue> {
+    fn parse<T: FromStr>(text: &str) -> color_eyre::Result<dicom::core::value::C<T>> {
+        text.split('\\')
+            .map(|value| {
+                value
+                    .trim()
+                    .parse()
+                    .map_err(|_| eyre!("{:?} is not a number", value))
+            })
+            .collect()
+    }
+    Ok(match vr {
+        VR::US => PrimitiveValue::U16(parse(text)?),
+        VR::SS => PrimitiveValue::I16(parse(text)?),
+        VR::UL => PrimitiveValue::U32(parse(text)?),
+        VR::SL => PrimitiveValue::I32(parse(text)?),
+        VR::FL | VR::OF => PrimitiveValue::F32(parse(text)?),
+        VR::FD | VR::OD => PrimitiveValue::F64(parse(text)?),
+        VR::SQ | VR::OB | VR::OW | VR::UN | VR::AT | VR::OL | VR::OV | VR::SV | VR::UV => {
+            bail!("{:?} values cannot be set from text", vr)
+        }
+        _ if text.contains('\\') => {
+            PrimitiveValue::Strs(text.split('\\').map(str::to_string).collect())
+        }
+        _ => PrimitiveValue::from(text),
+    })
+}
+
+/// The value of the attribute as text, without padding
+pub(crate) fn text_of(object: &InMemDicomObject, tag: Tag) -> Option<(VR, String)> {
+    let element = object.element_opt(tag).ok().flatten()?;
+    let text = element.to_str().ok()?;
+    Some((element.vr(), text.trim_end_matches(['\0', ' ']).to_string()))
+}
+
+/// Replaces the value of the attribute, keeping its VR
+fn put_text(object: &mut InMemDicomObject, tag: Tag, vr: VR, text: &str) {
+    match to_value(vr, text) {
+        Ok(value) => {
+            object.put(DataElement::new(tag, vr, value));
+        }
+        Err(e) => warn!("Could not set {}: {:#}", tag, e),
+    }
+}
+
+/// Sets the attribute from a text, keeping its VR when it is already
+/// there and using the VR of the dictionary otherwise
+pub(crate) fn set_text(
+    object: &mut InMemDicomObject,
+    tag: Tag,
+    text: &str,
+) -> color_eyre::Result<()> {
+    let vr = match object.element_opt(tag) {
+        Ok(Some(element)) if element.vr() != VR::UN => element.vr(),
+        _ => dictionary_vr(tag),
+    };
+    object.put(DataElement::new(tag, vr, to_value(vr, text)?));
+    Ok(())
+}
+
+fn apply_rules(rules: &[CompiledRule], object: &mut InMemDicomObject) {
+    for rule in rules {
+        apply_rule(rule, object);
+    }
+}
+
+fn apply_rule(rule: &CompiledRule, object: &mut InMemDicomObject) {
+    match rule {
+        CompiledRule::Set { tag, vr, value } => {
+            // Keep the VR of the attribute for the tags the dictionary
+            // does not know
+            let vr = match object.element_opt(*tag) {
+                Ok(Some(element)) if element.vr() != VR::UN => element.vr(),
+                _ => *vr,
+            };
+            put_text(object, *tag, vr, value);
+        }
+        CompiledRule::Remove { tag } => {
+            object.remove_element(*tag);
+        }
+        CompiledRule::Copy { from, to } => {
+            let Some((vr, text)) = text_of(object, *from) else {
+                return;
+            };
+            let target_vr = StandardDataDictionary
+                .by_tag(*to)
+                .map(|entry| entry.vr())
+                .unwrap_or(vr);
+            put_text(object, *to, target_vr, &text);
+        }
+        CompiledRule::RegexReplace {
+            tag,
+            regex,
+            replacement,
+        } => {
+            if let Some((vr, text)) = text_of(object, *tag) {
+                let replaced = regex.replace_all(&text, replacement.as_str());
+                put_text(object, *tag, vr, &replaced);
+            }
+        }
+        CompiledRule::Prefix { tag, prefix } => {
+            if let Some((vr, text)) = text_of(object, *tag) {
+                put_text(object, *tag, vr, &format!("{}{}", prefix, text));
+            }
+        }
+        CompiledRule::Uppercase { tag } => {
+            if let Some((vr, text)) = text_of(object, *tag) {
+                put_text(object, *tag, vr, &text.to_uppercase());
+            }
+        }
+        CompiledRule::DateShift { tag, days } => {
+            if let Some((vr, text)) = text_of(object, *tag) {
+                match shift_dates(vr, &text, *days) {
+                    Ok(shifted) => put_text(object, *tag, vr, &shifted),
+                    Err(e) => warn!("Could not shift {}: {:#}", tag, e),
+                }
+            }
+        }
+        CompiledRule::If {
+            tag,
+            regex,
+            then,
+            otherwise,
+        } => {
+            let text = text_of(object, *tag)
+                .map(|(_, text)| text)
+                .unwrap_or_default();
+            if regex.is_match(&text) {
+                apply_rules(then, object);
+            } else {
+                apply_rules(otherwise, object);
+            }
+        }
+    }
+}
+
+/// Moves the dates of a DA or DT value by `days`, the time part of a
+/// DT is kept
+fn shift_dates(vr: VR, text: &str, days: i64) -> color_eyre::Result<String> {
+    if vr != VR::DA && vr != VR::DT {
+        bail!("{:?} is not a date VR", vr);
+    }
+    let shifted: color_eyre::Result<Vec<String>> = text
+        .split('\\')
+        .map(|value| {
+            let value = value.trim();
+            if value.len() < 8 || !value.is_char_boundary(8) {
+                bail!("{:?} is not a date", value);
+            }
+            let (date, rest) = value.split_at(8);
+            let date = NaiveDate::parse_from_str(date, "%Y%m%d")
+                .wrap_err_with(|| format!("{:?} is not a date", value))?;
+            let date = date
+                .checked_add_signed(Duration::days(days))
+                .ok_or_else(|| eyre!("{} shifted by {} days is out of range", date, days))?;
+            Ok(format!("{}{}", date.format("%Y%m%d"), rest))
+        })
+        .collect();
+    Ok(shifted?.join("\\"))
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::{core::value::Value, dicom_value, dictionary_std::tags};
+    use serde_json::json;
+
+    use super::*;
+
+    fn morpher(rules: serde_json::Value) -> color_eyre::Result<Morpher> {
+        Morpher::new(&serde_json::from_value::<Vec<Rule>>(rules).unwrap())
+    }
+
+    fn object() -> InMemDicomObject {
+        InMemDicomObject::from_element_iter([
+            DataElement::new(tags::PATIENT_ID, VR::LO, dicom_value!(Str, "12345")),
+            DataElement::new(tags::PATIENT_NAME, VR::PN, dicom_value!(Str, "Doe^John")),
+            DataElement::new(tags::STUDY_DATE, VR::DA, dicom_value!(Str, "20240301")),
+            DataElement::new(
+                tags::ACQUISITION_DATE_TIME,
+                VR::DT,
+                dicom_value!(Str, "20240228235959.5"),
+            ),
+            DataElement::new(tags::INSTITUTION_NAME, VR::LO, dicom_value!(Str, "General")),
+            DataElement::new(tags::MODALITY, VR::CS, dicom_value!(Str, "CT")),
+        ])
+    }
+
+    fn text(object: &InMemDicomObject, tag: Tag) -> Option<String> {
+        text_of(object, tag).map(|(_, text)| text)
+    }
+
+    #[test]
+    fn applies_the_rules_in_order() {
+        let morpher = morpher(json!([
+            { "type": "Copy", "from": "PatientID", "to": "IssuerOfPatientID" },
+            { "type": "RegexReplace", "tag": "PatientID", "pattern": "^(\\d{3})", "replacement": "X$1-" },
+            { "type": "Prefix", "tag": "(0010,0020)", "prefix": "EXT-" },
+            { "type": "Uppercase", "tag": "00100010" },
+            { "type": "Remove", "tag": "InstitutionName" },
+            { "type": "Set", "tag": "0028,0010", "value": "512" },
+            { "type": "Set", "tag": "ImageType", "value": "ORIGINAL\\PRIMARY" },
+            { "type": "DateShift", "tag": "StudyDate", "days": -1 },
+            { "type": "DateShift", "tag": "AcquisitionDateTime", "days": 1 },
+        ]))
+        .unwrap();
+        let mut object = object();
+        morpher.apply(&mut object);
+
+        assert_eq!(text(&object, tags::PATIENT_ID).unwrap(), "EXT-X123-45");
+        assert_eq!(text(&object, tags::ISSUER_OF_PATIENT_ID).unwrap(), "12345");
+        assert_eq!(text(&object, tags::PATIENT_NAME).unwrap(), "DOE^JOHN");
+        assert_eq!(text(&object, tags::INSTITUTION_NAME), None);
+        let rows = object.element(tags::ROWS).unwrap();
+        assert_eq!((rows.vr(), rows.to_int::<u16>().unwrap()), (VR::US, 512));
+        let image_type = object.element(tags::IMAGE_TYPE).unwrap();
+        assert_eq!(image_type.vr(), VR::CS);
+        assert_eq!(image_type.to_multi_str().unwrap().len(), 2);
+        assert_eq!(text(&object, tags::STUDY_DATE).unwrap(), "20240229");
+        assert_eq!(
+            text(&object, tags::ACQUISITION_DATE_TIME).unwrap(),
+            "20240229235959.5"
+        );
+    }
+
+    #[test]
+    fn conditions_choose_the_rules() {
+        let morpher = morpher(json!([{
+            "type": "If",
+            "tag": "Modality",
+            "matches": "^(CT|MR)$",
+            "then": [{ "type": "Set", "tag": "StationName", "value": "CROSS" }],
+            "else": [{ "type": "Remove", "tag": "PatientID" }],
+        }, {
+            "type": "If",
+            "tag": "AccessionNumber",
+            "matches": "^$",
+            "then": [{ "type": "Set", "tag": "AccessionNumber", "value": "NONE" }],
+        }]))
+        .unwrap();
+        let mut cross_sectional = object();
+        morpher.apply(&mut cross_sectional);
+        assert_eq!(text(&cross_sectional, tags::STATION_NAME).unwrap(), "CROSS");
+        assert_eq!(text(&cross_sectional, tags::PATIENT_ID).unwrap(), "12345");
+        // A missing attribute matches as an empty value
+        assert_eq!(
+            text(&cross_sectional, tags::ACCESSION_NUMBER).unwrap(),
+            "NONE"
+        );
+
+        let mut object = object();
+        object.put(DataElement::new(
+            tags::MODALITY,
+            VR::CS,
+            dicom_value!(Str, "US"),
+        ));
+        morpher.apply(&mut object);
+        assert_eq!(text(&object, tags::STATION_NAME), None);
+        assert_eq!(text(&object, tags::PATIENT_ID), None);
+    }
+
+    #[test]
+    fn skips_the_rules_that_do_not_apply() {
+        let morpher = morpher(json!([
+            { "type": "DateShift", "tag": "PatientName", "days": 1 },
+            { "type": "Prefix", "tag": "AccessionNumber", "prefix": "EXT-" },
+            { "type": "Set", "tag": "(0009,1001)", "value": "private" },
+        ]))
+        .unwrap();
+        let mut object = object();
+        object.put(DataElement::new(
+            Tag(0x0009, 0x1001),
+            VR::UN,
+            Value::Primitive(PrimitiveValue::U8(vec![1, 2].into())),
+        ));
+        morpher.apply(&mut object);
+        assert_eq!(text(&object, tags::PATIENT_NAME).unwrap(), "Doe^John");
+        assert_eq!(text(&object, tags::ACCESSION_NUMBER), None);
+        // The tags the dictionary does not know are set as LO
+        let private = object.element(Tag(0x0009, 0x1001)).unwrap();
+        assert_eq!(private.vr(), VR::LO);
+        assert_eq!(private.to_str().unwrap(), "private");
+    }
+
+    #[test]
+    fn refuses_invalid_rules() {
+        assert!(morpher(json!([{ "type": "Remove", "tag": "NotAKeyword" }])).is_err());
+        assert!(morpher(json!([{ "type": "Set", "tag": "Rows", "value": "many" }])).is_err());
+        assert!(morpher(json!([{ "type": "Set", "tag": "PixelData", "value": "0" }])).is_err());
+        assert!(morpher(json!([{
+            "type": "RegexReplace", "tag": "PatientID", "pattern": "(", "replacement": ""
+        }]))
+        .is_err());
+        assert!(morpher(json!([{
+            "type": "If", "tag": "Modality", "matches": "CT",
+            "then": [{ "type": "Uppercase", "tag": "Unknown" }],
+        }]))
+        .is_err());
+        assert_eq!(parse_tag(" 0010,0020 ").unwrap(), tags::PATIENT_ID);
+    }
+}
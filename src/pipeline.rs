@@ -0,0 +1,890 @@
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+};
+
+use color_eyre::eyre::Context;
+use dicom::object::DefaultDicomObject;
+use tracing::{debug, info, warn};
+
+use crate::{
+    backend::{build_backend, StorageBackend},
+    charset::normalize,
+    destination::{build_destination, Delivery, Queued},
+    filtering::InstanceFilter,
+    index::{DeliveryState, InstanceIndex},
+    processor::Processor,
+    quarantine::{list_items, quarantine_instance, quarantine_raw, QuarantineItem},
+    reconciliation::Reconciler,
+    stage::Stage,
+    storage::{sanitize, Duplicate, FileStore, SpaceGuard, Stored},
+    utils::{Channel, CharsetConfig, Config, ValidationPolicy},
+    validation::{ValidationReport, Validator},
+};
+
+/// Success
+pub(crate) const STATUS_SUCCESS: u16 = 0x0000;
+/// Processing failure
+pub(crate) const STATUS_PROCESSING_FAILURE: u16 = 0x0110;
+/// Duplicate SOP instance, the instance was already received
+pub(crate) const STATUS_DUPLICATE_SOP_INSTANCE: u16 = 0x0111;
+/// Out of resources, the storage of the channel is full
+pub(crate) const STATUS_OUT_OF_RESOURCES: u16 = 0xA700;
+/// Data set does not match SOP class, the instance is refused
+pub(crate) const STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS: u16 = 0xA900;
+/// Data set does not match SOP class, the instance is accepted anyway
+pub(crate) const STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS_WARNING: u16 = 0xB007;
+
+/// Cannot understand, the instance could not be read
+pub(crate) const STATUS_CANNOT_UNDERSTAND: u16 = 0xC000;
+
+/// Whether the DIMSE status means the instance was accepted, possibly
+/// with a warning
+pub(crate) fn is_success(status: u16) -> bool {
+    status == STATUS_SUCCESS || (0xB000..=0xBFFF).contains(&status)
+}
+
+/// The number of instances a channel source can lag behind the channel
+/// it is fed by before the upstream channel waits for it
+const BUS_CAPACITY: usize = 64;
+
+/// What became of an instance handed to a channel
+enum Outcome {
+    /// The instance went through the channel, or was stopped by it
+    Done(u16),
+    /// The instance was set aside in the quarantine of the channel
+    Quarantined(u16),
+}
+
+impl Outcome {
+    /// The DIMSE status to report to the sender
+    fn status(&self) -> u16 {
+        match self {
+            Outcome::Done(status) | Outcome::Quarantined(status) => *status,
+        }
+    }
+}
+
+/// What a channel stage decided to do with an instance
+#[derive(Debug)]
+pub(crate) enum Verdict {
+    /// Forward the instance to the destinations of the channel with
+    /// these names
+    Forward(Vec<String>),
+    /// Acknowledge the instance without going further
+    Drop,
+}
+
+/// Where an instance comes from
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Origin {
+    /// The AET of the sender, for instances received over DICOM
+    pub(crate) calling_aet: Option<String>,
+    /// The AET the sender called, for instances received over DICOM
+    pub(crate) called_aet: Option<String>,
+    /// The address of the sender, or the file the instance was read from
+    pub(crate) peer: String,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.calling_aet, &self.called_aet) {
+            (Some(calling), Some(called)) => write!(f, "{} -> {} ({})", calling, called, self.peer),
+            _ => write!(f, "{}", self.peer),
+        }
+    }
+}
+
+/// A DICOM instance received by a channel, whatever its source
+#[derive(Debug, Clone)]
+pub(crate) struct Instance {
+    pub(crate) object: DefaultDicomObject,
+    pub(crate) origin: Origin,
+}
+
+impl Instance {
+    pub(crate) fn sop_class_uid(&self) -> &str {
+        self.object.meta().media_storage_sop_class_uid()
+    }
+
+    pub(crate) fn sop_instance_uid(&self) -> &str {
+        self.object.meta().media_storage_sop_instance_uid()
+    }
+}
+
+/// The id of a subscription and where to send its instances
+type Subscriber = (u64, SyncSender<Instance>);
+
+/// Hands the instances processed by a channel to the channels fed by it
+#[derive(Default)]
+pub(crate) struct Bus {
+    subscribers: Mutex<HashMap<String, Vec<Subscriber>>>,
+    next_id: AtomicU64,
+}
+
+/// The instances published by a channel, the subscription ends when
+/// this is dropped
+pub(crate) struct Subscription {
+    bus: Arc<Bus>,
+    channel: String,
+    id: u64,
+    pub(crate) receiver: Receiver<Instance>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut subscribers = self.bus.subscribers.lock().unwrap();
+        if let Some(senders) = subscribers.get_mut(&self.channel) {
+            senders.retain(|(id, _)| *id != self.id);
+        }
+    }
+}
+
+impl Bus {
+    /// Subscribes to the instances processed by `channel`
+    pub(crate) fn subscribe(self: &Arc<Self>, channel: &str) -> Subscription {
+        let (sender, receiver) = sync_channel(BUS_CAPACITY);
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .entry(channel.to_string())
+            .or_default()
+            .push((id, sender));
+        Subscription {
+            bus: self.clone(),
+            channel: channel.to_string(),
+            id,
+            receiver,
+        }
+    }
+
+    /// Sends the instance to every channel subscribed to `channel`
+    pub(crate) fn publish(&self, channel: &str, instance: &Instance) {
+        // Sending may block, the lock must not be held meanwhile
+        let senders = match self.subscribers.lock().unwrap().get(channel) {
+            Some(senders) => senders.clone(),
+            None => return,
+        };
+        for (id, sender) in senders {
+            if sender.send(instance.clone()).is_err() {
+                debug!("Subscriber {} of channel {} is gone", id, channel);
+            }
+        }
+    }
+}
+
+/// What a channel does with the instances its source receives.
+/// It is the same whatever the source of the channel is.
+pub(crate) struct Pipeline {
+    channel: String,
+    charset: Option<CharsetConfig>,
+    validator: Option<Validator>,
+    reconciler: Option<Reconciler>,
+    quarantine_dir: Option<PathBuf>,
+    pre: Stage,
+    storage: Option<FileStore>,
+    space_guard: Option<SpaceGuard>,
+    index: Option<Arc<InstanceIndex>>,
+    /// Whether the duplicates stored again are forwarded again
+    forward_duplicates: bool,
+    processor: Option<Processor>,
+    destinations: Vec<Delivery>,
+    bus: Arc<Bus>,
+}
+
+impl Pipeline {
+    /// Builds the pipeline of the channel and launches the delivery
+    /// threads of its destinations
+    pub(crate) fn new(
+        channel: &Channel,
+        config: &Config,
+        bus: Arc<Bus>,
+        shutdown_signal: Arc<AtomicBool>,
+    ) -> color_eyre::Result<Self> {
+        let index = channel
+            .storage
+            .as_ref()
+            .filter(|storage| storage.index)
+            .map(|storage| InstanceIndex::open(&storage.out_dir).map(Arc::new))
+            .transpose()
+            .wrap_err("Invalid instance index")?;
+        let mut destinations = Vec::new();
+        for destination in channel.destinations.iter() {
+            let built = build_destination(destination, channel, config)
+                .wrap_err_with(|| format!("Invalid destination {}", destination))?;
+            let filter = InstanceFilter::new(&destination.drop).wrap_err_with(|| {
+                format!("Invalid drop filters for destination {}", destination)
+            })?;
+            let post = Stage::new(&destination.post)
+                .wrap_err_with(|| format!("Invalid post stage for destination {}", destination))?;
+            destinations.push(Delivery::start(
+                destination.to_string(),
+                channel.name.clone(),
+                filter,
+                built,
+                post,
+                index.clone(),
+                shutdown_signal.clone(),
+            ));
+        }
+        Ok(Self {
+            channel: channel.name.clone(),
+            charset: channel.charset.clone(),
+            validator: channel.validation.as_ref().map(Validator::new),
+            reconciler: channel
+                .reconciliation
+                .as_ref()
+                .map(Reconciler::new)
+                .transpose()
+                .wrap_err("Invalid patient reconciliation")?,
+            quarantine_dir: channel.quarantine_dir.clone(),
+            pre: Stage::new(&channel.pre).wrap_err("Invalid pre stage")?,
+            storage: channel
+                .storage
+                .as_ref()
+                .map(|storage| {
+                    FileStore::new(
+                        build_backend(storage)?,
+                        storage.path_template.as_deref(),
+                        storage.checksums,
+                        storage.duplicates.policy,
+                    )
+                })
+                .transpose()
+                .wrap_err("Invalid storage")?,
+            space_guard: channel.storage.as_ref().and_then(|storage| {
+                storage
+                    .min_free_mb
+                    .map(|min_free_mb| SpaceGuard::new(&storage.out_dir, min_free_mb))
+            }),
+            index,
+            forward_duplicates: channel
+                .storage
+                .as_ref()
+                .is_some_and(|storage| storage.duplicates.forward),
+            processor: channel.processor.as_ref().map(Processor::new),
+            destinations,
+            bus,
+        })
+    }
+
+    /// Whether the storage of the channel is under its free space
+    /// watermark, the instances being refused until it is not
+    pub(crate) fn is_out_of_space(&self) -> bool {
+        self.space_guard
+            .as_ref()
+            .is_some_and(|space_guard| space_guard.is_low())
+    }
+
+    /// The index of the instances stored by the channel, if any
+    pub(crate) fn index(&self) -> Option<&Arc<InstanceIndex>> {
+        self.index.as_ref()
+    }
+
+    /// The backend of the storage of the channel, if any
+    pub(crate) fn backend(&self) -> Option<&Arc<dyn StorageBackend>> {
+        self.storage.as_ref().map(FileStore::backend)
+    }
+
+    /// Reloads the scripts and the mapping of the channel if their files
+    /// changed
+    pub(crate) fn reload(&self) {
+        self.pre.reload();
+        for destination in self.destinations.iter() {
+            destination.reload();
+        }
+        if let Some(reconciler) = &self.reconciler {
+            reconciler.reload();
+        }
+    }
+
+    /// Runs the instance through the channel and returns the DIMSE
+    /// status to report to the sender
+    pub(crate) fn ingest(&self, instance: Instance) -> u16 {
+        self.process(instance).status()
+    }
+
+    fn process(&self, mut instance: Instance) -> Outcome {
+        debug!(
+            "Channel {} received {} from {}",
+            self.channel,
+            instance.sop_instance_uid(),
+            instance.origin
+        );
+        if self.is_out_of_space() {
+            warn!(
+                "Channel {} refuses {}, its storage is full",
+                self.channel,
+                instance.sop_instance_uid()
+            );
+            return Outcome::Done(STATUS_OUT_OF_RESOURCES);
+        }
+        let mut status = STATUS_SUCCESS;
+        if let Some(charset) = &self.charset {
+            // The instance is quarantined as received
+            let received =
+                (charset.unmappable == ValidationPolicy::Quarantine).then(|| instance.clone());
+            let problem = match normalize(&mut instance.object) {
+                Ok(unmappable) if unmappable.is_empty() => None,
+                Ok(unmappable) => Some(format!(
+                    "Unmappable characters in {}",
+                    unmappable
+                        .iter()
+                        .map(|value| value.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )),
+                Err(e) => Some(format!("{:#}", e)),
+            };
+            if let Some(problem) = problem {
+                warn!(
+                    "Channel {}: {}: {}",
+                    self.channel,
+                    instance.sop_instance_uid(),
+                    problem
+                );
+                match charset.unmappable {
+                    ValidationPolicy::Warn => {
+                        status = STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS_WARNING;
+                    }
+                    ValidationPolicy::Reject => {
+                        return Outcome::Done(STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS)
+                    }
+                    ValidationPolicy::Quarantine => {
+                        return self.quarantine(
+                            received.as_ref().unwrap_or(&instance),
+                            &problem,
+                            None,
+                            STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS_WARNING,
+                        );
+                    }
+                }
+            }
+        }
+        if let Some(validator) = &self.validator {
+            let report = validator.validate(&instance.object);
+            if !report.problems.is_empty() {
+                warn!("Channel {}: {}", self.channel, report.summary());
+                if let Err(e) = validator.write_report(&report) {
+                    warn!("Could not write the validation report: {:#}", e);
+                }
+                match validator.policy {
+                    ValidationPolicy::Warn => {
+                        status = STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS_WARNING;
+                    }
+                    ValidationPolicy::Reject => {
+                        return Outcome::Done(STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS)
+                    }
+                    ValidationPolicy::Quarantine => {
+                        return self.quarantine(
+                            &instance,
+                            &report.summary(),
+                            Some(&report),
+                            STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS_WARNING,
+                        );
+                    }
+                }
+            }
+        }
+        if let Some(reconciler) = &self.reconciler {
+            match reconciler.apply(&mut instance.object) {
+                Some(local) => debug!(
+                    "Channel {} reconciled {} with patient {}",
+                    self.channel,
+                    instance.sop_instance_uid(),
+                    local.patient_id
+                ),
+                None => {
+                    return self.quarantine(
+                        &instance,
+                        "The patient is not in the mapping",
+                        None,
+                        STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS_WARNING,
+                    )
+                }
+            }
+        }
+        let destinations: Vec<String> = self
+            .destinations
+            .iter()
+            .map(|destination| destination.name().to_string())
+            .collect();
+        let mut selected = match self.pre.apply(&mut instance, &self.channel, &destinations) {
+            Ok(Verdict::Forward(names)) => names,
+            Ok(Verdict::Drop) => {
+                info!(
+                    "The pre stage of channel {} dropped {}",
+                    self.channel,
+                    instance.sop_instance_uid()
+                );
+                return Outcome::Done(STATUS_SUCCESS);
+            }
+            Err(e) => {
+                warn!(
+                    "The pre stage of channel {} failed on {}: {:#}",
+                    self.channel,
+                    instance.sop_instance_uid(),
+                    e
+                );
+                return self.quarantine_rejected(&instance, &format!("{:#}", e));
+            }
+        };
+        let mut stored = None;
+        let mut forward = true;
+        if let Some(storage) = &self.storage {
+            match storage.store(&instance) {
+                Ok(written) => {
+                    match written.duplicate {
+                        None => info!("Stored {}", written.location),
+                        Some(duplicate) => {
+                            info!(
+                                "Channel {} received {} again, {} {}",
+                                self.channel,
+                                instance.sop_instance_uid(),
+                                duplicate,
+                                written.location
+                            );
+                            match duplicate {
+                                Duplicate::Refused => {
+                                    return Outcome::Done(STATUS_DUPLICATE_SOP_INSTANCE)
+                                }
+                                Duplicate::Identical => return Outcome::Done(status),
+                                Duplicate::Replaced | Duplicate::Versioned => {
+                                    forward = self.forward_duplicates
+                                }
+                                Duplicate::Changed => (),
+                            }
+                        }
+                    }
+                    stored = Some(written);
+                }
+                Err(e) => {
+                    warn!(
+                        "Channel {} could not store {}: {:#}",
+                        self.channel,
+                        instance.sop_instance_uid(),
+                        e
+                    );
+                    return Outcome::Done(STATUS_PROCESSING_FAILURE);
+                }
+            }
+        }
+        let mut instance_id = stored
+            .as_ref()
+            .and_then(|stored| self.index_stored(&instance, stored));
+        if let Some(processor) = &self.processor {
+            let sop_instance_uid = instance.sop_instance_uid().to_string();
+            let processed = processor.run(&mut instance, &selected);
+            // The stored copy follows the instance the program modified
+            if let (Ok((_, true)), Some(storage), Some(written)) =
+                (&processed, &self.storage, &stored)
+            {
+                match storage.replace(written, &instance) {
+                    Ok(replaced) => {
+                        debug!("Stored {} as modified by the processor", replaced.location);
+                        instance_id = self.index_stored(&instance, &replaced);
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Channel {} could not store {} as modified by the processor: {:#}",
+                            self.channel, sop_instance_uid, e
+                        );
+                        return Outcome::Done(STATUS_PROCESSING_FAILURE);
+                    }
+                }
+            }
+            match processed {
+                Ok((Verdict::Forward(names), _)) => selected = names,
+                Ok((Verdict::Drop, _)) => {
+                    info!(
+                        "The processor of channel {} dropped {}",
+                        self.channel, sop_instance_uid
+                    );
+                    return Outcome::Done(STATUS_SUCCESS);
+                }
+                Err(e) => {
+                    warn!(
+                        "The processor of channel {} rejected {}: {:#}",
+                        self.channel, sop_instance_uid, e
+                    );
+                    return self.quarantine_rejected(&instance, &format!("{:#}", e));
+                }
+            }
+        }
+        if !forward {
+            debug!(
+                "Channel {} does not forward {} again",
+                self.channel,
+                instance.sop_instance_uid()
+            );
+            return Outcome::Done(status);
+        }
+        self.bus.publish(&self.channel, &instance);
+        for destination in self.destinations.iter() {
+            if !selected.iter().any(|name| name == destination.name()) {
+                debug!(
+                    "Channel {} does not send {} to {}",
+                    self.channel,
+                    instance.sop_instance_uid(),
+                    destination.name()
+                );
+                continue;
+            }
+            if destination.drops(&instance) {
+                info!(
+                    "Channel {} drops {} for {}, it is filtered out",
+                    self.channel,
+                    instance.sop_instance_uid(),
+                    destination.name()
+                );
+                self.note_delivery(instance_id, &instance, destination, DeliveryState::Dropped);
+                continue;
+            }
+            self.note_delivery(instance_id, &instance, destination, DeliveryState::Pending);
+            // The destinations read the stored instance back, not to keep
+            // it in memory while they are behind
+            let queued = match (&self.storage, &stored) {
+                (Some(storage), Some(stored)) => Queued::Stored {
+                    backend: storage.backend().clone(),
+                    key: stored.key.clone(),
+                    sop_instance_uid: instance.sop_instance_uid().to_string(),
+                    origin: instance.origin.clone(),
+                    instance_id,
+                },
+                _ => Queued::Instance(Box::new(instance.clone())),
+            };
+            destination.push(queued);
+        }
+        Outcome::Done(status)
+    }
+}
+
+impl Pipeline {
+    /// Records the stored instance in the index of the channel, if any,
+    /// and returns its id there
+    fn index_stored(&self, instance: &Instance, stored: &Stored) -> Option<i64> {
+        let index = self.index.as_ref()?;
+        match index.record(instance, &stored.location, stored.size) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                warn!(
+                    "Channel {} could not index {}: {:#}",
+                    self.channel,
+                    instance.sop_instance_uid(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Records what became of the instance with the id for the
+    /// destination in the index of the channel
+    fn note_delivery(
+        &self,
+        instance_id: Option<i64>,
+        instance: &Instance,
+        destination: &Delivery,
+        state: DeliveryState,
+    ) {
+        if let (Some(index), Some(instance_id)) = (&self.index, instance_id) {
+            index.note_delivery(
+                instance_id,
+                instance.sop_instance_uid(),
+                destination.name(),
+                state,
+            );
+        }
+    }
+
+    /// Sets the instance aside in the quarantine of the channel, the
+    /// sender being told `status`
+    fn quarantine(
+        &self,
+        instance: &Instance,
+        reason: &str,
+        report: Option<&ValidationReport>,
+        status: u16,
+    ) -> Outcome {
+        let Some(dir) = &self.quarantine_dir else {
+            warn!(
+                "Channel {} has no quarantine for {}",
+                self.channel,
+                instance.sop_instance_uid()
+            );
+            return Outcome::Done(STATUS_PROCESSING_FAILURE);
+        };
+        match quarantine_instance(dir, &self.channel, instance, reason, report) {
+            Ok(path) => {
+                info!(
+                    "Quarantined {} in {}",
+                    instance.sop_instance_uid(),
+                    path.display()
+                );
+                Outcome::Quarantined(status)
+            }
+            Err(e) => {
+                warn!(
+                    "Channel {} could not quarantine {}: {:#}",
+                    self.channel,
+                    instance.sop_instance_uid(),
+                    e
+                );
+                Outcome::Done(STATUS_PROCESSING_FAILURE)
+            }
+        }
+    }
+
+    /// Keeps an instance rejected by the script or the processor in the
+    /// quarantine of the channel, if it has one. The sender is still
+    /// told the instance failed.
+    fn quarantine_rejected(&self, instance: &Instance, reason: &str) -> Outcome {
+        match self.quarantine_dir {
+            Some(_) => self.quarantine(instance, reason, None, STATUS_PROCESSING_FAILURE),
+            None => Outcome::Done(STATUS_PROCESSING_FAILURE),
+        }
+    }
+
+    /// Keeps bytes that could not be read as an instance in the
+    /// quarantine of the channel, if it has one, and returns the
+    /// status to report to the sender
+    pub(crate) fn quarantine_unreadable(
+        &self,
+        bytes: &[u8],
+        transfer_syntax: Option<&str>,
+        sop_instance_uid: Option<&str>,
+        origin: &Origin,
+        reason: &str,
+    ) -> u16 {
+        warn!(
+            "Channel {} could not read an instance from {}: {}",
+            self.channel, origin, reason
+        );
+        if let Some(dir) = &self.quarantine_dir {
+            match quarantine_raw(
+                dir,
+                &self.channel,
+                bytes,
+                transfer_syntax,
+                sop_instance_uid,
+                origin,
+                reason,
+            ) {
+                Ok(path) => info!("Quarantined the received bytes in {}", path.display()),
+                Err(e) => warn!(
+                    "Channel {} could not quarantine the received bytes: {:#}",
+                    self.channel, e
+                ),
+            }
+        }
+        STATUS_CANNOT_UNDERSTAND
+    }
+
+    /// Runs the items released from the quarantine through the channel
+    /// again. They leave the quarantine once accepted or quarantined
+    /// anew, and are kept otherwise.
+    pub(crate) fn release_quarantined(&self) {
+        let Some(dir) = &self.quarantine_dir else {
+            return;
+        };
+        let items = match list_items(dir) {
+            Ok(items) => items,
+            Err(e) => {
+                warn!("Could not read the quarantine {}: {:#}", dir.display(), e);
+                return;
+            }
+        };
+        for item in items.into_iter().filter(QuarantineItem::is_released) {
+            let released = match item.instance() {
+                Ok(instance) => match self.process(instance) {
+                    Outcome::Done(status) if is_success(status) => Ok(()),
+                    Outcome::Done(status) => Err(format!("the channel answered {:#06x}", status)),
+                    Outcome::Quarantined(_) => Ok(()),
+                },
+                Err(e) => Err(format!("{:#}", e)),
+            };
+            let result = match released {
+                Ok(()) => {
+                    info!("Released {} into channel {}", item.id, self.channel);
+                    item.purge()
+                }
+                Err(reason) => {
+                    warn!("Could not release {}: {}", item.id, reason);
+                    item.hold()
+                }
+            };
+            if let Err(e) = result {
+                warn!("{:#}", e);
+            }
+        }
+    }
+}
+
+/// Writes the instance to a temporary file of its own, named after
+/// its SOPInstanceUID, for a program to read. The caller deletes it.
+pub(crate) fn write_temporary(instance: &Instance) -> color_eyre::Result<PathBuf> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let dir = env::temp_dir().join(format!("eai-rs-{}", std::process::id()));
+    fs::create_dir_all(&dir)
+        .wrap_err_with(|| format!("Could not create the directory {}", dir.display()))?;
+    let file_path = dir.join(format!(
+        "{}-{}.dcm",
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        sanitize(instance.sop_instance_uid())
+    ));
+    instance
+        .object
+        .write_to_file(&file_path)
+        .wrap_err("Could not save DICOM object to file")?;
+    Ok(file_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use dicom::{
+        core::{DataElement, VR},
+        dicom_value,
+        dictionary_std::tags,
+        object::{FileMetaTableBuilder, InMemDicomObject},
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    /// An empty directory of its own for a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("eai-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A channel without destinations validating its instances
+    fn pipeline(policy: &str, dir: &Path) -> Pipeline {
+        build(json!({
+            "name": "in",
+            "source": { "type": "HotFolder", "path": dir.join("incoming") },
+            "validation": { "policy": policy, "report_dir": dir.join("reports") },
+            "quarantine_dir": dir.join("quarantine"),
+            "destinations": [],
+            "status": "Stopped",
+        }))
+    }
+
+    fn build(channel: serde_json::Value) -> Pipeline {
+        let channel: Channel = serde_json::from_value(channel).unwrap();
+        Pipeline::new(
+            &channel,
+            &Config::new(),
+            Arc::new(Bus::default()),
+            Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap()
+    }
+
+    /// A Secondary Capture Image without its Modality
+    fn nonconformant() -> Instance {
+        let object = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_CLASS_UID,
+                VR::UI,
+                dicom_value!(Str, "1.2.840.10008.5.1.4.1.1.7"),
+            ),
+            DataElement::new(
+                tags::SOP_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, "1.2.3.4.5.1"),
+            ),
+        ]);
+        let meta = FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+            .media_storage_sop_instance_uid("1.2.3.4.5.1")
+            .transfer_syntax("1.2.840.10008.1.2.1")
+            .build()
+            .unwrap();
+        Instance {
+            object: object.with_exact_meta(meta),
+            origin: Origin {
+                calling_aet: Some("MODALITY".to_string()),
+                called_aet: Some("EAI".to_string()),
+                peer: "127.0.0.1:40000".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn validation_policies_answer_their_status() {
+        let dir = test_dir("pipeline-warn");
+        assert_eq!(
+            pipeline("Warn", &dir).ingest(nonconformant()),
+            STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS_WARNING
+        );
+        assert!(dir.join("reports/1.2.3.4.5.1.json").is_file());
+        assert!(list_items(&dir.join("quarantine")).unwrap().is_empty());
+
+        let dir = test_dir("pipeline-reject");
+        assert_eq!(
+            pipeline("Reject", &dir).ingest(nonconformant()),
+            STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS
+        );
+        assert!(dir.join("reports/1.2.3.4.5.1.json").is_file());
+        assert!(list_items(&dir.join("quarantine")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn quarantined_instances_keep_their_report() {
+        let dir = test_dir("pipeline-quarantine");
+        assert_eq!(
+            pipeline("Quarantine", &dir).ingest(nonconformant()),
+            STATUS_DATA_SET_DOES_NOT_MATCH_SOP_CLASS_WARNING
+        );
+        let items = list_items(&dir.join("quarantine")).unwrap();
+        assert_eq!(items.len(), 1);
+        let record = &items[0].record;
+        assert_eq!(record.channel, "in");
+        assert_eq!(record.sop_instance_uid.as_deref(), Some("1.2.3.4.5.1"));
+        let report = record.report.as_ref().unwrap();
+        assert_eq!(record.reason, report.summary());
+        assert_eq!(report.iod.as_deref(), Some("Secondary Capture Image"));
+        assert!(report
+            .problems
+            .iter()
+            .any(|problem| problem.message == "Modality is missing (Type 1)"));
+        assert_eq!(
+            items[0].instance().unwrap().sop_instance_uid(),
+            "1.2.3.4.5.1"
+        );
+    }
+
+    #[test]
+    fn refuses_the_instances_when_the_storage_is_full() {
+        let dir = test_dir("pipeline-full");
+        let channel = |min_free_mb: u64| {
+            json!({
+                "name": "in",
+                "source": { "type": "HotFolder", "path": dir.join("incoming") },
+                "storage": { "out_dir": dir.join("store"), "min_free_mb": min_free_mb },
+                "destinations": [],
+                "status": "Stopped",
+            })
+        };
+        let full = build(channel(u64::MAX >> 20));
+        assert!(full.is_out_of_space());
+        assert_eq!(full.ingest(nonconformant()), STATUS_OUT_OF_RESOURCES);
+        assert!(!dir.join("store/1.2.3.4.5.1.dcm").exists());
+
+        let roomy = build(channel(0));
+        assert!(!roomy.is_out_of_space());
+        assert_eq!(roomy.ingest(nonconformant()), STATUS_SUCCESS);
+        assert!(dir.join("store/1.2.3.4.5.1.dcm").is_file());
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::eyre::{bail, Context};
+use dicom::object::open_file;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{
+    external_command::{run, substitute},
+    pipeline::{write_temporary, Instance, Verdict},
+    utils::{ProcessorConfig, ProcessorInput},
+};
+
+/// What the program decided, written as JSON on the last line of its
+/// standard output
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Decision {
+    action: Action,
+    /// Why the instance is rejected, for the logs
+    reason: Option<String>,
+    /// The names of the destinations to send the instance to, all of
+    /// them when not given
+    destinations: Option<Vec<String>>,
+    /// A modified instance to use instead of the received one
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    #[default]
+    Accept,
+    Reject,
+    Drop,
+}
+
+/// The processes a program may run at once, shared by the channels
+/// running the same program
+struct Slots {
+    /// The running processes and the limit
+    state: Mutex<(usize, usize)>,
+    freed: Condvar,
+}
+
+/// Frees its slot when dropped
+struct Slot<'a>(&'a Slots);
+
+impl Slots {
+    fn acquire(&self) -> Slot<'_> {
+        let mut state = self.state.lock().unwrap();
+        while state.0 >= state.1 {
+            state = self.freed.wait(state).unwrap();
+        }
+        state.0 += 1;
+        Slot(self)
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().0 -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// The slots of a program, with the limit of the channel started last
+fn program_slots(program: &str, limit: usize) -> Arc<Slots> {
+    static SLOTS: OnceLock<Mutex<HashMap<String, Arc<Slots>>>> = OnceLock::new();
+    let slots = SLOTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(program.to_string())
+        .or_insert_with(|| {
+            Arc::new(Slots {
+                state: Mutex::new((0, limit)),
+                freed: Condvar::new(),
+            })
+        })
+        .clone();
+    slots.state.lock().unwrap().1 = limit.max(1);
+    slots.freed.notify_all();
+    slots
+}
+
+/// A channel stage running an external program on every instance. The
+/// program gets the path of the instance, or the instance on its
+/// standard input, and decides with its exit code or a JSON decision
+/// on its standard output.
+pub(crate) struct Processor {
+    program: String,
+    args: Vec<String>,
+    input: ProcessorInput,
+    timeout: Duration,
+    slots: Arc<Slots>,
+}
+
+impl Processor {
+    pub(crate) fn new(config: &ProcessorConfig) -> Self {
+        Self {
+            program: config.program.clone(),
+            args: config.args.clone(),
+            input: config.input,
+            timeout: Duration::from_secs(config.timeout),
+            slots: program_slots(&config.program, config.max_processes),
+        }
+    }
+
+    /// Runs the program on a temporary copy of the instance, replacing
+    /// the instance with the modified one if any, and tells whether it
+    /// was modified. An error means the instance is rejected.
+    pub(crate) fn run(
+        &self,
+        instance: &mut Instance,
+        destinations: &[String],
+    ) -> color_eyre::Result<(Verdict, bool)> {
+        let _slot = self.slots.acquire();
+        let file = match self.input {
+            ProcessorInput::Stdin => None,
+            ProcessorInput::File => Some(write_temporary(instance)?),
+        };
+        let result = self.process(instance, file.as_deref(), destinations);
+        if let Some(file) = file {
+            if let Err(e) = fs::remove_file(&file) {
+                debug!("Could not delete {}: {}", file.display(), e);
+            }
+        }
+        result
+    }
+
+    fn process(
+        &self,
+        instance: &mut Instance,
+        file: Option<&Path>,
+        destinations: &[String],
+    ) -> color_eyre::Result<(Verdict, bool)> {
+        let (args, input) = match file {
+            Some(file) => (substitute(&self.args, file), None),
+            None => {
+                let mut input = Vec::new();
+                instance
+                    .object
+                    .write_all(&mut input)
+                    .wrap_err("Could not write the DICOM object")?;
+                (self.args.clone(), Some(input))
+            }
+        };
+        let before = file.and_then(version);
+        let output = run(&self.program, &args, input, self.timeout)?;
+
+        // The decision is the last line when it is a JSON object, the
+        // other lines are taken as logs
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines: Vec<&str> = stdout
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .collect();
+        let decision: Decision = match lines.last() {
+            Some(line) if line.trim_start().starts_with('{') => {
+                let decision = serde_json::from_str(line)
+                    .wrap_err_with(|| format!("{} wrote an invalid decision", self.program))?;
+                lines.pop();
+                decision
+            }
+            _ => Decision::default(),
+        };
+        for line in lines {
+            debug!("{}: {}", self.program, line);
+        }
+        let reason = decision
+            .reason
+            .as_ref()
+            .map(|reason| format!(": {}", reason))
+            .unwrap_or_default();
+        if !output.status.success() {
+            bail!("{} exited with {}{}", self.program, output.status, reason);
+        }
+        match decision.action {
+            Action::Accept => {}
+            Action::Reject => bail!("{} rejected the instance{}", self.program, reason),
+            Action::Drop => return Ok((Verdict::Drop, false)),
+        }
+
+        let modified = match (decision.file, file) {
+            (Some(modified), _) => Some(modified),
+            (None, Some(file)) if version(file) != before => Some(file.to_path_buf()),
+            _ => None,
+        };
+        if let Some(modified) = &modified {
+            debug!("Reading the instance modified by {}", self.program);
+            instance.object = open_file(modified)
+                .wrap_err_with(|| format!("Could not read {}", modified.display()))?;
+        }
+        let verdict = Verdict::Forward(match decision.destinations {
+            Some(names) => destinations
+                .iter()
+                .filter(|name| names.contains(name))
+                .cloned()
+                .collect(),
+            None => destinations.to_vec(),
+        });
+        Ok((verdict, modified.is_some()))
+    }
+}
+
+/// Tells whether a file was modified
+fn version(path: &Path) -> Option<(SystemTime, u64)> {
+    fs::metadata(path)
+        .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+        .ok()
+}
@@ -0,0 +1,653 @@
+//! Pseudonymization: the UIDs and PatientID of the instances replaced
+//! with values derived from a secret, the original values kept in a key
+//! store to re-identify the instances.
+
+use std::{
+    collections::HashMap,
+    env,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use color_eyre::eyre::{bail, eyre, Context};
+use dicom::{
+    core::{value::Value, DataElement, Length, PrimitiveValue, Tag, VR},
+    dicom_value,
+    dictionary_std::tags,
+    object::{DefaultDicomObject, InMemDicomObject},
+};
+use ring::{
+    aead, hmac, pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    deidentify::{is_replaced_uid, set_media_storage_sop_instance_uid, uid_from_digest},
+    utils::Pseudonymization,
+};
+
+/// The version of the key store format
+const KEY_STORE_VERSION: u32 = 1;
+
+/// The PBKDF2 iterations deriving the keys of an encrypted key store
+/// from its passphrase
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Encrypted in the header of an encrypted key store, to tell a wrong
+/// passphrase from a corrupted store
+const CHECK_TEXT: &[u8] = b"eai-rs key store";
+
+const NONCE_LEN: usize = 12;
+
+/// The first line of a key store
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    /// The secret the pseudonyms are derived from, in a store that is
+    /// not encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+    /// The salt the keys are derived from the passphrase with, in an
+    /// encrypted store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    salt: Option<String>,
+    /// The check text, encrypted, in an encrypted store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    check: Option<String>,
+}
+
+/// What a pseudonym replaces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Kind {
+    Uid,
+    PatientId,
+}
+
+/// A pseudonym and the value it replaces, a line of the key store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    pub(crate) kind: Kind,
+    pub(crate) pseudonym: String,
+    pub(crate) original: String,
+}
+
+/// A line of an encrypted key store, an entry encrypted with its nonce
+#[derive(Debug, Serialize, Deserialize)]
+struct Sealed {
+    sealed: String,
+}
+
+/// The pseudonyms given so far and the secret they are derived from,
+/// kept in a file with one JSON line per pseudonym
+pub(crate) struct KeyStore {
+    path: PathBuf,
+    file: File,
+    secret: hmac::Key,
+    cipher: Option<aead::LessSafeKey>,
+    entries: HashMap<String, Entry>,
+}
+
+impl KeyStore {
+    /// Opens the key store, creating it if needed. A store is encrypted
+    /// when created with a passphrase, and cannot be opened without it.
+    pub(crate) fn open(path: &Path, passphrase: Option<&str>) -> color_eyre::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("Could not create the directory {}", dir.display()))?;
+        }
+        match OpenOptions::new().append(true).create_new(true).open(path) {
+            Ok(mut file) => {
+                let (header, secret, cipher) = new_keys(passphrase)?;
+                write_line(&mut file, &header)
+                    .wrap_err_with(|| format!("Could not write {}", path.display()))?;
+                debug!("Created the key store {}", path.display());
+                return Ok(Self {
+                    path: path.to_path_buf(),
+                    file,
+                    secret,
+                    cipher,
+                    entries: HashMap::new(),
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => {
+                return Err(e).wrap_err_with(|| format!("Could not create {}", path.display()))
+            }
+        }
+
+        let reader = BufReader::new(
+            File::open(path).wrap_err_with(|| format!("Could not open {}", path.display()))?,
+        );
+        let mut lines = reader.lines();
+        let header: Header = match lines.next() {
+            Some(line) => serde_json::from_str(
+                &line.wrap_err_with(|| format!("Could not read {}", path.display()))?,
+            )
+            .wrap_err_with(|| format!("{} is not a key store", path.display()))?,
+            None => bail!("{} is empty", path.display()),
+        };
+        if header.version != KEY_STORE_VERSION {
+            bail!(
+                "{} is a version {} key store, only version {} is supported",
+                path.display(),
+                header.version,
+                KEY_STORE_VERSION
+            );
+        }
+        let (secret, cipher) = existing_keys(&header, passphrase)
+            .wrap_err_with(|| format!("Could not open the key store {}", path.display()))?;
+
+        let mut store = Self {
+            path: path.to_path_buf(),
+            file: OpenOptions::new()
+                .append(true)
+                .open(path)
+                .wrap_err_with(|| format!("Could not open {}", path.display()))?,
+            secret,
+            cipher,
+            entries: HashMap::new(),
+        };
+        for (number, line) in lines.enumerate() {
+            let line = line.wrap_err_with(|| format!("Could not read {}", path.display()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = store
+                .parse_entry(&line)
+                .wrap_err_with(|| format!("Invalid line {} in {}", number + 2, path.display()))?;
+            store.entries.insert(entry.pseudonym.clone(), entry);
+        }
+        debug!(
+            "Opened the key store {} with {} pseudonyms",
+            path.display(),
+            store.entries.len()
+        );
+        Ok(store)
+    }
+
+    /// The pseudonym of the value, recorded in the store the first time
+    pub(crate) fn pseudonym(
+        &mut self,
+        kind: Kind,
+        original: &str,
+        prefix: &str,
+    ) -> color_eyre::Result<String> {
+        let pseudonym = match kind {
+            Kind::Uid => uid_from_digest(self.digest("uid", original).as_ref()),
+            Kind::PatientId => {
+                let digest = self.digest("patient_id", original);
+                let hex: String = digest.as_ref()[..8]
+                    .iter()
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect();
+                format!("{}{}", prefix, hex)
+            }
+        };
+        if !self.entries.contains_key(&pseudonym) {
+            let entry = Entry {
+                kind,
+                pseudonym: pseudonym.clone(),
+                original: original.to_string(),
+            };
+            self.record(&entry)
+                .wrap_err_with(|| format!("Could not write to {}", self.path.display()))?;
+            self.entries.insert(pseudonym.clone(), entry);
+        }
+        Ok(pseudonym)
+    }
+
+    /// The entry of a pseudonym, to re-identify an instance
+    pub(crate) fn original(&self, pseudonym: &str) -> Option<&Entry> {
+        self.entries.get(pseudonym)
+    }
+
+    /// Every pseudonym in the store
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.values()
+    }
+
+    fn digest(&self, kind: &str, original: &str) -> hmac::Tag {
+        hmac::sign(&self.secret, format!("{}:{}", kind, original).as_bytes())
+    }
+
+    fn record(&mut self, entry: &Entry) -> color_eyre::Result<()> {
+        match &self.cipher {
+            Some(cipher) => {
+                let sealed = Sealed {
+                    sealed: seal(cipher, &serde_json::to_vec(entry)?)?,
+                };
+                write_line(&mut self.file, &sealed)
+            }
+            None => write_line(&mut self.file, entry),
+        }
+    }
+
+    fn parse_entry(&self, line: &str) -> color_eyre::Result<Entry> {
+        match &self.cipher {
+            Some(cipher) => {
+                let sealed: Sealed = serde_json::from_str(line)?;
+                Ok(serde_json::from_slice(&unseal(cipher, &sealed.sealed)?)?)
+            }
+            None => Ok(serde_json::from_str(line)?),
+        }
+    }
+}
+
+/// The header and keys of a new key store
+fn new_keys(
+    passphrase: Option<&str>,
+) -> color_eyre::Result<(Header, hmac::Key, Option<aead::LessSafeKey>)> {
+    let random = SystemRandom::new();
+    let mut bytes = [0; 32];
+    random
+        .fill(&mut bytes)
+        .map_err(|_| eyre!("Could not generate a secret"))?;
+    let Some(passphrase) = passphrase else {
+        let header = Header {
+            version: KEY_STORE_VERSION,
+            secret: Some(STANDARD.encode(bytes)),
+            salt: None,
+            check: None,
+        };
+        return Ok((header, hmac::Key::new(hmac::HMAC_SHA256, &bytes), None));
+    };
+    let (secret, cipher) = derive_keys(passphrase, &bytes)?;
+    let header = Header {
+        version: KEY_STORE_VERSION,
+        secret: None,
+        salt: Some(STANDARD.encode(bytes)),
+        check: Some(seal(&cipher, CHECK_TEXT)?),
+    };
+    Ok((header, secret, Some(cipher)))
+}
+
+/// The keys of an existing key store
+fn existing_keys(
+    header: &Header,
+    passphrase: Option<&str>,
+) -> color_eyre::Result<(hmac::Key, Option<aead::LessSafeKey>)> {
+    match (header, passphrase) {
+        (
+            Header {
+                secret: Some(secret),
+                ..
+            },
+            None,
+        ) => {
+            let secret = STANDARD.decode(secret).wrap_err("Invalid secret")?;
+            Ok((hmac::Key::new(hmac::HMAC_SHA256, &secret), None))
+        }
+        (
+            Header {
+                salt: Some(salt),
+                check: Some(check),
+                ..
+            },
+            Some(passphrase),
+        ) => {
+            let salt = STANDARD.decode(salt).wrap_err("Invalid salt")?;
+            let (secret, cipher) = derive_keys(passphrase, &salt)?;
+            match unseal(&cipher, check) {
+                Ok(text) if text == CHECK_TEXT => Ok((secret, Some(cipher))),
+                _ => bail!("Wrong passphrase"),
+            }
+        }
+        (
+            Header {
+                secret: Some(_), ..
+            },
+            Some(_),
+        ) => bail!("The key store is not encrypted"),
+        (_, None) => bail!("The key store is encrypted, a passphrase is needed"),
+        _ => bail!("Invalid header"),
+    }
+}
+
+/// Derives the secret of the pseudonyms and the encryption key from
+/// the passphrase
+fn derive_keys(
+    passphrase: &str,
+    salt: &[u8],
+) -> color_eyre::Result<(hmac::Key, aead::LessSafeKey)> {
+    let mut keys = [0; 64];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut keys,
+    );
+    let (encryption_key, secret) = keys.split_at(32);
+    let cipher = aead::UnboundKey::new(&aead::AES_256_GCM, encryption_key)
+        .map_err(|_| eyre!("Invalid encryption key"))?;
+    Ok((
+        hmac::Key::new(hmac::HMAC_SHA256, secret),
+        aead::LessSafeKey::new(cipher),
+    ))
+}
+
+/// Encrypts the data with a random nonce, returns both in base64
+fn seal(cipher: &aead::LessSafeKey, data: &[u8]) -> color_eyre::Result<String> {
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| eyre!("Could not generate a nonce"))?;
+    let mut sealed = data.to_vec();
+    cipher
+        .seal_in_place_append_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::empty(),
+            &mut sealed,
+        )
+        .map_err(|_| eyre!("Could not encrypt"))?;
+    Ok(STANDARD.encode([nonce.as_slice(), &sealed].concat()))
+}
+
+fn unseal(cipher: &aead::LessSafeKey, text: &str) -> color_eyre::Result<Vec<u8>> {
+    let mut bytes = STANDARD.decode(text).wrap_err("Invalid base64")?;
+    if bytes.len() < NONCE_LEN {
+        bail!("Truncated data");
+    }
+    let mut sealed = bytes.split_off(NONCE_LEN);
+    let nonce =
+        aead::Nonce::try_assume_unique_for_key(&bytes).map_err(|_| eyre!("Invalid nonce"))?;
+    let data = cipher
+        .open_in_place(nonce, aead::Aad::empty(), &mut sealed)
+        .map_err(|_| eyre!("Could not decrypt"))?;
+    Ok(data.to_vec())
+}
+
+/// Appends a JSON line in one write, so that the lines of the
+/// destinations sharing a store are not mixed, and flushes it to disk
+fn write_line<T: Serialize>(file: &mut File, value: &T) -> color_eyre::Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Opens the key store of a pseudonymization, reading its passphrase
+/// from the environment
+pub(crate) fn open_key_store(config: &Pseudonymization) -> color_eyre::Result<KeyStore> {
+    let passphrase = match &config.passphrase_env {
+        Some(variable) => Some(env::var(variable).wrap_err_with(|| {
+            format!(
+                "The passphrase of {} is not set in {}",
+                config.key_store.display(),
+                variable
+            )
+        })?),
+        None => None,
+    };
+    KeyStore::open(&config.key_store, passphrase.as_deref())
+}
+
+/// Replaces the UIDs and PatientID of the instances of a stage
+/// with their pseudonyms
+pub(crate) struct Pseudonymizer {
+    store: KeyStore,
+    patient_id_prefix: String,
+}
+
+impl Pseudonymizer {
+    pub(crate) fn new(config: &Pseudonymization) -> color_eyre::Result<Self> {
+        Ok(Self {
+            store: open_key_store(config)?,
+            patient_id_prefix: config.patient_id_prefix.clone(),
+        })
+    }
+
+    /// Pseudonymizes the instance. It fails when the pseudonyms cannot
+    /// be recorded, the instance could not be re-identified.
+    pub(crate) fn apply(&mut self, object: &mut DefaultDicomObject) -> color_eyre::Result<()> {
+        self.replace_uids(object)?;
+
+        let patient_id = object
+            .element(tags::PATIENT_ID)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|id| id.trim_end_matches(['\0', ' ']).to_string())
+            .filter(|id| !id.is_empty());
+        if let Some(patient_id) = patient_id {
+            let pseudonym =
+                self.store
+                    .pseudonym(Kind::PatientId, &patient_id, &self.patient_id_prefix)?;
+            object.put(DataElement::new(
+                tags::PATIENT_ID,
+                VR::LO,
+                dicom_value!(Str, pseudonym),
+            ));
+        }
+
+        // The file meta information follows the new SOP Instance UID
+        let sop_instance_uid = object
+            .element(tags::SOP_INSTANCE_UID)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|uid| uid.trim_end_matches('\0').to_string());
+        if let Some(uid) = sop_instance_uid {
+            set_media_storage_sop_instance_uid(object, &uid);
+        }
+        Ok(())
+    }
+
+    /// Replaces the UIDs the de-identification profile replaces, in the
+    /// sequences as well
+    fn replace_uids(&mut self, object: &mut InMemDicomObject) -> color_eyre::Result<()> {
+        let tags: Vec<Tag> = object.tags().collect();
+        for tag in tags {
+            let Ok(element) = object.element(tag) else {
+                continue;
+            };
+            if element.vr() == VR::SQ {
+                let Ok(element) = object.take_element(tag) else {
+                    continue;
+                };
+                let mut items = element.into_value().into_items().unwrap_or_default();
+                let result = items
+                    .iter_mut()
+                    .try_for_each(|item| self.replace_uids(item));
+                object.put(DataElement::new(
+                    tag,
+                    VR::SQ,
+                    Value::Sequence {
+                        items,
+                        size: Length::UNDEFINED,
+                    },
+                ));
+                result?;
+            } else if is_replaced_uid(tag) {
+                let Ok(uids) = element.to_multi_str() else {
+                    continue;
+                };
+                let uids = uids
+                    .iter()
+                    .map(|uid| uid.trim_end_matches('\0'))
+                    .map(|uid| match uid {
+                        "" => Ok(String::new()),
+                        uid => self.store.pseudonym(Kind::Uid, uid, ""),
+                    })
+                    .collect::<color_eyre::Result<Vec<String>>>()?;
+                object.put(DataElement::new(
+                    tag,
+                    VR::UI,
+                    PrimitiveValue::Strs(uids.into()),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dicom::object::FileMetaTableBuilder;
+
+    use super::*;
+
+    /// An empty directory of its own for a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("eai-rs-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn pseudonyms_are_stable_across_openings() {
+        let path = test_dir("pseudonyms").join("keys.jsonl");
+        let mut store = KeyStore::open(&path, None).unwrap();
+        let uid = store.pseudonym(Kind::Uid, "1.2.3.4", "").unwrap();
+        let patient_id = store.pseudonym(Kind::PatientId, "PID001", "ANON").unwrap();
+
+        assert!(uid.starts_with("2.25."));
+        assert!(patient_id.starts_with("ANON"));
+        assert_eq!(patient_id.len(), "ANON".len() + 16);
+        assert_ne!(uid, store.pseudonym(Kind::Uid, "1.2.3.5", "").unwrap());
+        assert_eq!(uid, store.pseudonym(Kind::Uid, "1.2.3.4", "").unwrap());
+        // The same value of another kind gets another pseudonym
+        assert_ne!(
+            store.pseudonym(Kind::PatientId, "1.2.3.4", "").unwrap(),
+            store.pseudonym(Kind::Uid, "1.2.3.4", "").unwrap()
+        );
+        drop(store);
+
+        let mut store = KeyStore::open(&path, None).unwrap();
+        assert_eq!(store.entries().count(), 4);
+        assert_eq!(uid, store.pseudonym(Kind::Uid, "1.2.3.4", "").unwrap());
+        let entry = store.original(&patient_id).unwrap();
+        assert_eq!(entry.kind, Kind::PatientId);
+        assert_eq!(entry.original, "PID001");
+        assert!(store.original("unknown").is_none());
+    }
+
+    #[test]
+    fn stores_have_secrets_of_their_own() {
+        let dir = test_dir("secrets");
+        let mut first = KeyStore::open(&dir.join("first.jsonl"), None).unwrap();
+        let mut second = KeyStore::open(&dir.join("second.jsonl"), None).unwrap();
+        assert_ne!(
+            first.pseudonym(Kind::Uid, "1.2.3.4", "").unwrap(),
+            second.pseudonym(Kind::Uid, "1.2.3.4", "").unwrap()
+        );
+    }
+
+    #[test]
+    fn encrypted_store_round_trip() {
+        let path = test_dir("encrypted").join("keys.jsonl");
+        let mut store = KeyStore::open(&path, Some("passphrase")).unwrap();
+        let patient_id = store.pseudonym(Kind::PatientId, "PID001", "").unwrap();
+        drop(store);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("PID001"));
+        assert!(KeyStore::open(&path, None).is_err());
+        assert!(KeyStore::open(&path, Some("wrong")).is_err());
+
+        let mut store = KeyStore::open(&path, Some("passphrase")).unwrap();
+        assert_eq!(store.original(&patient_id).unwrap().original, "PID001");
+        assert_eq!(
+            patient_id,
+            store.pseudonym(Kind::PatientId, "PID001", "").unwrap()
+        );
+    }
+
+    #[test]
+    fn plain_store_refuses_a_passphrase() {
+        let path = test_dir("plain").join("keys.jsonl");
+        KeyStore::open(&path, None).unwrap();
+        assert!(KeyStore::open(&path, Some("passphrase")).is_err());
+    }
+
+    #[test]
+    fn replaces_the_uids_and_patient_id() {
+        let dir = test_dir("apply");
+        let config = Pseudonymization {
+            key_store: dir.join("keys.jsonl"),
+            passphrase_env: None,
+            patient_id_prefix: "P".to_string(),
+        };
+        let referenced = InMemDicomObject::from_element_iter([DataElement::new(
+            tags::REFERENCED_SOP_INSTANCE_UID,
+            VR::UI,
+            dicom_value!(Str, "1.2.3.4.5.2"),
+        )]);
+        let object = InMemDicomObject::from_element_iter([
+            DataElement::new(
+                tags::SOP_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, "1.2.3.4.5.1"),
+            ),
+            DataElement::new(
+                tags::STUDY_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, "1.2.3.4"),
+            ),
+            DataElement::new(tags::PATIENT_ID, VR::LO, dicom_value!(Str, "PID001")),
+            DataElement::new(
+                tags::REFERENCED_IMAGE_SEQUENCE,
+                VR::SQ,
+                Value::Sequence {
+                    items: vec![referenced].into(),
+                    size: Length::UNDEFINED,
+                },
+            ),
+        ]);
+        let meta = FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+            .media_storage_sop_instance_uid("1.2.3.4.5.1")
+            .transfer_syntax("1.2.840.10008.1.2.1")
+            .build()
+            .unwrap();
+        let mut object = object.with_exact_meta(meta);
+        let mut pseudonymizer = Pseudonymizer::new(&config).unwrap();
+        pseudonymizer.apply(&mut object).unwrap();
+
+        let store = &mut pseudonymizer.store;
+        let text = |tag| {
+            object
+                .element(tag)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .trim_end_matches('\0')
+                .to_string()
+        };
+        assert_eq!(
+            text(tags::SOP_INSTANCE_UID),
+            store.pseudonym(Kind::Uid, "1.2.3.4.5.1", "").unwrap()
+        );
+        assert_eq!(
+            text(tags::STUDY_INSTANCE_UID),
+            store.pseudonym(Kind::Uid, "1.2.3.4", "").unwrap()
+        );
+        assert_eq!(
+            text(tags::PATIENT_ID),
+            store.pseudonym(Kind::PatientId, "PID001", "P").unwrap()
+        );
+        assert_eq!(
+            object
+                .meta()
+                .media_storage_sop_instance_uid()
+                .trim_end_matches('\0'),
+            text(tags::SOP_INSTANCE_UID)
+        );
+        let items = object
+            .element(tags::REFERENCED_IMAGE_SEQUENCE)
+            .unwrap()
+            .items()
+            .unwrap();
+        let referenced = items[0].element(tags::REFERENCED_SOP_INSTANCE_UID).unwrap();
+        assert_eq!(
+            referenced.to_str().unwrap().trim_end_matches('\0'),
+            store.pseudonym(Kind::Uid, "1.2.3.4.5.2", "").unwrap()
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! The instances a channel set aside for an operator to look at, and
+//! released back into the channel once dealt with
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{bail, Context, ContextCompat};
+use dicom::{
+    dictionary_std::tags,
+    encoding::TransferSyntaxIndex,
+    object::{
+        file::ReadPreamble, open_file, DefaultDicomObject, FileMetaTableBuilder, InMemDicomObject,
+        OpenFileOptions,
+    },
+    transfer_syntax::TransferSyntaxRegistry,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    pipeline::{Instance, Origin},
+    storage::sanitize,
+    validation::ValidationReport,
+};
+
+/// The file of a quarantined instance in its item directory
+const INSTANCE_FILE: &str = "instance.dcm";
+/// The bytes received for an instance that could not be read
+const RAW_FILE: &str = "received.raw";
+/// The file describing a quarantined instance in its item directory
+const RECORD_FILE: &str = "record.json";
+/// Present in the item directories to release into their channel
+const RELEASE_FILE: &str = "release";
+
+/// Why and from where an instance was quarantined, kept next to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct QuarantineRecord {
+    pub(crate) channel: String,
+    pub(crate) reason: String,
+    /// When the instance was quarantined, in seconds since the Unix epoch
+    pub(crate) quarantined_at: u64,
+    pub(crate) sop_instance_uid: Option<String>,
+    pub(crate) calling_aet: Option<String>,
+    pub(crate) called_aet: Option<String>,
+    pub(crate) peer: String,
+    /// The transfer syntax of the received bytes, for the datasets
+    /// received without file meta information
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) transfer_syntax: Option<String>,
+    /// The validation report, for the nonconformant instances
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) report: Option<ValidationReport>,
+}
+
+impl QuarantineRecord {
+    fn origin(&self) -> Origin {
+        Origin {
+            calling_aet: self.calling_aet.clone(),
+            called_aet: self.called_aet.clone(),
+            peer: self.peer.clone(),
+        }
+    }
+}
+
+/// An instance in a quarantine, named after its directory
+#[derive(Debug)]
+pub(crate) struct QuarantineItem {
+    pub(crate) id: String,
+    pub(crate) path: PathBuf,
+    pub(crate) record: QuarantineRecord,
+}
+
+impl QuarantineItem {
+    fn open(path: PathBuf) -> color_eyre::Result<Self> {
+        let record_path = path.join(RECORD_FILE);
+        let record = fs::read_to_string(&record_path)
+            .wrap_err_with(|| format!("Could not read {}", record_path.display()))?;
+        Ok(Self {
+            id: path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            record: serde_json::from_str(&record)
+                .wrap_err_with(|| format!("Could not parse {}", record_path.display()))?,
+            path,
+        })
+    }
+
+    /// Whether the bytes were kept as received, not being readable
+    pub(crate) fn is_raw(&self) -> bool {
+        self.path.join(RAW_FILE).exists()
+    }
+
+    /// Whether the item waits to be released into its channel
+    pub(crate) fn is_released(&self) -> bool {
+        self.path.join(RELEASE_FILE).exists()
+    }
+
+    /// Asks the running channel to take the instance again
+    pub(crate) fn release(&self) -> color_eyre::Result<()> {
+        fs::write(self.path.join(RELEASE_FILE), b"")
+            .wrap_err_with(|| format!("Could not release {}", self.id))
+    }
+
+    /// Keeps the item in the quarantine, when releasing it failed
+    pub(crate) fn hold(&self) -> color_eyre::Result<()> {
+        fs::remove_file(self.path.join(RELEASE_FILE))
+            .wrap_err_with(|| format!("Could not hold {}", self.id))
+    }
+
+    /// Deletes the item from the quarantine
+    pub(crate) fn purge(&self) -> color_eyre::Result<()> {
+        fs::remove_dir_all(&self.path)
+            .wrap_err_with(|| format!("Could not delete {}", self.path.display()))
+    }
+
+    /// Reads the quarantined instance, as it came from its sender. The
+    /// bytes kept as received may have been fixed in the meantime.
+    pub(crate) fn instance(&self) -> color_eyre::Result<Instance> {
+        let object = if self.is_raw() {
+            let bytes = fs::read(self.path.join(RAW_FILE))
+                .wrap_err_with(|| format!("Could not read the bytes of {}", self.id))?;
+            read_raw(&bytes, self.record.transfer_syntax.as_deref())?
+        } else {
+            open_file(self.path.join(INSTANCE_FILE))
+                .wrap_err_with(|| format!("Could not read the instance of {}", self.id))?
+        };
+        Ok(Instance {
+            object,
+            origin: self.record.origin(),
+        })
+    }
+}
+
+/// Reads bytes received for an instance: a dataset in the transfer
+/// syntax when given, a DICOM file otherwise
+pub(crate) fn read_raw(
+    bytes: &[u8],
+    transfer_syntax: Option<&str>,
+) -> color_eyre::Result<DefaultDicomObject> {
+    let Some(uid) = transfer_syntax else {
+        return OpenFileOptions::new()
+            .read_preamble(ReadPreamble::Auto)
+            .from_reader(bytes)
+            .wrap_err("Failed to read DICOM file");
+    };
+    let ts = TransferSyntaxRegistry
+        .get(uid)
+        .wrap_err_with(|| format!("Unknown transfer syntax {}", uid))?;
+    let object = InMemDicomObject::read_dataset_with_ts(bytes, ts)
+        .wrap_err("Failed to read DICOM data object")?;
+    let meta = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(
+            object
+                .element(tags::SOP_CLASS_UID)
+                .wrap_err("Missing SOP Class UID")?
+                .to_str()
+                .wrap_err("Could not retrieve SOP Class UID")?,
+        )
+        .media_storage_sop_instance_uid(
+            object
+                .element(tags::SOP_INSTANCE_UID)
+                .wrap_err("Missing SOP Instance UID")?
+                .to_str()
+                .wrap_err("Could not retrieve SOP Instance UID")?,
+        )
+        .transfer_syntax(uid)
+        .build()
+        .wrap_err("Failed to build DICOM meta file information")?;
+    Ok(object.with_exact_meta(meta))
+}
+
+/// Creates the directory of a new item, named after the time and the
+/// instance, the UID made safe for a file name
+fn create_item(dir: &Path, sop_instance_uid: Option<&str>) -> color_eyre::Result<(PathBuf, u64)> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let name = match sop_instance_uid {
+        Some(uid) => format!("{}-{}", now.as_millis(), sanitize(uid)),
+        None => format!("{}-unknown", now.as_millis()),
+    };
+    let mut item = dir.join(&name);
+    let mut n = 1;
+    while item.exists() {
+        item = dir.join(format!("{}-{}", name, n));
+        n += 1;
+    }
+    fs::create_dir_all(&item)
+        .wrap_err_with(|| format!("Could not create the directory {}", item.display()))?;
+    Ok((item, now.as_secs()))
+}
+
+fn write_record(item: &Path, record: &QuarantineRecord) -> color_eyre::Result<()> {
+    fs::write(
+        item.join(RECORD_FILE),
+        serde_json::to_string_pretty(record)?,
+    )
+    .wrap_err_with(|| format!("Could not write the record of {}", item.display()))
+}
+
+/// Saves the instance in its own directory of the quarantine, with the
+/// record of why it is there
+pub(crate) fn quarantine_instance(
+    dir: &Path,
+    channel: &str,
+    instance: &Instance,
+    reason: &str,
+    report: Option<&ValidationReport>,
+) -> color_eyre::Result<PathBuf> {
+    let sop_instance_uid = instance.sop_instance_uid().trim_end_matches('\0');
+    let (item, quarantined_at) = create_item(dir, Some(sop_instance_uid))?;
+    instance
+        .object
+        .write_to_file(item.join(INSTANCE_FILE))
+        .wrap_err("Could not save DICOM object to file")?;
+    write_record(
+        &item,
+        &QuarantineRecord {
+            channel: channel.to_string(),
+            reason: reason.to_string(),
+            quarantined_at,
+            sop_instance_uid: Some(sop_instance_uid.to_string()),
+            calling_aet: instance.origin.calling_aet.clone(),
+            called_aet: instance.origin.called_aet.clone(),
+            peer: instance.origin.peer.clone(),
+            transfer_syntax: None,
+            report: report.cloned(),
+        },
+    )?;
+    Ok(item)
+}
+
+/// Saves bytes that could not be read as an instance, as they were
+/// received
+pub(crate) fn quarantine_raw(
+    dir: &Path,
+    channel: &str,
+    bytes: &[u8],
+    transfer_syntax: Option<&str>,
+    sop_instance_uid: Option<&str>,
+    origin: &Origin,
+    reason: &str,
+) -> color_eyre::Result<PathBuf> {
+    let sop_instance_uid = sop_instance_uid
+        .map(|uid| uid.trim_end_matches('\0'))
+        .filter(|uid| !uid.is_empty());
+    let (item, quarantined_at) = create_item(dir, sop_instance_uid)?;
+    fs::write(item.join(RAW_FILE), bytes)
+        .wrap_err_with(|| format!("Could not save the bytes in {}", item.display()))?;
+    write_record(
+        &item,
+        &QuarantineRecord {
+            channel: channel.to_string(),
+            reason: reason.to_string(),
+            quarantined_at,
+            sop_instance_uid: sop_instance_uid.map(str::to_string),
+            calling_aet: origin.calling_aet.clone(),
+            called_aet: origin.called_aet.clone(),
+            peer: origin.peer.clone(),
+            transfer_syntax: transfer_syntax.map(str::to_string),
+            report: None,
+        },
+    )?;
+    Ok(item)
+}
+
+/// The items of a quarantine, oldest first
+pub(crate) fn list_items(dir: &Path) -> color_eyre::Result<Vec<QuarantineItem>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let entries =
+        fs::read_dir(dir).wrap_err_with(|| format!("Could not read {}", dir.display()))?;
+    let mut items = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.join(RECORD_FILE).exists() {
+            items.push(QuarantineItem::open(path)?);
+        }
+    }
+    items.sort_by(|a, b| (a.record.quarantined_at, &a.id).cmp(&(b.record.quarantined_at, &b.id)));
+    Ok(items)
+}
+
+/// The item of a quarantine with this id
+pub(crate) fn find_item(dir: &Path, id: &str) -> color_eyre::Result<QuarantineItem> {
+    if id.is_empty() || id.contains(['/', '\\']) || id == "." || id == ".." {
+        bail!("Invalid quarantine item {}", id);
+    }
+    let path = dir.join(id);
+    if !path.join(RECORD_FILE).exists() {
+        bail!("{} is not in the quarantine {}", id, dir.display());
+    }
+    QuarantineItem::open(path)
+}
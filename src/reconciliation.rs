@@ -0,0 +1,452 @@
+//! The reconciliation of the patients of outside instances with the
+//! local ones, with a mapping table kept as CSV:
+//!
+//! ```csv
+//! issuer,patient_id,patient_name,birth_date,local_patient_id,local_issuer
+//! OTHER_HOSPITAL,A123,,,0045678,MAIN
+//! ,,DOE^JANE,19700101,0045679,MAIN
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+
+use color_eyre::eyre::{bail, Context};
+use dicom::{
+    core::{value::Value, DataElement, Length, PrimitiveValue, Tag, VR},
+    dictionary_std::tags,
+    object::InMemDicomObject,
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::utils::ReconciliationConfig;
+
+/// A row of the mapping table. A row matches on the issuer and the
+/// patient ID, or on the name and the birth date, when they are given.
+#[derive(Debug, Deserialize)]
+struct Row {
+    #[serde(default)]
+    issuer: String,
+    #[serde(default)]
+    patient_id: String,
+    #[serde(default)]
+    patient_name: String,
+    #[serde(default)]
+    birth_date: String,
+    local_patient_id: String,
+    #[serde(default)]
+    local_issuer: String,
+}
+
+/// The local identity of a patient
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LocalPatient {
+    pub(crate) patient_id: String,
+    pub(crate) issuer: String,
+}
+
+#[derive(Debug, Default)]
+struct Mapping {
+    by_id: HashMap<(String, String), LocalPatient>,
+    by_name: HashMap<(String, String), LocalPatient>,
+}
+
+impl Mapping {
+    fn load(path: &Path) -> color_eyre::Result<Self> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .wrap_err_with(|| format!("Could not read the mapping {}", path.display()))?;
+        let mut mapping = Mapping::default();
+        for (i, row) in reader.deserialize::<Row>().enumerate() {
+            // The header is the first line
+            let line = i + 2;
+            let row =
+                row.wrap_err_with(|| format!("Invalid row at {}:{}", path.display(), line))?;
+            if row.local_patient_id.is_empty() {
+                bail!("No local_patient_id at {}:{}", path.display(), line);
+            }
+            let local = LocalPatient {
+                patient_id: row.local_patient_id,
+                issuer: row.local_issuer,
+            };
+            let by_id = !row.patient_id.is_empty();
+            let by_name = !row.patient_name.is_empty() && !row.birth_date.is_empty();
+            if !by_id && !by_name {
+                bail!(
+                    "Neither a patient_id nor a patient_name and birth_date at {}:{}",
+                    path.display(),
+                    line
+                );
+            }
+            // The instances reconciled already, such as those of a
+            // channel fed by another, are found by their local identifiers
+            mapping
+                .by_id
+                .entry((local.issuer.clone(), local.patient_id.clone()))
+                .or_insert_with(|| local.clone());
+            if by_id {
+                mapping
+                    .by_id
+                    .insert((row.issuer, row.patient_id), local.clone());
+            }
+            if by_name {
+                mapping
+                    .by_name
+                    .insert((normalize_name(&row.patient_name), row.birth_date), local);
+            }
+        }
+        Ok(mapping)
+    }
+
+    fn find(&self, object: &InMemDicomObject) -> Option<&LocalPatient> {
+        let text = |tag| value_of(object, tag);
+        let patient_id = text(tags::PATIENT_ID);
+        if !patient_id.is_empty() {
+            if let Some(local) = self
+                .by_id
+                .get(&(text(tags::ISSUER_OF_PATIENT_ID), patient_id))
+            {
+                return Some(local);
+            }
+        }
+        let name = normalize_name(&text(tags::PATIENT_NAME));
+        let birth_date = text(tags::PATIENT_BIRTH_DATE);
+        if name.is_empty() || birth_date.is_empty() {
+            return None;
+        }
+        self.by_name.get(&(name, birth_date))
+    }
+}
+
+/// The alphabetic representation of a person name, without case nor
+/// trailing empty components
+fn normalize_name(name: &str) -> String {
+    let alphabetic = name.split('=').next().unwrap_or_default();
+    alphabetic
+        .split('^')
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join("^")
+        .trim_end_matches('^')
+        .to_uppercase()
+}
+
+/// Replaces the patient identifiers of the instances with the local ones,
+/// keeping the original ones in the Other Patient IDs Sequence. The
+/// mapping is loaded again when its file changes.
+pub(crate) struct Reconciler {
+    path: PathBuf,
+    mapping: RwLock<Arc<Mapping>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Reconciler {
+    pub(crate) fn new(config: &ReconciliationConfig) -> color_eyre::Result<Self> {
+        let modified = modification_time(&config.mapping);
+        Ok(Self {
+            path: config.mapping.clone(),
+            mapping: RwLock::new(Arc::new(Mapping::load(&config.mapping)?)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Loads the mapping again if its file changed since the last time.
+    /// A mapping that cannot be read anymore is kept as it was.
+    pub(crate) fn reload(&self) {
+        let modified = modification_time(&self.path);
+        let mut last_modified = self.modified.lock().unwrap();
+        if modified == *last_modified {
+            return;
+        }
+        *last_modified = modified;
+        match Mapping::load(&self.path) {
+            Ok(mapping) => {
+                info!(
+                    "Reloaded the mapping {} ({} patient IDs, {} names)",
+                    self.path.display(),
+                    mapping.by_id.len(),
+                    mapping.by_name.len()
+                );
+                *self.mapping.write().unwrap() = Arc::new(mapping);
+            }
+            Err(e) => warn!("Keeping the previous version of the mapping: {:#}", e),
+        }
+    }
+
+    /// Replaces the patient identifiers with the local ones, returning
+    /// them, or `None` when the patient is not in the mapping
+    pub(crate) fn apply(&self, object: &mut InMemDicomObject) -> Option<LocalPatient> {
+        let mapping = self.mapping.read().unwrap().clone();
+        let local = mapping.find(object)?.clone();
+
+        let original_id = value_of(object, tags::PATIENT_ID);
+        let original_issuer = value_of(object, tags::ISSUER_OF_PATIENT_ID);
+        if (original_id.as_str(), original_issuer.as_str())
+            == (local.patient_id.as_str(), local.issuer.as_str())
+        {
+            return Some(local);
+        }
+
+        // The outside identifiers are kept with the other ones
+        let mut items = object
+            .take_element(tags::OTHER_PATIENT_I_DS_SEQUENCE)
+            .ok()
+            .and_then(|element| element.into_value().into_items())
+            .unwrap_or_default();
+        let known = items.iter().any(|item| {
+            value_of(item, tags::PATIENT_ID) == original_id
+                && value_of(item, tags::ISSUER_OF_PATIENT_ID) == original_issuer
+        });
+        if !original_id.is_empty() && !known {
+            let mut item = InMemDicomObject::new_empty();
+            item.put(DataElement::new(
+                tags::PATIENT_ID,
+                VR::LO,
+                PrimitiveValue::from(original_id),
+            ));
+            if !original_issuer.is_empty() {
+                item.put(DataElement::new(
+                    tags::ISSUER_OF_PATIENT_ID,
+                    VR::LO,
+                    PrimitiveValue::from(original_issuer),
+                ));
+            }
+            item.put(DataElement::new(
+                tags::TYPE_OF_PATIENT_ID,
+                VR::CS,
+                PrimitiveValue::from("TEXT"),
+            ));
+            items.push(item);
+        }
+        object.put(DataElement::new(
+            tags::OTHER_PATIENT_I_DS_SEQUENCE,
+            VR::SQ,
+            Value::Sequence {
+                items,
+                size: Length::UNDEFINED,
+            },
+        ));
+
+        object.put(DataElement::new(
+            tags::PATIENT_ID,
+            VR::LO,
+            PrimitiveValue::from(local.patient_id.as_str()),
+        ));
+        if local.issuer.is_empty() {
+            object.remove_element(tags::ISSUER_OF_PATIENT_ID);
+        } else {
+            object.put(DataElement::new(
+                tags::ISSUER_OF_PATIENT_ID,
+                VR::LO,
+                PrimitiveValue::from(local.issuer.as_str()),
+            ));
+        }
+        Some(local)
+    }
+}
+
+fn value_of(object: &InMemDicomObject, tag: Tag) -> String {
+    object
+        .element(tag)
+        .ok()
+        .and_then(|element| element.to_str().ok())
+        .map(|value| value.trim_matches([' ', '\0']).to_string())
+        .unwrap_or_default()
+}
+
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Checks that the mapping can be loaded
+pub(crate) fn check(config: &ReconciliationConfig) -> color_eyre::Result<()> {
+    Mapping::load(&config.mapping).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        fs::File,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use dicom::dicom_value;
+
+    use super::*;
+
+    const MAPPING: &str = "issuer,patient_id,patient_name,birth_date,local_patient_id,local_issuer
+OTHER_HOSPITAL,A123,,,0045678,MAIN
+,,DOE^JANE,19700101,0045679,MAIN
+";
+
+    /// A reconciler of the mapping, written in a directory of its own
+    fn reconciler(name: &str, mapping: &str) -> (Reconciler, PathBuf) {
+        let dir = env::temp_dir().join(format!("eai-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mapping.csv");
+        write_mapping(&path, mapping, 1);
+        let config = ReconciliationConfig {
+            mapping: path.clone(),
+        };
+        (Reconciler::new(&config).unwrap(), path)
+    }
+
+    /// Writes the mapping, dated `seconds` after the epoch for the
+    /// reloads to see it changed
+    fn write_mapping(path: &Path, mapping: &str, seconds: u64) {
+        fs::write(path, mapping).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(seconds))
+            .unwrap();
+    }
+
+    fn patient(issuer: &str, patient_id: &str, name: &str, birth_date: &str) -> InMemDicomObject {
+        let mut object = InMemDicomObject::from_element_iter([
+            DataElement::new(tags::PATIENT_ID, VR::LO, dicom_value!(Str, patient_id)),
+            DataElement::new(tags::PATIENT_NAME, VR::PN, dicom_value!(Str, name)),
+            DataElement::new(
+                tags::PATIENT_BIRTH_DATE,
+                VR::DA,
+                dicom_value!(Str, birth_date),
+            ),
+        ]);
+        if !issuer.is_empty() {
+            object.put(DataElement::new(
+                tags::ISSUER_OF_PATIENT_ID,
+                VR::LO,
+                dicom_value!(Str, issuer),
+            ));
+        }
+        object
+    }
+
+    /// The patient IDs and issuers of the Other Patient IDs Sequence
+    fn other_ids(object: &InMemDicomObject) -> Vec<(String, String, String)> {
+        object
+            .element(tags::OTHER_PATIENT_I_DS_SEQUENCE)
+            .map(|element| {
+                element
+                    .items()
+                    .unwrap()
+                    .iter()
+                    .map(|item| {
+                        (
+                            value_of(item, tags::PATIENT_ID),
+                            value_of(item, tags::ISSUER_OF_PATIENT_ID),
+                            value_of(item, tags::TYPE_OF_PATIENT_ID),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn main(patient_id: &str) -> Option<LocalPatient> {
+        Some(LocalPatient {
+            patient_id: patient_id.to_string(),
+            issuer: "MAIN".to_string(),
+        })
+    }
+
+    #[test]
+    fn replaces_the_identifiers_keeping_the_outside_ones() {
+        let (reconciler, _) = reconciler("reconcile-id", MAPPING);
+        let mut object = patient("OTHER_HOSPITAL", "A123", "Doe^Jane", "19800101");
+        assert_eq!(reconciler.apply(&mut object), main("0045678"));
+        assert_eq!(value_of(&object, tags::PATIENT_ID), "0045678");
+        assert_eq!(value_of(&object, tags::ISSUER_OF_PATIENT_ID), "MAIN");
+        let outside = vec![(
+            "A123".to_string(),
+            "OTHER_HOSPITAL".to_string(),
+            "TEXT".to_string(),
+        )];
+        assert_eq!(other_ids(&object), outside);
+
+        // Reconciled already, nothing changes
+        assert_eq!(reconciler.apply(&mut object), main("0045678"));
+        assert_eq!(other_ids(&object), outside);
+    }
+
+    #[test]
+    fn falls_back_on_the_name_and_birth_date() {
+        let (reconciler, _) = reconciler("reconcile-name", MAPPING);
+        // The case, the trailing components and the ideographic
+        // representation are ignored
+        let mut object = patient("", "B7", "doe^jane^^=ドウ^ジェーン", "19700101");
+        assert_eq!(reconciler.apply(&mut object), main("0045679"));
+        assert_eq!(other_ids(&object)[0].0, "B7");
+
+        // The patient ID is only known with its issuer
+        let mut object = patient("ELSEWHERE", "A123", "Doe^John", "19700101");
+        assert_eq!(reconciler.apply(&mut object), None);
+        assert_eq!(value_of(&object, tags::PATIENT_ID), "A123");
+        assert!(other_ids(&object).is_empty());
+        let mut object = patient("", "", "Doe^Jane", "");
+        assert_eq!(reconciler.apply(&mut object), None);
+    }
+
+    #[test]
+    fn reloads_the_mapping_when_it_changes() {
+        let (reconciler, path) = reconciler("reconcile-reload", MAPPING);
+        let mut object = patient("OTHER_HOSPITAL", "C9", "", "");
+        assert_eq!(reconciler.apply(&mut object), None);
+
+        // An invalid mapping is not loaded
+        write_mapping(&path, "issuer,patient_id\nOTHER_HOSPITAL,C9\n", 2);
+        reconciler.reload();
+        assert_eq!(reconciler.apply(&mut object.clone()), None);
+        assert_eq!(
+            reconciler.apply(&mut patient("OTHER_HOSPITAL", "A123", "", "")),
+            main("0045678")
+        );
+
+        write_mapping(
+            &path,
+            &format!("{}OTHER_HOSPITAL,C9,,,0045680,\n", MAPPING),
+            3,
+        );
+        reconciler.reload();
+        let local = reconciler.apply(&mut object).unwrap();
+        assert_eq!(local.patient_id, "0045680");
+        // Without a local issuer, the outside one is removed
+        assert_eq!(value_of(&object, tags::ISSUER_OF_PATIENT_ID), "");
+    }
+
+    #[test]
+    fn refuses_invalid_mappings() {
+        let dir = env::temp_dir().join(format!("eai-rs-mappings-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mapping.csv");
+        let config = ReconciliationConfig {
+            mapping: path.clone(),
+        };
+        for (mapping, error) in [
+            (
+                "patient_id,local_patient_id\nA1,\n",
+                "No local_patient_id at",
+            ),
+            (
+                "patient_name,local_patient_id\nDOE^JANE,L1\n",
+                "Neither a patient_id nor a patient_name and birth_date at",
+            ),
+        ] {
+            fs::write(&path, mapping).unwrap();
+            let message = format!("{:#}", check(&config).unwrap_err());
+            assert!(message.starts_with(error), "{}", message);
+            assert!(message.ends_with("mapping.csv:2"), "{}", message);
+        }
+        fs::remove_file(&path).unwrap();
+        assert!(check(&config).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! The deletion of the instances a channel stored, run in the
+//! background following its retention policy. The instances are found
+//! in the index of the channel, those still pending delivery or whose
+//! delivery failed are never deleted.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    backend::StorageBackend,
+    index::{now, IndexedFile, InstanceIndex},
+    source::POLL_TIMEOUT,
+    storage::disk_space,
+    utils::{RetentionPolicy, Storage},
+};
+
+/// The time between two cleanups
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+/// How long an instance is left alone once received, so that it is
+/// queued for its destinations before it is considered delivered
+const GRACE_PERIOD: u64 = 10 * 60;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The retention policy of a channel, applied to its storage
+pub(crate) struct Retention {
+    channel: String,
+    dir: PathBuf,
+    policy: RetentionPolicy,
+    index: Arc<InstanceIndex>,
+    backend: Arc<dyn StorageBackend>,
+}
+
+impl Retention {
+    /// The retention of the storage, if it has a policy
+    pub(crate) fn new(
+        channel: &str,
+        storage: &Storage,
+        index: Option<&Arc<InstanceIndex>>,
+        backend: &Arc<dyn StorageBackend>,
+    ) -> Option<Self> {
+        if storage.retention == RetentionPolicy::Never {
+            return None;
+        }
+        let Some(index) = index else {
+            warn!(
+                "Channel {} has a retention policy without an index, nothing is deleted",
+                channel
+            );
+            return None;
+        };
+        Some(Self {
+            channel: channel.to_string(),
+            dir: storage.out_dir.clone(),
+            policy: storage.retention.clone(),
+            index: index.clone(),
+            backend: backend.clone(),
+        })
+    }
+
+    /// Launches the thread cleaning up the storage until the shutdown
+    /// signal is set
+    pub(crate) fn start(self, shutdown_signal: Arc<AtomicBool>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut waited = CLEANUP_INTERVAL;
+            while !shutdown_signal.load(Ordering::SeqCst) {
+                if waited >= CLEANUP_INTERVAL {
+                    if let Err(e) = self.clean_up() {
+                        warn!("The cleanup of channel {} failed: {:#}", self.channel, e);
+                    }
+                    waited = Duration::ZERO;
+                }
+                thread::sleep(POLL_TIMEOUT);
+                waited += POLL_TIMEOUT;
+            }
+        })
+    }
+
+    /// Deletes the instances the policy no longer keeps
+    fn clean_up(&self) -> color_eyre::Result<()> {
+        let received_before = now().saturating_sub(GRACE_PERIOD);
+        let deleted = match self.policy {
+            RetentionPolicy::Never => 0,
+            RetentionPolicy::AfterDelivery => {
+                self.delete(&self.index.delivered(received_before)?)?
+            }
+            RetentionPolicy::KeepDays { days } => {
+                let received_before =
+                    received_before.min(now().saturating_sub(days * SECONDS_PER_DAY));
+                self.delete(&self.index.delivered(received_before)?)?
+            }
+            RetentionPolicy::DiskUsage { max_percent } => {
+                let mut deleted = 0;
+                loop {
+                    let used = disk_space(&self.dir)?.used_percent();
+                    if used <= f64::from(max_percent) {
+                        break;
+                    }
+                    let Some((study, instances)) =
+                        self.index.oldest_delivered_study(received_before)?
+                    else {
+                        warn!(
+                            "The storage of channel {} is {:.0}% full and has no delivered study left to delete",
+                            self.channel, used
+                        );
+                        break;
+                    };
+                    info!(
+                        "The storage of channel {} is {:.0}% full, deleting study {}",
+                        self.channel, used, study
+                    );
+                    deleted += self.delete(&instances)?;
+                }
+                deleted
+            }
+        };
+        if deleted > 0 {
+            info!(
+                "Channel {} deleted {} stored instance(s)",
+                self.channel, deleted
+            );
+        } else {
+            debug!("Channel {} has nothing to delete", self.channel);
+        }
+        Ok(())
+    }
+
+    /// Deletes the files and forgets them. The files the backend does
+    /// not know of, such as those of a former backend, are only
+    /// forgotten.
+    fn delete(&self, instances: &[IndexedFile]) -> color_eyre::Result<usize> {
+        for (id, location) in instances {
+            match self.backend.key(location) {
+                Some(key) => self.backend.delete(&key)?,
+                None => warn!(
+                    "{} is not in the storage of channel {}, forgetting it",
+                    location, self.channel
+                ),
+            }
+            self.index.remove(*id)?;
+        }
+        Ok(instances.len())
+    }
+}
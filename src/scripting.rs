@@ -0,0 +1,231 @@
+//! The Rhai scripts of the channels, for the transformations the rules
+//! cannot express.
+
+use std::{
+    cell::Cell,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use color_eyre::eyre::{bail, eyre, Context};
+use dicom::{dictionary_std::tags, object::DefaultDicomObject};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, ImmutableString, Map, Scope, AST};
+use tracing::{debug, info, warn};
+
+use crate::{
+    deidentify::set_media_storage_sop_instance_uid,
+    morphing::{parse_tag, set_text, text_of},
+    pipeline::{Instance, Verdict},
+    utils::ScriptConfig,
+};
+
+/// The deepest function calls and expressions of a script, to keep a
+/// runaway script from exhausting the stack
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+
+/// The largest strings, arrays and maps a script can build
+const MAX_STRING_SIZE: usize = 1 << 20;
+const MAX_COLLECTION_SIZE: usize = 10_000;
+
+thread_local! {
+    /// When the script running on the thread has to stop. The engines
+    /// check it from the thread running the script, so that the runs of
+    /// a script on several threads each have their own.
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// The dataset of an instance as seen by a script
+#[derive(Debug, Clone)]
+struct Dataset(DefaultDicomObject);
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+impl Dataset {
+    fn get(&mut self, tag: &str) -> ScriptResult<Dynamic> {
+        let tag = parse_tag(tag).map_err(|e| e.to_string())?;
+        Ok(text_of(&self.0, tag)
+            .map(|(_, text)| Dynamic::from(text))
+            .unwrap_or(Dynamic::UNIT))
+    }
+
+    fn set(&mut self, tag: &str, value: Dynamic) -> ScriptResult<()> {
+        let tag = parse_tag(tag).map_err(|e| e.to_string())?;
+        set_text(&mut self.0, tag, &value.to_string())
+            .map_err(|e| format!("Cannot set {}: {:#}", tag, e).into())
+    }
+
+    fn remove(&mut self, tag: &str) -> ScriptResult<bool> {
+        let tag = parse_tag(tag).map_err(|e| e.to_string())?;
+        Ok(self.0.remove_element(tag))
+    }
+
+    fn has(&mut self, tag: &str) -> ScriptResult<bool> {
+        let tag = parse_tag(tag).map_err(|e| e.to_string())?;
+        Ok(matches!(self.0.element_opt(tag), Ok(Some(_))))
+    }
+}
+
+/// The script of a stage, compiled once and compiled again when its
+/// file changes
+pub(crate) struct Script {
+    path: PathBuf,
+    timeout: Duration,
+    engine: Engine,
+    ast: RwLock<Arc<AST>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Script {
+    pub(crate) fn new(config: &ScriptConfig) -> color_eyre::Result<Self> {
+        let engine = build_engine(&config.path);
+        let modified = modification_time(&config.path);
+        let ast = compile(&engine, &config.path)?;
+        Ok(Self {
+            path: config.path.clone(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            engine,
+            ast: RwLock::new(Arc::new(ast)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Compiles the script again if its file changed since the last
+    /// time. A script that does not compile anymore is kept as it was.
+    pub(crate) fn reload(&self) {
+        let modified = modification_time(&self.path);
+        let mut last_modified = self.modified.lock().unwrap();
+        if modified == *last_modified {
+            return;
+        }
+        *last_modified = modified;
+        match compile(&self.engine, &self.path) {
+            Ok(ast) => {
+                *self.ast.write().unwrap() = Arc::new(ast);
+                info!("Reloaded the script {}", self.path.display());
+            }
+            Err(e) => warn!("Keeping the previous version of the script: {:#}", e),
+        }
+    }
+
+    /// Runs the script on the instance. It sees the dataset, where the
+    /// instance comes from and the names of the destinations, and can
+    /// modify the dataset, drop the instance or remove destinations.
+    pub(crate) fn run(
+        &self,
+        instance: &mut Instance,
+        channel: &str,
+        destinations: &[String],
+    ) -> color_eyre::Result<Verdict> {
+        let mut context = Map::new();
+        context.insert("channel".into(), channel.to_string().into());
+        context.insert("peer".into(), instance.origin.peer.clone().into());
+        for (key, value) in [
+            ("calling_aet", &instance.origin.calling_aet),
+            ("called_aet", &instance.origin.called_aet),
+        ] {
+            let value = value.clone().map(Dynamic::from).unwrap_or(Dynamic::UNIT);
+            context.insert(key.into(), value);
+        }
+
+        let mut scope = Scope::new();
+        scope.push("dataset", Dataset(instance.object.clone()));
+        scope.push_constant("context", context);
+        scope.push(
+            "destinations",
+            destinations
+                .iter()
+                .cloned()
+                .map(Dynamic::from)
+                .collect::<Array>(),
+        );
+        scope.push("drop", false);
+
+        let ast = self.ast.read().unwrap().clone();
+        let outer = DEADLINE.replace(Some(Instant::now() + self.timeout));
+        let result = self.engine.run_ast_with_scope(&mut scope, &ast);
+        DEADLINE.set(outer);
+        match result {
+            Ok(()) => {}
+            Err(e) if matches!(*e, EvalAltResult::ErrorTerminated(..)) => {
+                bail!(
+                    "{} ran for more than {:?}",
+                    self.path.display(),
+                    self.timeout
+                )
+            }
+            Err(e) => return Err(eyre!("{}", e)).wrap_err(self.path.display().to_string()),
+        }
+
+        if scope.get_value::<bool>("drop").unwrap_or(false) {
+            return Ok(Verdict::Drop);
+        }
+        let Some(Dataset(object)) = scope.remove::<Dataset>("dataset") else {
+            bail!("{} replaced the dataset", self.path.display());
+        };
+        let Some(selected) = scope.get_value::<Array>("destinations") else {
+            bail!("{} replaced the destinations", self.path.display());
+        };
+        instance.object = object;
+        // The file meta information follows a new SOP Instance UID
+        if let Some((_, uid)) = text_of(&instance.object, tags::SOP_INSTANCE_UID) {
+            if uid != instance.sop_instance_uid().trim_end_matches('\0') {
+                set_media_storage_sop_instance_uid(&mut instance.object, &uid);
+            }
+        }
+        Ok(Verdict::Forward(
+            selected.into_iter().map(|name| name.to_string()).collect(),
+        ))
+    }
+}
+
+/// A sandboxed engine: scripts cannot reach the file system, and are
+/// stopped once past the deadline
+fn build_engine(path: &Path) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_EXPR_DEPTH)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_COLLECTION_SIZE)
+        .set_max_map_size(MAX_COLLECTION_SIZE)
+        .on_progress(|_| match DEADLINE.get() {
+            Some(deadline) if Instant::now() > deadline => Some(Dynamic::UNIT),
+            _ => None,
+        });
+    let name = path.display().to_string();
+    let debug_name = name.clone();
+    engine
+        .on_print(move |text| info!("{}: {}", name, text))
+        .on_debug(move |text, _, position| debug!("{} {}: {}", debug_name, position, text));
+    engine
+        .register_type_with_name::<Dataset>("Dataset")
+        .register_fn("get", Dataset::get)
+        .register_fn("set", Dataset::set)
+        .register_fn("remove", Dataset::remove)
+        .register_fn("has", Dataset::has)
+        .register_indexer_get_set(
+            |dataset: &mut Dataset, tag: ImmutableString| dataset.get(&tag),
+            |dataset: &mut Dataset, tag: ImmutableString, value: Dynamic| dataset.set(&tag, value),
+        );
+    engine
+}
+
+fn compile(engine: &Engine, path: &Path) -> color_eyre::Result<AST> {
+    let source = fs::read_to_string(path)
+        .wrap_err_with(|| format!("Could not read the script {}", path.display()))?;
+    engine
+        .compile(source)
+        .map_err(|e| eyre!("{} at {}", e, path.display()))
+}
+
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Checks that the script compiles
+pub(crate) fn check(config: &ScriptConfig) -> color_eyre::Result<()> {
+    compile(&Engine::new(), &config.path).map(|_| ())
+}
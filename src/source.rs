@@ -0,0 +1,91 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc,
+    },
+    time::Duration,
+};
+
+use color_eyre::eyre::ContextCompat;
+
+use crate::{
+    hot_folder::HotFolder,
+    pipeline::{Bus, Pipeline},
+    store_scp::DicomListener,
+    stow_rs::StowRs,
+    utils::{Channel, Config, SourceConfig},
+};
+
+/// How long the sources wait on a blocking call before checking the
+/// shutdown signal again
+pub(crate) const POLL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Something feeding a channel with instances
+pub(crate) trait Source: Send {
+    /// Hands the received instances to the pipeline until the shutdown
+    /// signal is set
+    fn run(&mut self, pipeline: &Pipeline, shutdown_signal: &AtomicBool) -> color_eyre::Result<()>;
+
+    /// Returns a function unblocking `run` once the shutdown signal is
+    /// set, for the sources waiting on calls without a timeout
+    fn waker(&self) -> Box<dyn Fn() + Send> {
+        Box::new(|| {})
+    }
+}
+
+/// Builds the source described in the channel's config
+pub(crate) fn build_source(
+    channel: &Channel,
+    config: &Config,
+    bus: &Arc<Bus>,
+) -> color_eyre::Result<Box<dyn Source>> {
+    Ok(match &channel.source {
+        SourceConfig::Dicom { local_ae } => {
+            let local_ae = config
+                .local_ae(local_ae)
+                .wrap_err_with(|| format!("Unknown local AE {}", local_ae))?;
+            Box::new(DicomListener::new(local_ae.clone()))
+        }
+        SourceConfig::HotFolder {
+            path,
+            poll_interval,
+            processed_dir,
+            failed_dir,
+        } => Box::new(HotFolder::new(
+            path.clone(),
+            Duration::from_secs(*poll_interval),
+            processed_dir.clone(),
+            failed_dir.clone(),
+        )),
+        SourceConfig::StowRs { bind, max_body_mb } => {
+            Box::new(StowRs::new(bind.clone(), *max_body_mb))
+        }
+        SourceConfig::Channel { channel: upstream } => Box::new(ChannelSource {
+            upstream: upstream.clone(),
+            bus: bus.clone(),
+        }),
+    })
+}
+
+/// A source fed by the instances processed by another channel
+pub(crate) struct ChannelSource {
+    upstream: String,
+    bus: Arc<Bus>,
+}
+
+impl Source for ChannelSource {
+    fn run(&mut self, pipeline: &Pipeline, shutdown_signal: &AtomicBool) -> color_eyre::Result<()> {
+        let subscription = self.bus.subscribe(&self.upstream);
+        while !shutdown_signal.load(Ordering::SeqCst) {
+            match subscription.receiver.recv_timeout(POLL_TIMEOUT) {
+                Ok(instance) => {
+                    pipeline.ingest(instance);
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+//! The processing stages of a channel: the `pre` stage runs once on the
+//! instances as they come in, the `post` stage of each destination on
+//! its own copy of the instances, just before they are sent.
+
+use std::sync::Mutex;
+
+use color_eyre::eyre::Context;
+
+use crate::{
+    deidentify::Deidentifier,
+    morphing::Morpher,
+    pipeline::{Instance, Verdict},
+    pseudonymize::Pseudonymizer,
+    scripting::Script,
+    utils::StageConfig,
+};
+
+/// A stage with its steps checked and ready to run
+pub(crate) struct Stage {
+    deidentifier: Option<Deidentifier>,
+    pseudonymizer: Option<Mutex<Pseudonymizer>>,
+    morpher: Morpher,
+    script: Option<Script>,
+}
+
+impl Stage {
+    pub(crate) fn new(config: &StageConfig) -> color_eyre::Result<Self> {
+        let pseudonymizer = config
+            .pseudonymize
+            .as_ref()
+            .map(Pseudonymizer::new)
+            .transpose()
+            .wrap_err("Invalid pseudonymization")?;
+        Ok(Self {
+            deidentifier: config
+                .deidentify
+                .as_ref()
+                .map(|deidentify| Deidentifier::new(deidentify, pseudonymizer.is_some()))
+                .transpose()
+                .wrap_err("Invalid de-identification")?,
+            pseudonymizer: pseudonymizer.map(Mutex::new),
+            morpher: Morpher::new(&config.rules).wrap_err("Invalid rules")?,
+            script: config
+                .script
+                .as_ref()
+                .map(Script::new)
+                .transpose()
+                .wrap_err("Invalid script")?,
+        })
+    }
+
+    /// Compiles the script again if its file changed
+    pub(crate) fn reload(&self) {
+        if let Some(script) = &self.script {
+            script.reload();
+        }
+    }
+
+    /// Runs the steps on the instance. The script, if any, decides
+    /// where the instance goes among `destinations`, otherwise it goes
+    /// to all of them.
+    pub(crate) fn apply(
+        &self,
+        instance: &mut Instance,
+        channel: &str,
+        destinations: &[String],
+    ) -> color_eyre::Result<Verdict> {
+        if let Some(deidentifier) = &self.deidentifier {
+            deidentifier
+                .apply(&mut instance.object)
+                .wrap_err("It could not be de-identified")?;
+        }
+        if let Some(pseudonymizer) = &self.pseudonymizer {
+            pseudonymizer
+                .lock()
+                .unwrap()
+                .apply(&mut instance.object)
+                .wrap_err("It could not be pseudonymized")?;
+        }
+        self.morpher.apply(&mut instance.object);
+        match &self.script {
+            Some(script) => script
+                .run(instance, channel, destinations)
+                .wrap_err("The script failed"),
+            None => Ok(Verdict::Forward(destinations.to_vec())),
+        }
+    }
+}
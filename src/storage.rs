@@ -0,0 +1,953 @@
+//! Where the instances are written in a storage backend, following a
+//! path template such as `{PatientID}/{StudyDate}_{AccessionNumber}/{SeriesNumber}/{InstanceNumber}.dcm`.
+//! The local files are written under a temporary name and renamed once
+//! on disk, so that a crash never leaves a truncated instance behind.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use color_eyre::eyre::{bail, Context};
+use dicom::{
+    core::Tag,
+    dictionary_std::tags,
+    object::{file::ReadPreamble, open_file, InMemDicomObject, OpenFileOptions},
+};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+
+use crate::{
+    backend::{FileInfo, StorageBackend},
+    index::INDEX_DATABASE,
+    morphing::parse_tag,
+    pipeline::Instance,
+    utils::DuplicatePolicy,
+};
+
+/// The template used when none is configured, a flat directory
+const DEFAULT_PATH_TEMPLATE: &str = "{SOPInstanceUID}.dcm";
+/// The most characters an attribute value takes in a path
+const MAX_VALUE_LENGTH: usize = 64;
+/// Replaces the empty values in the paths
+const UNKNOWN_VALUE: &str = "UNKNOWN";
+/// The index of the checksums in the directory, in the format of
+/// `sha256sum`
+pub(crate) const INDEX_FILE: &str = "SHA256SUMS";
+/// The extension of the files being written
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Makes the temporary names unique among the threads
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A piece of a path component
+#[derive(Debug, Clone)]
+enum Piece {
+    Text(String),
+    Attribute(Tag),
+}
+
+/// A path template, checked once: the attributes between braces are
+/// replaced with their value in each instance
+#[derive(Debug, Clone)]
+pub(crate) struct PathTemplate {
+    components: Vec<Vec<Piece>>,
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        Self::new(DEFAULT_PATH_TEMPLATE).expect("The default path template is valid")
+    }
+}
+
+impl PathTemplate {
+    /// Parses the template, failing on unknown attributes and on paths
+    /// that could leave the directory
+    pub(crate) fn new(template: &str) -> color_eyre::Result<Self> {
+        if template.starts_with('/') || template.contains('\\') {
+            bail!("The path template {:?} is not a relative path", template);
+        }
+        let components = template
+            .split('/')
+            .map(|component| {
+                if component.is_empty() || component == "." || component == ".." {
+                    bail!(
+                        "The path template {:?} has an invalid component {:?}",
+                        template,
+                        component
+                    );
+                }
+                parse_component(component)
+                    .wrap_err_with(|| format!("Invalid path template {:?}", template))
+            })
+            .collect::<color_eyre::Result<_>>()?;
+        Ok(Self { components })
+    }
+
+    /// The key of the instance, its path relative to the storage. The
+    /// values are made safe for the file system.
+    pub(crate) fn render(&self, object: &InMemDicomObject) -> String {
+        self.components
+            .iter()
+            .map(|pieces| {
+                pieces
+                    .iter()
+                    .map(|piece| match piece {
+                        Piece::Text(text) => text.clone(),
+                        Piece::Attribute(tag) => sanitize(&value_of(object, *tag)),
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+fn parse_component(component: &str) -> color_eyre::Result<Vec<Piece>> {
+    let mut pieces = Vec::new();
+    let mut rest = component;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            pieces.push(Piece::Text(rest[..start].to_string()));
+        }
+        let Some(end) = rest[start..].find('}') else {
+            bail!("Unclosed brace in {:?}", component);
+        };
+        pieces.push(Piece::Attribute(parse_tag(&rest[start + 1..start + end])?));
+        rest = &rest[start + end + 1..];
+    }
+    if rest.contains('}') {
+        bail!("Unopened brace in {:?}", component);
+    }
+    if !rest.is_empty() {
+        pieces.push(Piece::Text(rest.to_string()));
+    }
+    Ok(pieces)
+}
+
+/// The value of an attribute, without its padding
+pub(crate) fn value_of(object: &InMemDicomObject, tag: Tag) -> String {
+    object
+        .element(tag)
+        .ok()
+        .and_then(|element| element.to_str().ok())
+        .map(|value| value.trim_matches([' ', '\0']).to_string())
+        .unwrap_or_default()
+}
+
+/// Keeps the letters, digits, dots, dashes and underscores of the
+/// value, the other characters becoming underscores. A value cannot
+/// start with a dot nor be empty.
+pub(crate) fn sanitize(value: &str) -> String {
+    let sanitized: String = value
+        .chars()
+        .take(MAX_VALUE_LENGTH)
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let sanitized = match sanitized.strip_prefix('.') {
+        Some(rest) => format!("_{}", rest),
+        None => sanitized,
+    };
+    if sanitized.is_empty() {
+        UNKNOWN_VALUE.to_string()
+    } else {
+        sanitized
+    }
+}
+
+/// The SOP Instance UID of a stored file, from its file meta group,
+/// reading no further than the start of the data set
+pub(crate) fn stored_sop_instance_uid(file: impl Read) -> Option<String> {
+    OpenFileOptions::new()
+        .read_preamble(ReadPreamble::Always)
+        .read_until(tags::PATIENT_NAME)
+        .from_reader(file)
+        .ok()
+        .map(|object| {
+            object
+                .meta()
+                .media_storage_sop_instance_uid()
+                .trim_end_matches('\0')
+                .to_string()
+        })
+}
+
+/// `_n` added to the file name of the key, before its extension
+fn with_suffix(key: &str, n: u32) -> String {
+    let (dir, name) = match key.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), key),
+    };
+    match name.rsplit_once('.').filter(|(stem, _)| !stem.is_empty()) {
+        Some((stem, extension)) => format!("{}{}_{}.{}", dir, stem, n, extension),
+        None => format!("{}{}_{}", dir, name, n),
+    }
+}
+
+/// Writes the file under a temporary name next to it, flushes it to
+/// the disk and renames it, replacing any file at the path
+pub(crate) fn write_atomically(path: &Path, bytes: &[u8]) -> color_eyre::Result<()> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let temp = path.with_file_name(format!(
+        ".{}.{}-{}.{}",
+        file_name,
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+        TEMP_EXTENSION
+    ));
+    let written = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e).wrap_err_with(|| format!("Could not write {}", path.display()));
+    }
+    // The rename itself is on disk once the directory is
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        File::open(parent)
+            .and_then(|dir| dir.sync_all())
+            .wrap_err_with(|| format!("Could not flush the directory {}", parent.display()))?;
+    }
+    Ok(())
+}
+
+/// Whether the file was left by an interrupted write
+fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().starts_with('.'))
+        .unwrap_or_default()
+        && path
+            .extension()
+            .is_some_and(|extension| extension == TEMP_EXTENSION)
+}
+
+/// The outcome of `verify`
+#[derive(Debug, Default)]
+pub(crate) struct Verification {
+    /// The number of files read
+    pub(crate) checked: usize,
+    /// What is wrong with the directory, one line per file
+    pub(crate) problems: Vec<String>,
+}
+
+/// Reads every file of a store directory again, reporting the files
+/// that are not readable DICOM, whose checksum no longer matches the
+/// index, and the leftovers of interrupted writes
+pub(crate) fn verify(dir: &Path) -> color_eyre::Result<Verification> {
+    if !dir.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+    let index = read_index(dir)?;
+    let mut verification = Verification::default();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = fs::read_dir(&current)
+            .wrap_err_with(|| format!("Could not read the directory {}", current.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let relative = path.strip_prefix(dir).unwrap_or(&path);
+            // The checksums and the instance index with its journal
+            if relative == Path::new(INDEX_FILE)
+                || relative.to_string_lossy().starts_with(INDEX_DATABASE)
+            {
+                continue;
+            }
+            if is_temp_file(&path) {
+                verification.problems.push(format!(
+                    "{} was left by an interrupted write",
+                    relative.display()
+                ));
+                continue;
+            }
+            verification.checked += 1;
+            let bytes = match fs::read(&path) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    verification.problems.push(format!(
+                        "{} is not readable: {}",
+                        relative.display(),
+                        e
+                    ));
+                    continue;
+                }
+            };
+            if let Some(expected) = index.get(relative) {
+                let actual = hex(&Sha256::digest(&bytes));
+                if &actual != expected {
+                    verification.problems.push(format!(
+                        "{} does not match its checksum, {} instead of {}",
+                        relative.display(),
+                        actual,
+                        expected
+                    ));
+                    continue;
+                }
+            }
+            if let Err(e) = open_file(&path) {
+                verification.problems.push(format!(
+                    "{} is not valid DICOM: {}",
+                    relative.display(),
+                    e
+                ));
+            }
+        }
+    }
+    Ok(verification)
+}
+
+/// The checksums of the index, the last one of a path being the one
+/// of the file written last
+fn read_index(dir: &Path) -> color_eyre::Result<HashMap<PathBuf, String>> {
+    let path = dir.join(INDEX_FILE);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let content =
+        fs::read_to_string(&path).wrap_err_with(|| format!("Could not read {}", path.display()))?;
+    Ok(content
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(checksum, file)| (PathBuf::from(file), checksum.to_string()))
+        .collect())
+}
+
+/// The instance as written to a file
+fn encode(instance: &Instance) -> color_eyre::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    instance
+        .object
+        .write_all(&mut bytes)
+        .wrap_err("Could not encode the DICOM object")?;
+    Ok(bytes)
+}
+
+/// The bytes in lowercase hexadecimal, as checksums are written
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The space of the file system holding a directory, in bytes
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DiskSpace {
+    /// Available to the unprivileged users
+    pub(crate) available: u64,
+    pub(crate) used: u64,
+}
+
+impl DiskSpace {
+    /// The share of the space in use, as `df` reports it
+    pub(crate) fn used_percent(&self) -> f64 {
+        let usable = self.used + self.available;
+        if usable == 0 {
+            return 0.0;
+        }
+        self.used as f64 * 100.0 / usable as f64
+    }
+}
+
+/// The space of the file system holding `dir`, or the directory it
+/// will be created in
+#[cfg(unix)]
+pub(crate) fn disk_space(dir: &Path) -> color_eyre::Result<DiskSpace> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let existing = dir
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(dir);
+    let path = CString::new(existing.as_os_str().as_bytes())
+        .wrap_err_with(|| format!("Invalid path {}", dir.display()))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: the path is a valid C string and stat is written by statvfs
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .wrap_err_with(|| format!("Could not read the disk space of {}", dir.display()));
+    }
+    let block = stat.f_frsize as u64;
+    Ok(DiskSpace {
+        available: stat.f_bavail as u64 * block,
+        used: (stat.f_blocks as u64).saturating_sub(stat.f_bfree as u64) * block,
+    })
+}
+
+/// The space of the file system holding `dir`
+#[cfg(not(unix))]
+pub(crate) fn disk_space(dir: &Path) -> color_eyre::Result<DiskSpace> {
+    bail!(
+        "The disk space of {} cannot be read on this platform",
+        dir.display()
+    )
+}
+
+/// Watches the free space of a storage, telling when it falls under
+/// its watermark and when it is back above
+#[derive(Debug)]
+pub(crate) struct SpaceGuard {
+    dir: PathBuf,
+    /// In bytes
+    min_free: u64,
+    low: AtomicBool,
+}
+
+impl SpaceGuard {
+    pub(crate) fn new(dir: &Path, min_free_mb: u64) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            min_free: min_free_mb * 1024 * 1024,
+            low: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the free space is under the watermark. The space that
+    /// cannot be read is taken as enough.
+    pub(crate) fn is_low(&self) -> bool {
+        let space = match disk_space(&self.dir) {
+            Ok(space) => space,
+            Err(e) => {
+                warn!("{:#}", e);
+                return false;
+            }
+        };
+        let low = space.available < self.min_free;
+        let was_low = self.low.swap(low, Ordering::Relaxed);
+        if low && !was_low {
+            error!(
+                "Only {} MB left for {}, under the {} MB watermark: refusing new instances",
+                space.available / (1024 * 1024),
+                self.dir.display(),
+                self.min_free / (1024 * 1024)
+            );
+        } else if was_low && !low {
+            info!(
+                "{} MB free for {} again, accepting new instances",
+                space.available / (1024 * 1024),
+                self.dir.display()
+            );
+        }
+        low
+    }
+}
+
+/// Where an instance was stored, and whether it was received before
+#[derive(Debug)]
+pub(crate) struct Stored {
+    /// The key of the new file or of the one kept
+    pub(crate) key: String,
+    /// Where the instance is in the backend, the new file or the one kept
+    pub(crate) location: String,
+    /// The size of the file written, 0 when nothing was written
+    pub(crate) size: u64,
+    pub(crate) duplicate: Option<Duplicate>,
+}
+
+/// What the store did with an instance it already had
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Duplicate {
+    /// The stored instance was replaced
+    Replaced,
+    /// The stored instance was replaced with a different content
+    Changed,
+    /// The new instance was stored next to the first one
+    Versioned,
+    /// The new instance is the same as the stored one, nothing was written
+    Identical,
+    /// The stored instance was kept, the new one was not written
+    Refused,
+}
+
+impl fmt::Display for Duplicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Duplicate::Replaced => write!(f, "replacing"),
+            Duplicate::Changed => write!(f, "changed, replacing"),
+            Duplicate::Versioned => write!(f, "stored as"),
+            Duplicate::Identical => write!(f, "identical to"),
+            Duplicate::Refused => write!(f, "refused, keeping"),
+        }
+    }
+}
+
+/// The instances written to a backend, following a path template
+#[derive(Debug)]
+pub(crate) struct FileStore {
+    backend: Arc<dyn StorageBackend>,
+    template: PathTemplate,
+    /// Whether the checksums are recorded in the index
+    checksums: bool,
+    /// Serializes the additions to the index
+    index: Mutex<()>,
+    duplicates: DuplicatePolicy,
+}
+
+impl FileStore {
+    /// A store in the backend, flat when there is no template
+    pub(crate) fn new(
+        backend: Arc<dyn StorageBackend>,
+        template: Option<&str>,
+        checksums: bool,
+        duplicates: DuplicatePolicy,
+    ) -> color_eyre::Result<Self> {
+        Ok(Self {
+            backend,
+            template: template
+                .map(PathTemplate::new)
+                .transpose()?
+                .unwrap_or_default(),
+            checksums,
+            index: Mutex::new(()),
+            duplicates,
+        })
+    }
+
+    /// The backend the instances are written to
+    pub(crate) fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
+    }
+
+    /// Writes the instance at the key given by the template. Another
+    /// instance stored at the key is kept and the new one gets a
+    /// suffix, the same instance is handled by the duplicate policy.
+    pub(crate) fn store(&self, instance: &Instance) -> color_eyre::Result<Stored> {
+        let rendered = self.template.render(&instance.object);
+        let mut key = rendered.clone();
+        let mut n = 1;
+        let mut duplicate = None;
+        let mut previous = None;
+        while let Some(existing) = self.backend.head(&key)? {
+            if existing.sop_instance_uid.as_deref() == Some(instance.sop_instance_uid()) {
+                match self.duplicates {
+                    DuplicatePolicy::KeepFirst => {
+                        return Ok(self.stored(&key, 0, Some(Duplicate::Refused)))
+                    }
+                    // The next free key takes the new version
+                    DuplicatePolicy::KeepBoth => duplicate = Some(Duplicate::Versioned),
+                    DuplicatePolicy::Overwrite => {
+                        duplicate = Some(Duplicate::Replaced);
+                        break;
+                    }
+                    DuplicatePolicy::IgnoreIdentical => {
+                        duplicate = Some(Duplicate::Changed);
+                        previous = Some(existing);
+                        break;
+                    }
+                }
+            }
+            key = with_suffix(&rendered, n);
+            n += 1;
+        }
+        let bytes = encode(instance)?;
+        let size = bytes.len() as u64;
+        let sha256 = hex(&Sha256::digest(&bytes));
+        if let Some(previous) = previous {
+            // The backends that do not keep the checksum read the file
+            let identical = match previous.sha256 {
+                Some(checksum) => checksum == sha256,
+                None => self
+                    .backend
+                    .get(&key)?
+                    .is_some_and(|stored| hex(&Sha256::digest(stored)) == sha256),
+            };
+            if identical {
+                return Ok(self.stored(&key, size, Some(Duplicate::Identical)));
+            }
+        }
+        self.put(&key, instance, &bytes, &sha256)?;
+        Ok(self.stored(&key, size, duplicate))
+    }
+
+    /// Writes the instance over the one stored at `stored`, once it was
+    /// modified after being stored
+    pub(crate) fn replace(
+        &self,
+        stored: &Stored,
+        instance: &Instance,
+    ) -> color_eyre::Result<Stored> {
+        let bytes = encode(instance)?;
+        let sha256 = hex(&Sha256::digest(&bytes));
+        self.put(&stored.key, instance, &bytes, &sha256)?;
+        Ok(self.stored(&stored.key, bytes.len() as u64, stored.duplicate))
+    }
+
+    fn put(
+        &self,
+        key: &str,
+        instance: &Instance,
+        bytes: &[u8],
+        sha256: &str,
+    ) -> color_eyre::Result<()> {
+        let info = FileInfo {
+            sop_instance_uid: Some(instance.sop_instance_uid().to_string()),
+            sha256: Some(sha256.to_string()),
+        };
+        self.backend.put(key, bytes, &info)?;
+        if self.checksums {
+            self.record_checksum(key, sha256)?;
+        }
+        Ok(())
+    }
+
+    fn stored(&self, key: &str, size: u64, duplicate: Option<Duplicate>) -> Stored {
+        Stored {
+            key: key.to_string(),
+            location: self.backend.location(key),
+            size,
+            duplicate,
+        }
+    }
+
+    /// Appends the checksum of a file written in the store to the index,
+    /// which only a local store has
+    fn record_checksum(&self, key: &str, sha256: &str) -> color_eyre::Result<()> {
+        let Some(index) = self.backend.local_path(INDEX_FILE) else {
+            bail!("The checksums are only recorded in a local storage");
+        };
+        let line = format!("{}  {}\n", sha256, key);
+        let _guard = self.index.lock().unwrap();
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index)
+            .and_then(|mut file| {
+                file.write_all(line.as_bytes())?;
+                file.sync_data()
+            })
+            .wrap_err_with(|| format!("Could not update {}", index.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use dicom::{
+        core::{DataElement, VR},
+        dicom_value,
+        object::FileMetaTableBuilder,
+    };
+
+    use super::*;
+    use crate::{backend::LocalBackend, pipeline::Origin};
+
+    /// An empty directory of its own for a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("eai-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn object(patient_id: &str, study_uid: &str, sop_uid: &str) -> InMemDicomObject {
+        InMemDicomObject::from_element_iter([
+            DataElement::new(tags::PATIENT_ID, VR::LO, dicom_value!(Str, patient_id)),
+            DataElement::new(
+                tags::STUDY_INSTANCE_UID,
+                VR::UI,
+                dicom_value!(Str, study_uid),
+            ),
+            DataElement::new(tags::SOP_INSTANCE_UID, VR::UI, dicom_value!(Str, sop_uid)),
+        ])
+    }
+
+    /// A received instance of the patient, its Series Description telling
+    /// the versions of an instance apart
+    fn instance(patient_id: &str, sop_uid: &str, description: &str) -> Instance {
+        let mut object = object(patient_id, "1.2.3", sop_uid);
+        object.put(DataElement::new(
+            tags::SERIES_DESCRIPTION,
+            VR::LO,
+            dicom_value!(Str, description),
+        ));
+        let meta = FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+            .media_storage_sop_instance_uid(sop_uid)
+            .transfer_syntax("1.2.840.10008.1.2.1")
+            .build()
+            .unwrap();
+        Instance {
+            object: object.with_exact_meta(meta),
+            origin: Origin {
+                calling_aet: Some("MODALITY".to_string()),
+                called_aet: Some("EAI".to_string()),
+                peer: "127.0.0.1:40000".to_string(),
+            },
+        }
+    }
+
+    /// A store in `dir` recording the checksums
+    fn store(dir: &Path, template: &str, duplicates: DuplicatePolicy) -> FileStore {
+        FileStore::new(
+            Arc::new(LocalBackend::new(dir)),
+            Some(template),
+            true,
+            duplicates,
+        )
+        .unwrap()
+    }
+
+    /// The names of the files of a directory, sorted
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn sanitize_keeps_values_in_their_directory() {
+        assert_eq!(sanitize("1.2.840.113619"), "1.2.840.113619");
+        assert_eq!(sanitize("Doe^John"), "Doe_John");
+        assert_eq!(sanitize("../../etc"), "_._.._etc");
+        assert_eq!(sanitize(".."), "_.");
+        assert_eq!(sanitize("a/b\\c"), "a_b_c");
+        assert_eq!(sanitize(""), UNKNOWN_VALUE);
+        assert_eq!(sanitize("Müller"), "Müller");
+        assert_eq!(sanitize(&"9".repeat(100)).len(), MAX_VALUE_LENGTH);
+    }
+
+    #[test]
+    fn renders_the_attributes_of_the_template() {
+        let template = PathTemplate::new("{PatientID}/{0020000D}/{(0008,0018)}.dcm").unwrap();
+        let key = template.render(&object("PID 001 ", "1.2.3", "1.2.3.4"));
+        assert_eq!(key, "PID_001/1.2.3/1.2.3.4.dcm");
+
+        let key = PathTemplate::default().render(&object("", "", ""));
+        assert_eq!(key, "UNKNOWN.dcm");
+    }
+
+    #[test]
+    fn rendered_keys_stay_in_the_storage() {
+        let template = PathTemplate::new("{PatientID}/{SOPInstanceUID}.dcm").unwrap();
+        let key = template.render(&object("../..", "1.2.3", "../../../x"));
+        assert_eq!(key, "_._../_._.._.._x.dcm");
+        assert!(!key.split('/').any(|component| component == ".."));
+    }
+
+    #[test]
+    fn rejects_templates_leaving_the_storage() {
+        for template in [
+            "/{SOPInstanceUID}.dcm",
+            "../{SOPInstanceUID}.dcm",
+            "a/./{SOPInstanceUID}.dcm",
+            "a//{SOPInstanceUID}.dcm",
+            "a\\{SOPInstanceUID}.dcm",
+            "{SOPInstanceUID.dcm",
+            "SOPInstanceUID}.dcm",
+            "{NotAnAttribute}.dcm",
+        ] {
+            assert!(PathTemplate::new(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn suffixes_go_before_the_extension() {
+        assert_eq!(with_suffix("a/b/1.2.3.dcm", 1), "a/b/1.2.3_1.dcm");
+        assert_eq!(with_suffix("file", 2), "file_2");
+        assert_eq!(with_suffix("a/.hidden", 3), "a/.hidden_3");
+    }
+
+    #[test]
+    fn hex_is_lowercase() {
+        assert_eq!(hex(&[0x00, 0xAB, 0x7F]), "00ab7f");
+    }
+
+    #[test]
+    fn writes_atomically_without_leftovers() {
+        let dir = test_dir("atomic");
+        let path = dir.join("1.2.3.dcm");
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(files(&dir), ["1.2.3.dcm"]);
+
+        // A failed write leaves nothing behind
+        assert!(write_atomically(&dir.join("missing/1.2.3.dcm"), b"third").is_err());
+        assert_eq!(files(&dir), ["1.2.3.dcm"]);
+        assert!(is_temp_file(Path::new("a/.1.2.3.dcm.42-0.tmp")));
+        assert!(!is_temp_file(Path::new("a/1.2.3.dcm")));
+    }
+
+    #[test]
+    fn verify_reports_the_corrupted_files() {
+        let dir = test_dir("verify");
+        let store = store(
+            &dir,
+            "{PatientID}/{SOPInstanceUID}.dcm",
+            DuplicatePolicy::Overwrite,
+        );
+        for sop_uid in ["1.2.3.1", "1.2.3.2"] {
+            store.store(&instance("P1", sop_uid, "Chest")).unwrap();
+        }
+        let verification = verify(&dir).unwrap();
+        assert_eq!(verification.checked, 2);
+        assert!(
+            verification.problems.is_empty(),
+            "{:?}",
+            verification.problems
+        );
+
+        // A flipped byte in the data set, still readable DICOM
+        let corrupted = dir.join("P1/1.2.3.1.dcm");
+        let mut bytes = fs::read(&corrupted).unwrap();
+        let at = bytes
+            .windows(5)
+            .position(|window| window == b"Chest")
+            .unwrap();
+        bytes[at] = b'c';
+        fs::write(&corrupted, &bytes).unwrap();
+        // Files that are not in the index of the checksums
+        fs::write(dir.join("P1/notes.dcm"), b"not DICOM").unwrap();
+        fs::write(dir.join("P1/.1.2.3.3.dcm.42-0.tmp"), b"partial").unwrap();
+
+        let verification = verify(&dir).unwrap();
+        assert_eq!(verification.checked, 3);
+        let mut problems = verification.problems;
+        problems.sort();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(
+            problems[0].starts_with("P1/.1.2.3.3.dcm.42-0.tmp was left by an interrupted write")
+        );
+        assert!(problems[1].starts_with("P1/1.2.3.1.dcm does not match its checksum, "));
+        assert!(problems[2].starts_with("P1/notes.dcm is not valid DICOM: "));
+
+        assert!(verify(&dir.join("missing")).is_err());
+    }
+
+    /// The checksums recorded in the index of the store
+    fn checksums(dir: &Path) -> usize {
+        fs::read_to_string(dir.join(INDEX_FILE))
+            .unwrap()
+            .lines()
+            .count()
+    }
+
+    const TEMPLATE: &str = "{PatientID}/{SOPInstanceUID}.dcm";
+
+    #[test]
+    fn other_instances_at_the_same_key_get_a_suffix() {
+        let dir = test_dir("store-suffix");
+        let store = store(&dir, "{PatientID}.dcm", DuplicatePolicy::KeepFirst);
+        let first = store.store(&instance("P1", "1.2.3.1", "Chest")).unwrap();
+        let second = store.store(&instance("P1", "1.2.3.2", "Chest")).unwrap();
+        assert_eq!((first.key.as_str(), first.duplicate), ("P1.dcm", None));
+        assert_eq!((second.key.as_str(), second.duplicate), ("P1_1.dcm", None));
+        assert_eq!(second.location, dir.join("P1_1.dcm").display().to_string());
+        assert_eq!(files(&dir), ["P1.dcm", "P1_1.dcm", INDEX_FILE]);
+    }
+
+    #[test]
+    fn keep_first_refuses_the_duplicates() {
+        let dir = test_dir("store-keep-first");
+        let store = store(&dir, TEMPLATE, DuplicatePolicy::KeepFirst);
+        let first = store.store(&instance("P1", "1.2.3.1", "Chest")).unwrap();
+        let bytes = fs::read(dir.join(&first.key)).unwrap();
+
+        let again = store.store(&instance("P1", "1.2.3.1", "Abdomen")).unwrap();
+        assert_eq!(again.key, first.key);
+        assert_eq!(again.duplicate, Some(Duplicate::Refused));
+        assert_eq!(again.size, 0);
+        assert_eq!(fs::read(dir.join(&first.key)).unwrap(), bytes);
+        assert_eq!(checksums(&dir), 1);
+    }
+
+    #[test]
+    fn keep_both_stores_the_duplicates_next_to_the_first() {
+        let dir = test_dir("store-keep-both");
+        let store = store(&dir, TEMPLATE, DuplicatePolicy::KeepBoth);
+        let keys: Vec<(String, Option<Duplicate>)> = ["Chest", "Chest", "Abdomen"]
+            .iter()
+            .map(|description| {
+                let stored = store
+                    .store(&instance("P1", "1.2.3.1", description))
+                    .unwrap();
+                (stored.key, stored.duplicate)
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                ("P1/1.2.3.1.dcm".to_string(), None),
+                ("P1/1.2.3.1_1.dcm".to_string(), Some(Duplicate::Versioned)),
+                ("P1/1.2.3.1_2.dcm".to_string(), Some(Duplicate::Versioned)),
+            ]
+        );
+        assert_eq!(files(&dir.join("P1")).len(), 3);
+        assert_eq!(checksums(&dir), 3);
+    }
+
+    #[test]
+    fn overwrite_replaces_the_stored_instance() {
+        let dir = test_dir("store-overwrite");
+        let store = store(&dir, TEMPLATE, DuplicatePolicy::Overwrite);
+        store.store(&instance("P1", "1.2.3.1", "Chest")).unwrap();
+        let again = store.store(&instance("P1", "1.2.3.1", "Abdomen")).unwrap();
+        assert_eq!(again.key, "P1/1.2.3.1.dcm");
+        assert_eq!(again.duplicate, Some(Duplicate::Replaced));
+        let stored = open_file(dir.join(&again.key)).unwrap();
+        assert_eq!(value_of(&stored, tags::SERIES_DESCRIPTION), "Abdomen");
+        assert_eq!(files(&dir.join("P1")), ["1.2.3.1.dcm"]);
+        // The last checksum of the file is the one checked
+        assert_eq!(checksums(&dir), 2);
+        assert!(verify(&dir).unwrap().problems.is_empty());
+    }
+
+    #[test]
+    fn ignore_identical_only_replaces_the_changed_instances() {
+        let dir = test_dir("store-ignore-identical");
+        let store = store(&dir, TEMPLATE, DuplicatePolicy::IgnoreIdentical);
+        let first = store.store(&instance("P1", "1.2.3.1", "Chest")).unwrap();
+
+        let identical = store.store(&instance("P1", "1.2.3.1", "Chest")).unwrap();
+        assert_eq!(identical.key, first.key);
+        assert_eq!(identical.duplicate, Some(Duplicate::Identical));
+        assert_eq!(checksums(&dir), 1);
+
+        let changed = store.store(&instance("P1", "1.2.3.1", "Abdomen")).unwrap();
+        assert_eq!(changed.key, first.key);
+        assert_eq!(changed.duplicate, Some(Duplicate::Changed));
+        let stored = open_file(dir.join(&changed.key)).unwrap();
+        assert_eq!(value_of(&stored, tags::SERIES_DESCRIPTION), "Abdomen");
+        assert_eq!(checksums(&dir), 2);
+    }
+
+    #[test]
+    fn space_guard_tells_when_the_space_is_low() {
+        let dir = test_dir("space-guard");
+        let space = disk_space(&dir).unwrap();
+        assert!((0.0..=100.0).contains(&space.used_percent()));
+        // The directory to be created is on the file system of its parent
+        assert!(disk_space(&dir.join("missing/deeper")).is_ok());
+
+        assert!(!SpaceGuard::new(&dir, 0).is_low());
+        let guard = SpaceGuard::new(&dir, space.available / (1024 * 1024) + 1024 * 1024);
+        assert!(guard.is_low());
+        assert!(guard.is_low());
+        // The space that cannot be read is taken as enough
+        assert!(!SpaceGuard::new(Path::new("invalid\0path"), u64::MAX >> 20).is_low());
+    }
+}
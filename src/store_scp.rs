@@ -3,20 +3,67 @@ use dicom::{
     core::{DataElement, PrimitiveValue, VR},
     dicom_value,
     dictionary_std::tags,
-    encoding::TransferSyntaxIndex,
-    object::{FileMetaTableBuilder, InMemDicomObject, StandardDataDictionary},
+    object::{InMemDicomObject, StandardDataDictionary},
     transfer_syntax::TransferSyntaxRegistry,
 };
-use dicom_ul::{association::ServerAssociationOptions, pdu::PDataValueType, Pdu};
+use dicom_ul::{
+    association::{ServerAssociation, ServerAssociationOptions},
+    pdu::{
+        AssociationRJResult, AssociationRJServiceProviderPresentationReason, AssociationRJSource,
+        PDataValue, PDataValueType,
+    },
+    read_pdu, write_pdu, Pdu,
+};
 use std::{
-    net::{Ipv4Addr, SocketAddrV4, TcpListener},
-    sync::atomic::Ordering,
+    io::{self, ErrorKind, Write},
+    net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
 };
 use tracing::{debug, info, warn};
 
-use crate::utils::{Node, Status, ABSTRACT_SYNTAXES};
+use crate::{
+    pipeline::{Instance, Origin, Pipeline},
+    quarantine::read_raw,
+    source::{Source, POLL_TIMEOUT},
+    utils::{LocalAe, ABSTRACT_SYNTAXES},
+};
+
+/// How long the reading of a PDU may stall before the association is
+/// dropped
+const PDU_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A channel source running a C-STORE scp with one of the local AEs
+pub(crate) struct DicomListener {
+    local_ae: LocalAe,
+}
+
+impl DicomListener {
+    pub(crate) fn new(local_ae: LocalAe) -> Self {
+        Self { local_ae }
+    }
+}
 
-pub(crate) fn store_scp(node: &mut Node) -> color_eyre::Result<()> {
+impl Source for DicomListener {
+    fn run(&mut self, pipeline: &Pipeline, shutdown_signal: &AtomicBool) -> color_eyre::Result<()> {
+        store_scp(&self.local_ae, pipeline, shutdown_signal)
+    }
+
+    fn waker(&self) -> Box<dyn Fn() + Send> {
+        // The listener is blocked waiting for a connection and only
+        // checks the shutdown signal when one comes in
+        let port = self.local_ae.port;
+        Box::new(move || {
+            let _ = TcpStream::connect(("127.0.0.1", port));
+        })
+    }
+}
+
+pub(crate) fn store_scp(
+    node: &LocalAe,
+    pipeline: &Pipeline,
+    shutdown_signal: &AtomicBool,
+) -> color_eyre::Result<()> {
     let listen_addr = SocketAddrV4::new(Ipv4Addr::from(0), node.port);
     let listener = match TcpListener::bind(listen_addr) {
         Ok(l) => l,
@@ -29,12 +76,6 @@ pub(crate) fn store_scp(node: &mut Node) -> color_eyre::Result<()> {
         }
     };
 
-    let mut buffer: Vec<u8> = Vec::with_capacity(node.max_pdu as usize);
-    let mut instance_buffer: Vec<u8> = Vec::with_capacity(1024 * 1024);
-    let mut msgid = 1;
-    let mut sop_class_uid = "".to_string();
-    let mut sop_instance_uid = "".to_string();
-
     let mut options = ServerAssociationOptions::new()
         .accept_any() // TODO: accept only the peers in the config
         .ae_title(node.aet().clone())
@@ -59,24 +100,50 @@ pub(crate) fn store_scp(node: &mut Node) -> color_eyre::Result<()> {
     debug!("Dicom node {:?} configuration: {:?}", node.aet(), options); // TODO: improve the debug output
 
     info!("{:?} listening on {}:{}", node.aet(), node.ip, node.port);
-    node.status = Status::Started;
 
     for tcp_stream in listener.incoming() {
-        if node.shutdown_signal.load(Ordering::SeqCst) {
+        if shutdown_signal.load(Ordering::SeqCst) {
             info!("Shutting down store_scp for {}", node.aet());
             break;
         }
-        let stream = tcp_stream.wrap_err("Error getting TCP stream in storeSCP")?;
-        info!(
-            "New tcp connection from: {}",
-            stream
-                .peer_addr()
-                .wrap_err("Error getting peer adress from the TCP stream")?
-        );
+        let stream = match tcp_stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Error getting TCP stream in storeSCP: {}", e);
+                continue;
+            }
+        };
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("Error getting peer adress from the TCP stream: {}", e);
+                continue;
+            }
+        };
+        info!("New tcp connection from: {}", peer);
 
-        let mut association = options
-            .establish(stream)
-            .wrap_err("Error establishing the association")?;
+        if pipeline.is_out_of_space() {
+            if let Err(e) = reject_transient(stream, node.max_pdu, node.strict) {
+                warn!("Could not reject the association from {}: {:#}", peer, e);
+            }
+            continue;
+        }
+
+        // A peer stalling in the middle of a PDU is dropped
+        if let Err(e) = stream.set_read_timeout(Some(PDU_TIMEOUT)) {
+            warn!(
+                "Could not set the timeout of the connection from {}: {}",
+                peer, e
+            );
+            continue;
+        }
+        let mut association = match options.establish(stream) {
+            Ok(association) => association,
+            Err(e) => {
+                warn!("Error establishing the association with {}: {}", peer, e);
+                continue;
+            }
+        };
 
         info!(
             "New dicom association from {}",
@@ -88,173 +155,60 @@ pub(crate) fn store_scp(node: &mut Node) -> color_eyre::Result<()> {
             association.presentation_contexts()
         );
 
+        let mut transfer = Transfer::default();
+        let mut failed = false;
         loop {
-            if node.shutdown_signal.load(Ordering::SeqCst) {
+            if shutdown_signal.load(Ordering::SeqCst) {
                 // TODO: Handle the abort/release request
                 info!("Shutting down store_scp for {}", node.aet());
                 break;
             }
+            match wait_for_pdu(association.inner_stream()) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    warn!(
+                        "Error waiting for data from {}: {}",
+                        association.client_ae_title(),
+                        e
+                    );
+                    break;
+                }
+            }
             match association.receive() {
-                Ok(mut pdu) => {
-                    match pdu {
-                        Pdu::PData { ref mut data } => {
-                            if data.is_empty() {
-                                debug!("Ignoring empty PData PDU");
-                                continue;
-                            }
-                            if data[0].value_type == PDataValueType::Data && !data[0].is_last {
-                                instance_buffer.append(&mut data[0].data);
-                            } else if data[0].value_type == PDataValueType::Command
-                                && data[0].is_last
-                            {
-                                // Commands are always in implict VR LE
-                                let ts =
-                            dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN
-                                .erased();
-                                let data_value = &data[0];
-                                let v = &data_value.data;
-
-                                let obj = InMemDicomObject::read_dataset_with_ts(v.as_slice(), &ts)
-                                    .wrap_err("Failed to read incoming DICOM command")?;
-
-                                let command_field = obj
-                                    .element(tags::COMMAND_FIELD)
-                                    .wrap_err("Missing Command Field")?
-                                    .uint16()
-                                    .wrap_err("Command Field is not an integer")?;
-
-                                if command_field == 0x0030 {
-                                    // Handle C-ECHO-RQ
-                                    let cecho_response = create_cecho_response(msgid);
-                                    let mut cecho_data = Vec::new();
-
-                                    cecho_response
-                                        .write_dataset_with_ts(&mut cecho_data, &ts)
-                                        .wrap_err("Could not write C-ECHO response object")?;
-
-                                    let pdu_response = Pdu::PData {
-                                        data: vec![dicom_ul::pdu::PDataValue {
-                                            presentation_context_id: data[0]
-                                                .presentation_context_id,
-                                            value_type: PDataValueType::Command,
-                                            is_last: true,
-                                            data: cecho_data,
-                                        }],
-                                    };
-                                    association
-                                        .send(&pdu_response)
-                                        .wrap_err("Failed to send C-ECHO response object to SCU")?;
-                                } else {
-                                    msgid = obj
-                                        .element(tags::MESSAGE_ID)
-                                        .wrap_err("Missing Message ID")?
-                                        .to_int()
-                                        .wrap_err("Message ID is not an integer")?;
-
-                                    sop_class_uid = obj
-                                        .element(tags::AFFECTED_SOP_CLASS_UID)
-                                        .wrap_err("Missing Affected SOP Class UID")?
-                                        .to_str()
-                                        .wrap_err("Could not retrieve Affected SOP Class UID")?
-                                        .to_string();
-
-                                    sop_instance_uid = obj
-                                        .element(tags::AFFECTED_SOP_INSTANCE_UID)
-                                        .wrap_err("Missing Affected SOP Instance UID")?
-                                        .to_str()
-                                        .wrap_err("Could not retrieve Affected SOP Instance UID")?
-                                        .to_string();
-                                }
-
-                                instance_buffer.clear();
-                            } else if data[0].value_type == PDataValueType::Data && data[0].is_last
-                            {
-                                instance_buffer.append(&mut data[0].data);
-
-                                let presentation_context = association
-                                    .presentation_contexts()
-                                    .iter()
-                                    .find(|pc| pc.id == data[0].presentation_context_id)
-                                    .wrap_err("Missing presentation context")?;
-                                let ts = &presentation_context.transfer_syntax;
-
-                                let obj = InMemDicomObject::read_dataset_with_ts(
-                                    instance_buffer.as_slice(),
-                                    TransferSyntaxRegistry.get(ts).unwrap(),
-                                )
-                                .wrap_err("Failed to read DICOM data object")?;
-                                let file_meta = FileMetaTableBuilder::new()
-                                    .media_storage_sop_class_uid(
-                                        obj.element(tags::SOP_CLASS_UID)
-                                            .wrap_err("Missing SOP Class UID")?
-                                            .to_str()
-                                            .wrap_err("Could not retrieve SOP Class UID")?,
-                                    )
-                                    .media_storage_sop_instance_uid(
-                                        obj.element(tags::SOP_INSTANCE_UID)
-                                            .wrap_err("Missing SOP Instance UID")?
-                                            .to_str()
-                                            .wrap_err("Missing SOP Instance UID")?,
-                                    )
-                                    .transfer_syntax(ts)
-                                    .build()
-                                    .wrap_err("Failed to build DICOM meta file information")?;
-                                let file_obj = obj.with_exact_meta(file_meta);
-
-                                // Write the files to the current directory with their SOPInstanceUID as filenames
-                                let mut file_path =
-                                    node.out_dir.clone().wrap_err("Could not get out dir")?;
-                                file_path.push(
-                                    sop_instance_uid.trim_end_matches('\0').to_string() + ".dcm",
-                                );
-                                file_obj
-                                    .write_to_file(&file_path)
-                                    .wrap_err("Could not save DICOM object to file")?;
-                                info!("Stored {}", file_path.display());
-
-                                // Send C-STORE-RSP object
-                                // commands are always in implict VR LE
-                                let ts =
-                                dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN
-                                    .erased();
-
-                                let obj = create_cstore_response(
-                                    msgid,
-                                    &sop_class_uid,
-                                    &sop_instance_uid,
-                                );
-
-                                let mut obj_data = Vec::new();
-
-                                obj.write_dataset_with_ts(&mut obj_data, &ts)
-                                    .wrap_err("Could not write response object")?;
-
-                                let pdu_response = Pdu::PData {
-                                    data: vec![dicom_ul::pdu::PDataValue {
-                                        presentation_context_id: data[0].presentation_context_id,
-                                        value_type: PDataValueType::Command,
-                                        is_last: true,
-                                        data: obj_data,
-                                    }],
-                                };
-                                association
-                                    .send(&pdu_response)
-                                    .wrap_err("Failed to send response object to SCU")?;
-                            }
-                        }
-                        Pdu::ReleaseRQ => {
-                            buffer.clear();
-                            association
-                                .send(&Pdu::ReleaseRP)
-                                .wrap_err("Error sending release response")?;
-                            info!(
-                                "Released association with {}",
-                                association.client_ae_title()
-                            );
-                        }
-                        _ => {} // TODO: handle the other PDUs
+                Ok(Pdu::PData { mut data }) => {
+                    if let Err(e) = handle_pdata(
+                        &mut association,
+                        &mut data,
+                        &mut transfer,
+                        node,
+                        pipeline,
+                        &peer.to_string(),
+                    ) {
+                        warn!(
+                            "Aborting the association with {}: {:#}",
+                            association.client_ae_title(),
+                            e
+                        );
+                        failed = true;
+                        break;
                     }
                 }
+                Ok(Pdu::ReleaseRQ) => {
+                    if let Err(e) = association.send(&Pdu::ReleaseRP) {
+                        warn!(
+                            "Error sending release response to {}: {}",
+                            association.client_ae_title(),
+                            e
+                        );
+                        break;
+                    }
+                    info!(
+                        "Released association with {}",
+                        association.client_ae_title()
+                    );
+                }
+                Ok(_) => {} // TODO: handle the other PDUs
                 Err(err @ dicom_ul::association::server::Error::Receive { .. }) => {
                     debug!(
                         "Dicom association server error while receiving data {}",
@@ -268,16 +222,210 @@ pub(crate) fn store_scp(node: &mut Node) -> color_eyre::Result<()> {
                 }
             }
         }
-        info!("Dropping connection with {}", association.client_ae_title());
+        if failed {
+            if let Err(e) = association.abort() {
+                debug!("Could not abort the association: {}", e);
+            }
+        } else {
+            info!("Dropping connection with {}", association.client_ae_title());
+        }
     }
 
     Ok(())
 }
 
+/// Whether a PDU starts coming in on the stream within `POLL_TIMEOUT`,
+/// for an idle association not to hold the shutdown of the channel.
+/// The PDU itself is then read with `PDU_TIMEOUT`.
+fn wait_for_pdu(stream: &TcpStream) -> io::Result<bool> {
+    stream.set_read_timeout(Some(POLL_TIMEOUT))?;
+    let ready = match stream.peek(&mut [0; 1]) {
+        // A closed connection is reported by the next read
+        Ok(_) => true,
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => false,
+        Err(e) => return Err(e),
+    };
+    stream.set_read_timeout(Some(PDU_TIMEOUT))?;
+    Ok(ready)
+}
+
+/// The state of the C-STORE operation under way on an association
+#[derive(Default)]
+struct Transfer {
+    instance_buffer: Vec<u8>,
+    message_id: u16,
+    sop_class_uid: String,
+    sop_instance_uid: String,
+}
+
+/// Answers the commands and ingests the instances of a P-DATA PDU. An
+/// error leaves the association in an unknown state, it is aborted.
+fn handle_pdata(
+    association: &mut ServerAssociation,
+    data: &mut [PDataValue],
+    transfer: &mut Transfer,
+    node: &LocalAe,
+    pipeline: &Pipeline,
+    peer: &str,
+) -> color_eyre::Result<()> {
+    if data.is_empty() {
+        debug!("Ignoring empty PData PDU");
+        return Ok(());
+    }
+    if data[0].value_type == PDataValueType::Data && !data[0].is_last {
+        transfer.instance_buffer.append(&mut data[0].data);
+    } else if data[0].value_type == PDataValueType::Command && data[0].is_last {
+        // Commands are always in implict VR LE
+        let ts = dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN.erased();
+        let data_value = &data[0];
+        let v = &data_value.data;
+
+        let obj = InMemDicomObject::read_dataset_with_ts(v.as_slice(), &ts)
+            .wrap_err("Failed to read incoming DICOM command")?;
+
+        let command_field = obj
+            .element(tags::COMMAND_FIELD)
+            .wrap_err("Missing Command Field")?
+            .uint16()
+            .wrap_err("Command Field is not an integer")?;
+
+        if command_field == 0x0030 {
+            // Handle C-ECHO-RQ
+            let cecho_response = create_cecho_response(transfer.message_id);
+            let mut cecho_data = Vec::new();
+
+            cecho_response
+                .write_dataset_with_ts(&mut cecho_data, &ts)
+                .wrap_err("Could not write C-ECHO response object")?;
+
+            let pdu_response = Pdu::PData {
+                data: vec![PDataValue {
+                    presentation_context_id: data[0].presentation_context_id,
+                    value_type: PDataValueType::Command,
+                    is_last: true,
+                    data: cecho_data,
+                }],
+            };
+            association
+                .send(&pdu_response)
+                .wrap_err("Failed to send C-ECHO response object to SCU")?;
+        } else {
+            transfer.message_id = obj
+                .element(tags::MESSAGE_ID)
+                .wrap_err("Missing Message ID")?
+                .to_int()
+                .wrap_err("Message ID is not an integer")?;
+
+            transfer.sop_class_uid = obj
+                .element(tags::AFFECTED_SOP_CLASS_UID)
+                .wrap_err("Missing Affected SOP Class UID")?
+                .to_str()
+                .wrap_err("Could not retrieve Affected SOP Class UID")?
+                .to_string();
+
+            transfer.sop_instance_uid = obj
+                .element(tags::AFFECTED_SOP_INSTANCE_UID)
+                .wrap_err("Missing Affected SOP Instance UID")?
+                .to_str()
+                .wrap_err("Could not retrieve Affected SOP Instance UID")?
+                .to_string();
+        }
+
+        transfer.instance_buffer.clear();
+    } else if data[0].value_type == PDataValueType::Data && data[0].is_last {
+        transfer.instance_buffer.append(&mut data[0].data);
+
+        let presentation_context = association
+            .presentation_contexts()
+            .iter()
+            .find(|pc| pc.id == data[0].presentation_context_id)
+            .wrap_err("Missing presentation context")?;
+        let ts = &presentation_context.transfer_syntax;
+
+        let origin = Origin {
+            calling_aet: Some(association.client_ae_title().trim().to_string()),
+            called_aet: Some(node.aet().clone()),
+            peer: peer.to_string(),
+        };
+        // Hand the instance to the channel, its status is
+        // the one of the response. What cannot be read is
+        // kept in the quarantine of the channel.
+        let status = match read_raw(&transfer.instance_buffer, Some(ts)) {
+            Ok(object) => pipeline.ingest(Instance { object, origin }),
+            Err(e) => pipeline.quarantine_unreadable(
+                &transfer.instance_buffer,
+                Some(ts),
+                Some(&transfer.sop_instance_uid),
+                &origin,
+                &format!("{:#}", e),
+            ),
+        };
+
+        // Send C-STORE-RSP object
+        // commands are always in implict VR LE
+        let ts = dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN.erased();
+
+        let obj = create_cstore_response(
+            transfer.message_id,
+            &transfer.sop_class_uid,
+            &transfer.sop_instance_uid,
+            status,
+        );
+
+        let mut obj_data = Vec::new();
+
+        obj.write_dataset_with_ts(&mut obj_data, &ts)
+            .wrap_err("Could not write response object")?;
+
+        let pdu_response = Pdu::PData {
+            data: vec![PDataValue {
+                presentation_context_id: data[0].presentation_context_id,
+                value_type: PDataValueType::Command,
+                is_last: true,
+                data: obj_data,
+            }],
+        };
+        association
+            .send(&pdu_response)
+            .wrap_err("Failed to send response object to SCU")?;
+    }
+    Ok(())
+}
+
+/// Rejects the association requested on the stream as transient, the
+/// local limit being exceeded, so that the peer tries again later
+fn reject_transient(mut stream: TcpStream, max_pdu: u32, strict: bool) -> color_eyre::Result<()> {
+    let pdu = read_pdu(&mut stream, max_pdu, strict).wrap_err("Could not read the request")?;
+    if let Pdu::AssociationRQ {
+        calling_ae_title, ..
+    } = pdu
+    {
+        info!(
+            "Rejecting the association from {}, the storage is full",
+            calling_ae_title.trim()
+        );
+    }
+    let mut buffer = Vec::new();
+    write_pdu(
+        &mut buffer,
+        &Pdu::AssociationRJ {
+            result: AssociationRJResult::Transient,
+            source: AssociationRJSource::ServiceProviderPresentation(
+                AssociationRJServiceProviderPresentationReason::LocalLimitExceeded,
+            ),
+        },
+    )
+    .wrap_err("Could not write the rejection")?;
+    stream
+        .write_all(&buffer)
+        .wrap_err("Could not send the rejection")
+}
+
 fn create_cstore_response(
     message_id: u16,
     sop_class_uid: &str,
     sop_instance_uid: &str,
+    status: u16,
 ) -> InMemDicomObject<StandardDataDictionary> {
     InMemDicomObject::from_element_iter([
         DataElement::new(
@@ -312,7 +460,7 @@ fn create_cstore_response(
             VR::US,
             dicom_value!(U16, [0x0101]),
         ),
-        DataElement::new(tags::STATUS, VR::US, dicom_value!(U16, [0x0000])),
+        DataElement::new(tags::STATUS, VR::US, dicom_value!(U16, [status])),
         DataElement::new(
             tags::AFFECTED_SOP_INSTANCE_UID,
             VR::UI,
@@ -0,0 +1,427 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::eyre::{bail, Context, ContextCompat};
+use dicom::{
+    core::{DataElement, PrimitiveValue, VR},
+    dicom_value,
+    dictionary_std::tags,
+    encoding::TransferSyntaxIndex,
+    object::{
+        open_file, DefaultDicomObject, FileMetaTable, InMemDicomObject, OpenFileOptions,
+        StandardDataDictionary,
+    },
+    transfer_syntax::TransferSyntaxRegistry,
+};
+use dicom_ul::{
+    association::ClientAssociationOptions, pdu::PDataValueType, ClientAssociation, Pdu,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    destination::Destination,
+    pipeline::{is_success, Instance},
+    utils::{even_len, RemoteAe},
+};
+
+const EXPLICIT_VR_LE: &str = "1.2.840.10008.1.2.1";
+const IMPLICIT_VR_LE: &str = "1.2.840.10008.1.2";
+
+/// A presentation context proposed by the SCU and accepted by the peer
+#[derive(Debug)]
+struct AcceptedContext {
+    id: u8,
+    abstract_syntax: String,
+    transfer_syntax: String,
+}
+
+/// A C-STORE SCU association with a remote node.
+/// The presentation contexts are negotiated once, from the list of
+/// SOP classes and transfer syntaxes the association will carry.
+pub(crate) struct StoreScu {
+    association: ClientAssociation,
+    contexts: Vec<AcceptedContext>,
+    message_id: u16,
+}
+
+impl StoreScu {
+    /// Establishes an association with the node at `address`
+    /// (`aet@host:port`) proposing one presentation context per
+    /// distinct SOP class of `objects`.
+    pub(crate) fn connect(
+        calling_aet: &str,
+        address: &str,
+        max_pdu: u32,
+        metas: &[&FileMetaTable],
+    ) -> color_eyre::Result<Self> {
+        // (abstract syntax, transfer syntaxes) in proposal order,
+        // the n-th proposed context is given the id n + 1
+        let mut proposed: Vec<(String, Vec<String>)> = Vec::new();
+        for meta in metas {
+            let sop_class = meta.media_storage_sop_class_uid().to_string();
+            let ts = meta.transfer_syntax().to_string();
+            match proposed.iter_mut().find(|(uid, _)| *uid == sop_class) {
+                Some((_, syntaxes)) => {
+                    if !syntaxes.contains(&ts) {
+                        syntaxes.insert(0, ts);
+                    }
+                }
+                None => {
+                    let mut syntaxes = vec![ts];
+                    for native in [EXPLICIT_VR_LE, IMPLICIT_VR_LE] {
+                        if !syntaxes.iter().any(|s| s == native) {
+                            syntaxes.push(native.to_string());
+                        }
+                    }
+                    proposed.push((sop_class, syntaxes));
+                }
+            }
+        }
+
+        let mut options = ClientAssociationOptions::new()
+            .calling_ae_title(calling_aet.to_string())
+            .max_pdu_length(max_pdu);
+        for (abstract_syntax, transfer_syntaxes) in &proposed {
+            options = options
+                .with_presentation_context(abstract_syntax.clone(), transfer_syntaxes.clone());
+        }
+
+        let association = options
+            .establish_with(address)
+            .wrap_err_with(|| format!("Could not establish association with {}", address))?;
+
+        let contexts = association
+            .presentation_contexts()
+            .iter()
+            .filter_map(|pc| {
+                proposed
+                    .get(pc.id as usize - 1)
+                    .map(|(abstract_syntax, _)| AcceptedContext {
+                        id: pc.id,
+                        abstract_syntax: abstract_syntax.clone(),
+                        transfer_syntax: pc.transfer_syntax.trim_end_matches('\0').to_string(),
+                    })
+            })
+            .collect();
+        debug!("Accepted presentation contexts: {:?}", contexts);
+
+        Ok(Self {
+            association,
+            contexts,
+            message_id: 1,
+        })
+    }
+
+    /// Whether the negotiated presentation contexts can carry the instance
+    pub(crate) fn can_store(&self, obj: &DefaultDicomObject) -> bool {
+        self.find_context(obj).is_ok()
+    }
+
+    /// Picks the presentation context the instance is sent with
+    fn find_context(&self, obj: &DefaultDicomObject) -> color_eyre::Result<&AcceptedContext> {
+        let sop_class_uid = obj.meta().media_storage_sop_class_uid();
+        let file_ts = obj.meta().transfer_syntax();
+
+        let candidates: Vec<&AcceptedContext> = self
+            .contexts
+            .iter()
+            .filter(|pc| pc.abstract_syntax == sop_class_uid)
+            .collect();
+        // Prefer sending the instance as is, otherwise re-encode it in a
+        // native transfer syntax when it does not hold encapsulated pixel data
+        match candidates.iter().find(|pc| pc.transfer_syntax == file_ts) {
+            Some(pc) => Ok(pc),
+            None => {
+                let source_ts = TransferSyntaxRegistry
+                    .get(file_ts)
+                    .wrap_err_with(|| format!("Unknown transfer syntax {}", file_ts))?;
+                if !source_ts.is_codec_free() {
+                    bail!(
+                        "No presentation context accepted for {} in {}, transcoding is not supported",
+                        sop_class_uid,
+                        file_ts
+                    );
+                }
+                candidates.first().copied().wrap_err_with(|| {
+                    format!("No presentation context accepted for {}", sop_class_uid)
+                })
+            }
+        }
+    }
+
+    /// Sends one instance over the association and returns the status
+    /// of the C-STORE-RSP.
+    pub(crate) fn store(&mut self, obj: &DefaultDicomObject) -> color_eyre::Result<u16> {
+        let sop_class_uid = obj.meta().media_storage_sop_class_uid();
+        let sop_instance_uid = obj.meta().media_storage_sop_instance_uid();
+        let context = self.find_context(obj)?;
+        let context_id = context.id;
+        let ts = TransferSyntaxRegistry
+            .get(&context.transfer_syntax)
+            .wrap_err_with(|| format!("Unknown transfer syntax {}", context.transfer_syntax))?;
+
+        let mut object_data = Vec::new();
+        obj.write_dataset_with_ts(&mut object_data, ts)
+            .wrap_err("Could not write the DICOM data set")?;
+
+        let message_id = self.message_id;
+        self.message_id = self.message_id.wrapping_add(1);
+
+        // Commands are always in implict VR LE
+        let command_ts =
+            dicom_transfer_syntax_registry::entries::IMPLICIT_VR_LITTLE_ENDIAN.erased();
+        let mut command_data = Vec::new();
+        create_cstore_request(message_id, sop_class_uid, sop_instance_uid)
+            .write_dataset_with_ts(&mut command_data, &command_ts)
+            .wrap_err("Could not write C-STORE request object")?;
+
+        self.association
+            .send(&Pdu::PData {
+                data: vec![dicom_ul::pdu::PDataValue {
+                    presentation_context_id: context_id,
+                    value_type: PDataValueType::Command,
+                    is_last: true,
+                    data: command_data,
+                }],
+            })
+            .wrap_err("Failed to send C-STORE request")?;
+
+        {
+            let mut pdata = self.association.send_pdata(context_id);
+            std::io::Write::write_all(&mut pdata, &object_data)
+                .wrap_err("Failed to send the DICOM data set")?;
+            pdata
+                .finish()
+                .wrap_err("Failed to send the DICOM data set")?;
+        }
+
+        match self
+            .association
+            .receive()
+            .wrap_err("Failed to receive C-STORE response")?
+        {
+            Pdu::PData { data } if !data.is_empty() => {
+                let rsp =
+                    InMemDicomObject::read_dataset_with_ts(data[0].data.as_slice(), &command_ts)
+                        .wrap_err("Failed to read C-STORE response")?;
+                let status = rsp
+                    .element(tags::STATUS)
+                    .wrap_err("Missing Status in C-STORE response")?
+                    .uint16()
+                    .wrap_err("Status is not an integer")?;
+                Ok(status)
+            }
+            pdu => bail!("Unexpected PDU in response to C-STORE: {:?}", pdu),
+        }
+    }
+
+    /// Gracefully releases the association
+    pub(crate) fn release(self) -> color_eyre::Result<()> {
+        self.association
+            .release()
+            .wrap_err("Error releasing the association")
+    }
+}
+
+/// A destination sending the instances to a remote AE. The association
+/// is kept open between instances and released once idle.
+pub(crate) struct DicomDestination {
+    calling_aet: String,
+    remote_ae: RemoteAe,
+    scu: Option<StoreScu>,
+}
+
+impl DicomDestination {
+    pub(crate) fn new(calling_aet: String, remote_ae: RemoteAe) -> Self {
+        Self {
+            calling_aet,
+            remote_ae,
+            scu: None,
+        }
+    }
+}
+
+impl Destination for DicomDestination {
+    fn send(&mut self, instance: &Instance) -> color_eyre::Result<()> {
+        // The open association may have been negotiated for other
+        // SOP classes or transfer syntaxes
+        if let Some(scu) = self.scu.take_if(|scu| !scu.can_store(&instance.object)) {
+            if let Err(e) = scu.release() {
+                debug!("{:#}", e);
+            }
+        }
+        let mut scu = match self.scu.take() {
+            Some(scu) => scu,
+            None => StoreScu::connect(
+                &self.calling_aet,
+                &self.remote_ae.address(),
+                self.remote_ae.max_pdu,
+                &[instance.object.meta()],
+            )?,
+        };
+        // A failed send leaves the association in an unknown state, it
+        // is dropped and the next attempt opens a new one
+        let status = scu.store(&instance.object)?;
+        self.scu = Some(scu);
+        if !is_success(status) {
+            bail!(
+                "{} answered with status {:#06x}",
+                self.remote_ae.aet,
+                status
+            );
+        }
+        if status != 0x0000 {
+            warn!(
+                "{} stored {} with warning status {:#06x}",
+                self.remote_ae.aet,
+                instance.sop_instance_uid(),
+                status
+            );
+        }
+        Ok(())
+    }
+
+    fn idle(&mut self) {
+        if let Some(scu) = self.scu.take() {
+            if let Err(e) = scu.release() {
+                debug!("{:#}", e);
+            }
+        }
+    }
+
+    fn retries(&self) -> (u32, Duration) {
+        (
+            self.remote_ae.retries,
+            Duration::from_secs(self.remote_ae.retry_interval),
+        )
+    }
+}
+
+/// Lists every DICOM file found in `paths` with its file meta group,
+/// walking directories recursively. Only the meta group is read, files
+/// that cannot be read as DICOM are skipped.
+pub(crate) fn collect_dicom_files(paths: &[PathBuf]) -> Vec<(PathBuf, FileMetaTable)> {
+    let mut files = Vec::new();
+    for path in paths {
+        collect_dicom_files_into(path, &mut files);
+    }
+    files
+}
+
+fn collect_dicom_files_into(path: &Path, files: &mut Vec<(PathBuf, FileMetaTable)>) {
+    if path.is_dir() {
+        let entries = match std::fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not read directory {}: {}", path.display(), e);
+                return;
+            }
+        };
+        let mut children: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+        children.sort();
+        for child in children {
+            collect_dicom_files_into(&child, files);
+        }
+    } else {
+        match OpenFileOptions::new()
+            .read_until(tags::SOP_CLASS_UID)
+            .open_file(path)
+        {
+            Ok(obj) => files.push((path.to_path_buf(), obj.meta().clone())),
+            Err(e) => warn!("Skipping {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Sends every DICOM file found in `paths` to the node at `address`
+/// over a single association, opening them one at a time. Returns the
+/// number of instances that were successfully stored.
+pub(crate) fn send_files(
+    calling_aet: &str,
+    address: &str,
+    max_pdu: u32,
+    paths: &[PathBuf],
+) -> color_eyre::Result<usize> {
+    let files = collect_dicom_files(paths);
+    if files.is_empty() {
+        bail!("No DICOM files found");
+    }
+    let metas: Vec<&FileMetaTable> = files.iter().map(|(_, meta)| meta).collect();
+    let mut scu = StoreScu::connect(calling_aet, address, max_pdu, &metas)?;
+
+    let mut stored = 0;
+    for (path, _) in &files {
+        let obj = match open_file(path) {
+            Ok(obj) => obj,
+            Err(e) => {
+                warn!("Failed to send {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        match scu.store(&obj) {
+            Ok(0x0000) => {
+                info!("Sent {}", path.display());
+                stored += 1;
+            }
+            Ok(status @ 0xB000..=0xBFFF) => {
+                warn!(
+                    "Sent {} with warning status {:#06x}",
+                    path.display(),
+                    status
+                );
+                stored += 1;
+            }
+            Ok(status) => warn!("Failed to send {}: status {:#06x}", path.display(), status),
+            Err(e) => warn!("Failed to send {}: {:#}", path.display(), e),
+        }
+    }
+    scu.release()?;
+
+    Ok(stored)
+}
+
+fn create_cstore_request(
+    message_id: u16,
+    sop_class_uid: &str,
+    sop_instance_uid: &str,
+) -> InMemDicomObject<StandardDataDictionary> {
+    InMemDicomObject::from_element_iter([
+        DataElement::new(
+            tags::COMMAND_GROUP_LENGTH,
+            VR::UL,
+            PrimitiveValue::from(
+                8 + even_len(sop_class_uid)
+                    + 8
+                    + 2
+                    + 8
+                    + 2
+                    + 8
+                    + 2
+                    + 8
+                    + 2
+                    + 8
+                    + even_len(sop_instance_uid),
+            ),
+        ),
+        DataElement::new(
+            tags::AFFECTED_SOP_CLASS_UID,
+            VR::UI,
+            dicom_value!(Str, sop_class_uid),
+        ),
+        DataElement::new(tags::COMMAND_FIELD, VR::US, dicom_value!(U16, [0x0001])),
+        DataElement::new(tags::MESSAGE_ID, VR::US, dicom_value!(U16, [message_id])),
+        DataElement::new(tags::PRIORITY, VR::US, dicom_value!(U16, [0x0000])),
+        DataElement::new(
+            tags::COMMAND_DATA_SET_TYPE,
+            VR::US,
+            dicom_value!(U16, [0x0000]),
+        ),
+        DataElement::new(
+            tags::AFFECTED_SOP_INSTANCE_UID,
+            VR::UI,
+            dicom_value!(Str, sop_instance_uid),
+        ),
+    ])
+}
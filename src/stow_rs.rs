@@ -0,0 +1,389 @@
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use color_eyre::eyre::{bail, eyre, Context};
+use dicom::{
+    dictionary_std::tags,
+    object::{file::ReadPreamble, OpenFileOptions},
+};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{debug, info, warn};
+
+use crate::{
+    destination::Destination,
+    pipeline::{is_success, Instance, Origin, Pipeline},
+    source::{Source, POLL_TIMEOUT},
+};
+
+/// Failure reason of an instance whose StudyInstanceUID does not match
+/// the one of the request URL
+const STATUS_STUDY_MISMATCH: u16 = 0xA900;
+
+/// A source receiving instances over DICOMweb STOW-RS
+/// (`POST /studies` and `POST /studies/{StudyInstanceUID}`)
+pub(crate) struct StowRs {
+    bind: String,
+    /// The largest request body accepted, in bytes
+    max_body: u64,
+}
+
+impl StowRs {
+    pub(crate) fn new(bind: String, max_body_mb: u64) -> Self {
+        Self {
+            bind,
+            max_body: max_body_mb * 1024 * 1024,
+        }
+    }
+}
+
+impl Source for StowRs {
+    fn run(&mut self, pipeline: &Pipeline, shutdown_signal: &AtomicBool) -> color_eyre::Result<()> {
+        let server = Server::http(&self.bind)
+            .map_err(|e| eyre!("Could not listen on {}: {}", self.bind, e))?;
+        info!("STOW-RS listening on {}", self.bind);
+        while !shutdown_signal.load(Ordering::SeqCst) {
+            let request = match server.recv_timeout(POLL_TIMEOUT) {
+                Ok(Some(request)) => request,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Error receiving a STOW-RS request: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = handle_request(request, pipeline, self.max_body) {
+                warn!("Error answering a STOW-RS request: {:#}", e);
+            }
+        }
+        info!("Shutting down the STOW-RS server on {}", self.bind);
+        Ok(())
+    }
+}
+
+fn handle_request(
+    mut request: Request,
+    pipeline: &Pipeline,
+    max_body: u64,
+) -> color_eyre::Result<()> {
+    let peer = request
+        .remote_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    debug!("{} {} from {}", request.method(), request.url(), peer);
+
+    let path = request.url().split('?').next().unwrap_or_default();
+    let study_uid = match path.trim_end_matches('/').rsplit_once("/studies") {
+        Some((_, "")) => None,
+        Some((_, rest)) if rest.starts_with('/') && !rest[1..].contains('/') => {
+            Some(rest[1..].to_string())
+        }
+        _ => return respond(request, 404, None),
+    };
+    if *request.method() != Method::Post {
+        return respond(request, 405, None);
+    }
+
+    let content_type = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Content-Type"))
+        .map(|h| h.value.as_str().to_string())
+        .unwrap_or_default();
+    let Some(boundary) = multipart_boundary(&content_type) else {
+        return respond(request, 415, None);
+    };
+
+    if request.body_length().unwrap_or(0) as u64 > max_body {
+        return respond(request, 413, None);
+    }
+    // A chunked body has no announced length, at most one byte more
+    // than allowed is read to tell whether it is too large
+    let mut body = Vec::new();
+    let reader: &mut dyn Read = request.as_reader();
+    reader
+        .take(max_body + 1)
+        .read_to_end(&mut body)
+        .wrap_err("Could not read the request body")?;
+    if body.len() as u64 > max_body {
+        return respond(request, 413, None);
+    }
+    let parts = split_multipart(&body, &boundary);
+    if parts.is_empty() {
+        return respond(request, 400, None);
+    }
+
+    let mut referenced = Vec::new();
+    let mut failed = Vec::new();
+    for part in parts {
+        let origin = Origin {
+            peer: peer.clone(),
+            ..Default::default()
+        };
+        let object = match OpenFileOptions::new()
+            .read_preamble(ReadPreamble::Always)
+            .from_reader(part)
+        {
+            Ok(object) => object,
+            Err(e) => {
+                let status =
+                    pipeline.quarantine_unreadable(part, None, None, &origin, &e.to_string());
+                failed.push(sop_reference("", "", Some(status)));
+                continue;
+            }
+        };
+        let instance = Instance { object, origin };
+        let sop_class_uid = instance.sop_class_uid().to_string();
+        let sop_instance_uid = instance.sop_instance_uid().to_string();
+        if let Some(study_uid) = &study_uid {
+            let instance_study_uid = instance
+                .object
+                .element(tags::STUDY_INSTANCE_UID)
+                .ok()
+                .and_then(|e| e.to_str().ok())
+                .map(|uid| uid.trim_end_matches('\0').to_string())
+                .unwrap_or_default();
+            if instance_study_uid != *study_uid {
+                failed.push(sop_reference(
+                    &sop_class_uid,
+                    &sop_instance_uid,
+                    Some(STATUS_STUDY_MISMATCH),
+                ));
+                continue;
+            }
+        }
+        let status = pipeline.ingest(instance);
+        if is_success(status) {
+            referenced.push(sop_reference(&sop_class_uid, &sop_instance_uid, None));
+        } else {
+            failed.push(sop_reference(
+                &sop_class_uid,
+                &sop_instance_uid,
+                Some(status),
+            ));
+        }
+    }
+
+    let code = match (referenced.is_empty(), failed.is_empty()) {
+        (_, true) => 200,
+        (false, false) => 202,
+        (true, false) => 409,
+    };
+    let mut response = serde_json::Map::new();
+    if !referenced.is_empty() {
+        response.insert(
+            "00081199".to_string(),
+            json!({ "vr": "SQ", "Value": referenced }),
+        );
+    }
+    if !failed.is_empty() {
+        response.insert(
+            "00081198".to_string(),
+            json!({ "vr": "SQ", "Value": failed }),
+        );
+    }
+    respond(request, code, Some(Value::Object(response)))
+}
+
+fn respond(request: Request, code: u16, body: Option<Value>) -> color_eyre::Result<()> {
+    let response = match body {
+        Some(body) => Response::from_string(body.to_string())
+            .with_status_code(code)
+            .with_header(
+                Header::from_bytes("Content-Type", "application/dicom+json")
+                    .expect("static header"),
+            )
+            .boxed(),
+        None => Response::empty(code).boxed(),
+    };
+    request
+        .respond(response)
+        .wrap_err("Could not send the response")
+}
+
+/// An item of the Referenced or Failed SOP Sequence of the response
+fn sop_reference(sop_class_uid: &str, sop_instance_uid: &str, failure: Option<u16>) -> Value {
+    let mut item = serde_json::Map::new();
+    if !sop_class_uid.is_empty() {
+        item.insert(
+            "00081150".to_string(),
+            json!({ "vr": "UI", "Value": [sop_class_uid] }),
+        );
+    }
+    if !sop_instance_uid.is_empty() {
+        item.insert(
+            "00081155".to_string(),
+            json!({ "vr": "UI", "Value": [sop_instance_uid] }),
+        );
+    }
+    if let Some(failure) = failure {
+        item.insert(
+            "00081197".to_string(),
+            json!({ "vr": "US", "Value": [failure] }),
+        );
+    }
+    Value::Object(item)
+}
+
+/// The boundary of the requests sent by the STOW-RS destination
+const BOUNDARY: &str = "eai-rs-stow-rs-boundary";
+
+/// A destination posting the instances to a DICOMweb STOW-RS endpoint
+pub(crate) struct StowRsDestination {
+    url: String,
+    headers: BTreeMap<String, String>,
+}
+
+impl StowRsDestination {
+    pub(crate) fn new(url: String, headers: BTreeMap<String, String>) -> Self {
+        Self { url, headers }
+    }
+}
+
+impl Destination for StowRsDestination {
+    fn send(&mut self, instance: &Instance) -> color_eyre::Result<()> {
+        let mut body =
+            format!("--{}\r\nContent-Type: application/dicom\r\n\r\n", BOUNDARY).into_bytes();
+        instance
+            .object
+            .write_all(&mut body)
+            .wrap_err("Could not write the DICOM object")?;
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+
+        let mut request = ureq::post(&self.url).set(
+            "Content-Type",
+            &format!(
+                "multipart/related; type=\"application/dicom\"; boundary={}",
+                BOUNDARY
+            ),
+        );
+        for (name, value) in self.headers.iter() {
+            request = request.set(name, value);
+        }
+        let response = match request.send_bytes(&body) {
+            Ok(response) => response,
+            Err(ureq::Error::Status(code, response)) => {
+                bail!(
+                    "{} answered with HTTP {}: {}",
+                    self.url,
+                    code,
+                    response.into_string().unwrap_or_default()
+                )
+            }
+            Err(e) => return Err(e).wrap_err_with(|| format!("Could not post to {}", self.url)),
+        };
+        // 202 means some of the instances were not stored, with a single
+        // instance per request it is the one sent
+        if response.status() == 202 {
+            let body = response.into_string().unwrap_or_default();
+            let failed = serde_json::from_str::<Value>(&body)
+                .ok()
+                .is_some_and(|body| body.get("00081198").is_some());
+            if failed {
+                bail!("{} refused the instance: {}", self.url, body);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the boundary of a `multipart/related` content type
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';').map(str::trim);
+    if !params.next()?.eq_ignore_ascii_case("multipart/related") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+/// Splits a multipart body into the bodies of its parts, dropping the
+/// part headers
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut parts = Vec::new();
+    let mut positions = Vec::new();
+    let mut i = 0;
+    while i + delimiter.len() <= body.len() {
+        if body[i..].starts_with(&delimiter) {
+            positions.push(i);
+            i += delimiter.len();
+        } else {
+            i += 1;
+        }
+    }
+    for window in positions.windows(2) {
+        let part = &body[window[0] + delimiter.len()..window[1]];
+        // The part ends with the CRLF preceding the next delimiter
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        let Some(header_end) = find(part, b"\r\n\r\n") else {
+            continue;
+        };
+        parts.push(&part[header_end + 4..]);
+    }
+    parts
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_boundary_of_multipart_related() {
+        assert_eq!(
+            multipart_boundary("multipart/related; type=\"application/dicom\"; boundary=abc123")
+                .as_deref(),
+            Some("abc123")
+        );
+        assert_eq!(
+            multipart_boundary("Multipart/Related;BOUNDARY=\"quoted boundary\"").as_deref(),
+            Some("quoted boundary")
+        );
+        assert_eq!(
+            multipart_boundary("multipart/related; type=application/dicom"),
+            None
+        );
+        assert_eq!(
+            multipart_boundary("multipart/form-data; boundary=abc"),
+            None
+        );
+        assert_eq!(multipart_boundary("application/dicom"), None);
+        assert_eq!(multipart_boundary(""), None);
+    }
+
+    #[test]
+    fn splits_the_parts_and_drops_their_headers() {
+        let body = b"preamble\r\n\
+            --abc\r\nContent-Type: application/dicom\r\n\r\nfirst\r\n\
+            --abc\r\nContent-Type: application/dicom\r\nContent-Length: 8\r\n\r\nsec\r\nond\r\n\
+            --abc--\r\nepilogue";
+        let parts = split_multipart(body, "abc");
+        assert_eq!(parts, vec![&b"first"[..], &b"sec\r\nond"[..]]);
+    }
+
+    #[test]
+    fn keeps_binary_parts_whole() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut body = b"--b\r\nContent-Type: application/dicom\r\n\r\n".to_vec();
+        body.extend_from_slice(&data);
+        body.extend_from_slice(b"\r\n--b--\r\n");
+        assert_eq!(split_multipart(&body, "b"), vec![&data[..]]);
+    }
+
+    #[test]
+    fn skips_parts_without_headers_and_bodies_without_parts() {
+        assert!(split_multipart(b"--abc\r\nno headers\r\n--abc--", "abc").is_empty());
+        assert!(split_multipart(b"--other\r\n\r\ndata\r\n--other--", "abc").is_empty());
+        assert!(split_multipart(b"", "abc").is_empty());
+    }
+}
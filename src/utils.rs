@@ -1,152 +1,750 @@
-use core::hash::Hash;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt,
     fs::File,
-    hash::Hasher,
-    io::{BufReader, BufWriter, Error},
+    io::{BufReader, BufWriter, Error, ErrorKind},
+    net::ToSocketAddrs,
     path::{Path, PathBuf},
-    sync::atomic::{AtomicBool, Ordering},
 };
 
 use serde::{Deserialize, Serialize};
 
-use crate::store_scp;
+use crate::{
+    filtering::InstanceFilter,
+    migration::{self, migrate, CONFIG_VERSION},
+    morphing::Morpher,
+    reconciliation,
+    s3::MIN_PART_SIZE_MB,
+    scripting,
+    storage::PathTemplate,
+};
 
-/// A Channel describes a flow of data between an origin node which
-/// is the key of the addresses hashmap and a list of destination
-/// nodes.
-/// The program will try to launch a C-STORE scp with the origin
-/// node (if the status is set to Started) that forwards the data
-/// to the destination nodes.
+/// A Channel describes a flow of data between a source feeding it
+/// instances and a list of destinations they are forwarded to.
+/// The AEs are referenced by their name in the `local_aes` and
+/// `remote_aes` registries of the config.
+/// The program will try to launch the source (if the status is set
+/// to Started) that forwards the data to the destinations.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub(crate) struct Channel {
     pub(crate) name: String,
-    pub(crate) source: Node,
-    pub(crate) destinations: Vec<Node>,
+    /// Where the instances of the channel come from
+    pub(crate) source: SourceConfig,
+    /// The processing of the instances as they come in, once, before
+    /// they are stored and forwarded
+    #[serde(default)]
+    pub(crate) pre: StageConfig,
+    /// Where the received instances are kept, if anywhere
+    #[serde(default)]
+    pub(crate) storage: Option<Storage>,
+    /// The conversion of the text of the instances to UTF-8, before
+    /// anything else is done with them
+    #[serde(default)]
+    pub(crate) charset: Option<CharsetConfig>,
+    /// The checks of the instances against their IOD, before the pre
+    /// stage
+    #[serde(default)]
+    pub(crate) validation: Option<ValidationConfig>,
+    /// The replacement of the patient identifiers with the local ones,
+    /// after the validation
+    #[serde(default)]
+    pub(crate) reconciliation: Option<ReconciliationConfig>,
+    /// Where the instances set aside by the channel are kept
+    #[serde(default)]
+    pub(crate) quarantine_dir: Option<PathBuf>,
+    /// The external program run on the instances once they are stored
+    #[serde(default)]
+    pub(crate) processor: Option<ProcessorConfig>,
+    /// Where the instances are forwarded to
+    pub(crate) destinations: Vec<DestinationConfig>,
     pub(crate) status: Status,
 }
 
-/// A Node is a data structure representing a dicom destination
-/// At the moment of this writing it mainly represents an abstraction
-/// over a C-STORE scp.
-/// It is used to represent both the source and the destination of a
-/// channel and does not discriminate between the two.
-/// IMPROVE: There might be a better way to do this abstraction
-#[derive(Serialize, Deserialize)]
-pub(crate) struct Node {
-    /// The AET of the node
-    pub(crate) aet: String, // This CANNOT be mutable since it is used as a key in a HashMap
-    /// The IP address of the node
+/// The source of a channel. Whatever the source, the instances go
+/// through the same storage, processing and forwarding.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub(crate) enum SourceConfig {
+    /// A C-STORE scp run with one of the local AEs
+    Dicom {
+        /// The name of the local AE
+        local_ae: String,
+    },
+    /// A directory watched for new DICOM files
+    HotFolder {
+        /// The watched directory
+        path: PathBuf,
+        /// The number of seconds between two scans of the directory
+        #[serde(default = "default_poll_interval")]
+        poll_interval: u64,
+        /// Where the files are moved once ingested, they are deleted
+        /// when not set
+        #[serde(default)]
+        processed_dir: Option<PathBuf>,
+        /// Where the files that could not be ingested are moved, they
+        /// are left in place and ignored when not set
+        #[serde(default)]
+        failed_dir: Option<PathBuf>,
+    },
+    /// A DICOMweb STOW-RS endpoint
+    StowRs {
+        /// The address the HTTP server listens on, as host:port
+        bind: String,
+        /// The largest request body accepted, in MB, bigger requests
+        /// are answered with 413
+        #[serde(default = "default_max_body_mb")]
+        max_body_mb: u64,
+    },
+    /// The instances processed by another channel
+    Channel {
+        /// The name of the upstream channel
+        channel: String,
+    },
+}
+
+impl SourceConfig {
+    /// The name of the local AE the source runs, if any
+    pub(crate) fn local_ae(&self) -> Option<&String> {
+        match self {
+            SourceConfig::Dicom { local_ae } => Some(local_ae),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SourceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceConfig::Dicom { local_ae } => write!(f, "dicom:{}", local_ae),
+            SourceConfig::HotFolder { path, .. } => write!(f, "folder:{}", path.display()),
+            SourceConfig::StowRs { bind, .. } => write!(f, "stow-rs:{}", bind),
+            SourceConfig::Channel { channel } => write!(f, "channel:{}", channel),
+        }
+    }
+}
+
+/// A destination of a channel. Each destination is delivered to on its
+/// own, a slow or unreachable one does not hold the others back.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub(crate) struct DestinationConfig {
+    /// Where the instances are sent
+    #[serde(flatten)]
+    pub(crate) kind: DestinationKind,
+    /// The processing of the instances just before they are sent to
+    /// this destination, on a copy of its own
+    #[serde(default)]
+    pub(crate) post: StageConfig,
+    /// The instances never sent to this destination, those matching
+    /// any of the filters
+    #[serde(default)]
+    pub(crate) drop: Vec<DropFilter>,
+}
+
+/// Instances a destination does not want, matching all of the given
+/// criteria
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct DropFilter {
+    /// The SOP Class UIDs, one of which the instance has
+    pub(crate) sop_classes: Vec<String>,
+    /// The modalities, one of which the instance has
+    pub(crate) modalities: Vec<String>,
+    /// Values of ImageType, one of which the instance has, e.g.
+    /// `LOCALIZER`
+    pub(crate) image_type: Vec<String>,
+    /// A regular expression found in the SeriesDescription
+    pub(crate) series_description: Option<String>,
+}
+
+/// The processing of the instances at one point of a channel, the steps
+/// running in the order of the fields: de-identification,
+/// pseudonymization, rules and script
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct StageConfig {
+    /// De-identifies the instances
+    pub(crate) deidentify: Option<Deidentification>,
+    /// Replaces the UIDs and PatientID of the instances with pseudonyms
+    pub(crate) pseudonymize: Option<Pseudonymization>,
+    /// The rules modifying the instances
+    pub(crate) rules: Vec<Rule>,
+    /// The script run on the instances, which can also drop them
+    pub(crate) script: Option<ScriptConfig>,
+}
+
+impl StageConfig {
+    /// Returns a human readable list of the problems found in the stage
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Err(e) = Morpher::new(&self.rules) {
+            problems.push(format!("{:#}", e));
+        }
+        if let Some(script) = &self.script {
+            if let Err(e) = scripting::check(script) {
+                problems.push(format!("{:#}", e));
+            }
+        }
+        for mask in self
+            .deidentify
+            .iter()
+            .flat_map(|deidentification| deidentification.blackout.iter())
+        {
+            if mask.regions.is_empty()
+                || mask
+                    .regions
+                    .iter()
+                    .any(|region| region.width == 0 || region.height == 0)
+            {
+                problems.push("A blackout mask has no regions or an empty one".to_string());
+            }
+        }
+        if let Some(pseudonymization) = &self.pseudonymize {
+            if pseudonymization.patient_id_prefix.len() > MAX_PATIENT_ID_PREFIX {
+                problems.push(format!(
+                    "The PatientID prefix is longer than {} characters",
+                    MAX_PATIENT_ID_PREFIX
+                ));
+            }
+        }
+        problems
+    }
+}
+
+/// The options of a de-identification with the Basic Application Level
+/// Confidentiality Profile of PS3.15 Annex E. Without options the
+/// profile removes or empties every identifying attribute.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Deidentification {
+    /// Keeps the dates and times
+    pub(crate) retain_longitudinal_temporal_information: bool,
+    /// Keeps the sex, age, size, weight and other characteristics of
+    /// the patient
+    pub(crate) retain_patient_characteristics: bool,
+    /// Keeps the identifiers of the devices
+    pub(crate) retain_device_identity: bool,
+    /// Keeps the name and address of the institution
+    pub(crate) retain_institution_identity: bool,
+    /// Keeps the UIDs instead of replacing them
+    pub(crate) retain_uids: bool,
+    /// Keeps the descriptions and comments, with the patient's names
+    /// and identifiers removed from them
+    pub(crate) clean_descriptors: bool,
+    /// The masks blacking out the text some devices burn into the pixels
+    pub(crate) blackout: Vec<BlackoutMask>,
+    /// The environment variable holding the secret the replacing UIDs
+    /// are derived from, `EAI_UID_SECRET` when unset
+    pub(crate) uid_secret_env: Option<String>,
+}
+
+/// The regions of the pixels blacked out in the instances of a device.
+/// The mask applies to the instances matching all of its criteria, a
+/// criterion left out matches every instance.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub(crate) struct BlackoutMask {
+    /// The Manufacturer, ignoring the case
+    #[serde(default)]
+    pub(crate) manufacturer: Option<String>,
+    /// The Manufacturer's Model Name, ignoring the case
+    #[serde(default)]
+    pub(crate) model: Option<String>,
+    #[serde(default)]
+    pub(crate) rows: Option<u16>,
+    #[serde(default)]
+    pub(crate) columns: Option<u16>,
+    pub(crate) regions: Vec<Region>,
+}
+
+/// A rectangle of pixels, from its top left corner
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct Region {
+    pub(crate) x: u16,
+    pub(crate) y: u16,
+    pub(crate) width: u16,
+    pub(crate) height: u16,
+}
+
+/// How instances are pseudonymized. The pseudonyms are derived from a
+/// secret kept in the key store, so that an original value always gets
+/// the same pseudonym, and the original values are recorded there to
+/// re-identify the instances.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub(crate) struct Pseudonymization {
+    /// The key store file, created if needed. It can be shared by
+    /// several destinations.
+    pub(crate) key_store: PathBuf,
+    /// The environment variable holding the passphrase the key store is
+    /// encrypted with, it is not encrypted when unset
+    #[serde(default)]
+    pub(crate) passphrase_env: Option<String>,
+    /// Prepended to the pseudonyms replacing PatientID
+    #[serde(default)]
+    pub(crate) patient_id_prefix: String,
+}
+
+/// The longest PatientID prefix, the pseudonyms have to fit in a LO
+const MAX_PATIENT_ID_PREFIX: usize = 48;
+
+/// The kinds of destinations and their settings
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub(crate) enum DestinationKind {
+    /// C-STORE requests to one of the remote AEs
+    Dicom {
+        /// The name of the remote AE
+        remote_ae: String,
+    },
+    /// A directory the instances are written to
+    Folder {
+        /// The directory, created if needed
+        path: PathBuf,
+        /// Where the instances go in the directory, as for the storage
+        #[serde(default)]
+        path_template: Option<String>,
+    },
+    /// A DICOMweb STOW-RS endpoint
+    StowRs {
+        /// The URL the instances are posted to, usually ending with `/studies`
+        url: String,
+        /// Extra headers of the requests, typically `Authorization`
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    /// A zip archive per study
+    ZipArchive {
+        /// The directory the `<StudyInstanceUID>.zip` archives are written to
+        path: PathBuf,
+    },
+    /// A program run for each instance
+    Command {
+        /// The program to run
+        program: String,
+        /// Its arguments, `{file}` is replaced with the path of the
+        /// instance, which is appended when no argument contains it
+        #[serde(default)]
+        args: Vec<String>,
+        /// The number of seconds the program may run before it is killed
+        #[serde(default = "default_command_timeout")]
+        timeout: u64,
+    },
+}
+
+impl DestinationConfig {
+    /// The name of the remote AE the destination sends to, if any
+    pub(crate) fn remote_ae(&self) -> Option<&String> {
+        match &self.kind {
+            DestinationKind::Dicom { remote_ae } => Some(remote_ae),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DestinationConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            DestinationKind::Dicom { remote_ae } => write!(f, "dicom:{}", remote_ae),
+            DestinationKind::Folder { path, .. } => write!(f, "folder:{}", path.display()),
+            DestinationKind::StowRs { url, .. } => write!(f, "stow-rs:{}", url),
+            DestinationKind::ZipArchive { path } => write!(f, "zip:{}", path.display()),
+            DestinationKind::Command { program, .. } => write!(f, "command:{}", program),
+        }
+    }
+}
+
+/// A rule modifying the attributes of the instances. Tags are given by
+/// keyword (`PatientID`) or number (`(0010,0020)`, `0010,0020` or
+/// `00100020`) and values as text, multiple values being separated
+/// with a backslash.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub(crate) enum Rule {
+    /// Sets the value of the attribute, adding it when missing
+    Set { tag: String, value: String },
+    /// Removes the attribute
+    Remove { tag: String },
+    /// Copies the value of an attribute to another one
+    Copy { from: String, to: String },
+    /// Replaces the matches of a regular expression in the value,
+    /// `$1` in the replacement referring to the first group
+    RegexReplace {
+        tag: String,
+        pattern: String,
+        replacement: String,
+    },
+    /// Prepends a text to the value
+    Prefix { tag: String, prefix: String },
+    /// Converts the value to upper case
+    Uppercase { tag: String },
+    /// Moves the dates of a DA or DT attribute by a number of days
+    DateShift { tag: String, days: i64 },
+    /// Runs `then` when the value, empty if the attribute is missing,
+    /// matches the regular expression, `else` otherwise
+    If {
+        tag: String,
+        matches: String,
+        then: Vec<Rule>,
+        #[serde(default, rename = "else")]
+        otherwise: Vec<Rule>,
+    },
+}
+
+fn default_command_timeout() -> u64 {
+    60
+}
+
+/// Where a channel keeps the instances it receives
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct Storage {
+    /// The directory the received instances are written to, which
+    /// only keeps the index when they go to a bucket
+    pub(crate) out_dir: PathBuf,
+    /// The bucket of an S3-compatible object store the instances are
+    /// uploaded to instead of `out_dir`
+    #[serde(default)]
+    pub(crate) s3: Option<S3Config>,
+    /// Where the instances go in the directory, e.g.
+    /// `{PatientID}/{StudyInstanceUID}/{SOPInstanceUID}.dcm`, the
+    /// directory is flat when not set
+    #[serde(default)]
+    pub(crate) path_template: Option<String>,
+    /// Whether the SHA-256 of every stored file is recorded in the
+    /// `SHA256SUMS` index of `out_dir`, checked by `eai-rs verify`
+    #[serde(default)]
+    pub(crate) checksums: bool,
+    /// What happens when an instance already stored is received again
+    #[serde(default)]
+    pub(crate) duplicates: Duplicates,
+    /// Whether the stored instances and their deliveries are recorded
+    /// in the `index.sqlite` database of `out_dir`
+    #[serde(default)]
+    pub(crate) index: bool,
+    /// When the stored instances are deleted, which needs the index
+    #[serde(default)]
+    pub(crate) retention: RetentionPolicy,
+    /// The free space, in MB, of the file system of `out_dir` under
+    /// which the instances are refused with Out of Resources (0xA700)
+    #[serde(default)]
+    pub(crate) min_free_mb: Option<u64>,
+}
+
+/// A bucket of an S3-compatible object store, such as MinIO
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct S3Config {
+    /// The URL of the store, e.g. `http://localhost:9000`
+    pub(crate) endpoint: String,
+    pub(crate) bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub(crate) region: String,
+    /// Prepended to the keys of the instances in the bucket
+    #[serde(default)]
+    pub(crate) prefix: Option<String>,
+    /// The environment variables holding the access key and its secret
+    #[serde(default = "default_access_key_env")]
+    pub(crate) access_key_env: String,
+    #[serde(default = "default_secret_key_env")]
+    pub(crate) secret_key_env: String,
+    /// Whether the bucket is in the path of the URLs, as MinIO expects,
+    /// or in the host name
+    #[serde(default = "default_path_style")]
+    pub(crate) path_style: bool,
+    /// The size of the parts of the multipart uploads, in MB. The
+    /// smaller files are uploaded at once.
+    #[serde(default = "default_part_size_mb")]
+    pub(crate) part_size_mb: u64,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_access_key_env() -> String {
+    "AWS_ACCESS_KEY_ID".to_string()
+}
+
+fn default_secret_key_env() -> String {
+    "AWS_SECRET_ACCESS_KEY".to_string()
+}
+
+fn default_path_style() -> bool {
+    true
+}
+
+fn default_part_size_mb() -> u64 {
+    8
+}
+
+/// When a channel deletes the instances it stored. The instances still
+/// pending delivery, or whose delivery failed, are never deleted.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub(crate) enum RetentionPolicy {
+    /// The instances are kept
+    #[default]
+    Never,
+    /// Once delivered to all the destinations
+    AfterDelivery,
+    /// Once delivered and received more than `days` days ago
+    KeepDays { days: u64 },
+    /// When the file system of the storage is more than `max_percent`
+    /// full, the oldest delivered studies are deleted until it is not
+    DiskUsage { max_percent: u8 },
+}
+
+/// How a channel handles the instances received again, with the
+/// SOPInstanceUID of an instance it already stored
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct Duplicates {
+    #[serde(default)]
+    pub(crate) policy: DuplicatePolicy,
+    /// Whether the duplicates that are stored go to the destinations
+    /// again, they are only stored otherwise
+    #[serde(default)]
+    pub(crate) forward: bool,
+}
+
+/// What is stored when an instance is received again
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum DuplicatePolicy {
+    /// The new instance replaces the stored one
+    #[default]
+    Overwrite,
+    /// The stored instance is kept, the new one is refused with the
+    /// Duplicate SOP Instance status (0x0111)
+    KeepFirst,
+    /// Both are kept, the new one with a `_n` suffix
+    KeepBoth,
+    /// The new instance is ignored when identical to the stored one,
+    /// it replaces it and is forwarded as a new instance otherwise
+    IgnoreIdentical,
+}
+
+/// A Rhai script run by a stage on every instance. The script file is
+/// reloaded when it changes.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct ScriptConfig {
+    /// The script file
+    pub(crate) path: PathBuf,
+    /// How long the script may run on one instance before it is
+    /// stopped, in milliseconds
+    #[serde(default = "default_script_timeout")]
+    pub(crate) timeout_ms: u64,
+}
+
+fn default_script_timeout() -> u64 {
+    1000
+}
+
+/// How a channel checks its instances against their IOD
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct ValidationConfig {
+    /// What happens to the instances that do not conform
+    #[serde(default)]
+    pub(crate) policy: ValidationPolicy,
+    /// Where the JSON report of each nonconformant instance is written
+    #[serde(default)]
+    pub(crate) report_dir: Option<PathBuf>,
+}
+
+/// How a channel replaces the patient identifiers of outside instances
+/// with the local ones. The instances of the patients missing from the
+/// mapping are quarantined.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct ReconciliationConfig {
+    /// The CSV mapping table, loaded again when it changes
+    pub(crate) mapping: PathBuf,
+}
+
+/// How a channel converts the text of its instances to UTF-8
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct CharsetConfig {
+    /// What happens to the instances with characters that could not be
+    /// converted
+    #[serde(default)]
+    pub(crate) unmappable: ValidationPolicy,
+}
+
+/// What happens to the instances that do not conform to their IOD
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum ValidationPolicy {
+    /// Accepted with a warning status (0xB007)
+    #[default]
+    Warn,
+    /// Refused with an error status (0xA900)
+    Reject,
+    /// Moved to the quarantine of the channel
+    Quarantine,
+}
+
+/// An external program run by a channel on every instance, after it is
+/// stored. It accepts the instance by exiting with 0, and can write a
+/// JSON decision on its standard output.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct ProcessorConfig {
+    /// The program to run
+    pub(crate) program: String,
+    /// Its arguments, `{file}` is replaced with the path of the instance
+    #[serde(default)]
+    pub(crate) args: Vec<String>,
+    /// How the instance is given to the program
+    #[serde(default)]
+    pub(crate) input: ProcessorInput,
+    /// How long the program may run, in seconds
+    #[serde(default = "default_command_timeout")]
+    pub(crate) timeout: u64,
+    /// How many instances the program processes at once, shared by the
+    /// channels running the same program
+    #[serde(default = "default_max_processes")]
+    pub(crate) max_processes: usize,
+}
+
+/// How an external program gets the instance
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum ProcessorInput {
+    /// The path of the file, the program can modify it in place
+    #[default]
+    File,
+    /// The file on the standard input
+    Stdin,
+}
+
+fn default_max_processes() -> usize {
+    4
+}
+
+fn default_poll_interval() -> u64 {
+    5
+}
+
+fn default_max_body_mb() -> u64 {
+    512
+}
+
+/// A LocalAe is an application entity run by eai-rs, a C-STORE scp
+/// listening for the instances of a channel.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct LocalAe {
+    /// The AET of the scp
+    pub(crate) aet: String,
+    /// The IP address the scp is reachable at
     pub(crate) ip: String,
-    /// The port of the node
+    /// The port the scp listens on
     pub(crate) port: u16,
-    /// Whether the node only accepts uncompressed data
+    /// Whether the scp only accepts uncompressed data
     pub(crate) uncompressed_only: bool,
     /// The maximum PDU size
     pub(crate) max_pdu: u32,
-    /// Whether the node enforce PDU size
+    /// Whether the scp enforces the PDU size
     pub(crate) strict: bool,
-    /// The output directory if relevant
-    pub(crate) out_dir: Option<PathBuf>,
-    /// The node's status
-    pub(crate) status: Status,
-    /// The node's shutdown signal, this is set to true when the
-    /// node should be shutdown
-    pub(crate) shutdown_signal: AtomicBool,
 }
 
-impl fmt::Debug for Node {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Node")
-            .field("aet", &self.aet)
-            .field("ip", &self.ip)
-            .field("port", &self.port)
-            .field("uncompressed_only", &self.uncompressed_only)
-            .field("max_pdu", &self.max_pdu)
-            .field("strict", &self.strict)
-            .field("out_dir", &self.out_dir)
-            .field(
-                "shutdown_signal",
-                &self.shutdown_signal.load(Ordering::SeqCst),
-            )
-            .finish()
+impl LocalAe {
+    pub(crate) fn aet(&self) -> &String {
+        &self.aet
+    }
+
+    /// Returns the problems found in the AE definition
+    pub(crate) fn validate(&self) -> Vec<String> {
+        validate_address(&self.aet, self.port, self.max_pdu)
     }
 }
 
-impl Eq for Node {}
+/// A RemoteAe is an application entity outside of eai-rs, typically
+/// a PACS, the channels forward their instances to with C-STORE
+/// requests.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct RemoteAe {
+    /// The AET of the remote node
+    pub(crate) aet: String,
+    /// The IP address or host name of the remote node
+    pub(crate) ip: String,
+    /// The port of the remote node
+    pub(crate) port: u16,
+    /// The AET eai-rs calls the remote node with, defaults to the
+    /// AET of the channel's local AE
+    #[serde(default)]
+    pub(crate) calling_aet: Option<String>,
+    /// Whether the remote node only accepts uncompressed data
+    #[serde(default)]
+    pub(crate) uncompressed_only: bool,
+    /// The maximum PDU size
+    #[serde(default = "default_max_pdu")]
+    pub(crate) max_pdu: u32,
+    /// How many times a failed send is retried
+    #[serde(default = "default_retries")]
+    pub(crate) retries: u32,
+    /// The number of seconds to wait between two attempts
+    #[serde(default = "default_retry_interval")]
+    pub(crate) retry_interval: u64,
+    /// The certificates used to secure the association
+    #[serde(default)]
+    pub(crate) tls: Option<TlsTrust>,
+}
 
-impl PartialEq for Node {
-    fn eq(&self, other: &Self) -> bool {
-        self.aet == other.aet
-            && self.ip == other.ip
-            && self.port == other.port
-            && self.uncompressed_only == other.uncompressed_only
-            && self.max_pdu == other.max_pdu
-            && self.strict == other.strict
-            && self.out_dir == other.out_dir
-            && self.status == other.status
+impl RemoteAe {
+    /// The `aet@host:port` address of the node
+    pub(crate) fn address(&self) -> String {
+        format!("{}@{}:{}", self.aet, self.ip, self.port)
     }
-}
 
-impl Clone for Node {
-    fn clone(&self) -> Self {
-        Self {
-            aet: self.aet.clone(),
-            ip: self.ip.clone(),
-            port: self.port,
-            uncompressed_only: self.uncompressed_only,
-            max_pdu: self.max_pdu,
-            strict: self.strict,
-            out_dir: self.out_dir.clone(),
-            status: self.status.clone(),
-            shutdown_signal: AtomicBool::new(self.shutdown_signal.load(Ordering::SeqCst)),
+    /// Returns the problems found in the AE definition
+    pub(crate) fn validate(&self) -> Vec<String> {
+        let mut problems = validate_address(&self.aet, self.port, self.max_pdu);
+        if let Some(calling_aet) = &self.calling_aet {
+            if calling_aet.is_empty() || calling_aet.len() > 16 {
+                problems.push(format!(
+                    "calling AE title {:?} must be between 1 and 16 characters",
+                    calling_aet
+                ));
+            }
         }
+        if self.tls.is_some() {
+            problems.push("TLS is not supported yet".to_string());
+        }
+        problems
     }
 }
 
-impl Hash for Node {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.aet.hash(state);
-        // self.ip.hash(state);
-        // self.port.hash(state);
-        // self.uncompressed_only.hash(state);
-        // self.max_pdu.hash(state);
-        // self.strict.hash(state);
-        // self.out_dir.hash(state);
-        // self.status.hash(state);
-    }
+/// The certificates trusted when talking to a remote AE over TLS
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub(crate) struct TlsTrust {
+    /// The CA bundle the remote certificate is checked against
+    pub(crate) ca_file: PathBuf,
+    /// The client certificate presented to the remote node, if required
+    #[serde(default)]
+    pub(crate) certificate: Option<PathBuf>,
+    /// The private key of the client certificate
+    #[serde(default)]
+    pub(crate) private_key: Option<PathBuf>,
 }
 
-impl Node {
-    // pub(crate) fn new(aet: String, ip: String, port: u16) -> Self {
-    //     Self {
-    //         aet,
-    //         ip,
-    //         port,
-    //         uncompressed_only: false,
-    //         strict: false,
-    //         max_pdu: 16384,
-    //         out_dir: Some(PathBuf::from(".")),
-    //         status: Default::default(),
-    //         shutdown_signal: AtomicBool::new(false),
-    //     }
-    // }
-
-    pub(crate) fn start_node(&mut self) {
-        self.shutdown_signal.store(false, Ordering::SeqCst);
-        self.status = Status::Started;
-        store_scp(self).unwrap();
-    }
+fn default_max_pdu() -> u32 {
+    16384
+}
 
-    pub(crate) fn stop_node(&mut self) {
-        self.shutdown_signal.store(true, Ordering::SeqCst);
-        self.status = Status::Stopped;
-    }
+fn default_retries() -> u32 {
+    3
+}
 
-    pub(crate) fn aet(&self) -> &String {
-        &self.aet
+fn default_retry_interval() -> u64 {
+    30
+}
+
+/// Returns the problems found in the network definition of an AE
+fn validate_address(aet: &str, port: u16, max_pdu: u32) -> Vec<String> {
+    let mut problems = Vec::new();
+    if aet.is_empty() || aet.len() > 16 {
+        problems.push(format!(
+            "AE title {:?} must be between 1 and 16 characters",
+            aet
+        ));
+    }
+    if port == 0 {
+        problems.push("port 0 is not valid".to_string());
     }
+    if max_pdu < 4096 {
+        problems.push(format!("max_pdu {} is below 4096", max_pdu));
+    }
+    problems
 }
 
 #[derive(Default, Debug, Eq, PartialEq, Hash, Clone, Serialize, Deserialize)]
@@ -178,6 +776,15 @@ impl LogLevel {
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub(crate) struct Config {
+    /// The version of the config format, used to migrate older files
+    #[serde(default)]
+    pub(crate) version: u32,
+    /// A map of the local AEs to their name, used as channel sources
+    #[serde(default)]
+    pub(crate) local_aes: HashMap<String, LocalAe>,
+    /// A map of the remote AEs to their name, shared by the channels
+    #[serde(default)]
+    pub(crate) remote_aes: HashMap<String, RemoteAe>,
     /// A map of channels to their id
     pub(crate) channels: HashMap<u64, Channel>,
     /// The log level of the application
@@ -187,21 +794,40 @@ pub(crate) struct Config {
 impl Config {
     pub(crate) fn new() -> Self {
         Self {
+            version: CONFIG_VERSION,
+            local_aes: HashMap::new(),
+            remote_aes: HashMap::new(),
             channels: HashMap::new(),
             log_level: LogLevel::Info,
         }
     }
 
+    /// Reads the config file, migrating it from older formats
     pub(crate) fn from_json_file(path: &Path) -> Result<Self, Error> {
         let file = match File::open(path) {
             Ok(file) => file,
             Err(_) => return Ok(Self::new()),
         };
         let reader = BufReader::new(file);
-        let config: Config = serde_json::from_reader(reader)?;
+        let value: serde_json::Value = serde_json::from_reader(reader)?;
+        let value = migrate(value).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let config: Config = serde_json::from_value(value)?;
         Ok(config)
     }
 
+    /// Whether the config file exists in a format older than the current
+    /// one, and so needs `eai-rs migrate` before it is written over
+    pub(crate) fn is_outdated(path: &Path) -> Result<bool, Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Ok(false),
+        };
+        let value: serde_json::Value = serde_json::from_reader(BufReader::new(file))?;
+        let version =
+            migration::version(&value).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(version < CONFIG_VERSION as u64)
+    }
+
     pub(crate) fn to_json_file(&self, path: &Path) -> Result<(), Error> {
         let file = File::create(path)?;
         let writer = BufWriter::new(file);
@@ -209,15 +835,289 @@ impl Config {
         Ok(())
     }
 
+    /// Returns the local AE registered under `name`
+    pub(crate) fn local_ae(&self, name: &str) -> Option<&LocalAe> {
+        self.local_aes.get(name)
+    }
+
+    /// Returns the remote AE registered under `name`
+    pub(crate) fn remote_ae(&self, name: &str) -> Option<&RemoteAe> {
+        self.remote_aes.get(name)
+    }
+
+    /// Returns a human readable list of the problems found in the config,
+    /// an empty list means the config can be run.
+    pub(crate) fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, local_ae) in self.local_aes.iter() {
+            problems.extend(
+                local_ae
+                    .validate()
+                    .into_iter()
+                    .map(|p| format!("Local AE {}: {}", name, p)),
+            );
+        }
+        for (name, remote_ae) in self.remote_aes.iter() {
+            problems.extend(
+                remote_ae
+                    .validate()
+                    .into_iter()
+                    .map(|p| format!("Remote AE {}: {}", name, p)),
+            );
+        }
+        let mut ports: HashMap<u16, &str> = HashMap::new();
+        let mut names: HashMap<&str, u64> = HashMap::new();
+        for (id, channel) in self.channels.iter() {
+            if channel.name.is_empty() {
+                problems.push(format!("Channel {} has an empty name", id));
+            }
+            if let Some(other) = names.insert(&channel.name, *id) {
+                problems.push(format!(
+                    "Channels {} and {} are both named {}",
+                    other, id, channel.name
+                ));
+            }
+            problems.extend(
+                channel
+                    .pre
+                    .validate()
+                    .into_iter()
+                    .map(|p| format!("Channel {}: {}", channel.name, p)),
+            );
+            if channel
+                .validation
+                .as_ref()
+                .is_some_and(|validation| validation.policy == ValidationPolicy::Quarantine)
+                && channel.quarantine_dir.is_none()
+            {
+                problems.push(format!(
+                    "Channel {} quarantines the nonconformant instances without a quarantine_dir",
+                    channel.name
+                ));
+            }
+            if channel
+                .charset
+                .as_ref()
+                .is_some_and(|charset| charset.unmappable == ValidationPolicy::Quarantine)
+                && channel.quarantine_dir.is_none()
+            {
+                problems.push(format!(
+                    "Channel {} quarantines the instances with unmappable characters without a quarantine_dir",
+                    channel.name
+                ));
+            }
+            if let Some(reconciliation) = &channel.reconciliation {
+                if let Err(e) = reconciliation::check(reconciliation) {
+                    problems.push(format!("Channel {}: {:#}", channel.name, e));
+                }
+                if channel.quarantine_dir.is_none() {
+                    problems.push(format!(
+                        "Channel {} reconciles the patients without a quarantine_dir",
+                        channel.name
+                    ));
+                }
+            }
+            if let Some(template) = channel
+                .storage
+                .as_ref()
+                .and_then(|storage| storage.path_template.as_ref())
+            {
+                if let Err(e) = PathTemplate::new(template) {
+                    problems.push(format!("Channel {}: {:#}", channel.name, e));
+                }
+            }
+            if let Some(storage) = &channel.storage {
+                if storage.retention != RetentionPolicy::Never && !storage.index {
+                    problems.push(format!(
+                        "Channel {} has a retention policy without an index",
+                        channel.name
+                    ));
+                }
+                if let Some(s3) = &storage.s3 {
+                    if storage.checksums {
+                        problems.push(format!(
+                            "Channel {} records checksums, only a local storage has them",
+                            channel.name
+                        ));
+                    }
+                    if storage.min_free_mb.is_some()
+                        || matches!(storage.retention, RetentionPolicy::DiskUsage { .. })
+                    {
+                        problems.push(format!(
+                            "Channel {} watches the disk space of an S3 storage",
+                            channel.name
+                        ));
+                    }
+                    if s3.part_size_mb < MIN_PART_SIZE_MB {
+                        problems.push(format!(
+                            "Channel {} uploads parts of {} MB, S3 needs at least {} MB",
+                            channel.name, s3.part_size_mb, MIN_PART_SIZE_MB
+                        ));
+                    }
+                }
+                if let RetentionPolicy::DiskUsage { max_percent } = storage.retention {
+                    if !(1..100).contains(&max_percent) {
+                        problems.push(format!(
+                            "Channel {} has a disk usage retention of {}%, it must be between 1 and 99",
+                            channel.name, max_percent
+                        ));
+                    }
+                }
+            }
+            if let Some(processor) = &channel.processor {
+                if processor.program.is_empty() {
+                    problems.push(format!(
+                        "Channel {} has a processor without a program",
+                        channel.name
+                    ));
+                }
+            }
+            for destination in channel.destinations.iter() {
+                problems.extend(destination.post.validate().into_iter().map(|p| {
+                    format!(
+                        "Channel {}, destination {}: {}",
+                        channel.name, destination, p
+                    )
+                }));
+                if let DestinationKind::Folder {
+                    path_template: Some(template),
+                    ..
+                } = &destination.kind
+                {
+                    if let Err(e) = PathTemplate::new(template) {
+                        problems.push(format!(
+                            "Channel {}, destination {}: {:#}",
+                            channel.name, destination, e
+                        ));
+                    }
+                }
+                if let Err(e) = InstanceFilter::new(&destination.drop) {
+                    problems.push(format!(
+                        "Channel {}, destination {}: {:#}",
+                        channel.name, destination, e
+                    ));
+                }
+                match &destination.kind {
+                    DestinationKind::Dicom { remote_ae } => {
+                        if self.remote_ae(remote_ae).is_none() {
+                            problems.push(format!(
+                                "Channel {} references the unknown remote AE {}",
+                                channel.name, remote_ae
+                            ));
+                        }
+                    }
+                    DestinationKind::StowRs { url, .. } => {
+                        if !url.starts_with("http://") && !url.starts_with("https://") {
+                            problems.push(format!(
+                                "Channel {} posts to {} which is not an HTTP URL",
+                                channel.name, url
+                            ));
+                        }
+                    }
+                    DestinationKind::Command { program, .. } => {
+                        if program.is_empty() {
+                            problems.push(format!(
+                                "Channel {} has a command destination without a program",
+                                channel.name
+                            ));
+                        }
+                    }
+                    DestinationKind::Folder { .. } | DestinationKind::ZipArchive { .. } => {}
+                }
+            }
+            match &channel.source {
+                SourceConfig::Dicom { local_ae } => {
+                    let Some(source) = self.local_ae(local_ae) else {
+                        problems.push(format!(
+                            "Channel {} references the unknown local AE {}",
+                            channel.name, local_ae
+                        ));
+                        continue;
+                    };
+                    if let Some(other) = ports.insert(source.port, &channel.name) {
+                        problems.push(format!(
+                            "Channels {} and {} both listen on port {}",
+                            other, channel.name, source.port
+                        ));
+                    }
+                }
+                SourceConfig::HotFolder { path, .. } => {
+                    if !path.is_dir() {
+                        problems.push(format!(
+                            "Channel {} watches {} which is not a directory",
+                            channel.name,
+                            path.display()
+                        ));
+                    }
+                }
+                SourceConfig::StowRs { bind, .. } => {
+                    if bind.to_socket_addrs().is_err() {
+                        problems.push(format!(
+                            "Channel {} cannot listen on {}",
+                            channel.name, bind
+                        ));
+                    }
+                }
+                SourceConfig::Channel { channel: upstream } => {
+                    if self.channel_by_name(upstream).is_none() {
+                        problems.push(format!(
+                            "Channel {} is fed by the unknown channel {}",
+                            channel.name, upstream
+                        ));
+                    } else if self.feeds_itself(channel) {
+                        problems.push(format!(
+                            "Channel {} is part of a loop of channels",
+                            channel.name
+                        ));
+                    }
+                }
+            }
+        }
+        problems
+    }
+
+    /// Returns the channel named `name`
+    pub(crate) fn channel_by_name(&self, name: &str) -> Option<&Channel> {
+        self.channels.values().find(|channel| channel.name == name)
+    }
+
+    /// Whether following the channel sources upstream leads back to
+    /// the given channel
+    fn feeds_itself(&self, channel: &Channel) -> bool {
+        let mut current = channel;
+        for _ in 0..self.channels.len() {
+            let SourceConfig::Channel { channel: upstream } = &current.source else {
+                return false;
+            };
+            match self.channel_by_name(upstream) {
+                Some(upstream) if upstream.name == channel.name => return true,
+                Some(upstream) => current = upstream,
+                None => return false,
+            }
+        }
+        false
+    }
+
     /// Returns a list of actions that need to be performed.
     /// It is important to use this method on the config passing
     /// the state and not the other way around.
+    /// A channel is modified when its own definition or the definition
+    /// of one of the AEs it references has changed.
     pub(crate) fn diff(&self, config: &Config) -> Vec<Actions<Channel>> {
         let mut actions = Vec::new();
         for (id, channel) in self.channels.iter() {
             if let Some(other_channel) = config.channels.get(id) {
-                if channel != other_channel {
-                    actions.push(Actions::Modify(channel.clone()));
+                let aes_changed = other_channel
+                    .source
+                    .local_ae()
+                    .is_some_and(|name| self.local_ae(name) != config.local_ae(name))
+                    || other_channel
+                        .destinations
+                        .iter()
+                        .filter_map(DestinationConfig::remote_ae)
+                        .any(|name| self.remote_ae(name) != config.remote_ae(name));
+                if channel != other_channel || aes_changed {
+                    actions.push(Actions::Modify(channel.clone(), other_channel.clone()));
                 }
             } else {
                 actions.push(Actions::Delete(channel.clone()));
@@ -235,7 +1135,8 @@ impl Config {
 #[derive(Debug)]
 pub(crate) enum Actions<T> {
     Create(T),
-    Modify(T),
+    /// The previous and the new version
+    Modify(T, T),
     Delete(T),
 }
 
@@ -278,3 +1179,12 @@ pub(crate) static ABSTRACT_SYNTAXES: &[&str] = &[
     "1.2.840.10008.5.1.4.1.1.88.33",  // Comprehensive SR Storage
     "1.2.840.10008.1.1",              // Verification SOP Class
 ];
+
+/// The AET eai-rs calls remote nodes with when none is configured
+pub(crate) const DEFAULT_CALLING_AET: &str = "EAI-RS";
+
+/// Returns the length of a command element value once padded to an
+/// even number of bytes, as the encoder will write it.
+pub(crate) fn even_len(value: &str) -> i32 {
+    (value.len() as i32 + 1) & !1
+}
@@ -0,0 +1,741 @@
+//! Checks of the instances against the modules of their IOD (PS3.3)
+//! and the value representations of PS3.5.
+
+use std::{fs, path::PathBuf, sync::OnceLock};
+
+use chrono::NaiveDate;
+use color_eyre::eyre::Context;
+use dicom::{
+    core::{dictionary::DictionaryEntry, header::Header, DataDictionary, Tag, VR},
+    dictionary_std::{tags, StandardDataDictionary},
+    object::InMemDicomObject,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    storage::sanitize,
+    utils::{ValidationConfig, ValidationPolicy},
+};
+
+/// The requirement type of an attribute in a module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Requirement {
+    /// Present with a value
+    Type1,
+    /// Present, possibly empty
+    Type2,
+}
+
+/// The number of values an attribute has
+#[derive(Debug, Clone, Copy)]
+enum Vm {
+    Exactly(usize),
+    AtLeast(usize),
+    /// Not checked, for the binary values
+    Any,
+}
+
+use Requirement::*;
+use Vm::*;
+
+type Module = &'static [(Tag, Requirement, Vm)];
+
+static PATIENT: Module = &[
+    (tags::PATIENT_NAME, Type2, Exactly(1)),
+    (tags::PATIENT_ID, Type2, Exactly(1)),
+    (tags::PATIENT_BIRTH_DATE, Type2, Exactly(1)),
+    (tags::PATIENT_SEX, Type2, Exactly(1)),
+];
+
+static GENERAL_STUDY: Module = &[
+    (tags::STUDY_INSTANCE_UID, Type1, Exactly(1)),
+    (tags::STUDY_DATE, Type2, Exactly(1)),
+    (tags::STUDY_TIME, Type2, Exactly(1)),
+    (tags::REFERRING_PHYSICIAN_NAME, Type2, Exactly(1)),
+    (tags::STUDY_ID, Type2, Exactly(1)),
+    (tags::ACCESSION_NUMBER, Type2, Exactly(1)),
+];
+
+static GENERAL_SERIES: Module = &[
+    (tags::MODALITY, Type1, Exactly(1)),
+    (tags::SERIES_INSTANCE_UID, Type1, Exactly(1)),
+    (tags::SERIES_NUMBER, Type2, Exactly(1)),
+];
+
+static FRAME_OF_REFERENCE: Module = &[
+    (tags::FRAME_OF_REFERENCE_UID, Type1, Exactly(1)),
+    (tags::POSITION_REFERENCE_INDICATOR, Type2, Exactly(1)),
+];
+
+static GENERAL_EQUIPMENT: Module = &[(tags::MANUFACTURER, Type2, Exactly(1))];
+
+static SC_EQUIPMENT: Module = &[(tags::CONVERSION_TYPE, Type1, Exactly(1))];
+
+static GENERAL_IMAGE: Module = &[(tags::INSTANCE_NUMBER, Type2, Exactly(1))];
+
+static IMAGE_PLANE: Module = &[
+    (tags::PIXEL_SPACING, Type1, Exactly(2)),
+    (tags::IMAGE_ORIENTATION_PATIENT, Type1, Exactly(6)),
+    (tags::IMAGE_POSITION_PATIENT, Type1, Exactly(3)),
+    (tags::SLICE_THICKNESS, Type2, Exactly(1)),
+];
+
+static IMAGE_PIXEL: Module = &[
+    (tags::SAMPLES_PER_PIXEL, Type1, Exactly(1)),
+    (tags::PHOTOMETRIC_INTERPRETATION, Type1, Exactly(1)),
+    (tags::ROWS, Type1, Exactly(1)),
+    (tags::COLUMNS, Type1, Exactly(1)),
+    (tags::BITS_ALLOCATED, Type1, Exactly(1)),
+    (tags::BITS_STORED, Type1, Exactly(1)),
+    (tags::HIGH_BIT, Type1, Exactly(1)),
+    (tags::PIXEL_REPRESENTATION, Type1, Exactly(1)),
+    (tags::PIXEL_DATA, Type1, Any),
+];
+
+static CT_IMAGE: Module = &[
+    (tags::IMAGE_TYPE, Type1, AtLeast(2)),
+    (tags::KVP, Type2, Exactly(1)),
+    (tags::ACQUISITION_NUMBER, Type2, Exactly(1)),
+    (tags::RESCALE_INTERCEPT, Type1, Exactly(1)),
+    (tags::RESCALE_SLOPE, Type1, Exactly(1)),
+];
+
+static MR_IMAGE: Module = &[
+    (tags::IMAGE_TYPE, Type1, AtLeast(2)),
+    (tags::SCANNING_SEQUENCE, Type1, AtLeast(1)),
+    (tags::SEQUENCE_VARIANT, Type1, AtLeast(1)),
+    (tags::SCAN_OPTIONS, Type2, AtLeast(1)),
+    (tags::MR_ACQUISITION_TYPE, Type2, Exactly(1)),
+    (tags::ECHO_TIME, Type2, Exactly(1)),
+    (tags::ECHO_TRAIN_LENGTH, Type2, Exactly(1)),
+];
+
+static CR_SERIES: Module = &[
+    (tags::BODY_PART_EXAMINED, Type2, Exactly(1)),
+    (tags::VIEW_POSITION, Type2, Exactly(1)),
+];
+
+static DX_IMAGE: Module = &[(tags::IMAGE_TYPE, Type1, AtLeast(2))];
+
+static PET_SERIES: Module = &[
+    (tags::SERIES_DATE, Type1, Exactly(1)),
+    (tags::SERIES_TIME, Type1, Exactly(1)),
+    (tags::UNITS, Type1, Exactly(1)),
+    (tags::CORRECTED_IMAGE, Type2, AtLeast(1)),
+    (tags::DECAY_CORRECTION, Type1, Exactly(1)),
+];
+
+static PET_IMAGE: Module = &[
+    (tags::IMAGE_TYPE, Type1, AtLeast(2)),
+    (tags::RESCALE_INTERCEPT, Type1, Exactly(1)),
+    (tags::RESCALE_SLOPE, Type1, Exactly(1)),
+];
+
+static SOP_COMMON: Module = &[
+    (tags::SOP_CLASS_UID, Type1, Exactly(1)),
+    (tags::SOP_INSTANCE_UID, Type1, Exactly(1)),
+];
+
+/// The IODs checked, by SOP Class UID, with their mandatory modules
+#[rustfmt::skip]
+static IODS: &[(&str, &str, &[Module])] = &[
+    (
+        "1.2.840.10008.5.1.4.1.1.2",
+        "CT Image",
+        &[PATIENT, GENERAL_STUDY, GENERAL_SERIES, FRAME_OF_REFERENCE, GENERAL_EQUIPMENT,
+          GENERAL_IMAGE, IMAGE_PLANE, IMAGE_PIXEL, CT_IMAGE, SOP_COMMON],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.4",
+        "MR Image",
+        &[PATIENT, GENERAL_STUDY, GENERAL_SERIES, FRAME_OF_REFERENCE, GENERAL_EQUIPMENT,
+          GENERAL_IMAGE, IMAGE_PLANE, IMAGE_PIXEL, MR_IMAGE, SOP_COMMON],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.1",
+        "Computed Radiography Image",
+        &[PATIENT, GENERAL_STUDY, GENERAL_SERIES, CR_SERIES, GENERAL_EQUIPMENT, GENERAL_IMAGE,
+          IMAGE_PIXEL, SOP_COMMON],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.1.1",
+        "Digital X-Ray Image - For Presentation",
+        &[PATIENT, GENERAL_STUDY, GENERAL_SERIES, GENERAL_EQUIPMENT, GENERAL_IMAGE, IMAGE_PIXEL,
+          DX_IMAGE, SOP_COMMON],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.1.1.1",
+        "Digital X-Ray Image - For Processing",
+        &[PATIENT, GENERAL_STUDY, GENERAL_SERIES, GENERAL_EQUIPMENT, GENERAL_IMAGE, IMAGE_PIXEL,
+          DX_IMAGE, SOP_COMMON],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.7",
+        "Secondary Capture Image",
+        &[PATIENT, GENERAL_STUDY, GENERAL_SERIES, GENERAL_EQUIPMENT, SC_EQUIPMENT, GENERAL_IMAGE,
+          IMAGE_PIXEL, SOP_COMMON],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.6.1",
+        "Ultrasound Image",
+        &[PATIENT, GENERAL_STUDY, GENERAL_SERIES, GENERAL_EQUIPMENT, GENERAL_IMAGE, IMAGE_PIXEL,
+          SOP_COMMON],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.3.1",
+        "Ultrasound Multi-frame Image",
+        &[PATIENT, GENERAL_STUDY, GENERAL_SERIES, GENERAL_EQUIPMENT, GENERAL_IMAGE, IMAGE_PIXEL,
+          SOP_COMMON],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.20",
+        "Nuclear Medicine Image",
+        &[PATIENT, GENERAL_STUDY, GENERAL_SERIES, FRAME_OF_REFERENCE, GENERAL_EQUIPMENT,
+          GENERAL_IMAGE, IMAGE_PIXEL, SOP_COMMON],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.128",
+        "Positron Emission Tomography Image",
+        &[PATIENT, GENERAL_STUDY, GENERAL_SERIES, PET_SERIES, FRAME_OF_REFERENCE,
+          GENERAL_EQUIPMENT, GENERAL_IMAGE, IMAGE_PLANE, IMAGE_PIXEL, PET_IMAGE, SOP_COMMON],
+    ),
+    (
+        "1.2.840.10008.5.1.4.1.1.12.1",
+        "X-Ray Angiographic Image",
+        &[PATIENT, GENERAL_STUDY, GENERAL_SERIES, GENERAL_EQUIPMENT, GENERAL_IMAGE, IMAGE_PIXEL,
+          SOP_COMMON],
+    ),
+];
+
+/// What is wrong with an attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ProblemKind {
+    MissingType1,
+    EmptyType1,
+    MissingType2,
+    WrongVr,
+    WrongVm,
+    InvalidValue,
+    InvalidUid,
+}
+
+/// A nonconformance of an instance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Problem {
+    /// The attribute, with the sequence items leading to it
+    pub(crate) tag: String,
+    /// The keyword of the attribute, when it is a standard one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) keyword: Option<String>,
+    pub(crate) kind: ProblemKind,
+    pub(crate) message: String,
+}
+
+/// The result of the validation of an instance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ValidationReport {
+    pub(crate) sop_class_uid: String,
+    pub(crate) sop_instance_uid: String,
+    /// The IOD the instance was checked against, none when eai-rs does
+    /// not know the modules of its SOP class and only checked the values
+    pub(crate) iod: Option<String>,
+    pub(crate) problems: Vec<Problem>,
+}
+
+impl ValidationReport {
+    /// A one line summary, for the logs and the quarantine
+    pub(crate) fn summary(&self) -> String {
+        let iod = self.iod.as_deref().unwrap_or("its SOP class");
+        match self.problems.first() {
+            Some(first) if self.problems.len() == 1 => {
+                format!(
+                    "{} does not conform to {}: {}",
+                    self.sop_instance_uid, iod, first.message
+                )
+            }
+            Some(first) => format!(
+                "{} does not conform to {}: {} and {} other problem(s)",
+                self.sop_instance_uid,
+                iod,
+                first.message,
+                self.problems.len() - 1
+            ),
+            None => format!("{} conforms to {}", self.sop_instance_uid, iod),
+        }
+    }
+}
+
+/// Checks the instances of a channel and tells what to do with the
+/// ones that do not conform
+pub(crate) struct Validator {
+    pub(crate) policy: ValidationPolicy,
+    report_dir: Option<PathBuf>,
+}
+
+impl Validator {
+    pub(crate) fn new(config: &ValidationConfig) -> Self {
+        Self {
+            policy: config.policy,
+            report_dir: config.report_dir.clone(),
+        }
+    }
+
+    pub(crate) fn validate(&self, object: &InMemDicomObject) -> ValidationReport {
+        validate(object)
+    }
+
+    /// Writes the report as `<SOPInstanceUID>.json` in the report
+    /// directory, if there is one, the UID made safe for a file name
+    pub(crate) fn write_report(&self, report: &ValidationReport) -> color_eyre::Result<()> {
+        let Some(dir) = &self.report_dir else {
+            return Ok(());
+        };
+        fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Could not create the directory {}", dir.display()))?;
+        let path = dir.join(sanitize(&report.sop_instance_uid) + ".json");
+        let json = serde_json::to_string_pretty(report)?;
+        fs::write(&path, json).wrap_err_with(|| format!("Could not write {}", path.display()))
+    }
+}
+
+/// Checks the instance against the modules of its IOD, and the values
+/// of all its attributes against their VR
+pub(crate) fn validate(object: &InMemDicomObject) -> ValidationReport {
+    let text = |tag| {
+        object
+            .element(tag)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|text| text.trim_end_matches(['\0', ' ']).to_string())
+            .unwrap_or_default()
+    };
+    let sop_class_uid = text(tags::SOP_CLASS_UID);
+    let mut report = ValidationReport {
+        sop_instance_uid: text(tags::SOP_INSTANCE_UID),
+        iod: None,
+        problems: Vec::new(),
+        sop_class_uid,
+    };
+    if let Some((_, name, modules)) = IODS.iter().find(|(uid, ..)| *uid == report.sop_class_uid) {
+        report.iod = Some(name.to_string());
+        let mut checked = Vec::new();
+        for (tag, requirement, vm) in modules.iter().flat_map(|module| module.iter()) {
+            // Modules share some attributes
+            if checked.contains(tag) {
+                continue;
+            }
+            checked.push(*tag);
+            check_attribute(object, *tag, *requirement, *vm, &mut report.problems);
+        }
+    }
+    check_values(object, "", &mut report.problems);
+    report
+}
+
+fn keyword(tag: Tag) -> Option<String> {
+    StandardDataDictionary
+        .by_tag(tag)
+        .map(|entry| entry.alias().to_string())
+}
+
+fn problem(path: &str, tag: Tag, kind: ProblemKind, message: String) -> Problem {
+    Problem {
+        tag: format!("{}{}", path, tag),
+        keyword: keyword(tag),
+        kind,
+        message,
+    }
+}
+
+fn check_attribute(
+    object: &InMemDicomObject,
+    tag: Tag,
+    requirement: Requirement,
+    vm: Vm,
+    problems: &mut Vec<Problem>,
+) {
+    let name = keyword(tag).unwrap_or_else(|| tag.to_string());
+    let Ok(Some(element)) = object.element_opt(tag) else {
+        let (kind, requirement) = match requirement {
+            Type1 => (ProblemKind::MissingType1, 1),
+            Type2 => (ProblemKind::MissingType2, 2),
+        };
+        problems.push(problem(
+            "",
+            tag,
+            kind,
+            format!("{} is missing (Type {})", name, requirement),
+        ));
+        return;
+    };
+    let multiplicity = element.value().multiplicity() as usize;
+    let empty = multiplicity == 0
+        || element
+            .to_str()
+            .is_ok_and(|text| text.trim_end_matches(['\0', ' ']).is_empty());
+    if empty {
+        if requirement == Type1 {
+            problems.push(problem(
+                "",
+                tag,
+                ProblemKind::EmptyType1,
+                format!("{} is empty (Type 1)", name),
+            ));
+        }
+        return;
+    }
+
+    let vr = element.vr();
+    let expected_vr = StandardDataDictionary.by_tag(tag).map(|entry| entry.vr());
+    let vr_matches = match expected_vr {
+        _ if vr == VR::UN => true,
+        // Pixel data is OB or OW depending on the transfer syntax
+        _ if tag == tags::PIXEL_DATA => vr == VR::OB || vr == VR::OW,
+        Some(expected) => vr == expected,
+        None => true,
+    };
+    if !vr_matches {
+        problems.push(problem(
+            "",
+            tag,
+            ProblemKind::WrongVr,
+            format!(
+                "{} has the VR {:?} instead of {:?}",
+                name,
+                vr,
+                expected_vr.unwrap_or(vr)
+            ),
+        ));
+    }
+    let (vm_matches, expected) = match vm {
+        Exactly(n) => (multiplicity == n, n.to_string()),
+        AtLeast(n) => (multiplicity >= n, format!("{}-n", n)),
+        Any => (true, String::new()),
+    };
+    if !vm_matches {
+        problems.push(problem(
+            "",
+            tag,
+            ProblemKind::WrongVm,
+            format!(
+                "{} has {} value(s) instead of {}",
+                name, multiplicity, expected
+            ),
+        ));
+    }
+}
+
+/// Checks the values of every attribute against their VR, in the
+/// sequences as well
+fn check_values(object: &InMemDicomObject, path: &str, problems: &mut Vec<Problem>) {
+    for element in object.iter() {
+        let tag = element.tag();
+        if let Some(items) = element.items() {
+            for (index, item) in items.iter().enumerate() {
+                check_values(item, &format!("{}{}[{}].", path, tag, index), problems);
+            }
+            continue;
+        }
+        let Ok(values) = element.to_multi_str() else {
+            continue;
+        };
+        for value in values.iter() {
+            let value = value.trim_end_matches(['\0', ' ']);
+            if value.is_empty() {
+                continue;
+            }
+            if let Some(error) = check_value(element.vr(), value) {
+                let kind = if element.vr() == VR::UI {
+                    ProblemKind::InvalidUid
+                } else {
+                    ProblemKind::InvalidValue
+                };
+                let name = keyword(tag).unwrap_or_else(|| tag.to_string());
+                problems.push(problem(
+                    path,
+                    tag,
+                    kind,
+                    format!("{} {:?} {}", name, value, error),
+                ));
+            }
+        }
+    }
+}
+
+fn regex(cell: &'static OnceLock<Regex>, pattern: &str) -> &'static Regex {
+    cell.get_or_init(|| Regex::new(pattern).unwrap())
+}
+
+/// What is wrong with a value of the VR, if anything
+fn check_value(vr: VR, value: &str) -> Option<String> {
+    static UID: OnceLock<Regex> = OnceLock::new();
+    static AGE: OnceLock<Regex> = OnceLock::new();
+    static TIME: OnceLock<Regex> = OnceLock::new();
+    static CODE: OnceLock<Regex> = OnceLock::new();
+
+    let max_length = match vr {
+        VR::AE | VR::CS | VR::SH | VR::DS => 16,
+        VR::IS => 12,
+        VR::LO | VR::UI => 64,
+        VR::AS => 4,
+        _ => usize::MAX,
+    };
+    if value.chars().count() > max_length {
+        return Some(format!("is longer than {} characters", max_length));
+    }
+    match vr {
+        VR::UI if !regex(&UID, r"^(0|[1-9][0-9]*)(\.(0|[1-9][0-9]*))*$").is_match(value) => {
+            Some("is not a valid UID".to_string())
+        }
+        VR::AS if !regex(&AGE, r"^[0-9]{3}[DWMY]$").is_match(value) => {
+            Some("is not an age such as 045Y".to_string())
+        }
+        VR::CS if !regex(&CODE, r"^[A-Z0-9 _]*$").is_match(value) => {
+            Some("has characters not allowed in a code string".to_string())
+        }
+        VR::DA if value.len() != 8 || NaiveDate::parse_from_str(value, "%Y%m%d").is_err() => {
+            Some("is not a date as YYYYMMDD".to_string())
+        }
+        VR::TM
+            if !regex(
+                &TIME,
+                r"^([01][0-9]|2[0-3])([0-5][0-9]([0-5][0-9](\.[0-9]{1,6})?)?)?$",
+            )
+            .is_match(value) =>
+        {
+            Some("is not a time as HHMMSS.FFFFFF".to_string())
+        }
+        VR::DS if value.trim().parse::<f64>().is_err() => Some("is not a decimal".to_string()),
+        VR::IS if value.trim().parse::<i32>().is_err() => Some("is not an integer".to_string()),
+        VR::PN if value.split('=').any(|group| group.chars().count() > 64) => {
+            Some("has a component group longer than 64 characters".to_string())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use dicom::{
+        core::{value::Value, DataElement, Length, PrimitiveValue},
+        dicom_value,
+        object::mem::InMemElement,
+    };
+
+    use super::*;
+
+    const SECONDARY_CAPTURE: &str = "1.2.840.10008.5.1.4.1.1.7";
+
+    fn string(tag: Tag, vr: VR, value: &str) -> InMemElement {
+        DataElement::new(tag, vr, dicom_value!(Str, value))
+    }
+
+    fn empty(tag: Tag, vr: VR) -> InMemElement {
+        DataElement::new(tag, vr, PrimitiveValue::Empty)
+    }
+
+    /// A Secondary Capture Image with all the attributes of its modules
+    fn secondary_capture() -> InMemDicomObject {
+        InMemDicomObject::from_element_iter([
+            string(tags::SOP_CLASS_UID, VR::UI, SECONDARY_CAPTURE),
+            string(tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4.5.1"),
+            string(tags::STUDY_DATE, VR::DA, "20240102"),
+            string(tags::STUDY_TIME, VR::TM, "101530.25"),
+            empty(tags::ACCESSION_NUMBER, VR::SH),
+            string(tags::MODALITY, VR::CS, "OT"),
+            string(tags::CONVERSION_TYPE, VR::CS, "WSD"),
+            empty(tags::MANUFACTURER, VR::LO),
+            empty(tags::REFERRING_PHYSICIAN_NAME, VR::PN),
+            string(tags::PATIENT_NAME, VR::PN, "Doe^John"),
+            string(tags::PATIENT_ID, VR::LO, "PID001"),
+            string(tags::PATIENT_BIRTH_DATE, VR::DA, "19700101"),
+            string(tags::PATIENT_SEX, VR::CS, "M"),
+            string(tags::STUDY_INSTANCE_UID, VR::UI, "1.2.3.4"),
+            string(tags::SERIES_INSTANCE_UID, VR::UI, "1.2.3.4.5"),
+            string(tags::STUDY_ID, VR::SH, "1"),
+            string(tags::SERIES_NUMBER, VR::IS, "1"),
+            string(tags::INSTANCE_NUMBER, VR::IS, "1"),
+            DataElement::new(tags::SAMPLES_PER_PIXEL, VR::US, dicom_value!(U16, 1)),
+            string(tags::PHOTOMETRIC_INTERPRETATION, VR::CS, "MONOCHROME2"),
+            DataElement::new(tags::ROWS, VR::US, dicom_value!(U16, 2)),
+            DataElement::new(tags::COLUMNS, VR::US, dicom_value!(U16, 2)),
+            DataElement::new(tags::BITS_ALLOCATED, VR::US, dicom_value!(U16, 8)),
+            DataElement::new(tags::BITS_STORED, VR::US, dicom_value!(U16, 8)),
+            DataElement::new(tags::HIGH_BIT, VR::US, dicom_value!(U16, 7)),
+            DataElement::new(tags::PIXEL_REPRESENTATION, VR::US, dicom_value!(U16, 0)),
+            DataElement::new(tags::PIXEL_DATA, VR::OB, dicom_value!(U8, [0, 1, 2, 3])),
+        ])
+    }
+
+    fn kinds(report: &ValidationReport) -> Vec<(String, ProblemKind)> {
+        report
+            .problems
+            .iter()
+            .map(|problem| (problem.tag.clone(), problem.kind))
+            .collect()
+    }
+
+    #[test]
+    fn conformant_instances_have_no_problems() {
+        let report = validate(&secondary_capture());
+        assert_eq!(report.iod.as_deref(), Some("Secondary Capture Image"));
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(
+            report.summary(),
+            "1.2.3.4.5.1 conforms to Secondary Capture Image"
+        );
+    }
+
+    #[test]
+    fn reports_the_missing_and_empty_attributes() {
+        let mut object = secondary_capture();
+        object.remove_element(tags::STUDY_INSTANCE_UID);
+        object.put(empty(tags::MODALITY, VR::CS));
+        object.remove_element(tags::PATIENT_SEX);
+        // Type 2 may be empty
+        object.put(empty(tags::PATIENT_BIRTH_DATE, VR::DA));
+
+        let report = validate(&object);
+        assert_eq!(
+            kinds(&report),
+            [
+                ("(0010,0040)".to_string(), ProblemKind::MissingType2),
+                ("(0020,000D)".to_string(), ProblemKind::MissingType1),
+                ("(0008,0060)".to_string(), ProblemKind::EmptyType1),
+            ]
+        );
+        assert_eq!(
+            report.problems[1].keyword.as_deref(),
+            Some("StudyInstanceUID")
+        );
+        assert_eq!(
+            report.summary(),
+            "1.2.3.4.5.1 does not conform to Secondary Capture Image: \
+            PatientSex is missing (Type 2) and 2 other problem(s)"
+        );
+    }
+
+    #[test]
+    fn reports_the_wrong_vrs_and_multiplicities() {
+        let mut object = secondary_capture();
+        object.put(string(tags::CONVERSION_TYPE, VR::LO, "WSD"));
+        object.put(DataElement::new(
+            tags::PATIENT_NAME,
+            VR::PN,
+            dicom_value!(Strs, ["Doe^John", "Doe^J"]),
+        ));
+        // The pixel data may be OW
+        object.put(DataElement::new(
+            tags::PIXEL_DATA,
+            VR::OW,
+            dicom_value!(U16, [0, 1]),
+        ));
+
+        let report = validate(&object);
+        assert_eq!(
+            kinds(&report),
+            [
+                ("(0010,0010)".to_string(), ProblemKind::WrongVm),
+                ("(0008,0064)".to_string(), ProblemKind::WrongVr),
+            ]
+        );
+        assert_eq!(
+            report.problems[0].message,
+            "PatientName has 2 value(s) instead of 1"
+        );
+        assert_eq!(
+            report.problems[1].message,
+            "ConversionType has the VR LO instead of CS"
+        );
+    }
+
+    #[test]
+    fn checks_the_uids_and_values_in_the_sequences_as_well() {
+        let mut object = secondary_capture();
+        object.put(string(tags::SOP_INSTANCE_UID, VR::UI, "1.2.03.4"));
+        object.put(string(tags::STUDY_DATE, VR::DA, "20241301"));
+        object.put(string(tags::PATIENT_SEX, VR::CS, "m"));
+        object.put(DataElement::new(
+            tags::REFERENCED_SERIES_SEQUENCE,
+            VR::SQ,
+            Value::Sequence {
+                items: vec![InMemDicomObject::from_element_iter([string(
+                    tags::SERIES_INSTANCE_UID,
+                    VR::UI,
+                    "1.2.x",
+                )])]
+                .into(),
+                size: Length::UNDEFINED,
+            },
+        ));
+
+        let report = validate(&object);
+        assert_eq!(
+            kinds(&report),
+            [
+                ("(0008,0018)".to_string(), ProblemKind::InvalidUid),
+                ("(0008,0020)".to_string(), ProblemKind::InvalidValue),
+                (
+                    "(0008,1115)[0].(0020,000E)".to_string(),
+                    ProblemKind::InvalidUid
+                ),
+                ("(0010,0040)".to_string(), ProblemKind::InvalidValue),
+            ]
+        );
+        assert_eq!(
+            report.problems[0].message,
+            "SOPInstanceUID \"1.2.03.4\" is not a valid UID"
+        );
+
+        assert_eq!(check_value(VR::UI, "1.2.840.10008.1.2.1"), None);
+        assert!(check_value(VR::UI, &format!("1.{}", "2".repeat(63))).is_some());
+        assert!(check_value(VR::UI, "1..2").is_some());
+        assert_eq!(check_value(VR::TM, "2359"), None);
+        assert!(check_value(VR::TM, "2460").is_some());
+        assert_eq!(check_value(VR::AS, "045Y"), None);
+        assert!(check_value(VR::IS, "1.5").is_some());
+    }
+
+    #[test]
+    fn only_checks_the_values_of_unknown_sop_classes() {
+        let mut object = secondary_capture();
+        object.put(string(tags::SOP_CLASS_UID, VR::UI, "1.2.3.999"));
+        object.remove_element(tags::STUDY_INSTANCE_UID);
+        object.put(string(tags::PATIENT_BIRTH_DATE, VR::DA, "1970"));
+
+        let report = validate(&object);
+        assert_eq!(report.iod, None);
+        assert_eq!(
+            kinds(&report),
+            [("(0010,0030)".to_string(), ProblemKind::InvalidValue)]
+        );
+        assert!(report
+            .summary()
+            .contains("does not conform to its SOP class"));
+    }
+
+    #[test]
+    fn writes_the_report_named_after_the_instance() {
+        let dir = env::temp_dir().join(format!("eai-rs-reports-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let validator = Validator::new(&ValidationConfig {
+            policy: ValidationPolicy::Quarantine,
+            report_dir: Some(dir.clone()),
+        });
+        let mut object = secondary_capture();
+        object.remove_element(tags::MODALITY);
+        let report = validator.validate(&object);
+        validator.write_report(&report).unwrap();
+
+        let written: ValidationReport =
+            serde_json::from_slice(&fs::read(dir.join("1.2.3.4.5.1.json")).unwrap()).unwrap();
+        assert_eq!(written.sop_class_uid, SECONDARY_CAPTURE);
+        assert_eq!(kinds(&written), kinds(&report));
+        let json = fs::read_to_string(dir.join("1.2.3.4.5.1.json")).unwrap();
+        assert!(json.contains("\"kind\": \"missing_type1\""));
+    }
+}
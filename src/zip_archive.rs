@@ -0,0 +1,79 @@
+use std::{
+    fs::{self, OpenOptions},
+    path::PathBuf,
+};
+
+use color_eyre::eyre::Context;
+use dicom::dictionary_std::tags;
+use tracing::debug;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{destination::Destination, pipeline::Instance, storage::sanitize};
+
+/// A destination adding the instances to a zip archive per study,
+/// named after its StudyInstanceUID
+pub(crate) struct ZipArchive {
+    path: PathBuf,
+}
+
+impl ZipArchive {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Destination for ZipArchive {
+    fn send(&mut self, instance: &Instance) -> color_eyre::Result<()> {
+        let study_uid = instance
+            .object
+            .element(tags::STUDY_INSTANCE_UID)
+            .ok()
+            .and_then(|e| e.to_str().ok())
+            .map(|uid| uid.trim_end_matches('\0').trim().to_string())
+            .filter(|uid| !uid.is_empty())
+            .unwrap_or_else(|| "unknown-study".to_string());
+        // The UIDs come from the sender, they must not lead out of the
+        // directory nor out of the extracted archive
+        let study_uid = sanitize(&study_uid);
+        let entry_name = sanitize(instance.sop_instance_uid()) + ".dcm";
+
+        fs::create_dir_all(&self.path)
+            .wrap_err_with(|| format!("Could not create the directory {}", self.path.display()))?;
+        let archive_path = self.path.join(study_uid + ".zip");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&archive_path)
+            .wrap_err_with(|| format!("Could not open {}", archive_path.display()))?;
+
+        let mut writer = if file.metadata().map(|m| m.len()).unwrap_or(0) == 0 {
+            ZipWriter::new(file)
+        } else {
+            let existing = zip::ZipArchive::new(&file)
+                .wrap_err_with(|| format!("{} is not a zip archive", archive_path.display()))?;
+            // Instances sent twice are only archived once
+            if existing.index_for_name(&entry_name).is_some() {
+                debug!("{} is already in {}", entry_name, archive_path.display());
+                return Ok(());
+            }
+            ZipWriter::new_append(file)
+                .wrap_err_with(|| format!("Could not append to {}", archive_path.display()))?
+        };
+        writer
+            .start_file(
+                entry_name,
+                SimpleFileOptions::default().compression_method(CompressionMethod::Deflated),
+            )
+            .wrap_err("Could not add the instance to the archive")?;
+        instance
+            .object
+            .write_all(&mut writer)
+            .wrap_err("Could not write the DICOM object")?;
+        writer
+            .finish()
+            .wrap_err_with(|| format!("Could not write {}", archive_path.display()))?;
+        Ok(())
+    }
+}